@@ -0,0 +1,49 @@
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+
+/// Runs git in the crate dir, never in a repository above it
+///
+/// I.e. when the crate is vendored into other project, the hash of that
+/// project is not interesting.
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let mut cmd = Command::new("git");
+    cmd.args(args).current_dir(dir);
+    if let Some(parent) = dir.parent() {
+        cmd.env("GIT_CEILING_DIRECTORIES", parent);
+    }
+    cmd.output().ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+}
+
+fn main() {
+    let dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR")
+        .expect("cargo sets CARGO_MANIFEST_DIR"));
+    // builds from a source tarball have no git, hash is left empty then
+    let hash = git(&dir, &["rev-parse", "--short", "HEAD"])
+        .unwrap_or_else(String::new);
+    println!("cargo:rustc-env=LITHOS_GIT_HASH={}", hash);
+    let git_dir = match git(&dir, &["rev-parse", "--git-dir"]) {
+        Some(git_dir) => dir.join(git_dir),
+        None => return,
+    };
+    // HEAD changes on checkout, commits only change the branch ref (or
+    // `packed-refs`) and the reflog
+    let head = git_dir.join("HEAD");
+    println!("cargo:rerun-if-changed={}", head.display());
+    println!("cargo:rerun-if-changed={}",
+        git_dir.join("logs/HEAD").display());
+    let head = read_to_string(&head).unwrap_or_else(|_| String::new());
+    if head.starts_with("ref: ") {
+        let path = git_dir.join(head["ref: ".len()..].trim());
+        if path.exists() {
+            println!("cargo:rerun-if-changed={}", path.display());
+        } else {
+            println!("cargo:rerun-if-changed={}",
+                git_dir.join("packed-refs").display());
+        }
+    }
+}
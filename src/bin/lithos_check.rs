@@ -4,7 +4,7 @@ extern crate ipnetwork;
 extern crate libc;
 extern crate lithos;
 extern crate quire;
-extern crate scan_dir;
+extern crate regex;
 #[macro_use] extern crate log;
 
 
@@ -16,18 +16,27 @@ use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
-use argparse::{ArgumentParser, Parse, ParseOption, StoreTrue, Print, Collect};
+use argparse::{Parse, ParseOption, StoreOption, StoreTrue, Print, Collect};
 use ipnetwork::IpNetwork;
 use quire::{parse_config, Options};
+use regex::RegexSet;
 
 use lithos::utils::{in_mapping, check_mapping, relative};
 use lithos::range::in_range;
-use lithos::master_config::MasterConfig;
+use lithos::master_config::{MasterConfig, ContainerDefaults};
+use lithos::version;
+use lithos::cli;
+use lithos::schema::{config_schema, ConfigKind};
+use lithos::generation;
 use lithos::sandbox_config::SandboxConfig;
+use lithos::sandbox_dirs;
+use lithos::host_facts::HostFacts;
 use lithos::container_config::{ContainerConfig, Variables, replace_vars};
 use lithos::container_config::{Variable::TcpPort, Activation::Systemd};
-use lithos::container_config::TcpPortSettings;
-use lithos::child_config::{ChildConfig, ChildKind};
+use lithos::container_config::{TcpPortSettings, SeccompProfile};
+use lithos::container_config::{Volume, PersistentInfo, StatedirInfo};
+use lithos::child_config::{ChildConfig, ChildKind, parse_inline_container};
+use lithos::templates::render_child;
 use lithos::network::{get_host_name, get_host_ip};
 use lithos::id_map::{IdMapExt};
 
@@ -70,6 +79,31 @@ fn check_master_config(master: &MasterConfig, verbose: bool) {
         err!("Devfs dir ({:?}) must exist and contain device nodes",
             master.devfs_dir);
     }
+    if let Some(ref bundle) = master.trust_bundle {
+        if metadata(&bundle.path).map(|m| !m.is_file()).unwrap_or(true) {
+            err!("Trust bundle {:?} must be a file", bundle.path);
+        }
+        if !bundle.mount_point.is_absolute() {
+            err!("Trust bundle mount point must be absolute");
+        }
+    }
+    if let Some(ref enc) = master.config_log_encryption {
+        if metadata(&enc.age).is_err() {
+            err!("Can't find age binary at {:?}", enc.age);
+        }
+        if metadata(&enc.gzip).is_err() {
+            err!("Can't find gzip binary at {:?}", enc.gzip);
+        }
+    }
+    match master.knot_binary() {
+        Some(ref knot) if metadata(knot).is_ok() => {
+            if let Err(e) = version::check_knot_binary(knot) {
+                err!("{}", e);
+            }
+        }
+        Some(ref knot) => err!("Can't find lithos_knot binary at {:?}", knot),
+        None => err!("Can't find lithos_knot binary"),
+    }
 }
 
 fn check_sandbox_config(sandbox: &SandboxConfig) {
@@ -79,16 +113,43 @@ fn check_sandbox_config(sandbox: &SandboxConfig) {
     if sandbox.allow_groups.len() == 0 {
         err!("No allowed groups range. Please add `allow-groups: [1-1000]`");
     }
+    if let Some(ref default) = sandbox.default_container_config {
+        if !default.is_absolute() {
+            err!("Default container config {:?} must be absolute", default);
+        }
+    }
+    if let Some(ref key) = sandbox.image_signature_key {
+        if !key.exists() {
+            err!("Image signature key {:?} doesn't exist", key);
+        }
+    }
+    if let Some(ref info) = sandbox.info_file {
+        if !info.path.is_absolute() {
+            err!("Path of info-file {:?} must be absolute", info.path);
+        }
+    }
+    if let Err(e) = RegexSet::new(&sandbox.redact_variables) {
+        err!("Invalid pattern in redact-variables: {}", e);
+    }
+    if let Some(ref bridge) = sandbox.bridged_network {
+        if let Some(pool) = bridge.ip_pool {
+            if !network_contains(&bridge.network, pool.ip()) ||
+                pool.prefix() < bridge.network.prefix()
+            {
+                err!("IP pool {} is not inside network {}",
+                    pool, bridge.network);
+            }
+        }
+    }
     // TODO(tailhook) check allow_users/allow_groups against uid_map/gid_map
 }
 
 fn check_container(config_file: &Path,
-    sandbox: Option<&SandboxConfig>,)
+    sandbox: Option<&SandboxConfig>, defaults: &ContainerDefaults)
     -> Result<ContainerConfig, ()>
 {
-    // Only checks things that can be checked without other configs
     let config: ContainerConfig = match parse_config(config_file,
-        &ContainerConfig::validator(), &Options::default())
+        &ContainerConfig::validator_with(defaults), &Options::default())
     {
         Ok(cfg) => cfg,
         Err(e) => {
@@ -96,8 +157,52 @@ fn check_container(config_file: &Path,
             return Err(());
         }
     };
+    check_container_config(config, sandbox)
+}
+
+fn check_container_config(config: ContainerConfig,
+    sandbox: Option<&SandboxConfig>)
+    -> Result<ContainerConfig, ()>
+{
+    // Only checks things that can be checked without other configs
+    validate_tcp_ports(&config);
     validate_activation(&config);
     validate_substitutions(&config);
+    if let Some(SeccompProfile::File(ref path)) = config.seccomp_profile {
+        if !path.is_absolute() {
+            err!("Seccomp profile path {:?} must be absolute", path);
+        }
+    }
+    if let Some(ref drain) = config.drain_file {
+        if !drain.path.is_absolute() {
+            err!("Drain file path {:?} must be absolute", drain.path);
+        }
+    }
+    if let Some(ref hc) = config.health_check {
+        if !(hc.interval > 0.) {
+            err!("Health check interval must be positive");
+        }
+        if hc.timeout >= hc.interval {
+            err!("Health check timeout {} must be less than interval {}",
+                hc.timeout, hc.interval);
+        }
+    }
+    if let Some(ref ready) = config.readiness {
+        if !(ready.interval > 0.) {
+            err!("Readiness check interval must be positive");
+        }
+    }
+    if let Some(ref notify) = config.sd_notify {
+        if !notify.path.is_absolute() {
+            err!("Notify socket path {:?} must be absolute", notify.path);
+        }
+        if config.readiness.is_some() {
+            err!("readiness and sd-notify settings are mutually exclusive");
+        }
+        if notify.watchdog.map_or(false, |w| !(w > 0.)) {
+            err!("Watchdog timeout must be positive");
+        }
+    }
     if let Some(sandbox) = sandbox {
         if config.uid_map.len() > 0 {
             let user_id = config.user_id.or(sandbox.default_user);
@@ -139,7 +244,12 @@ fn network_contains(netw: &IpNetwork, ip: IpAddr) -> bool {
 
 fn validate_substitutions(config: &ContainerConfig) {
     let mut replacer = |varname: &str| {
-        if !config.variables.contains_key(varname) {
+        // host facts are checked when config is instantiated,
+        // secrets can only be decrypted by lithos_knot
+        if !varname.starts_with("fact:") &&
+            !varname.starts_with("secret:") &&
+            !config.variables.contains_key(varname)
+        {
             err!("undefined variable {:?}", varname);
         }
         ""
@@ -154,6 +264,21 @@ fn validate_substitutions(config: &ContainerConfig) {
     for val in &config.arguments {
          replace_vars(&val, &mut replacer);
     }
+    replace_vars(&config.executable, &mut replacer);
+    for val in config.state_files.values() {
+         replace_vars(&val, &mut replacer);
+    }
+    for vol in config.volumes.values() {
+        match *vol {
+            Volume::Readonly(ref path) |
+            Volume::Persistent(PersistentInfo { ref path, .. }) |
+            Volume::Statedir(StatedirInfo { ref path, .. })
+            => {
+                replace_vars(&path.to_string_lossy(), &mut replacer);
+            }
+            Volume::Tmpfs(..) => {}
+        }
+    }
 }
 
 fn validate_variable_types(config: &ContainerConfig, child_cfg: &ChildConfig,
@@ -170,6 +295,23 @@ fn validate_variable_types(config: &ContainerConfig, child_cfg: &ChildConfig,
     }
 }
 
+fn validate_tcp_ports(config: &ContainerConfig) {
+    let mut fds = BTreeMap::new();
+    for (port, props) in &config.tcp_ports {
+        let nfds = props.host.0.len() as i32;
+        if nfds > 1 && props.fd == 0 {
+            err!("Port {} has multiple hosts, so can't be passed as fd 0",
+                port);
+        }
+        for fd in props.fd..props.fd + nfds {
+            if let Some(other) = fds.insert(fd, port) {
+                err!("Ports {} and {} use the same file descriptor {}",
+                    other, port, fd);
+            }
+        }
+    }
+}
+
 fn validate_activation(config: &ContainerConfig) {
     let mut nsockets = 0;
     for (key, typ) in &config.variables {
@@ -179,7 +321,8 @@ fn validate_activation(config: &ContainerConfig) {
                 nsockets += 1;
                 let fd = 2+nsockets;
                 for (port, props) in &config.tcp_ports {
-                     if props.fd == fd {
+                     let nfds = props.host.0.len() as i32;
+                     if props.fd <= fd && fd < props.fd + nfds {
                         err!("Port {} conflicts with var {:?} \
                             for fd: {}. \
                             You may change file descriptor to a \
@@ -222,14 +365,25 @@ fn check(config_file: &Path, verbose: bool,
     };
 
     check_master_config(&master, verbose);
+    for dir in &[&master.sandboxes_dir, &master.processes_dir] {
+        let dir = config_file.parent().unwrap().join(dir);
+        if let Err(e) = generation::resolve(&dir) {
+            err!("{}", e);
+        }
+    }
+
+    // facts of the current host, so `@{fact:...}` variables are checked
+    let host_facts = HostFacts::gather()
+        .map_err(|e| warn!("Can't gather host facts: {}", e)).ok();
 
     let config_dir = config_file.parent().unwrap().join(&master.sandboxes_dir);
-    scan_dir::ScanDir::files().read(&config_dir, |iter| {
-        let yamls = iter.filter(|&(_, ref name)| name.ends_with(".yaml"));
-        for (entry, current_fn) in yamls {
-            // strip yaml suffix
-            let current_name = &current_fn[..current_fn.len()-5];
-            let sandbox: SandboxConfig = match parse_config(&entry.path(),
+    let config_dirs = sandbox_dirs::dirs(&master, config_file, &config_dir);
+    let mut total_instances = 0;
+    sandbox_dirs::list(&config_dirs).map(|configs| {
+        for (current_name, sandbox_fn) in &configs {
+            let current_name = &current_name[..];
+            let current_fn = format!("{}.yaml", current_name);
+            let sandbox: SandboxConfig = match parse_config(sandbox_fn,
                 &SandboxConfig::validator(), &Options::default()) {
                 Ok(cfg) => cfg,
                 Err(e) => {
@@ -253,7 +407,9 @@ fn check(config_file: &Path, verbose: bool,
             debug!("Checking {:?}", config_file);
             let all_children: BTreeMap<String, ChildConfig>;
             all_children = match parse_config(&config_file,
-                &ChildConfig::mapping_validator(), &Options::default()) {
+                &ChildConfig::mapping_validator_with(
+                    &master.container_defaults),
+                &Options::default()) {
                 Ok(cfg) => cfg,
                 Err(e) => {
                     warn!("Can't read child config for {:?}: {}",
@@ -261,7 +417,20 @@ fn check(config_file: &Path, verbose: bool,
                     continue;
                 }
             };
+            total_instances += all_children.values()
+                .filter(|child| child.kind == ChildKind::Daemon)
+                .map(|child| child.instances)
+                .sum::<usize>();
+            let processes_dir = config_file.parent().unwrap().to_path_buf();
             for (ref child_name, ref child_cfg) in all_children.iter() {
+                let mut child_cfg = (*child_cfg).clone();
+                if let Err(e) = render_child(&mut child_cfg, &processes_dir,
+                    &sandbox.template_values, &master.container_defaults)
+                {
+                    err!("{} (process {:?})", e, child_name);
+                    continue;
+                }
+                let child_cfg = &child_cfg;
                 let cfg_path = Path::new(&child_cfg.config);
                 if !cfg_path.is_absolute() {
                     err!("Config path must be absolute");
@@ -272,11 +441,45 @@ fn check(config_file: &Path, verbose: bool,
                         child_cfg.image, current_name, child_name);
                     continue;
                 }
+                if let Some(ref info) = sandbox.info_file {
+                    let dest = sandbox.image_dir.join(&child_cfg.image)
+                        .join(relative(&info.path, Path::new("/")));
+                    if info.path.is_absolute() &&
+                        !metadata(&dest).map(|m| m.is_file()).unwrap_or(false)
+                    {
+                        warn!("Image {} of process {:?} has no file {:?}, \
+                            info-file is not mounted there",
+                            child_cfg.image, child_name, info.path);
+                    }
+                }
                 debug!("Opening config for {:?}", child_name);
-                let config = match check_container(&sandbox.image_dir
-                    .join(&child_cfg.image)
-                    .join(&relative(cfg_path, &Path::new("/"))),
-                    Some(&sandbox))
+                let config_res = match child_cfg.container {
+                    Some(ref inline) => {
+                        match parse_inline_container(inline.as_json()) {
+                            Ok(config) => {
+                                check_container_config(config, Some(&sandbox))
+                            }
+                            Err(e) => {
+                                err!("{} (process {:?})", e, child_name);
+                                continue;
+                            }
+                        }
+                    }
+                    None => {
+                        let path = sandbox.image_dir
+                            .join(&child_cfg.image)
+                            .join(&relative(cfg_path, &Path::new("/")));
+                        match sandbox.default_container_config {
+                            Some(ref default) if !path.exists() => {
+                                check_container(default, Some(&sandbox),
+                                    &master.container_defaults)
+                            }
+                            _ => check_container(&path, Some(&sandbox),
+                                &master.container_defaults),
+                        }
+                    }
+                };
+                let config = match config_res
                 {
                     Ok(config) => config,
                     Err(()) => continue,
@@ -338,6 +541,69 @@ fn check(config_file: &Path, verbose: bool,
                 if !check_mapping(&sandbox.allow_groups, &config.gid_map) {
                     err!("Bad gid mapping (probably doesn't match allow_groups)");
                 }
+                if sandbox.allow_fuse && sandbox.uid_map.is_empty() &&
+                    config.uid_map.is_empty()
+                {
+                    err!("allow-fuse in sandbox {:?} requires uid-map",
+                        current_name);
+                }
+                if sandbox.debug_allow_ptrace && sandbox.uid_map.is_empty() &&
+                    config.uid_map.is_empty()
+                {
+                    err!("debug-allow-ptrace in sandbox {:?} requires uid-map",
+                        current_name);
+                }
+                if let Some(value) = config.restart_on_fd_usage {
+                    if !(value > 0. && value <= 1.) {
+                        err!("restart-on-fd-usage must be in range (0, 1], \
+                            got {}", value);
+                    }
+                }
+                if !config.unix_sockets.is_empty() &&
+                    sandbox.unix_sockets_dir.is_none()
+                {
+                    err!("unix-sockets require unix-sockets-dir \
+                        in sandbox {:?}", current_name);
+                }
+                if let Some(ref gpus) = config.gpus {
+                    if !sandbox.allow_gpus {
+                        err!("GPUs are not allowed in sandbox {:?}",
+                            current_name);
+                    }
+                    if gpus.driver_libraries.is_some() &&
+                        sandbox.gpu_driver_dir.is_none()
+                    {
+                        err!("gpus.driver-libraries requires gpu-driver-dir \
+                            in sandbox {:?}", current_name);
+                    }
+                }
+                if child_cfg.max_runtime.is_some() &&
+                    child_cfg.kind == ChildKind::Daemon
+                {
+                    err!("max-runtime is only supported for commands");
+                }
+                if child_cfg.retries.is_some() &&
+                    child_cfg.kind == ChildKind::Daemon
+                {
+                    err!("retries are only supported for commands");
+                }
+                if let Some(ref daemon) = child_cfg.inherit_sockets {
+                    if child_cfg.kind == ChildKind::Daemon {
+                        err!("inherit-sockets is only supported \
+                            for commands");
+                    }
+                    match all_children.get(daemon) {
+                        Some(d) if d.kind == ChildKind::Daemon => {}
+                        Some(_) => err!("inherit-sockets must refer to \
+                            a daemon, {:?} is a command", daemon),
+                        None => err!("inherit-sockets refers to unknown \
+                            process {:?}", daemon),
+                    }
+                    if master.control_socket.is_none() {
+                        err!("inherit-sockets requires control-socket \
+                            in master config");
+                    }
+                }
                 validate_variable_types(&config, &child_cfg, &sandbox);
                 validate_activation(&config);
                 validate_substitutions(&config);
@@ -361,6 +627,10 @@ fn check(config_file: &Path, verbose: bool,
                             }
                         } else if ichild.kind == ChildKind::Command {
                             // okay to have no IP for commands
+                        } else if bridge.ip_pool.is_some() {
+                            // allocated by lithos_tree
+                        } else if !bridge.dhcp_command.is_empty() {
+                            // received by DHCP client in lithos_knot
                         } else {
                             err!("{}: no IP address specified", name);
                         }
@@ -370,6 +640,8 @@ fn check(config_file: &Path, verbose: bool,
                             user_vars: &ichild.variables,
                             lithos_name: &name,
                             lithos_config_filename: &ichild.config,
+                            host_facts: host_facts.as_ref(),
+                            secrets: None,
                         }) {
                         Ok(x) => x,
                         Err(e) => {
@@ -381,6 +653,13 @@ fn check(config_file: &Path, verbose: bool,
                             continue;
                         }
                     };
+                    if let Err(e) = icfg.check_exec_size(
+                        sandbox.max_environ_size)
+                    {
+                        err!("Process {:?} of sandbox {:?} of image {:?} \
+                            can't be started: {}", &ichild.config,
+                            current_name, ichild.image, e.join("; "));
+                    }
                     for (port, pinfo) in icfg.tcp_ports {
                         if sandbox.bridged_network.is_none() ||
                            pinfo.external
@@ -398,8 +677,14 @@ fn check(config_file: &Path, verbose: bool,
             }
         }
     }).map_err(|e| {
-        err!("Can't read config directory {:?}: {}", config_dir, e);
+        err!("Can't read config directories {:?}: {}", config_dirs, e);
     }).ok();
+    if let Some(max) = master.max_total_instances {
+        if total_instances > max {
+            err!("Total number of instances {} is more than \
+                max-total-instances ({})", total_instances, max);
+        }
+    }
     if alter_config.is_some() {
         err!("Tree {:?} is not used", altered_sandbox);
     }
@@ -418,9 +703,6 @@ fn check_binaries() {
     if metadata(&dir.join("lithos_tree")).is_err() {
         err!("Can't find lithos_tree binary");
     }
-    if metadata(&dir.join("lithos_knot")).is_err() {
-        err!("Can't find lithos_knot binary");
-    }
 }
 
 fn main() {
@@ -435,8 +717,9 @@ fn main() {
     let mut alter_config = None;
     let mut sandbox_name = None;
     let mut check_containers = Vec::<String>::new();
+    let mut dump_schema = None::<ConfigKind>;
     {
-        let mut ap = ArgumentParser::new();
+        let mut ap = cli::Parser::new();
         ap.set_description("Checks if lithos configuration is ok");
         ap.refer(&mut config_file)
           .add_option(&["-C", "--config"], Parse,
@@ -469,22 +752,41 @@ fn main() {
             specified in multiple arguments.
             ")
           .metavar("FILE");
+        ap.refer(&mut dump_schema)
+          .add_option(&["--dump-schema"], StoreOption,
+            "Print JSON Schema of the config of the KIND (one of `master`,
+             `sandbox`, `child` or `container`) and exit. Useful to
+             validate configs in the editor or CI.")
+          .metavar("KIND");
         ap.add_option(&["--version"],
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "Show version");
-        match ap.parse_args() {
+        match cli::parse_args(&ap) {
             Ok(()) => {}
             Err(x) => {
                 exit(x);
             }
         }
     }
+    if let Some(kind) = dump_schema {
+        match config_schema(kind) {
+            Ok(schema) => {
+                println!("{:#}", schema);
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+    }
     if alter_config.is_some() && sandbox_name.is_none() {
         err!("Please specify --sandbox if you use --dir");
     }
     if check_containers.len() > 0 {
         for file in &check_containers {
-            check_container(Path::new(file), None).ok();
+            check_container(Path::new(file), None,
+                &ContainerDefaults::default()).ok();
         }
     } else {
         check_binaries();
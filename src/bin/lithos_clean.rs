@@ -21,13 +21,15 @@ use std::time::{SystemTime, Duration};
 
 use humantime::{parse_rfc3339, format_rfc3339_seconds};
 use quire::{parse_config, Options};
-use argparse::{ArgumentParser, Parse, ParseOption, StoreTrue, StoreConst};
+use argparse::{Parse, ParseOption, StoreTrue, StoreConst};
 use argparse::{Print, StoreOption};
 
 use lithos::child_config::ChildConfig;
 use lithos::master_config::MasterConfig;
+use lithos::cli;
 use lithos::MAX_CONFIG_LOGS;
 use lithos::sandbox_config::SandboxConfig;
+use lithos::sandbox_dirs;
 
 
 #[derive(Clone, Copy, Debug)]
@@ -70,7 +72,7 @@ fn main() {
     let mut days = None::<u32>;
     let mut keep_recent = None::<humantime::Duration>;
     {
-        let mut ap = ArgumentParser::new();
+        let mut ap = cli::Parser::new();
         ap.set_description("Show used/unused images and clean if needed");
         ap.refer(&mut config_file)
           .add_option(&["-C", "--config"], Parse,
@@ -119,7 +121,7 @@ fn main() {
         ap.add_option(&["--version"],
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "Show version of the lithos");
-        ap.parse_args_or_exit();
+        cli::parse_args_or_exit(&ap);
     }
     let master: MasterConfig = match parse_config(&config_file,
         &MasterConfig::validator(), &Options::default()) {
@@ -456,72 +458,71 @@ fn find_used_images(master: &MasterConfig, master_file: &Path,
     let mut no_clean_dirs = HashSet::new();
     let mut unused_logs = Vec::new();
     let childval = ChildConfig::mapping_validator();
-    scan_dir::ScanDir::files().read(&config_dir, |iter| -> Result<(), String> {
-        let yamls = iter.filter(|&(_, ref name)| name.ends_with(".yaml"));
-        for (entry, sandbox_fname) in yamls {
-            let sandbox_name = &sandbox_fname[..sandbox_fname.len()-5];  // strip .yaml
-            let sandbox_config: SandboxConfig = parse_config(&entry.path(),
-                &SandboxConfig::validator(), &Options::default())
-                .map_err(|e| e.to_string())?;
+    let config_dirs = sandbox_dirs::dirs(master, master_file, &config_dir);
+    let configs = sandbox_dirs::list(&config_dirs)
+        .map_err(|e| format!("Read dir error: {}", e))?;
+    for (sandbox_name, sandbox_fn) in &configs {
+        let sandbox_name = &sandbox_name[..];
+        let sandbox_config: SandboxConfig = parse_config(sandbox_fn,
+            &SandboxConfig::validator(), &Options::default())
+            .map_err(|e| e.to_string())?;
 
-            if sandbox_config.auto_clean == false {
-                no_clean_dirs.insert(sandbox_config.image_dir.clone());
-                if image_dirs.contains_key(&sandbox_config.image_dir) {
-                    error!("Conflicting `auto-clean` setting for {:?}",
-                        sandbox_config.image_dir);
-                    bad_dirs.insert(sandbox_config.image_dir.clone());
-                }
-                continue;
-            } else {
-                if no_clean_dirs.contains(&sandbox_config.image_dir) {
-                    error!("Conflicting `auto-clean` setting for {:?}",
-                        sandbox_config.image_dir);
-                    bad_dirs.insert(sandbox_config.image_dir.clone());
-                }
+        if sandbox_config.auto_clean == false {
+            no_clean_dirs.insert(sandbox_config.image_dir.clone());
+            if image_dirs.contains_key(&sandbox_config.image_dir) {
+                error!("Conflicting `auto-clean` setting for {:?}",
+                    sandbox_config.image_dir);
+                bad_dirs.insert(sandbox_config.image_dir.clone());
             }
-
-            let lev = image_dirs.entry(sandbox_config.image_dir.clone())
-                .or_insert(sandbox_config.image_dir_levels);
-            if *lev != sandbox_config.image_dir_levels {
-                error!("Conflicing image dir levels for {:?}",
+            continue;
+        } else {
+            if no_clean_dirs.contains(&sandbox_config.image_dir) {
+                error!("Conflicting `auto-clean` setting for {:?}",
                     sandbox_config.image_dir);
                 bad_dirs.insert(sandbox_config.image_dir.clone());
             }
+        }
 
-            let cfg = master_file.parent().unwrap()
-                .join(&master.processes_dir)
-                .join(sandbox_config.config_file.as_ref().unwrap_or(
-                    &PathBuf::from(&(sandbox_name.to_string() + ".yaml"))));
-            if cfg.exists() {
-                let all_children: BTreeMap<String, ChildConfig>;
-                all_children =
-                    parse_config(&cfg, &childval, &Options::default())
-                    .map_err(|e| format!("Can't read child config {:?}: {}",
-                                         sandbox_config.config_file, e))?;
-                for child in all_children.values() {
-                    // Current are always added
-                    images.insert(
-                        sandbox_config.image_dir.join(&child.image));
-                }
-            } else {
-                info!("No current processes for {}", sandbox_name);
-            }
+        let lev = image_dirs.entry(sandbox_config.image_dir.clone())
+            .or_insert(sandbox_config.image_dir_levels);
+        if *lev != sandbox_config.image_dir_levels {
+            error!("Conflicing image dir levels for {:?}",
+                sandbox_config.image_dir);
+            bad_dirs.insert(sandbox_config.image_dir.clone());
+        }
 
-            if sandbox_config.used_images_list.is_some() {
-                find_used_by_list(master, sandbox_name, &sandbox_config,
-                    &mut images, &mut bad_dirs);
-            } else if master.config_log_dir.is_some() {
-                find_used_by_log(master, sandbox_name, &sandbox_config,
-                    min_time, ver_min, ver_max,
-                    &mut images, &mut unused_logs, &mut bad_dirs);
-            } else {
-                error!("Neither `config-log-dir` nor `used-images-list` is \
-                        set for sandbox {:?}. Can't clean images.",
-                        sandbox_name);
+        let cfg = master_file.parent().unwrap()
+            .join(&master.processes_dir)
+            .join(sandbox_config.config_file.as_ref().unwrap_or(
+                &PathBuf::from(&(sandbox_name.to_string() + ".yaml"))));
+        if cfg.exists() {
+            let all_children: BTreeMap<String, ChildConfig>;
+            all_children =
+                parse_config(&cfg, &childval, &Options::default())
+                .map_err(|e| format!("Can't read child config {:?}: {}",
+                                     sandbox_config.config_file, e))?;
+            for child in all_children.values() {
+                // Current are always added
+                images.insert(
+                    sandbox_config.image_dir.join(&child.image));
             }
+        } else {
+            info!("No current processes for {}", sandbox_name);
         }
-        Ok(())
-    }).map_err(|e| format!("Read dir error: {}", e))??;
+
+        if sandbox_config.used_images_list.is_some() {
+            find_used_by_list(master, sandbox_name, &sandbox_config,
+                &mut images, &mut bad_dirs);
+        } else if master.config_log_dir.is_some() {
+            find_used_by_log(master, sandbox_name, &sandbox_config,
+                min_time, ver_min, ver_max,
+                &mut images, &mut unused_logs, &mut bad_dirs);
+        } else {
+            error!("Neither `config-log-dir` nor `used-images-list` is \
+                    set for sandbox {:?}. Can't clean images.",
+                    sandbox_name);
+        }
+    }
 
     for dir in &bad_dirs {
         error!("Can't reliably find out used images in the directory {:?}",
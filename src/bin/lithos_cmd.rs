@@ -3,7 +3,7 @@ extern crate libc;
 extern crate lithos;
 extern crate quire;
 extern crate regex;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 extern crate unshare;
 #[macro_use] extern crate log;
 
@@ -14,20 +14,38 @@ use std::process::exit;
 use std::path::{Path, PathBuf};
 use std::io::{stderr, Write};
 use std::collections::BTreeMap;
+use std::fs::{File, create_dir_all};
+use std::os::unix::io::FromRawFd;
+use std::thread::sleep;
+use std::time::Duration;
 
-use argparse::{ArgumentParser, Parse, List, StoreTrue, StoreOption, Print};
+use argparse::{Parse, List, StoreTrue, StoreOption, Print};
 use libc::getpid;
 use quire::{parse_config, Options};
 use regex::Regex;
 use serde_json::to_string;
-use unshare::{Command, Namespace};
+use unshare::{Command, Namespace, Fd};
 
 use lithos::setup::{clean_child, init_logging};
 use lithos::master_config::{MasterConfig, create_master_dirs};
+use lithos::version;
+use lithos::cli;
+use lithos::exit_report::EXIT_MAX_RUNTIME;
+use lithos::command_slots;
+use lithos::socket_loans;
+use lithos::metrics;
+use lithos::statsd::Statsd;
+use lithos::reason::Reason;
+use lithos::events;
 use lithos::sandbox_config::SandboxConfig;
+use lithos::sandbox_dirs;
 use lithos::child_config::{ChildConfig, ChildKind};
 
 
+fn duration(inp: f32) -> Duration {
+    Duration::from_millis((inp * 1000.) as u64)
+}
+
 fn run(master_cfg: &Path, sandbox_name: String,
     command_name: String, args: Vec<String>,
     log_stderr: bool, log_level: Option<log::LogLevel>)
@@ -46,8 +64,7 @@ fn run(master_cfg: &Path, sandbox_name: String,
     }
 
     let sandbox: SandboxConfig = try!(parse_config(
-        &master_cfg.parent().unwrap()
-         .join(&master.sandboxes_dir).join(sandbox_name.clone() + ".yaml"),
+        &sandbox_dirs::find(&master, master_cfg, &sandbox_name),
         &SandboxConfig::validator(), &Options::default())
         .map_err(|e| format!("Error reading sandbox config: {}", e)));
 
@@ -57,6 +74,11 @@ fn run(master_cfg: &Path, sandbox_name: String,
     } else {
         log_file = master.default_log_dir.join(format!("{}.log", sandbox_name));
     }
+    // sandbox log file may be in a subdirectory of the master log dir
+    if let Some(dir) = log_file.parent() {
+        try!(create_dir_all(dir)
+            .map_err(|e| format!("Can't create log dir {:?}: {}", dir, e)));
+    }
     try!(init_logging(&master, &log_file,
         &format!("{}-{}", master.syslog_app_name, sandbox_name),
         log_stderr,
@@ -73,7 +95,8 @@ fn run(master_cfg: &Path, sandbox_name: String,
     debug!("Children config {:?}", cfg);
     let sandbox_children: BTreeMap<String, ChildConfig>;
     sandbox_children = try!(parse_config(&cfg,
-            &ChildConfig::mapping_validator(), &Options::default())
+            &ChildConfig::mapping_validator_with(&master.container_defaults),
+            &Options::default())
         .map_err(|e| format!("Error reading children config: {}", e)));
     let child_cfg = try!(sandbox_children.get(&command_name)
         .ok_or(format!("Command {:?} not found", command_name)));
@@ -82,14 +105,19 @@ fn run(master_cfg: &Path, sandbox_name: String,
         return Err(format!("The target container is: {:?}", child_cfg.kind));
     }
 
+    let retries = child_cfg.retries.unwrap_or(0);
+    let retry_delay = child_cfg.retry_delay.unwrap_or(1.);
+    let inherit_sockets = child_cfg.inherit_sockets.clone();
     let child_cfg = child_cfg.instantiate(0)
         .map_err(|e| format!("can't instantiate: {}", e))?;
 
     let name = format!("{}/cmd.{}.{}", sandbox_name,
         command_name, unsafe { getpid() });
 
-    let mut cmd = Command::new(env::current_exe().unwrap()
-                     .parent().unwrap().join("lithos_knot"));
+    let knot = master.knot_binary()
+        .ok_or("Can't find lithos_knot binary".to_string())?;
+    version::check_knot_binary(&knot)?;
+    let mut cmd = Command::new(&knot);
 
     // Name is first here, so it's easily visible in ps
     cmd.arg("--name");
@@ -112,18 +140,92 @@ fn run(master_cfg: &Path, sandbox_name: String,
     cmd.unshare(&[Namespace::Mount, Namespace::Uts,
                  Namespace::Ipc, Namespace::Pid]);
 
-    info!("Running {:?}", cmd);
-
-    let res = match cmd.status() {
-        Ok(x) if x.success() => {
-            info!("Command {:?} {}", cmd, x);
-            Ok(())
+    // slot is held until the command (including retries) is finished
+    let _slot = match master.max_concurrent_commands {
+        Some(limit) => Some(command_slots::acquire(&master, limit)?),
+        None => None,
+    };
+    // loan is held until the command (including retries) is finished,
+    // the daemon isn't started by lithos_tree until then
+    let _loan = match inherit_sockets {
+        Some(ref daemon) => {
+            let loan = socket_loans::acquire(&master, &sandbox_name, daemon)?;
+            let fds = socket_loans::borrow(&master, &loan,
+                &sandbox_name, daemon)?;
+            info!("Borrowed {} sockets of {:?}", fds.len(), daemon);
+            let lock_fd = fds.iter().map(|&(target, _)| target)
+                .max().unwrap_or(2) + 1;
+            for (target, fd) in fds {
+                cmd.file_descriptor(target,
+                    Fd::from_file(unsafe { File::from_raw_fd(fd) }));
+            }
+            // knot holds the lock too, in case we are killed
+            cmd.file_descriptor(lock_fd, Fd::from_file(loan.lock_file()?));
+            Some(loan)
         }
-        Ok(x) => Err(format!("Command {:?} {}", cmd, x)),
-        Err(e) => Err(format!("Can't run {:?}: {}", cmd, e)),
+        None => None,
     };
+    let status = metrics::Command::new(&sandbox_name, &command_name);
+    let mut attempt = 0;
+    let (res, reason) = loop {
+        attempt += 1;
+        info!("Running {:?} (attempt {})", cmd, attempt);
+        status.attempts.set(attempt as i64);
+        let (res, reason) = match cmd.status() {
+            Ok(x) if x.success() => {
+                info!("Command {:?} {}", cmd, x);
+                status.exit_code.set(0);
+                (Ok(()), Reason::Exit)
+            }
+            Ok(ref x) if x.code() == Some(EXIT_MAX_RUNTIME) => {
+                status.exit_code.set(EXIT_MAX_RUNTIME as i64);
+                warn!("Command {:?} stopped, reason: {}",
+                    name, Reason::MaxRuntime);
+                events::emit(&master, "stop", json!({
+                    "process": name,
+                    "attempt": attempt,
+                    "reason": Reason::MaxRuntime.as_str(),
+                }));
+                (Err(format!("Command {:?} exceeded max-runtime", cmd)),
+                 Reason::MaxRuntime)
+            }
+            Ok(x) => {
+                status.exit_code.set(x.code().unwrap_or(-1) as i64);
+                (Err(format!("Command {:?} {}", cmd, x)), Reason::Crash)
+            }
+            Err(e) => {
+                (Err(format!("Can't run {:?}: {}", cmd, e)), Reason::Crash)
+            }
+        };
+        match res {
+            Err(ref e) if attempt <= retries => {
+                warn!("{}. Retrying in {}s ({} of {} retries)",
+                    e, retry_delay, attempt, retries);
+                clean_child(&name, &master, true, reason);
+                sleep(duration(retry_delay));
+            }
+            res => break (res, reason),
+        }
+    };
+    match res {
+        Ok(()) if attempt > 1 => {
+            warn!("Command {:?} succeeded after {} attempts",
+                name, attempt);
+        }
+        Ok(()) => {}
+        Err(ref e) => {
+            error!("Command {:?} failed after {} attempts: {}",
+                name, attempt, e);
+        }
+    }
+    status.succeeded.set(if res.is_ok() { 1 } else { 0 });
+    if let Some(ref cfg) = master.statsd {
+        Statsd::new(cfg).push(&status)
+            .map_err(|e| warn!("Error pushing to statsd: {}", e))
+            .ok();
+    }
 
-    clean_child(&name, &master, false);
+    clean_child(&name, &master, false, reason);
 
     return res;
 }
@@ -136,7 +238,7 @@ fn main() {
     let mut log_stderr: bool = false;
     let mut log_level: Option<log::LogLevel> = None;
     {
-        let mut ap = ArgumentParser::new();
+        let mut ap = cli::Parser::new();
         ap.set_description("Runs single ad-hoc command");
         ap.refer(&mut master_config)
           .add_option(&["--master"], Parse,
@@ -164,7 +266,7 @@ fn main() {
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "Show version");
         ap.stop_on_first_argument(true);
-        match ap.parse_args() {
+        match cli::parse_args(&ap) {
             Ok(()) => {}
             Err(x) => {
                 exit(x);
@@ -10,9 +10,10 @@ extern crate crypto;
 #[macro_use] extern crate structopt;
 
 
+use std::env;
 use std::fs::File;
 use std::io::{Read, BufReader, BufRead, Write, stdout, stderr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 use blake2::{Blake2b, digest::VariableOutput, digest::Input};
@@ -20,8 +21,11 @@ use failure::{Error, ResultExt};
 use regex::Regex;
 use ssh_keys::{PublicKey, PrivateKey, openssh};
 use structopt::StructOpt;
+use structopt::clap::{App, ArgSettings, Shell};
 
+use lithos::cli::{self, Help, Opt, Argument};
 use lithos::nacl;
+use lithos::image_manifest;
 
 
 #[derive(Debug, StructOpt)]
@@ -36,6 +40,8 @@ enum Options {
     Decrypt(DecryptOpt),
     #[structopt(name="check-key")]
     CheckKey(CheckKeyOpt),
+    #[structopt(name="image-manifest")]
+    ImageManifest(ImageManifestOpt),
 }
 
 #[derive(Debug, StructOpt)]
@@ -52,6 +58,12 @@ pub struct EncryptOpt {
         will be able do decrypt the data.
     ", default_value="", parse(try_from_str="validate_namespace"))]
     namespace: String,
+    #[structopt(long="scope", short="s", help="
+        name of the sandbox (`sandbox`) or the process (`sandbox/process`)
+        the secret is bound to. Other sandboxes and processes will not be
+        able to decrypt the data, even if they have access to the namespace.
+    ", parse(try_from_str="validate_scope"))]
+    scope: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -77,6 +89,14 @@ pub struct DecryptOpt {
     data: String,
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Print manifest of the image directory, \
+                     to sign for `image-signature-key`")]
+pub struct ImageManifestOpt {
+    #[structopt(help="image directory", parse(from_os_str))]
+    dir: PathBuf,
+}
+
 fn validate_namespace(namespace: &str) -> Result<String, Error> {
     if !Regex::new("^[a-zA-Z0-9_.-]*$").expect("valid re").is_match(namespace) {
         bail!("invalid namespace, \
@@ -85,6 +105,16 @@ fn validate_namespace(namespace: &str) -> Result<String, Error> {
     Ok(namespace.to_string())
 }
 
+fn validate_scope(scope: &str) -> Result<String, Error> {
+    let re = Regex::new("^[a-zA-Z0-9_.-]+(/[a-zA-Z0-9_.-]+)?$")
+        .expect("valid re");
+    if !re.is_match(scope) {
+        bail!("invalid scope, should be either `sandbox` or \
+            `sandbox/process`");
+    }
+    Ok(scope.to_string())
+}
+
 fn parse_public_key(filename: &str) -> Result<PublicKey, Error> {
     let mut buf = String::with_capacity(1024);
     File::open(filename)
@@ -116,17 +146,32 @@ fn encrypt(e: EncryptOpt) -> Result<(), Error> {
         PublicKey::Ed25519(key) => key,
         _ => bail!("Only ed25519 keys are supported"),
     };
-    let plaintext = format!("{}:{}", e.namespace, e.data);
+    let plaintext = match e.scope {
+        Some(ref scope) => format!("{}:{}:{}", e.namespace, scope, e.data),
+        None => format!("{}:{}", e.namespace, e.data),
+    };
     let cypher = nacl::crypto_box_edwards_seal(
         plaintext.as_bytes(), &key_bytes[..]);
     let mut buf = Vec::with_capacity(cypher.len() + 24);
     buf.write(&cypher).unwrap();
     let data = base64::encode(&buf);
-    println!("v2:{}:{}:{}:{}",
-        b2_short_hash(&key_bytes[..]),
-        b2_short_hash(e.namespace.as_bytes()),
-        b2_short_hash(e.data.as_bytes()),
-        data);
+    match e.scope {
+        Some(ref scope) => {
+            println!("v3:{}:{}:{}:{}:{}",
+                b2_short_hash(&key_bytes[..]),
+                b2_short_hash(e.namespace.as_bytes()),
+                b2_short_hash(scope.as_bytes()),
+                b2_short_hash(e.data.as_bytes()),
+                data);
+        }
+        None => {
+            println!("v2:{}:{}:{}:{}",
+                b2_short_hash(&key_bytes[..]),
+                b2_short_hash(e.namespace.as_bytes()),
+                b2_short_hash(e.data.as_bytes()),
+                data);
+        }
+    }
     Ok(())
 }
 
@@ -135,8 +180,8 @@ fn check_key(o: CheckKeyOpt) -> Result<(), Error> {
         PublicKey::Ed25519(key) => key,
         _ => bail!("Only ed25519 keys are supported"),
     };
-    if !o.data.starts_with("v2:") {
-        bail!("Only v1 secrets are supported");
+    if !o.data.starts_with("v2:") && !o.data.starts_with("v3:") {
+        bail!("Only v2 and v3 secrets are supported");
     }
     let mut it = o.data.splitn(3, ":");
     it.next(); // skip version
@@ -153,25 +198,29 @@ fn decrypt(e: DecryptOpt) -> Result<(), Error> {
         _ => bail!("Only ed25519 keys are supported"),
     };
     let (private_key, public_key) = key_bytes.split_at(32);
-    if !e.data.starts_with("v2:") {
-        bail!("Only v2 secrets are supported");
-    }
-    let mut it = e.data.split(":");
-    it.next(); // skip version
-    let (key_hash, ns_hash, secr_hash, cipher) = {
-        match (it.next(), it.next(), it.next(), it.next(), it.next()) {
-            (Some(key), Some(ns), Some(secr), Some(cipher), None) => {
-                (key, ns, secr, base64::decode(cipher)?)
-            }
-            _ => bail!("invalid key format"),
+    let parts = e.data.split(":").collect::<Vec<_>>();
+    let (key_hash, ns_hash, scope_hash, secr_hash, cipher) = match parts[..] {
+        ["v2", key, ns, secr, cipher] => (key, ns, None, secr, cipher),
+        ["v3", key, ns, scope, secr, cipher] => {
+            (key, ns, Some(scope), secr, cipher)
         }
+        ["v2", ..] | ["v3", ..] => bail!("invalid key format"),
+        _ => bail!("Only v2 and v3 secrets are supported"),
     };
+    let cipher = base64::decode(cipher)?;
 
     let plain = nacl::crypto_box_edwards_seal_open(
         &cipher, public_key, private_key)?;
-    let mut pair = plain.splitn(2, |&x| x == b':');
-    let namespace = pair.next().unwrap();
-    let secret = pair.next().ok_or(format_err!("decrypted data is invalid"))?;
+    let nparts = if scope_hash.is_some() { 3 } else { 2 };
+    let mut items = plain.splitn(nparts, |&x| x == b':');
+    let namespace = items.next().unwrap();
+    let scope = if scope_hash.is_some() {
+        Some(items.next().ok_or(format_err!("decrypted data is invalid"))?)
+    } else {
+        None
+    };
+    let secret = items.next()
+        .ok_or(format_err!("decrypted data is invalid"))?;
 
     if b2_short_hash(public_key) != key_hash {
         bail!("invalid key hash");
@@ -179,6 +228,11 @@ fn decrypt(e: DecryptOpt) -> Result<(), Error> {
     if b2_short_hash(&namespace) != ns_hash {
         bail!("invalid namespace hash");
     }
+    if let (Some(scope), Some(scope_hash)) = (scope, scope_hash) {
+        if b2_short_hash(&scope) != scope_hash {
+            bail!("invalid scope hash");
+        }
+    }
     if b2_short_hash(&secret) != secr_hash {
         bail!("invalid secret hash");
     }
@@ -186,6 +240,10 @@ fn decrypt(e: DecryptOpt) -> Result<(), Error> {
     let mut err = stderr();
     err.write_all(&namespace)?;
     err.write_all(b":")?;
+    if let Some(scope) = scope {
+        err.write_all(&scope)?;
+        err.write_all(b":")?;
+    }
     err.flush()?;
     let mut out = stdout();
     out.write_all(&secret)?;
@@ -194,13 +252,122 @@ fn decrypt(e: DecryptOpt) -> Result<(), Error> {
     Ok(())
 }
 
+fn image_manifest(o: ImageManifestOpt) -> Result<(), Error> {
+    let manifest = image_manifest::scan(&o.dir,
+        |path, _| image_manifest::file_hash(path))?;
+    let mut out = stdout();
+    out.write_all(image_manifest::format(&manifest).as_bytes())?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints completions generated by clap, as the tool has subcommands
+fn completions(shell: Option<&String>) -> i32 {
+    match shell.map(|x| x.parse::<Shell>()) {
+        Some(Ok(shell)) => {
+            Options::clap().gen_completions_to("lithos_crypt", shell,
+                &mut stdout());
+            0
+        }
+        Some(Err(e)) => {
+            eprintln!("Unknown shell {}", e);
+            2
+        }
+        None => {
+            eprintln!("Option --completions requires SHELL");
+            2
+        }
+    }
+}
+
+/// Help from the attributes above is indented and wrapped
+fn help_text(help: Option<&str>) -> String {
+    help.unwrap_or("").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn names(short: Option<char>, long: Option<&str>) -> Vec<String> {
+    short.map(|c| format!("-{}", c)).into_iter()
+        .chain(long.map(|x| format!("--{}", x)))
+        .collect()
+}
+
+/// Describes options of the app, like `cli::describe` does for argparse
+///
+/// Clap has no accessors for the arguments, so fields of its parser are
+/// used (they are public, but hidden from the docs).
+fn describe(app: &App, program: &str) -> Help {
+    let p = &app.p;
+    // help and version flags are added by clap when parsing
+    let mut options = vec![Opt {
+        names: names(Some('h'), Some("help")),
+        metavar: None,
+        help: "Prints help information".to_string(),
+    }];
+    if p.meta.version.is_some() {
+        options.push(Opt {
+            names: names(Some('V'), Some("version")),
+            metavar: None,
+            help: "Prints version information".to_string(),
+        });
+    }
+    for flag in &p.flags {
+        options.push(Opt {
+            names: names(flag.s.short, flag.s.long),
+            metavar: None,
+            help: help_text(flag.b.help),
+        });
+    }
+    for opt in &p.opts {
+        let metavar = opt.v.val_names.as_ref()
+            .and_then(|n| n.values().next().map(|x| x.to_string()))
+            .unwrap_or_else(|| opt.b.name.to_uppercase());
+        options.push(Opt {
+            names: names(opt.s.short, opt.s.long),
+            metavar: Some(metavar),
+            help: help_text(opt.b.help),
+        });
+    }
+    let arguments = p.positionals.values().map(|pos| Argument {
+        name: pos.b.name.to_string(),
+        help: help_text(pos.b.help),
+        required: pos.b.is_set(ArgSettings::Required),
+        multiple: pos.b.is_set(ArgSettings::Multiple),
+    }).collect::<Vec<_>>();
+    let mut usage = cli::usage(program, &arguments);
+    if !p.subcommands.is_empty() {
+        usage.push_str(" SUBCOMMAND");
+    }
+    Help {
+        program: program.to_string(),
+        usage: usage,
+        description: help_text(p.meta.about),
+        arguments: arguments,
+        options: options,
+        subcommands: p.subcommands.iter()
+            .map(|s| describe(s, &format!("{} {}", program, s.p.meta.name)))
+            .collect(),
+    }
+}
+
 fn main() {
     use Options::*;
+    let args = env::args().collect::<Vec<_>>();
+    match args.get(1).map(|x| &x[..]) {
+        Some("--completions") => exit(completions(args.get(2))),
+        Some("--help-json") => {
+            let mut help = describe(&Options::clap(), "lithos_crypt");
+            help.options.extend(cli::builtin_options());
+            println!("{}", cli::help_json(&help));
+            exit(0);
+        }
+        _ => {}
+    }
     let opt = Options::from_args();
     let res = match opt {
         Encrypt(e) => encrypt(e),
         Decrypt(d) => decrypt(d),
         CheckKey(c) => check_key(c),
+        ImageManifest(m) => image_manifest(m),
     };
     match res {
         Ok(()) => {
@@ -0,0 +1,108 @@
+extern crate argparse;
+extern crate env_logger;
+extern crate quire;
+extern crate lithos;
+
+
+use std::env;
+use std::io::{stderr, stdout, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use argparse::{Parse, Store, List, Print};
+use quire::{parse_config, Options};
+
+use lithos::master_config::MasterConfig;
+use lithos::cli;
+
+
+fn send_command(master_cfg: &Path, command: &str, args: &[String])
+    -> Result<String, String>
+{
+    let master: MasterConfig = parse_config(&master_cfg,
+        &MasterConfig::validator(), &Options::default())
+        .map_err(|e| format!("Can't parse master config: {}", e))?;
+    let path = master.control_socket_path()
+        .ok_or_else(|| format!("Control socket is disabled, \
+            set control-socket in {:?}", master_cfg))?;
+    let mut sock = UnixStream::connect(&path)
+        .map_err(|e| format!("Can't connect to {:?}: {}. \
+            Probably lithos_tree is not running", path, e))?;
+    let mut line = command.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    line.push('\n');
+    sock.write_all(line.as_bytes())
+        .map_err(|e| format!("Error sending command: {}", e))?;
+    let mut reply = String::with_capacity(100);
+    sock.read_to_string(&mut reply)
+        .map_err(|e| format!("Error reading reply: {}", e))?;
+    if reply.starts_with("ok: ") {
+        Ok(reply["ok: ".len()..].trim().to_string())
+    } else if reply.starts_with("error: ") {
+        Err(reply["error: ".len()..].trim().to_string())
+    } else {
+        Err(format!("Invalid reply {:?}", reply))
+    }
+}
+
+
+fn main() {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "warn");
+    }
+    env_logger::init();
+
+    let mut master_config = PathBuf::from("/etc/lithos/master.yaml");
+    let mut command = String::new();
+    let mut args = Vec::<String>::new();
+    {
+        let mut ap = cli::Parser::new();
+        ap.set_description("Sends a command to the running lithos_tree. \
+            Commands are:
+
+            group-start NAME -- start processes of the run group
+            group-stop NAME -- stop processes of the run group, they're not
+            restarted until the group is started again
+            group-restart NAME -- restart processes of the run group
+            drain -- stop all processes for host maintenance
+            undrain -- start processes stopped by drain
+            list -- list containers with pid, state and uptime (as JSON)
+            start NAME, stop NAME, restart NAME -- the same as group
+            commands, for a sandbox or a sandbox/process
+            reread SANDBOX -- reread configs of the sandbox, restarting
+            processes whose config is changed");
+        ap.refer(&mut master_config)
+          .add_option(&["--master"], Parse,
+            "Name of the master configuration file \
+                (default /etc/lithos/master.yaml)")
+          .metavar("FILE");
+        ap.refer(&mut command)
+          .add_argument("command", Store, "Command to send")
+          .required();
+        ap.refer(&mut args)
+          .add_argument("arguments", List, "Arguments of the command");
+        ap.add_option(&["--version"],
+            Print(env!("CARGO_PKG_VERSION").to_string()),
+            "Show version");
+        match cli::parse_args(&ap) {
+            Ok(()) => {}
+            Err(x) => {
+                exit(x);
+            }
+        }
+    }
+    match send_command(&master_config, &command, &args) {
+        Ok(text) => {
+            writeln!(&mut stdout(), "{}", text).ok();
+            exit(0);
+        }
+        Err(e) => {
+            writeln!(&mut stderr(), "Error: {}", e).ok();
+            exit(1);
+        }
+    }
+}
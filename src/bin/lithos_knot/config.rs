@@ -1,18 +1,20 @@
 use std::path::Path;
 
 use lithos::container_config::ContainerConfig;
-use lithos::child_config::ChildInstance;
-use lithos::utils::temporary_change_root;
+use lithos::master_config::MasterConfig;
+use lithos::child_config::{ChildInstance, parse_inline_container};
+use lithos::sandbox_config::SandboxConfig;
+use lithos::utils::read_container_config;
 
-use quire::{parse_config, Options};
 
-
-pub fn container_config(root: &Path, child_cfg: &ChildInstance)
+pub fn container_config(root: &Path, child_cfg: &ChildInstance,
+    sandbox: &SandboxConfig, master: &MasterConfig)
     -> Result<ContainerConfig, String>
 {
-    return temporary_change_root(root, || {
-        parse_config(&child_cfg.config,
-            &ContainerConfig::validator(), &Options::default())
-        .map_err(|e| e.to_string())
-    });
+    if let Some(ref json) = child_cfg.container {
+        return parse_inline_container(json);
+    }
+    return read_container_config(root, Path::new(&child_cfg.config),
+        sandbox.default_container_config.as_deref(),
+        &master.container_defaults);
 }
@@ -0,0 +1,224 @@
+//! Crash reports written into `crash-reports-dir`
+//!
+//! The root of the knot changes when the process is started, so the
+//! directory of reports and the stderr log are opened beforehand, and
+//! reports are written relative to these descriptors.
+use std::ffi::{CStr, CString};
+use std::fs::{File, create_dir_all};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use blake2::{Blake2b, digest::VariableOutput, digest::Input};
+use humantime::format_rfc3339_seconds;
+use libc;
+use serde_json::{to_string, to_writer_pretty};
+use unshare::ExitStatus;
+
+use lithos::cgroup::{CGroups, Controller};
+use lithos::child_config::ChildInstance;
+use lithos::master_config::MasterConfig;
+use lithos::utils::create_at;
+
+
+const STDERR_TAIL: u64 = 65536;
+
+#[derive(Serialize)]
+struct Report {
+    name: String,
+    time: String,
+    status: String,
+    exit_code: Option<i32>,
+    signal: Option<i32>,
+    uptime: u64,
+    memory_peak: Option<u64>,
+    image: String,
+    config_hash: String,
+}
+
+/// Everything known about the process at the time of its death
+pub struct Crash<'a> {
+    pub name: &'a str,
+    pub status: &'a ExitStatus,
+    pub uptime: Duration,
+    /// The `stdout-stderr-file` (inside the container), if output is not
+    /// in the stderr log
+    pub output_file: Option<&'a Path>,
+    pub cgroups: Option<&'a CGroups>,
+    pub config: &'a ChildInstance,
+}
+
+fn config_hash(config: &ChildInstance) -> String {
+    let mut buf = [0u8; 8];
+    let mut hash: Blake2b = VariableOutput::new(buf.len()).expect("blake2b");
+    hash.process(to_string(config).expect("serializable").as_bytes());
+    hash.variable_result(&mut buf[..]).expect("blake2b");
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_tail(mut src: &File) -> Result<Vec<u8>, io::Error> {
+    let len = src.metadata()?.len();
+    src.seek(SeekFrom::Start(len.saturating_sub(STDERR_TAIL)))?;
+    let mut buf = Vec::with_capacity(STDERR_TAIL as usize);
+    src.take(STDERR_TAIL).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn cstr(name: &str) -> CString {
+    CString::new(name).expect("name has no nulls")
+}
+
+fn check(rc: libc::c_int) -> Result<(), io::Error> {
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn create_dir_at(dir: &File, name: &str) -> Result<File, io::Error> {
+    let name = cstr(name);
+    check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o755) })?;
+    let fd = unsafe {
+        libc::openat(dir.as_raw_fd(), name.as_ptr(),
+            libc::O_RDONLY|libc::O_DIRECTORY|libc::O_NOFOLLOW|libc::O_CLOEXEC)
+    };
+    check(fd)?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn list_dir(dir: &File) -> Result<Vec<String>, io::Error> {
+    let fd = unsafe { libc::dup(dir.as_raw_fd()) };
+    check(fd)?;
+    let dirp = unsafe { libc::fdopendir(fd) };
+    if dirp.is_null() {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    unsafe { libc::rewinddir(dirp) };
+    let mut names = Vec::new();
+    loop {
+        let entry = unsafe { libc::readdir(dirp) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        let name = name.to_string_lossy();
+        if name != "." && name != ".." {
+            names.push(name.into_owned());
+        }
+    }
+    unsafe { libc::closedir(dirp) };
+    Ok(names)
+}
+
+/// Removes report `name`, which only contains files written by us
+fn remove_report(dir: &File, name: &str) -> Result<(), io::Error> {
+    let cname = cstr(name);
+    let fd = unsafe {
+        libc::openat(dir.as_raw_fd(), cname.as_ptr(),
+            libc::O_RDONLY|libc::O_DIRECTORY|libc::O_NOFOLLOW|libc::O_CLOEXEC)
+    };
+    check(fd)?;
+    let report = unsafe { File::from_raw_fd(fd) };
+    for file in list_dir(&report)? {
+        let file = cstr(&file);
+        check(unsafe {
+            libc::unlinkat(report.as_raw_fd(), file.as_ptr(), 0)
+        })?;
+    }
+    check(unsafe {
+        libc::unlinkat(dir.as_raw_fd(), cname.as_ptr(), libc::AT_REMOVEDIR)
+    })
+}
+
+/// Crash reports directory of the container, opened before the first start
+pub struct CrashReports {
+    dir: File,
+    keep: usize,
+    /// The stderr log of the sandbox
+    log: File,
+}
+
+impl CrashReports {
+    /// Returns `None` if crash reports are disabled or can't be written
+    pub fn open(master: &MasterConfig, name: &str, stderr_path: &Path)
+        -> Option<CrashReports>
+    {
+        if master.keep_crash_reports == 0 {
+            return None;
+        }
+        let path = master.crash_reports_path().join(name);
+        let dir = create_dir_all(&path).and_then(|()| File::open(&path))
+            .map_err(|e| warn!("Can't open crash report dir {:?}: {}",
+                path, e))
+            .ok()?;
+        let log = File::open(stderr_path)
+            .map_err(|e| warn!("Can't open {:?} for crash reports: {}",
+                stderr_path, e))
+            .ok()?;
+        Some(CrashReports { dir, keep: master.keep_crash_reports, log })
+    }
+
+    /// Removes all but `keep` latest reports (names are sortable by time)
+    fn remove_old(&self) -> Result<(), io::Error> {
+        let mut reports = list_dir(&self.dir)?;
+        reports.sort();
+        let num = reports.len().saturating_sub(self.keep);
+        for name in &reports[..num] {
+            remove_report(&self.dir, name)?;
+        }
+        Ok(())
+    }
+
+    /// Writes crash report
+    ///
+    /// Errors are only logged, as this is best effort.
+    pub fn write(&self, crash: &Crash) {
+        let now = SystemTime::now();
+        let stamp = now.duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::new(0, 0));
+        let name = format!("{:010}.{:03}",
+            stamp.as_secs(), stamp.subsec_millis());
+        let dir = match create_dir_at(&self.dir, &name) {
+            Ok(dir) => dir,
+            Err(e) => {
+                warn!("Can't create crash report dir {:?}: {}", name, e);
+                return;
+            }
+        };
+        let tail = match crash.output_file {
+            Some(path) => File::open(path).and_then(|f| read_tail(&f)),
+            None => read_tail(&self.log),
+        };
+        tail.and_then(|buf| create_at(&dir, "stderr.log")?.write_all(&buf))
+            .map_err(|e| warn!("Can't copy output to crash report: {}", e))
+            .ok();
+        let report = Report {
+            name: crash.name.to_string(),
+            time: format_rfc3339_seconds(now).to_string(),
+            status: crash.status.to_string(),
+            exit_code: crash.status.code(),
+            signal: crash.status.signal(),
+            uptime: crash.uptime.as_secs(),
+            memory_peak: crash.cgroups.and_then(|c| {
+                c.get_value(Controller::Memory, "memory.max_usage_in_bytes")
+                .map_err(|e| debug!("Can't read memory peak: {}", e)).ok()
+                .and_then(|x| x.trim().parse().ok())
+            }),
+            image: crash.config.image.clone(),
+            config_hash: config_hash(crash.config),
+        };
+        create_at(&dir, "report.json")
+            .map_err(|e| e.to_string())
+            .and_then(|f| to_writer_pretty(f, &report)
+                .map_err(|e| e.to_string()))
+            .map_err(|e| warn!("Can't write crash report {:?}: {}", name, e))
+            .ok();
+        info!("Crash report {:?} for {:?} written", name, crash.name);
+        self.remove_old()
+            .map_err(|e| warn!("Can't clean old crash reports: {}", e))
+            .ok();
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{stderr, Write};
+use std::path::{Path, PathBuf};
+
+use libc::getpid;
+use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD};
+use quire::{parse_config, Options as COptions};
+use serde_json::to_string;
+use signal::trap::Trap;
+use unshare::{Command, Namespace, reap_zombies};
+
+use lithos::child_config::{ChildConfig, ChildKind};
+use lithos::knot_options::Options;
+use lithos::master_config::{MasterConfig, create_master_dirs};
+use lithos::reason::Reason;
+use lithos::sandbox_config::SandboxConfig;
+use lithos::sandbox_dirs;
+use lithos::setup::clean_child;
+
+use dev_mode;
+
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Splits `sandbox/child[.instance]` into parts
+fn parse_target(target: &str) -> Result<(&str, &str, usize), String> {
+    let mut pair = target.splitn(2, '/');
+    let sandbox = pair.next().unwrap_or("");
+    let rest = pair.next().unwrap_or("");
+    let (child, instance) = match rest.rfind('.') {
+        Some(dot) => {
+            let instance = rest[dot+1..].parse()
+                .map_err(|_| format!("Bad instance number in {:?}", target))?;
+            (&rest[..dot], instance)
+        }
+        None => (rest, 0),
+    };
+    if !valid_name(sandbox) || !valid_name(child) {
+        return Err(format!("Expected `sandbox/child[.instance]`, got {:?}",
+            target));
+    }
+    Ok((sandbox, child, instance))
+}
+
+/// Runs a single child of the sandbox in foreground
+///
+/// Configs are read and instantiated the same way `lithos_tree` does, then
+/// the knot is executed (in new namespaces) with the resulting config.
+pub fn run(options: &Options, target: &str) -> Result<i32, String> {
+    let (sandbox_name, child_name, instance) = parse_target(target)?;
+    let master_file = &options.master_config;
+    let master: MasterConfig = parse_config(master_file,
+        &MasterConfig::validator(), &COptions::default())
+        .map_err(|e| format!("Error reading master config: {}", e))?;
+    let dev = options.dev || master.dev_mode;
+    if dev {
+        // namespaces of the knot below can only be created by root
+        dev_mode::enter_user_namespace()?;
+    }
+    create_master_dirs(&master)?;
+    let sandbox: SandboxConfig = parse_config(
+        sandbox_dirs::find(&master, master_file, sandbox_name),
+        &SandboxConfig::validator(), &COptions::default())
+        .map_err(|e| format!("Error reading sandbox config: {}", e))?;
+    let cfg = master_file.parent().unwrap()
+        .join(&master.processes_dir)
+        .join(sandbox.config_file.as_ref().map(PathBuf::from).unwrap_or(
+            PathBuf::from(format!("{}.yaml", sandbox_name))));
+    let children: BTreeMap<String, ChildConfig> = parse_config(&cfg,
+            &ChildConfig::mapping_validator_with(&master.container_defaults),
+            &COptions::default())
+        .map_err(|e| format!("Error reading children config: {}", e))?;
+    let child = children.get(child_name)
+        .ok_or(format!("Child {:?} not found in {:?}", child_name, cfg))?;
+    if child.kind != ChildKind::Daemon {
+        return Err(format!("Child {:?} is a {:?}, use lithos_cmd instead",
+            child_name, child.kind));
+    }
+    if instance >= child.instances {
+        return Err(format!("Child {:?} has only {} instances",
+            child_name, child.instances));
+    }
+    let instance_cfg = child.instantiate(instance)
+        .map_err(|e| format!("Can't instantiate: {}", e))?;
+    if instance_cfg.ip_address.is_none() &&
+        sandbox.bridged_network.as_ref()
+            .map(|b| b.ip_pool.is_some()).unwrap_or(false)
+    {
+        // logging is not initialized yet, as sandbox isn't known in advance
+        writeln!(&mut stderr(), "Warning: IP address is allocated by \
+            lithos_tree, container is run without it").ok();
+    }
+
+    // unique name, so it doesn't clash with the process run by lithos_tree
+    let name = format!("{}/debug.{}.{}.{}", sandbox_name, child_name,
+        instance, unsafe { getpid() });
+    let mut cmd = Command::new(env::current_exe()
+        .map_err(|e| format!("Can't find lithos_knot binary: {}", e))?);
+    cmd.arg("--name");
+    cmd.arg(&name);
+    cmd.arg("--master");
+    cmd.arg(Path::new(master_file));
+    cmd.arg("--config");
+    cmd.arg(to_string(&instance_cfg).expect("config is serializable"));
+    cmd.arg("--log-stderr");
+    if dev {
+        cmd.arg("--dev");
+    }
+    if let Some(log_level) = options.log_level {
+        cmd.arg(format!("--log-level={}", log_level));
+    }
+    cmd.env_clear();
+    cmd.env("TERM", env::var_os("TERM").unwrap_or(From::from("dumb")));
+    if let Some(x) = env::var_os("RUST_LOG") {
+        cmd.env("RUST_LOG", x);
+    }
+    if let Some(x) = env::var_os("RUST_BACKTRACE") {
+        cmd.env("RUST_BACKTRACE", x);
+    }
+    cmd.unshare(&[Namespace::Mount, Namespace::Uts,
+                  Namespace::Ipc, Namespace::Pid]);
+
+    let trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD]);
+    let child = cmd.spawn()
+        .map_err(|e| format!("Can't run {:?}: {}", cmd, e))?;
+    let mut code = 1;
+    'outer: for signal in trap {
+        match signal {
+            SIGINT | SIGTERM => {
+                // knot stops the process gracefully, respecting kill-timeout
+                child.signal(SIGTERM).ok();
+            }
+            SIGCHLD => {
+                for (pid, status) in reap_zombies() {
+                    if pid == child.pid() {
+                        writeln!(&mut stderr(), "Container {:?} {}",
+                            name, status).ok();
+                        code = status.code().unwrap_or(1);
+                        break 'outer;
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    clean_child(&name, &master, false, Reason::Exit);
+    Ok(code)
+}
+
@@ -0,0 +1,103 @@
+//! Local development mode (`--dev` or `dev-mode` in master config)
+//!
+//! When knot is run by a plain user, it enters a user namespace where the
+//! user is root, like `unshare --map-root-user` does. Only that single user
+//! is mapped, so the process runs as root of the namespace (i.e. as the
+//! user outside of it), whatever `user-id` is in the config.
+use std::fs::{File, read_to_string};
+use std::io::Write;
+
+use libc;
+use nix::sched::{unshare, CloneFlags};
+
+use lithos::id_map::IdMap;
+
+
+fn write_file(path: &str, data: &str) -> Result<(), String> {
+    File::create(path)
+        .and_then(|mut f| f.write_all(data.as_bytes()))
+        .map_err(|e| format!("Can't write {:?}: {}", path, e))
+}
+
+/// Enters a user (and mount) namespace, unless knot is already run by root
+pub fn enter_user_namespace() -> Result<(), String> {
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if uid == 0 {
+        return Ok(());
+    }
+    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+        .map_err(|e| format!("Can't create user namespace: {}", e))?;
+    // unprivileged user can't write gid_map unless setgroups is denied
+    write_file("/proc/self/setgroups", "deny")?;
+    write_file("/proc/self/uid_map", &format!("0 {} 1", uid))?;
+    write_file("/proc/self/gid_map", &format!("0 {} 1", gid))?;
+    Ok(())
+}
+
+/// Returns true if only a single user is mapped into current namespace
+pub fn single_user() -> bool {
+    read_to_string("/proc/self/uid_map").ok()
+        .map(|map| {
+            let lines = map.lines().collect::<Vec<_>>();
+            lines.len() == 1 &&
+                lines[0].split_whitespace().nth(2) == Some("1")
+        })
+        .unwrap_or(false)
+}
+
+/// Checks that `uid-map` and `gid-map` can be set up in the namespace of a
+/// plain user (see `single_user`)
+///
+/// Only root of that namespace (the user itself) can be mapped, otherwise
+/// the kernel refuses to write the maps and the container fails with
+/// uninformative `EPERM`.
+pub fn check_id_maps(uid_map: &[IdMap], gid_map: &[IdMap])
+    -> Result<(), String>
+{
+    let single = |map: &[IdMap]| {
+        map.iter().all(|item| item.outside == 0 && item.count == 1)
+    };
+    if single(uid_map) && single(gid_map) {
+        return Ok(());
+    }
+    Err(format!("Dev mode: only a single user is mapped into the user \
+        namespace of a plain user, so uid-map and gid-map can only map \
+        a single id to 0 (uid-map: {}, gid-map: {}). Remove them for \
+        development or run lithos_knot as root",
+        format_map(uid_map), format_map(gid_map)))
+}
+
+fn format_map(map: &[IdMap]) -> String {
+    let items = map.iter()
+        .map(|m| format!("{} {} {}", m.inside, m.outside, m.count))
+        .collect::<Vec<_>>();
+    format!("[{}]", items.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use lithos::id_map::IdMap;
+    use super::check_id_maps;
+
+    fn map(inside: u32, outside: u32, count: u32) -> IdMap {
+        IdMap { inside, outside, count }
+    }
+
+    #[test]
+    fn no_maps() {
+        assert_eq!(check_id_maps(&[], &[]), Ok(()));
+    }
+
+    #[test]
+    fn single_id() {
+        assert_eq!(check_id_maps(&[map(1000, 0, 1)], &[map(1000, 0, 1)]),
+            Ok(()));
+    }
+
+    #[test]
+    fn range() {
+        let err = check_id_maps(&[map(0, 100000, 65536)], &[map(0, 0, 1)])
+            .unwrap_err();
+        assert!(err.contains("uid-map: [0 100000 65536], gid-map: [0 0 1]"));
+    }
+}
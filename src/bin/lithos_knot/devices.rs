@@ -0,0 +1,93 @@
+use std::fs::{File, create_dir, read_dir, read_link, symlink_metadata};
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+use libmount::{self, BindMount};
+use nix::sys::stat::{major, minor};
+
+use lithos::cgroup::{CGroups, Controller};
+use lithos::container_config::InstantiatedConfig;
+use lithos::sandbox_config::SandboxConfig;
+
+use setup_gpu::gpu_devices;
+
+
+/// Returns device nodes to add to the standard ones in the container
+///
+/// Paths are relative to `/dev`.
+pub fn extra_devices(tree: &SandboxConfig, local: &InstantiatedConfig)
+    -> Result<Vec<PathBuf>, Error>
+{
+    let mut result = Vec::new();
+    if let Some(ref gpus) = local.gpus {
+        result.extend(gpu_devices(gpus)?);
+    }
+    if tree.allow_fuse {
+        result.push(PathBuf::from("fuse"));
+    }
+    Ok(result)
+}
+
+/// Mounts /dev of the container with extra devices added
+///
+/// The directory prepared by `lithos_mkdev` is shared by all containers, so
+/// instead of bind-mounting it, we recreate it on tmpfs, bind-mounting its
+/// entries and extra devices on top. Caller remounts it read-only.
+pub fn mount_dev(devfs_dir: &Path, devdir: &Path, devices: &[PathBuf])
+    -> Result<(), Error>
+{
+    libmount::Tmpfs::new(devdir).size_bytes(1 << 20).mode(0o755)
+        .mount().map_err(|e| format_err!("{}", e))?;
+    for entry in read_dir(devfs_dir)
+        .context(format!("can't list {:?}", devfs_dir))?
+    {
+        let entry = entry?;
+        let src = entry.path();
+        let dest = devdir.join(entry.file_name());
+        let typ = entry.file_type()?;
+        if typ.is_symlink() {
+            symlink(read_link(&src)?, &dest)
+                .context(format!("can't create {:?}", dest))?;
+            continue;
+        } else if typ.is_dir() {
+            create_dir(&dest).context(format!("can't create {:?}", dest))?;
+        } else {
+            File::create(&dest).context(format!("can't create {:?}", dest))?;
+        }
+        BindMount::new(&src, &dest).mount()
+            .map_err(|e| format_err!("{}", e))?;
+    }
+    for path in devices {
+        let dest = devdir.join(path);
+        if let Some(dir) = dest.parent() {
+            if !dir.exists() {
+                create_dir(dir).context(format!("can't create {:?}", dir))?;
+            }
+        }
+        File::create(&dest).context(format!("can't create {:?}", dest))?;
+        BindMount::new(Path::new("/dev").join(path), &dest).mount()
+            .map_err(|e| format_err!("{}", e))?;
+    }
+    Ok(())
+}
+
+/// Allows access to the extra devices in the devices cgroup
+pub fn allow_devices(cgroups: &CGroups, devices: &[PathBuf])
+    -> Result<(), Error>
+{
+    if !cgroups.has_controller(Controller::Devices) {
+        debug!("No devices cgroup, skipping device permissions");
+        return Ok(());
+    }
+    for path in devices {
+        let full_path = Path::new("/dev").join(path);
+        let meta = symlink_metadata(&full_path)
+            .context(format!("can't stat {:?}", full_path))?;
+        let rdev = meta.rdev();
+        cgroups.set_value(Controller::Devices, "devices.allow",
+                &format!("c {}:{} rwm", major(rdev), minor(rdev)))
+            .map_err(|e| format_err!("{}", e))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Marks the process as draining on planned stops (`drain-file`)
+//!
+//! The directory of the file is opened before the first start, and the file
+//! is accessed relative to it, because the root of the knot changes when
+//! the process is started. Symlinks are not followed, as the image may be
+//! writable by the container.
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use libc;
+use nix::fcntl::{openat, OFlag};
+use nix::sys::stat::Mode;
+
+use lithos::container_config::DrainFile;
+use lithos::utils::open_dir_nofollow;
+
+
+pub struct Marker {
+    dir: File,
+    name: CString,
+    pub delay: f32,
+}
+
+impl Marker {
+    /// Opens the directory of the file in the image mounted at `root`
+    pub fn open(root: &Path, config: &DrainFile) -> Result<Marker, String> {
+        let (dir, name) = match (config.path.parent(), config.path.file_name())
+        {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(format!("Invalid drain-file {:?}", config.path)),
+        };
+        let dir = open_dir_nofollow(root, dir)
+            .map_err(|e| format!("Can't open directory of drain-file {:?}: {}",
+                config.path, e))?;
+        Ok(Marker {
+            dir,
+            name: CString::new(name.as_bytes()).expect("path has no nulls"),
+            delay: config.delay,
+        })
+    }
+    /// Removes the file, so a freshly started process is not draining
+    pub fn clear(&self) -> Result<(), String> {
+        let rc = unsafe {
+            libc::unlinkat(self.dir.as_raw_fd(), self.name.as_ptr(), 0)
+        };
+        if rc != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(format!("Can't remove drain-file: {}", e));
+            }
+        }
+        Ok(())
+    }
+    pub fn mark(&self) -> Result<(), String> {
+        let fd = openat(self.dir.as_raw_fd(), self.name.as_bytes(),
+            OFlag::O_WRONLY|OFlag::O_CREAT|OFlag::O_TRUNC|OFlag::O_NOFOLLOW|
+            OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644))
+            .map_err(|e| format!("Can't create drain-file: {}", e))?;
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(b"draining\n")
+            .map_err(|e| format!("Can't write drain-file: {}", e))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Explanation of the common reasons why `execve` of the process fails
+//!
+//! Kernel only reports an error code, which for a binary built for another
+//! architecture or a script with Windows line endings is quite cryptic.
+//! So we look at the executable in the image to give a hint.
+use std::env::consts::ARCH;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use libc::{ENOENT, ENOEXEC, EUCLEAN};
+
+use lithos::utils::relative;
+
+
+/// Name of the architecture by the `e_machine` field of the ELF header
+fn elf_machine(machine: u16) -> Option<&'static str> {
+    let name = match machine {
+        3 => "x86",
+        8 => "mips",
+        20 => "powerpc",
+        21 => "powerpc64",
+        22 => "s390x",
+        40 => "arm",
+        62 => "x86_64",
+        183 => "aarch64",
+        243 => "riscv64",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Returns `e_machine` of the ELF file by its header
+fn elf_arch(header: &[u8]) -> Option<u16> {
+    if header.len() < 20 || !header.starts_with(b"\x7fELF") {
+        return None;
+    }
+    // EI_DATA: 1 is little endian, 2 is big endian
+    let machine = match header[5] {
+        1 => header[18] as u16 | (header[19] as u16) << 8,
+        2 => (header[18] as u16) << 8 | header[19] as u16,
+        _ => return None,
+    };
+    Some(machine)
+}
+
+fn explain_elf(executable: &Path, header: &[u8]) -> String {
+    match elf_arch(header) {
+        Some(machine) => match elf_machine(machine) {
+            Some(arch) if arch != ARCH => {
+                format!("{:?} is built for {}, host is {}",
+                    executable, arch, ARCH)
+            }
+            Some(_) => format!("{:?} is built for {}, but kernel can't \
+                run it, probably it's truncated or corrupted",
+                executable, ARCH),
+            None => format!("{:?} is built for unknown architecture \
+                (ELF machine {}), host is {}", executable, machine, ARCH),
+        },
+        None => format!("{:?} has invalid ELF header", executable),
+    }
+}
+
+fn explain_script(root: &Path, executable: &Path, header: &[u8])
+    -> Option<String>
+{
+    let line = header[2..].split(|&c| c == b'\n').next().unwrap_or(b"");
+    if line.ends_with(b"\r") {
+        return Some(format!("shebang line of {:?} ends with CR, \
+            the script probably has Windows line endings (CRLF)",
+            executable));
+    }
+    let line = String::from_utf8_lossy(line);
+    let interpreter = match line.split_whitespace().next() {
+        Some(interpreter) => Path::new(interpreter).to_path_buf(),
+        None => {
+            return Some(format!("shebang line of {:?} is empty",
+                executable));
+        }
+    };
+    if interpreter.is_absolute() &&
+        !root.join(relative(&interpreter, Path::new("/"))).exists()
+    {
+        return Some(format!("interpreter {:?} of {:?} doesn't exist \
+            in the image", interpreter, executable));
+    }
+    None
+}
+
+/// Returns a hint for the `errno` of failed exec, if we have one
+pub fn explain(root: &Path, executable: &Path, errno: i32)
+    -> Option<String>
+{
+    if errno != ENOEXEC && errno != EUCLEAN && errno != ENOENT {
+        return None;
+    }
+    if !executable.is_absolute() {
+        return None;
+    }
+    let path = root.join(relative(executable, Path::new("/")));
+    let mut header = [0u8; 256];
+    let bytes = match File::open(&path).and_then(|mut f| f.read(&mut header))
+    {
+        Ok(bytes) => bytes,
+        Err(_) if errno == ENOENT => {
+            return Some(format!("{:?} doesn't exist in the image",
+                executable));
+        }
+        Err(_) => return None,
+    };
+    let header = &header[..bytes];
+    if header.starts_with(b"\x7fELF") {
+        if errno == ENOENT {
+            // dynamic loader is missing, e.g. glibc binary in musl image
+            return Some(format!("{:?} exists, but its dynamic loader \
+                (ELF interpreter) doesn't exist in the image", executable));
+        }
+        return Some(explain_elf(executable, header));
+    }
+    if header.starts_with(b"#!") {
+        return explain_script(root, executable, header);
+    }
+    if errno != ENOENT {
+        return Some(format!("{:?} is neither an ELF binary nor a script \
+            with a shebang line", executable));
+    }
+    None
+}
@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc;
+use nix::sched::{setns, CloneFlags};
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, ForkResult};
+
+use lithos::mount::{MountRecord, unmount};
+
+
+/// Puts the process into its own mount namespace
+///
+/// Called in the child right before exec. The namespace is owned by the
+/// user namespace of the container, so the process having `CAP_SYS_ADMIN`
+/// there can mount FUSE filesystems, which are invisible to everything
+/// outside of the container.
+pub fn unshare_mounts() -> io::Result<()> {
+    // bare syscall, as it runs in the child after fork
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Unmounts FUSE filesystems left in the mount namespace of the process
+/// when dropped
+///
+/// Namespace is kept open after the process is dead, so we can clean it up
+/// even if some FUSE daemons are still running in the container.
+pub struct Cleanup {
+    mount_ns: File,
+}
+
+impl Cleanup {
+    pub fn new(pid: libc::pid_t) -> Result<Cleanup, String> {
+        File::open(format!("/proc/{}/ns/mnt", pid))
+            .map(|mount_ns| Cleanup { mount_ns })
+            .map_err(|e| format!("Can't open mount namespace: {}", e))
+    }
+}
+
+fn fuse_mounts() -> Result<Vec<String>, String> {
+    let mut buf = String::with_capacity(4096);
+    File::open("/proc/self/mountinfo")
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|e| format!("Can't read mountinfo: {}", e))?;
+    let mut result = Vec::new();
+    for line in buf.lines() {
+        let rec = MountRecord::from_str(line)
+            .map_err(|()| format!("Can't parse mountinfo line: {:?}", line))?;
+        if rec.fstype == "fuse" || rec.fstype == "fuseblk" ||
+            rec.fstype.starts_with("fuse.")
+        {
+            result.push(rec.mount_point.to_string());
+        }
+    }
+    // nested mounts first
+    result.reverse();
+    Ok(result)
+}
+
+fn cleanup_namespace(mount_ns: &File) -> Result<(), String> {
+    setns(mount_ns.as_raw_fd(), CloneFlags::CLONE_NEWNS)
+        .map_err(|e| format!("Can't join mount namespace: {}", e))?;
+    for path in fuse_mounts()? {
+        info!("Unmounting leftover FUSE filesystem {:?}", path);
+        unmount(Path::new(&path))?;
+    }
+    Ok(())
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        // setns() changes root of the process, so it's done in a subprocess
+        match fork() {
+            Ok(ForkResult::Child) => {
+                let code = match cleanup_namespace(&self.mount_ns) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        error!("FUSE cleanup: {}", e);
+                        1
+                    }
+                };
+                unsafe { libc::_exit(code) };
+            }
+            Ok(ForkResult::Parent { child }) => {
+                waitpid(child, None)
+                    .map_err(|e| error!("Can't wait FUSE cleanup: {}", e))
+                    .ok();
+            }
+            Err(e) => error!("Can't fork for FUSE cleanup: {}", e),
+        }
+    }
+}
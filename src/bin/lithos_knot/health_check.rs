@@ -0,0 +1,237 @@
+//! Periodic checks of the process (`health-check` and `readiness`)
+//!
+//! Checks are run from the main loop of the knot on `SIGALRM`, so each check
+//! blocks the loop for at most `timeout`. Network checks connect from the
+//! network namespace of the process.
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use nix::sched::{setns, CloneFlags};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use unshare;
+
+use lithos::container_config::{HealthCheck, HealthProbe, ReadinessCheck};
+use lithos::container_config::{ExecCheck, HttpCheck};
+use lithos::shared_metrics::{SharedCounters, Slot};
+
+
+/// Interval of polling the command of the `Exec` check
+const EXEC_POLL_INTERVAL: u64 = 10;
+
+/// Starts the command of the `Exec` check in the network namespace of the
+/// process (if it isn't in the namespace of the host)
+pub type Spawn<'a> =
+    &'a Fn(&ExecCheck, Option<&File>) -> Result<unshare::Child, String>;
+
+pub struct Checker<'a> {
+    name: &'a str,
+    config: &'a HealthCheck,
+    failures: u32,
+    next_check: Instant,
+}
+
+/// Waits until the process passes the `readiness` check
+pub struct Readiness<'a> {
+    name: &'a str,
+    config: &'a ReadinessCheck,
+    give_up: Instant,
+    next_check: Instant,
+}
+
+fn duration(inp: f32) -> Duration {
+    // zero timeout is not allowed for sockets
+    Duration::from_millis((inp * 1000.) as u64).max(Duration::from_millis(1))
+}
+
+/// Runs `f` in the network namespace `netns`, or in the current one if
+/// it's `None`
+fn in_namespace<T, F>(netns: Option<&File>, f: F) -> Result<T, String>
+    where F: FnOnce() -> Result<T, String>
+{
+    let netns = match netns {
+        Some(netns) => netns,
+        None => return f(),
+    };
+    let parent = File::open("/proc/self/ns/net")
+        .map_err(|e| format!("can't open parent namespace: {}", e))?;
+    setns(netns.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+        .map_err(|e| format!("can't join network namespace: {}", e))?;
+    let result = f();
+    setns(parent.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+        .expect("can return into parent namespace");
+    result
+}
+
+fn connect(addr: &SocketAddr, timeout: Duration)
+    -> Result<TcpStream, String>
+{
+    TcpStream::connect_timeout(addr, timeout)
+        .map_err(|e| format!("can't connect to {}: {}", addr, e))
+}
+
+fn http(check: &HttpCheck, timeout: Duration) -> Result<(), String> {
+    let addr = SocketAddr::new(check.host, check.port);
+    let mut sock = connect(&addr, timeout)?;
+    sock.set_read_timeout(Some(timeout))
+        .and_then(|()| sock.set_write_timeout(Some(timeout)))
+        .map_err(|e| format!("can't set timeout: {}", e))?;
+    write!(sock, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", check.path, addr)
+        .map_err(|e| format!("error sending request: {}", e))?;
+    let mut line = String::new();
+    BufReader::new(sock).read_line(&mut line)
+        .map_err(|e| format!("error reading response: {}", e))?;
+    let status = line.split_whitespace().nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| format!("bad status line {:?}", line.trim()))?;
+    if status >= 200 && status < 400 {
+        Ok(())
+    } else {
+        Err(format!("status {}", status))
+    }
+}
+
+fn exec(mut child: unshare::Child, timeout: Duration) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    let pid = Pid::from_raw(child.pid());
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {}
+            Ok(WaitStatus::Exited(_, 0)) => return Ok(()),
+            Ok(status) => return Err(format!("command {:?}", status)),
+            Err(e) => return Err(format!("can't wait command: {}", e)),
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(format!("command timed out"));
+        }
+        sleep(Duration::from_millis(EXEC_POLL_INTERVAL));
+    }
+}
+
+/// Runs the check once
+///
+/// `netns` is the network namespace of the process, if it isn't in the
+/// namespace of the host.
+fn probe(check: &HealthProbe, timeout: f32, netns: Option<&File>,
+    spawn: Spawn)
+    -> Result<(), String>
+{
+    let timeout = duration(timeout);
+    match *check {
+        HealthProbe::Tcp(ref check) => in_namespace(netns, || {
+            connect(&SocketAddr::new(check.host, check.port), timeout)
+            .map(|_| ())
+        }),
+        HealthProbe::Http(ref check) => in_namespace(netns, || {
+            http(check, timeout)
+        }),
+        HealthProbe::Exec(ref check) => {
+            spawn(check, netns).and_then(|child| exec(child, timeout))
+        }
+    }
+}
+
+impl<'a> Checker<'a> {
+    /// Starts checking a freshly started process, the first check is done
+    /// after `interval`
+    pub fn new(name: &'a str, config: &'a HealthCheck,
+        counters: Option<&SharedCounters>)
+        -> Checker<'a>
+    {
+        if let Some(c) = counters {
+            c.set(Slot::Unhealthy, 0);
+        }
+        Checker {
+            name,
+            config,
+            failures: 0,
+            next_check: Instant::now() + duration(config.interval),
+        }
+    }
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next_check <= now
+    }
+    /// Number of consecutive failed checks
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+    /// Runs the check, returns true if the process should be restarted
+    pub fn run(&mut self, netns: Option<&File>, spawn: Spawn,
+        counters: Option<&SharedCounters>)
+        -> bool
+    {
+        let result = probe(&self.config.check, self.config.timeout,
+            netns, spawn);
+        // checks may take a while, so interval is counted from the end
+        self.next_check = Instant::now() + duration(self.config.interval);
+        if let Some(c) = counters {
+            c.incr(Slot::HealthChecks);
+        }
+        match result {
+            Ok(()) => {
+                if self.failures > 0 {
+                    info!("[{}] Health check passed after {} failures",
+                        self.name, self.failures);
+                }
+                self.failures = 0;
+            }
+            Err(e) => {
+                self.failures += 1;
+                warn!("[{}] Health check failed ({} of {}): {}", self.name,
+                    self.failures, self.config.failure_threshold, e);
+                if let Some(c) = counters {
+                    c.incr(Slot::HealthCheckFailures);
+                }
+            }
+        }
+        if let Some(c) = counters {
+            c.set(Slot::Unhealthy, (self.failures > 0) as u64);
+        }
+        self.failures >= self.config.failure_threshold
+    }
+}
+
+impl<'a> Readiness<'a> {
+    /// Starts waiting for a freshly started process
+    pub fn new(name: &'a str, config: &'a ReadinessCheck) -> Readiness<'a> {
+        let now = Instant::now();
+        Readiness {
+            name,
+            config,
+            give_up: now + duration(config.max_wait),
+            next_check: now,
+        }
+    }
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next_check <= now && now < self.give_up
+    }
+    /// Returns true after `max-wait`, i.e. process will never be ready
+    pub fn expired(&self, now: Instant) -> bool {
+        now >= self.give_up
+    }
+    /// Runs the check, returns true if the process is ready
+    pub fn run(&mut self, netns: Option<&File>, spawn: Spawn) -> bool {
+        let result = probe(&self.config.check, self.config.timeout,
+            netns, spawn);
+        let now = Instant::now();
+        self.next_check = now + duration(self.config.interval);
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("[{}] Readiness check failed: {}", self.name, e);
+                if self.expired(self.next_check) {
+                    error!("[{}] Process is not ready in {}s, \
+                        giving up readiness checks",
+                        self.name, self.config.max_wait);
+                }
+                false
+            }
+        }
+    }
+}
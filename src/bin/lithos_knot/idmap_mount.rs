@@ -0,0 +1,155 @@
+//! Idmapped bind mounts (Linux 5.12+)
+//!
+//! Files on the volume are stored with ids as seen inside the container,
+//! and kernel translates them through the user namespace mapping on access.
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+
+use failure::{Error, ResultExt};
+use libc::{self, c_int, c_uint, c_long};
+
+use lithos::id_map::IdMap;
+
+// These are the same on all architectures (unified syscall numbering)
+const SYS_OPEN_TREE: c_long = 428;
+const SYS_MOVE_MOUNT: c_long = 429;
+const SYS_MOUNT_SETATTR: c_long = 442;
+
+const OPEN_TREE_CLONE: c_uint = 1;
+const AT_RECURSIVE: c_uint = 0x8000;
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x4;
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+fn cstr(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).expect("path has no nulls")
+}
+
+fn check(rc: c_long, operation: &str) -> Result<c_long, Error> {
+    if rc < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENOSYS) {
+            bail!("{}: idmapped mounts require Linux 5.12 or newer",
+                  operation);
+        }
+        bail!("{}: {}", operation, err);
+    }
+    Ok(rc)
+}
+
+fn write_map(path: &str, map: &[IdMap]) -> Result<(), Error> {
+    let mut buf = String::new();
+    for item in map {
+        buf.push_str(&format!("{} {} {}\n",
+            item.inside, item.outside, item.count));
+    }
+    OpenOptions::new().write(true).open(path)
+        .and_then(|mut f| f.write_all(buf.as_bytes()))
+        .context(format!("can't write {}", path))?;
+    Ok(())
+}
+
+/// Creates user namespace with specified mapping
+///
+/// Namespace is created by a short-living child process and is kept alive
+/// by the returned file descriptor.
+pub fn user_namespace(uid_map: &[IdMap], gid_map: &[IdMap])
+    -> Result<File, Error>
+{
+    let mut fds = [0 as c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        bail!("can't create pipe: {}", io::Error::last_os_error());
+    }
+    let (read_fd, write_fd) = (fds[0], fds[1]);
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        bail!("can't fork: {}", err);
+    }
+    if pid == 0 {
+        // child: create namespace, report and wait to be killed
+        unsafe {
+            libc::close(read_fd);
+            let code: u8 = if libc::unshare(libc::CLONE_NEWUSER) == 0 {
+                0
+            } else {
+                1
+            };
+            libc::write(write_fd, &code as *const u8 as *const _, 1);
+            loop {
+                libc::pause();
+            }
+        }
+    }
+    unsafe { libc::close(write_fd) };
+    let result = setup_namespace(pid, read_fd, uid_map, gid_map);
+    unsafe {
+        libc::close(read_fd);
+        libc::kill(pid, libc::SIGKILL);
+        libc::waitpid(pid, ::std::ptr::null_mut(), 0);
+    }
+    result
+}
+
+fn setup_namespace(pid: libc::pid_t, read_fd: RawFd,
+    uid_map: &[IdMap], gid_map: &[IdMap])
+    -> Result<File, Error>
+{
+    let mut code = 1u8;
+    let n = unsafe { libc::read(read_fd, &mut code as *mut u8 as *mut _, 1) };
+    if n != 1 || code != 0 {
+        bail!("can't create user namespace");
+    }
+    write_map(&format!("/proc/{}/uid_map", pid), uid_map)?;
+    write_map(&format!("/proc/{}/gid_map", pid), gid_map)?;
+    let ns = File::open(format!("/proc/{}/ns/user", pid))
+        .context("can't open user namespace")?;
+    Ok(ns)
+}
+
+/// Bind-mounts `src` to `dest` with ids mapped through `userns`
+pub fn bind_mount(src: &Path, dest: &Path, userns: &File)
+    -> Result<(), Error>
+{
+    let c_src = cstr(src);
+    let c_dest = cstr(dest);
+    let empty = CString::new("").expect("no nulls");
+    let fd = check(unsafe {
+        libc::syscall(SYS_OPEN_TREE, libc::AT_FDCWD, c_src.as_ptr(),
+            OPEN_TREE_CLONE | AT_RECURSIVE | libc::O_CLOEXEC as c_uint)
+    }, "open_tree")?;
+    // closed on any exit from this function
+    let tree = unsafe { File::from_raw_fd(fd as RawFd) };
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+    check(unsafe {
+        libc::syscall(SYS_MOUNT_SETATTR, tree.as_raw_fd(), empty.as_ptr(),
+            (libc::AT_EMPTY_PATH | AT_RECURSIVE as c_int) as c_uint,
+            &attr as *const MountAttr,
+            ::std::mem::size_of::<MountAttr>())
+    }, "mount_setattr")?;
+    check(unsafe {
+        libc::syscall(SYS_MOVE_MOUNT, tree.as_raw_fd(), empty.as_ptr(),
+            libc::AT_FDCWD, c_dest.as_ptr(), MOVE_MOUNT_F_EMPTY_PATH)
+    }, "move_mount")?;
+    Ok(())
+}
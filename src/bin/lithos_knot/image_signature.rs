@@ -0,0 +1,239 @@
+//! Verification of the image against a minisign-signed manifest
+//!
+//! Manifest (see `lithos::image_manifest`) lists every entry of the image.
+//! It's shipped next to the image directory as `<image>.manifest` and
+//! signed by `minisign` into `<image>.manifest.minisig`.
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{File, Metadata, create_dir_all, rename};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use base64;
+use blake2::{Blake2b, digest::VariableOutput, digest::Input};
+use crypto::ed25519;
+use failure::{Error, ResultExt};
+
+use lithos::image_manifest;
+
+
+struct PublicKey {
+    key_id: [u8; 8],
+    key: [u8; 32],
+}
+
+struct Signature {
+    prehashed: bool,
+    key_id: [u8; 8],
+    signature: Vec<u8>,
+    trusted_comment: String,
+    global_signature: Vec<u8>,
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    return name.into();
+}
+
+fn read_file(path: &Path) -> Result<String, Error> {
+    let mut buf = String::with_capacity(1024);
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .context(format!("can't read {:?}", path))?;
+    Ok(buf)
+}
+
+/// Returns non-empty lines of the file skipping `untrusted comment`
+fn data_lines(data: &str) -> Vec<&str> {
+    data.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with("untrusted comment:"))
+        .collect()
+}
+
+fn parse_public_key(path: &Path) -> Result<PublicKey, Error> {
+    let data = read_file(path)?;
+    let lines = data_lines(&data);
+    if lines.len() != 1 {
+        bail!("{:?} is not a minisign public key", path);
+    }
+    let raw = base64::decode(lines[0])
+        .context(format!("can't decode public key {:?}", path))?;
+    if raw.len() != 42 || &raw[..2] != b"Ed" {
+        bail!("{:?} is not an ed25519 minisign public key", path);
+    }
+    let mut key = PublicKey { key_id: [0; 8], key: [0; 32] };
+    key.key_id.copy_from_slice(&raw[2..10]);
+    key.key.copy_from_slice(&raw[10..]);
+    Ok(key)
+}
+
+fn parse_signature(path: &Path) -> Result<Signature, Error> {
+    let data = read_file(path)?;
+    let lines = data_lines(&data);
+    if lines.len() != 3 || !lines[1].starts_with("trusted comment: ") {
+        bail!("{:?} is not a minisign signature", path);
+    }
+    let raw = base64::decode(lines[0])
+        .context(format!("can't decode signature {:?}", path))?;
+    if raw.len() != 74 {
+        bail!("{:?} is not a minisign signature", path);
+    }
+    let prehashed = match &raw[..2] {
+        b"Ed" => false,
+        b"ED" => true,
+        _ => bail!("unsupported signature algorithm in {:?}", path),
+    };
+    let global_signature = base64::decode(lines[2])
+        .context(format!("can't decode signature {:?}", path))?;
+    if global_signature.len() != 64 {
+        bail!("{:?} is not a minisign signature", path);
+    }
+    let mut key_id = [0u8; 8];
+    key_id.copy_from_slice(&raw[2..10]);
+    Ok(Signature {
+        prehashed,
+        key_id,
+        signature: raw[10..].to_vec(),
+        trusted_comment: lines[1]["trusted comment: ".len()..].to_string(),
+        global_signature,
+    })
+}
+
+fn b2_hash(data: &[u8]) -> Vec<u8> {
+    let mut buf = [0u8; 64];
+    let mut hash: Blake2b = VariableOutput::new(buf.len()).expect("blake2b");
+    hash.process(data);
+    hash.variable_result(&mut buf[..]).expect("blake2b");
+    return buf.to_vec();
+}
+
+fn verify(key: &PublicKey, sig: &Signature, data: &[u8])
+    -> Result<(), Error>
+{
+    if key.key_id != sig.key_id {
+        bail!("signature is made by a different key");
+    }
+    let valid = if sig.prehashed {
+        ed25519::verify(&b2_hash(data), &key.key, &sig.signature)
+    } else {
+        ed25519::verify(data, &key.key, &sig.signature)
+    };
+    if !valid {
+        bail!("invalid signature");
+    }
+    let mut global = sig.signature.clone();
+    global.extend(sig.trusted_comment.as_bytes());
+    if !ed25519::verify(&global, &key.key, &sig.global_signature) {
+        bail!("invalid signature of the trusted comment");
+    }
+    Ok(())
+}
+
+/// Hashes of the files of the image by path, with the stat of the file
+///
+/// Hash of the file is reused on the next start, if device, inode, size,
+/// mtime and ctime of the file are unchanged (any change of the file or of
+/// its mode updates ctime).
+struct Cache {
+    path: PathBuf,
+    old: BTreeMap<PathBuf, (String, String)>,
+    new: BTreeMap<PathBuf, (String, String)>,
+}
+
+fn stat_key(meta: &Metadata) -> String {
+    format!("{}:{}:{}:{}.{}:{}.{}", meta.dev(), meta.ino(), meta.size(),
+        meta.mtime(), meta.mtime_nsec(), meta.ctime(), meta.ctime_nsec())
+}
+
+impl Cache {
+    fn open(dir: &Path, image: &Path) -> Cache {
+        let name: String = b2_hash(image.as_os_str().as_bytes())[..16]
+            .iter().map(|b| format!("{:02x}", b)).collect();
+        let path = dir.join(name);
+        let mut old = BTreeMap::new();
+        // missing or broken cache only means that files are hashed again
+        if let Ok(data) = read_file(&path) {
+            for line in data.lines() {
+                let mut parts = line.splitn(3, ' ');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(hash), Some(stat), Some(path)) => {
+                        old.insert(PathBuf::from(path),
+                            (stat.to_string(), hash.to_string()));
+                    }
+                    _ => break,
+                }
+            }
+        }
+        Cache { path, old, new: BTreeMap::new() }
+    }
+    fn hash(&mut self, root: &Path, path: &Path, meta: &Metadata)
+        -> Result<String, Error>
+    {
+        let rel = path.strip_prefix(root).unwrap_or(path).to_path_buf();
+        let stat = stat_key(meta);
+        let hash = match self.old.get(&rel) {
+            Some(&(ref old_stat, ref hash)) if old_stat == &stat => {
+                hash.clone()
+            }
+            _ => image_manifest::file_hash(path)?,
+        };
+        self.new.insert(rel, (stat, hash.clone()));
+        Ok(hash)
+    }
+    fn save(&self) {
+        let mut buf = String::new();
+        for (path, &(ref stat, ref hash)) in &self.new {
+            buf.push_str(&format!("{} {} {}\n",
+                hash, stat, path.display()));
+        }
+        let tmp = suffixed(&self.path, ".tmp");
+        create_dir_all(self.path.parent().expect("cache in a directory"))
+            .and_then(|()| File::create(&tmp))
+            .and_then(|mut f| f.write_all(buf.as_bytes()))
+            .and_then(|()| rename(&tmp, &self.path))
+            .map_err(|e| warn!("Can't write image hash cache {:?}: {}",
+                self.path, e))
+            .ok();
+    }
+}
+
+/// Checks that the mounted image matches the manifest signed by the `key`
+///
+/// Must be called on the mounted root (`root`) rather than on the image
+/// directory itself, so that exactly the files which are verified are
+/// used by the container. Every file, directory and symlink must be listed
+/// in the manifest with the same mode, checksum of the contents (or of the
+/// target for symlinks), and entries of the manifest must exist. Checksums
+/// of unchanged files are cached in `cache_dir`.
+pub fn verify_image(image: &Path, root: &Path, key: &Path, cache_dir: &Path)
+    -> Result<(), String>
+{
+    _verify_image(image, root, key, cache_dir)
+    .map_err(|e| format!("Error verifying signature of image {:?}: {}",
+        image, e))
+}
+
+fn _verify_image(image: &Path, root: &Path, key: &Path, cache_dir: &Path)
+    -> Result<(), Error>
+{
+    let key = parse_public_key(key)?;
+    let manifest_path = suffixed(image, ".manifest");
+    let mut manifest = Vec::new();
+    File::open(&manifest_path)
+        .and_then(|mut f| f.read_to_end(&mut manifest))
+        .context(format!("can't read {:?}", manifest_path))?;
+    let sig = parse_signature(&suffixed(&manifest_path, ".minisig"))?;
+    verify(&key, &sig, &manifest)?;
+    let expected = image_manifest::parse(&manifest)?;
+    let mut cache = Cache::open(cache_dir, image);
+    let actual = image_manifest::scan(root,
+        |path, meta| cache.hash(root, path, meta))?;
+    image_manifest::compare(&expected, &actual)?;
+    cache.save();
+    debug!("Image {:?} is verified by {:?}", image, manifest_path);
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! Throttling of block devices (`io-limits`)
+//!
+//! Uses `blkio` controller if it's enabled in `cgroup-controllers`, and
+//! `io.max` of the unified hierarchy otherwise.
+use std::fs::metadata;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+use nix::sys::stat::{major, minor};
+
+use lithos::cgroup::{self, CGroups, Controller};
+use lithos::container_config::IoLimit;
+
+
+fn device_number(limit: &IoLimit) -> Result<String, String> {
+    let meta = metadata(&limit.device)
+        .map_err(|e| format!("can't stat {:?}: {}", limit.device, e))?;
+    if !meta.file_type().is_block_device() {
+        return Err(format!("{:?} is not a block device", limit.device));
+    }
+    Ok(format!("{}:{}", major(meta.rdev()), minor(meta.rdev())))
+}
+
+/// Pairs of `blkio` file and the value written to it
+fn blkio_values(dev: &str, limit: &IoLimit) -> Vec<(&'static str, String)> {
+    let settings = [
+        ("blkio.throttle.read_bps_device", limit.read_bps),
+        ("blkio.throttle.write_bps_device", limit.write_bps),
+        ("blkio.throttle.read_iops_device", limit.read_iops),
+        ("blkio.throttle.write_iops_device", limit.write_iops),
+    ];
+    settings.iter()
+        .filter_map(|&(key, value)| {
+            value.map(|value| (key, format!("{} {}", dev, value)))
+        })
+        .collect()
+}
+
+/// Line of `io.max`, limits which aren't set are reset to `max`
+fn io_max_line(dev: &str, limit: &IoLimit) -> String {
+    let mut line = dev.to_string();
+    let settings = [
+        ("rbps", limit.read_bps),
+        ("wbps", limit.write_bps),
+        ("riops", limit.read_iops),
+        ("wiops", limit.write_iops),
+    ];
+    for &(key, value) in &settings {
+        match value {
+            Some(value) => line.push_str(&format!(" {}={}", key, value)),
+            None => line.push_str(&format!(" {}=max", key)),
+        }
+    }
+    line
+}
+
+fn set_blkio(cgroups: &CGroups, limits: &[IoLimit]) -> Result<(), String> {
+    for limit in limits {
+        let dev = device_number(limit)?;
+        for (key, value) in blkio_values(&dev, limit) {
+            cgroups.set_value(Controller::Blkio, key, &value)?;
+        }
+    }
+    Ok(())
+}
+
+fn set_io_max(name: &str, limits: &[IoLimit]) -> Result<(), String> {
+    cgroup::ensure_in_unified_group(name)?;
+    cgroup::enable_unified_controller(name, "io")?;
+    for limit in limits {
+        let dev = device_number(limit)?;
+        cgroup::set_unified_value(name, "io.max", &io_max_line(&dev, limit))?;
+    }
+    Ok(())
+}
+
+/// Applies limits to the cgroup of the knot, the process inherits it
+///
+/// `name` is the path of the cgroup relative to the root of the unified
+/// hierarchy, it's only used if there is no `blkio` controller.
+pub fn setup(cgroups: Option<&CGroups>, name: &str, limits: &[IoLimit])
+    -> Result<(), String>
+{
+    match cgroups {
+        Some(cgroups) if cgroups.has_controller(Controller::Blkio) => {
+            set_blkio(cgroups, limits)
+        }
+        _ if cgroup::unified_base().is_some() => set_io_max(name, limits),
+        _ => Err(format!("neither blkio cgroup controller nor \
+            unified cgroup hierarchy is available")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use lithos::container_config::IoLimit;
+    use super::{blkio_values, io_max_line, device_number};
+
+    fn limit(device: &str) -> IoLimit {
+        IoLimit {
+            device: PathBuf::from(device),
+            read_bps: Some(50_000_000),
+            write_bps: None,
+            read_iops: None,
+            write_iops: Some(500),
+        }
+    }
+
+    #[test]
+    fn blkio() {
+        assert_eq!(blkio_values("8:0", &limit("/dev/sda")), vec![
+            ("blkio.throttle.read_bps_device", "8:0 50000000".to_string()),
+            ("blkio.throttle.write_iops_device", "8:0 500".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn io_max() {
+        assert_eq!(io_max_line("8:0", &limit("/dev/sda")),
+            "8:0 rbps=50000000 wbps=max riops=max wiops=500");
+    }
+
+    #[test]
+    fn not_block_device() {
+        let err = device_number(&limit("/dev/null")).unwrap_err();
+        assert!(err.contains("is not a block device"), "{}", err);
+        let err = device_number(&limit("/dev/lithos-missing")).unwrap_err();
+        assert!(err.contains("can't stat"), "{}", err);
+    }
+}
@@ -1,6 +1,7 @@
 extern crate argparse;
 extern crate base64;
 extern crate blake2;
+extern crate crypto;
 extern crate humantime;
 extern crate ipnetwork;
 extern crate libc;
@@ -9,6 +10,7 @@ extern crate lithos;
 extern crate nix;
 extern crate quire;
 extern crate serde_json;
+extern crate sha2;
 extern crate signal;
 extern crate syslog;
 extern crate ssh_keys;
@@ -18,43 +20,89 @@ extern crate unshare;
 #[macro_use] extern crate serde_derive;
 
 use std::env;
+use std::ffi::CString;
 use std::str::FromStr;
-use std::io::{stderr, Write};
-use std::fs::OpenOptions;
+use std::io::{self, stderr, Read, Write};
+use std::fs::{File, OpenOptions, create_dir_all};
 use std::path::{Path};
 use std::time::{SystemTime, Instant, Duration};
 use std::thread::sleep;
 use std::process::exit;
-use std::net::SocketAddr;
+use std::ptr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{RawFd, FromRawFd};
 
 use humantime::format_rfc3339_seconds;
 use libmount::BindMount;
 use quire::{parse_config, Options as COptions};
+use serde_json::to_string;
 use signal::trap::Trap;
 use unshare::{Command, Stdio, Style, reap_zombies, Capability, Namespace};
 use nix::sys::signal::Signal;
-use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD};
+use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD, SIGALRM};
 use nix::sys::socket::{InetAddr, SockAddr};
 
 use lithos::cgroup;
+use lithos::child_config::{ChildInstance, INSTANCE_CONFIG_FILE};
+use lithos::ipam;
+use lithos::proc_stats::{self, CONTAINER_PID_FILE};
+use lithos::exit_report::{self, ExitReport, EXIT_MAX_RUNTIME};
+use lithos::exit_report::EXIT_REQUIREMENTS;
 use lithos::utils::{check_mapping, in_mapping, change_root};
-use lithos::utils::{temporary_change_root};
+use lithos::utils::{temporary_change_root, relative, create_at};
 use lithos::range::in_range;
 use lithos::master_config::MasterConfig;
-use lithos::sandbox_config::SandboxConfig;
-use lithos::container_config::{ContainerConfig, Variables};
+use lithos::sandbox_dirs;
+use lithos::host_facts::HostFacts;
+use lithos::sandbox_config::{SandboxConfig, RootMode};
+use lithos::container_config::{ContainerConfig, InstantiatedConfig};
+use lithos::container_config::{Variables};
 use lithos::container_config::ContainerKind::Daemon;
+use lithos::container_config::{Spread};
 use lithos::setup::{init_logging};
 use lithos::mount::{unmount, mount_private, mount_ro_recursive, mount_pseudo};
+use lithos::mount::{mount_proc_as_current};
 use lithos::limits::{set_fileno_limit};
 use lithos::knot_options::Options;
+use lithos::shared_metrics::{SharedCounters, Slot};
+use lithos::version;
 
-use setup_filesystem::{setup_filesystem, prepare_state_dir};
+use setup_filesystem::{setup_filesystem, prepare_state_dir, open_state_dir};
+use setup_filesystem::prepare_state_files;
+use setup_filesystem::{prepare_log_dirs, prepare_workdir, host_uid};
+use setup_filesystem::{host_gid, prepare_info_file};
+use setup_network::DhcpClient;
+use timings::Timings;
+use crash_report::{Crash, CrashReports};
+use network_hooks::NetworkHooks;
 
+mod netlink;
 mod setup_network;
+mod network_hooks;
 mod setup_filesystem;
+mod setup_gpu;
+mod devices;
+mod idmap_mount;
+mod root_copy;
+mod image_signature;
+mod fuse;
+mod setup_firewall;
+mod setup_egress;
 mod config;
 mod secrets;
+mod timings;
+mod crash_report;
+mod debug_run;
+mod stdio_log;
+mod exec_error;
+mod requirements;
+mod spread;
+mod seccomp;
+mod drain;
+mod io_limits;
+mod health_check;
+mod notify;
+mod dev_mode;
 
 struct SignalIter<'a> {
     trap: &'a mut Trap,
@@ -87,19 +135,430 @@ impl<'a> Iterator for SignalIter<'a> {
     }
 }
 
+/// Interval of checking `restart-on-fd-usage`, in seconds
+const FD_CHECK_INTERVAL: f32 = 5.;
+
+// not in the libc crate we use
+extern "C" {
+    fn setitimer(which: libc::c_int, new_value: *const libc::itimerval,
+        old_value: *mut libc::itimerval) -> libc::c_int;
+}
+
 fn duration(inp: f32) -> Duration {
     Duration::from_millis((inp * 1000.) as u64)
 }
 
+/// Network state kept across restarts of the process
+struct Network {
+    /// Namespace of the first process, set after it's started
+    netns: Option<File>,
+    hooks: NetworkHooks,
+    dhcp: DhcpClient,
+}
+
+/// Starts timer which sends SIGALRM to check file descriptors and health
+/// of the process periodically
+fn start_check_timer(seconds: f32) -> Result<(), String> {
+    let interval = libc::timeval {
+        tv_sec: seconds.trunc() as libc::time_t,
+        tv_usec: (seconds.fract() * 1e6) as libc::suseconds_t,
+    };
+    let timer = libc::itimerval { it_interval: interval, it_value: interval };
+    let rc = unsafe {
+        setitimer(libc::ITIMER_REAL, &timer, ptr::null_mut())
+    };
+    if rc != 0 {
+        return Err(format!("Can't set timer: {}", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Returns true if process uses more than `threshold` share of the
+/// file descriptor limit
+fn fd_usage_exceeded(pid: libc::pid_t, threshold: f32, default_limit: u64)
+    -> bool
+{
+    match proc_stats::read(pid) {
+        Ok(stats) => {
+            // process might change the limit itself
+            let limit = stats.fd_limit.unwrap_or(default_limit);
+            limit > 0 && stats.open_fds as f32 >= limit as f32 * threshold
+        }
+        Err(e) => {
+            debug!("Can't read stats of the process: {}", e);
+            false
+        }
+    }
+}
+
+/// Saves config, so `lithos_tree` can compare it on recovery
+fn save_instance_config(state_dir: &Path, config: &ChildInstance)
+    -> Result<(), String>
+{
+    let path = state_dir.join(INSTANCE_CONFIG_FILE);
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+        .open(&path)
+        .and_then(|mut f| {
+            f.write_all(to_string(config)
+                .expect("config is serializable").as_bytes())
+        })
+        .map_err(|e| format!("Can't write {:?}: {}", path, e))
+}
+
+/// Shortens the child name to what fits into the process name
+///
+/// Kernel keeps only 15 bytes of it, so `sandbox/` prefix is dropped when
+/// the full name doesn't fit, and then the end of the name is kept, as
+/// process name and instance number are most distinctive.
+fn process_title(name: &str) -> &str {
+    const MAX: usize = 15;
+    let name = if name.len() > MAX {
+        name.splitn(2, '/').last().unwrap_or(name)
+    } else {
+        name
+    };
+    let mut start = name.len().saturating_sub(MAX);
+    while !name.is_char_boundary(start) {
+        start += 1;
+    }
+    &name[start..]
+}
+
+/// Sets process name (as seen in `top` and `ps -o comm`) to the name of
+/// the child, instead of a bunch of `lithos_knot`
+///
+/// The full name is in the command-line (`lithos_knot --name ...`).
+fn set_process_title(name: &str) {
+    let cname = match CString::new(process_title(name)) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    let rc = unsafe {
+        libc::prctl(libc::PR_SET_NAME, cname.as_ptr() as libc::c_ulong,
+            0, 0, 0)
+    };
+    if rc != 0 {
+        debug!("Can't set process name: {}", io::Error::last_os_error());
+    }
+}
+
+/// Allows any process to ptrace the current one when Yama `ptrace_scope`
+/// is 1 (i.e. not only parent processes)
+fn allow_any_ptracer() -> io::Result<()> {
+    // PR_SET_PTRACER_ANY is (unsigned long)-1
+    let rc = unsafe {
+        libc::prctl(libc::PR_SET_PTRACER, !0 as libc::c_ulong, 0, 0, 0)
+    };
+    // EINVAL means Yama is not enabled, so nothing to relax
+    if rc != 0 && io::Error::last_os_error().raw_os_error()
+        != Some(libc::EINVAL)
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Prepares command to run, it's rebuilt on each restart of the process
+///
+/// When `network.netns` is set, process joins network namespace of the
+/// previous process instead of creating and setting up a new one.
+fn set_environ(cmd: &mut Command, options: &Options,
+    local: &InstantiatedConfig)
+{
+    // Should we propagate TERM?
+    cmd.env_clear();
+    cmd.env("TERM", env::var("TERM").unwrap_or("dumb".to_string()));
+    for (k, v) in local.environ.iter() {
+        cmd.env(k, v);
+    }
+    cmd.env("LITHOS_NAME", &options.name);
+    cmd.env("LITHOS_CONFIG", &options.config.config);
+    cmd.env("LITHOS_VERSION", version::build_info());
+    if let Some(ip) = options.config.ip_address {
+        cmd.env("LITHOS_IP_ADDRESS", ip.to_string());
+    }
+}
+
+fn set_id_maps(cmd: &mut Command, sandbox: &SandboxConfig,
+    local: &InstantiatedConfig)
+{
+    if sandbox.uid_map.len() > 0 || sandbox.gid_map.len() > 0 {
+        cmd.set_id_maps(
+            sandbox.uid_map.iter().map(|u| unshare::UidMap {
+                inside_uid: u.inside,
+                outside_uid: u.outside,
+                count: u.count,
+            }).collect(),
+            sandbox.gid_map.iter().map(|g| unshare::GidMap {
+                inside_gid: g.inside,
+                outside_gid: g.outside,
+                count: g.count,
+            }).collect());
+    } else if local.uid_map.len() > 0 || local.gid_map.len() > 0 {
+        cmd.set_id_maps(
+            local.uid_map.iter().map(|u| unshare::UidMap {
+                inside_uid: u.inside,
+                outside_uid: u.outside,
+                count: u.count,
+            }).collect(),
+            local.gid_map.iter().map(|g| unshare::GidMap {
+                inside_gid: g.inside,
+                outside_gid: g.outside,
+                count: g.count,
+            }).collect());
+    }
+}
+
+fn prepare_command(options: &Options, master: &MasterConfig,
+    sandbox: &SandboxConfig, local: &InstantiatedConfig,
+    user_id: u32, group_id: u32, network: &Network,
+    seccomp: Option<&seccomp::Filter>)
+    -> Result<Command, String>
+{
+    let mut cmd = Command::new(&local.executable);
+    cmd.uid(user_id);
+    cmd.gid(group_id);
+    let mut caps = Vec::new();
+    if sandbox.bridged_network.is_some() {
+        caps.push(Capability::CAP_NET_BIND_SERVICE);
+    }
+    if sandbox.allow_fuse {
+        // only effective in the user namespace of the container
+        caps.push(Capability::CAP_SYS_ADMIN);
+    }
+    if sandbox.debug_allow_ptrace {
+        // only effective in the user namespace of the container
+        caps.push(Capability::CAP_SYS_PTRACE);
+    }
+    if !caps.is_empty() {
+        cmd.keep_caps(&caps);
+    }
+    cmd.current_dir(&local.workdir);
+
+    set_environ(&mut cmd, options, local);
+    for var in &local.pid_env_vars {
+        cmd.env_var_with_pid(var);
+    }
+
+    cmd.args(&local.arguments);
+    cmd.args(&options.args);
+    set_id_maps(&mut cmd, sandbox, local);
+
+    let mount_dir = master.runtime_dir.join(&master.mount_dir);
+    let child_setup = move |_pid| {
+        change_root(&mount_dir, &mount_dir.join("tmp"))?;
+        unmount(Path::new("/tmp"))?;
+        Ok(())
+    };
+    let mut sockets = Vec::new();
+    if let Some(ref net) = sandbox.bridged_network {
+        if let Some(ref netns) = network.netns {
+            // restarting in place, interfaces are already set up
+            cmd.set_namespace(netns, Namespace::Net)
+                .map_err(|e| format!("Can't reuse network namespace: {}", e))?;
+            cmd.before_unfreeze(child_setup);
+        } else {
+            cmd.unshare(&[Namespace::Net]);
+            let net = net.clone();
+            let child = options.config.clone();
+            let name = options.name.clone();
+            let hooks = network.hooks.clone();
+            let dhcp = network.dhcp.clone();
+            cmd.before_unfreeze(move |pid| {
+                if let Some(link) = setup_network::setup(
+                    pid, &net, &child, &name, &dhcp)?
+                {
+                    hooks.setup(link)
+                        .map_err(|e| format!("network-hooks: {}", e))?;
+                }
+                child_setup(pid)?;
+                Ok(())
+            });
+        }
+        sockets = local.tcp_ports.iter()
+            .filter(|(_, v)| !v.external)
+            .flat_map(|(port, cfg)| {
+                cfg.sockets(*port).into_iter().map(move |(addr, fd)| {
+                    let addr = SockAddr::new_inet(InetAddr::from_std(&addr));
+                    (cfg.clone(), addr, fd)
+                })
+            })
+            .collect::<Vec<_>>();
+        setup_network::check_backlog(&sockets);
+    } else {
+        cmd.before_unfreeze(child_setup);
+    }
+    let private_mounts = sandbox.allow_fuse;
+    let allow_ptrace = sandbox.debug_allow_ptrace;
+    let seccomp = seccomp.cloned();
+    if !sockets.is_empty() || private_mounts || allow_ptrace ||
+        seccomp.is_some()
+    {
+        cmd.before_exec(move || {
+            for &(ref cfg, ref addr, fd) in &sockets {
+                unsafe {
+                    setup_network::open_socket(cfg, addr, fd)?;
+                }
+            }
+            if private_mounts {
+                fuse::unshare_mounts()?;
+            }
+            if allow_ptrace {
+                allow_any_ptracer()?;
+            }
+            // must be the last one, the filter may deny syscalls above
+            if let Some(ref filter) = seccomp {
+                seccomp::apply(filter)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(cmd)
+}
+
+/// Opens network namespace of the process if it's in a bridged network
+fn open_netns(sandbox: &SandboxConfig, pid: libc::pid_t)
+    -> Result<Option<File>, String>
+{
+    if sandbox.bridged_network.is_none() {
+        return Ok(None);
+    }
+    File::open(format!("/proc/{}/ns/net", pid))
+        .map(Some)
+        .map_err(|e| format!("Can't open network namespace: {}", e))
+}
+
+/// Command of the `pre-stop` hook or of the `Exec` health check
+///
+/// Knot shares mount, pid, uts and ipc namespaces (and the root) with the
+/// process of the container, so only user and network namespaces are set up
+/// here. `netns` is the namespace of the process, see `open_netns`.
+fn prepare_hook(options: &Options, sandbox: &SandboxConfig,
+    local: &InstantiatedConfig, executable: &str, arguments: &[String],
+    user_id: u32, group_id: u32, netns: Option<&File>)
+    -> Result<Command, String>
+{
+    let mut cmd = Command::new(executable);
+    cmd.uid(user_id);
+    cmd.gid(group_id);
+    cmd.current_dir(&local.workdir);
+    set_environ(&mut cmd, options, local);
+    cmd.args(arguments);
+    set_id_maps(&mut cmd, sandbox, local);
+    if let Some(netns) = netns {
+        cmd.set_namespace(netns, Namespace::Net)
+            .map_err(|e| format!("Can't join network namespace: {}", e))?;
+    }
+    Ok(cmd)
+}
+
+/// Starts a planned stop of the process
+///
+/// Writes the `drain-file` if it's configured, the rest of the stop is
+/// done by `terminate` when its delay expires. Returns whether the stop is
+/// started and the deadline of the stage.
+fn begin_stop(child: &unshare::Child,
+    drain: Option<&drain::Marker>, draining: &mut bool,
+    pre_stop: &mut Option<unshare::Child>,
+    start_pre_stop: &Fn(libc::pid_t) -> Option<(unshare::Child, f32)>,
+    kill_timeout: f32)
+    -> (bool, Instant)
+{
+    if let Some(marker) = drain {
+        match marker.mark() {
+            Ok(()) => {
+                *draining = true;
+                return (true, Instant::now() + duration(marker.delay));
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+    terminate(child, pre_stop, start_pre_stop, kill_timeout)
+}
+
+/// Terminates the process
+///
+/// Runs `pre-stop` hook if it's configured, `SIGTERM` is sent when the hook
+/// exits or its timeout expires. Otherwise `SIGTERM` is sent right away.
+/// Returns whether the stop is started and the deadline of the stage.
+fn terminate(child: &unshare::Child,
+    pre_stop: &mut Option<unshare::Child>,
+    start_pre_stop: &Fn(libc::pid_t) -> Option<(unshare::Child, f32)>,
+    kill_timeout: f32)
+    -> (bool, Instant)
+{
+    if let Some((hook, timeout)) = start_pre_stop(child.pid()) {
+        *pre_stop = Some(hook);
+        return (true, Instant::now() + duration(timeout));
+    }
+    (child.signal(SIGTERM).is_ok(), Instant::now() + duration(kill_timeout))
+}
+
+fn kill_pre_stop(hook: &mut unshare::Child) {
+    hook.kill()
+        .map_err(|e| error!("Can't kill pre-stop hook: {}", e)).ok();
+    hook.wait().ok();
+}
+
+/// Records pid of the container process, so `lithos_tree` can tell it from
+/// the helper processes when sampling resource usage
+fn write_container_pid(state_dir: &File, pid: i32) {
+    create_at(state_dir, CONTAINER_PID_FILE)
+        .and_then(|mut file| writeln!(file, "{}", pid))
+        .map_err(|e| warn!("Can't write {}: {}", CONTAINER_PID_FILE, e))
+        .ok();
+}
+
+/// Waits until `lithos_tree` puts the process into systemd scope
+fn wait_for_scope(fd: RawFd) -> Result<(), String> {
+    // file is closed here, so the container doesn't inherit it
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut buf = [0u8; 1];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Err(format!("Systemd scope is not registered")),
+            Ok(_) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("Can't read scope pipe: {}", e)),
+        }
+    }
+}
+
 fn run(options: &Options) -> Result<i32, String>
 {
-    let master: MasterConfig = try!(parse_config(&options.master_config,
+    if let Some(fd) = options.scope_fd {
+        wait_for_scope(fd)?;
+    }
+    let mut timings = Timings::start();
+    let mut master: MasterConfig = try!(parse_config(&options.master_config,
         &MasterConfig::validator(), &COptions::default())
         .map_err(|e| format!("Error reading master config: {}", e)));
+    if options.dev {
+        master.dev_mode = true;
+    }
+    if master.dev_mode {
+        dev_mode::enter_user_namespace()?;
+    }
+    let state_dir = open_state_dir(&master.state_path().join(&options.name))?;
+    let result = run_container(options, &master, &mut timings, &state_dir);
+    let report = match result {
+        Ok((_, ref report)) => report.clone(),
+        Err(_) => ExitReport::SetupFailure {
+            stage: timings.current_stage().to_string(),
+        },
+    };
+    exit_report::write(&state_dir, &report)
+        .map_err(|e| warn!("Can't write exit report: {}", e)).ok();
+    result.map(|(code, _)| code)
+}
+
+fn run_container(options: &Options, master: &MasterConfig,
+    timings: &mut Timings, state_fd: &File)
+    -> Result<(i32, ExitReport), String>
+{
     let sandbox_name = options.name[..].splitn(2, '/').next().unwrap();
     let sandbox: SandboxConfig = try!(parse_config(
-        &options.master_config.parent().unwrap()
-         .join(&master.sandboxes_dir).join(sandbox_name.to_string() + ".yaml"),
+        &sandbox_dirs::find(master, &options.master_config, sandbox_name),
         &SandboxConfig::validator(), &COptions::default())
         .map_err(|e| format!("Error reading sandbox config: {}", e)));
 
@@ -109,39 +568,83 @@ fn run(options: &Options) -> Result<i32, String>
     } else {
         log_file = master.default_log_dir.join(format!("{}.log", sandbox_name));
     }
-    try!(init_logging(&master, &log_file,
+    // sandbox log file may be in a subdirectory of the master log dir
+    if let Some(dir) = log_file.parent() {
+        try!(create_dir_all(dir)
+            .map_err(|e| format!("Can't create log dir {:?}: {}", dir, e)));
+    }
+    // dev mode traces each step to the terminal
+    try!(init_logging(master, &log_file,
         &format!("{}-{}", master.syslog_app_name, sandbox_name),
-        options.log_stderr,
+        options.log_stderr || master.dev_mode,
         options.log_level
+            .or(Some(log::LogLevel::Debug).filter(|_| master.dev_mode))
             .or(sandbox.log_level.as_ref()
                 .and_then(|x| FromStr::from_str(&x).ok()))
             .or_else(|| FromStr::from_str(&master.log_level).ok())
             .unwrap_or(log::LogLevel::Warn)));
 
+    let counters_path = master.knot_metrics_path().join(&options.name);
+    let counters = SharedCounters::create(&counters_path)
+        .map_err(|e| warn!("Can't open counters {:?}: {}", counters_path, e))
+        .ok();
+
     let stderr_path = master.stdio_log_dir
         .join(format!("{}.log", sandbox_name));
-    let mut stderr_file = try!(OpenOptions::new()
-                .create(true).append(true).write(true).open(&stderr_path)
-                .map_err(|e| format!(
-                    "Error opening stderr file {:?}: {}", stderr_path, e)));
+    let mut stderr_file = stdio_log::open(&stderr_path,
+        sandbox.stdio_log_max_size)?;
+    let crash_reports = CrashReports::open(master, &options.name,
+        &stderr_path);
 
     try!(mount_private(&Path::new("/")));
     let image_path = sandbox.image_dir.join(&options.config.image);
     let mount_dir = master.runtime_dir.join(&master.mount_dir);
-    try!(BindMount::new(&image_path, &mount_dir).mount()
-        .map_err(|e| e.to_string()));
-    try!(mount_ro_recursive(&mount_dir));
+    match sandbox.root_mode {
+        Some(RootMode::TmpfsCopy(ref opt)) => {
+            root_copy::mount_copy(&image_path, &mount_dir, opt.size)?;
+        }
+        Some(RootMode::Readonly) | None => {
+            try!(BindMount::new(&image_path, &mount_dir).mount()
+                .map_err(|e| e.to_string()));
+            try!(mount_ro_recursive(&mount_dir));
+        }
+    }
+    // verified after mounting, so the files checked are the files the
+    // container gets, even if the image directory is replaced meanwhile
+    if let Some(ref key) = sandbox.image_signature_key {
+        image_signature::verify_image(&image_path, &mount_dir, key,
+            &master.runtime_dir.join("image-hashes"))?;
+    }
 
     let container: ContainerConfig;
-    container = config::container_config(&mount_dir, &options.config)?;
+    container = config::container_config(&mount_dir, &options.config,
+        &sandbox, &master)?;
     if !container.kind.matches(options.config.kind) {
         return Err(format!("Container type mismatch {:?} != {:?}",
               container.kind, options.config.kind));
     }
+    if let Some(ref req) = container.requires {
+        if let Err(message) = requirements::check(req) {
+            error!("Container can't run on this host: {}", message);
+            return Ok((EXIT_REQUIREMENTS,
+                ExitReport::RequirementsNotMet { message }));
+        }
+    }
+    let host_facts = HostFacts::read(&master.host_facts_path())
+        .or_else(|e| {
+            warn!("{}. Gathering host facts again", e);
+            HostFacts::gather()
+        })?;
+    let decrypt = |value: &str| {
+        secrets::decode_value(&sandbox, &options.name, &options.config,
+            value)
+    };
     let mut local = container.instantiate(&Variables {
         user_vars: &options.config.variables,
         lithos_name: &options.name,
         lithos_config_filename: &options.config.config,
+        host_facts: Some(&host_facts),
+        secrets: Some(&decrypt),
     }).map_err(|e| format!("Variable substitution error: {}", e.join("; ")))?;
 
     let user_id = if
@@ -182,6 +685,38 @@ fn run(options: &Options) -> Result<i32, String>
         return Err(format!("No group id specified and no default is found"));
     };
 
+    let (user_id, group_id) = if master.dev_mode && dev_mode::single_user() {
+        // no other users are mapped into the namespace of a plain user
+        if sandbox.uid_map.len() > 0 || sandbox.gid_map.len() > 0 {
+            dev_mode::check_id_maps(&sandbox.uid_map, &sandbox.gid_map)?;
+        } else {
+            dev_mode::check_id_maps(&local.uid_map, &local.gid_map)?;
+        }
+        info!("[{}] Dev mode: running as uid 0 instead of {} (gid 0 \
+            instead of {})", options.name, user_id, group_id);
+        (0, 0)
+    } else {
+        (user_id, group_id)
+    };
+
+    if local.gpus.is_some() && !sandbox.allow_gpus {
+        return Err("GPUs are not allowed in sandbox \
+            (set `allow-gpus: true` in sandbox config)".to_string());
+    }
+
+    if sandbox.allow_fuse && sandbox.uid_map.is_empty() &&
+        local.uid_map.is_empty()
+    {
+        return Err("allow-fuse requires user namespace \
+            (set `uid-map` in sandbox or container config)".to_string());
+    }
+    if sandbox.debug_allow_ptrace && sandbox.uid_map.is_empty() &&
+        local.uid_map.is_empty()
+    {
+        return Err("debug-allow-ptrace requires user namespace \
+            (set `uid-map` in sandbox or container config)".to_string());
+    }
+
     if !check_mapping(&sandbox.allow_users, &local.uid_map) {
         return Err("Bad uid mapping (probably doesn't match allow_users)"
             .to_string());
@@ -191,18 +726,55 @@ fn run(options: &Options) -> Result<i32, String>
             .to_string());
     }
 
+    timings.stage("config");
+
     info!("[{}] Starting container", options.name);
-    let state_dir = &master.runtime_dir.join(&master.state_dir)
-        .join(&options.name);
-    try!(prepare_state_dir(state_dir, &local, &sandbox));
-    try!(setup_filesystem(&master, &sandbox, &local, state_dir));
-    if let Some(cgroup_parent) = master.cgroup_name {
+    let state_dir = &master.state_path().join(&options.name);
+    let pool_hosts = sandbox.bridged_network.as_ref()
+        .and_then(|net| net.ip_pool)
+        .map(|_| ipam::hosts_path(master, sandbox_name));
+    try!(prepare_state_dir(state_dir, &local, &sandbox,
+        pool_hosts.as_deref()));
+    prepare_state_files(state_dir, &local, &sandbox, user_id, group_id)?;
+    save_instance_config(state_dir, &options.config)?;
+    try!(prepare_info_file(state_fd, &options.name, &options.config,
+        &sandbox));
+    try!(prepare_log_dirs(&sandbox, &local));
+    try!(setup_filesystem(master, &sandbox, &local, state_dir));
+    try!(prepare_workdir(&sandbox, &local, &mount_dir, user_id, group_id));
+    timings.stage("mounts");
+    // container's own environment takes precedence
+    if let Some(ref bundle) = master.trust_bundle {
+        if mount_dir.join(relative(&bundle.mount_point, Path::new("/")))
+            .is_file()
+        {
+            local.environ.entry("SSL_CERT_FILE".to_string())
+                .or_insert_with(|| bundle.mount_point.display().to_string());
+        }
+    }
+    for (key, value) in &master.proxy_environ {
+        local.environ.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+    let cgroups = match master.cgroup_parent() {
         // Warning setting cgroup relative to it's own cgroup may not work
         // if we ever want to restart lithos_knot in-place
-        let cgroups = try!(cgroup::ensure_in_group(
+        Some(cgroup_parent) => match cgroup::ensure_in_group(
             &(cgroup_parent + "/" +
-              &options.name.replace("/", ":") + ".scope"),
-            &master.cgroup_controllers));
+              &cgroup::scope_name(&options.name, options.generation)),
+            &master.cgroup_controllers)
+        {
+            Ok(cgroups) => Some(cgroups),
+            // cgroup hierarchy is usually not writable in a container
+            // or by a plain user
+            Err(ref e) if master.nested || master.dev_mode => {
+                warn!("Running without cgroups: {}", e);
+                None
+            }
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+    if let Some(ref cgroups) = cgroups {
         cgroups.set_value(cgroup::Controller::Memory,
             "memory.limit_in_bytes",
             &format!("{}", local.memory_limit))
@@ -215,20 +787,69 @@ fn run(options: &Options) -> Result<i32, String>
                 "cpu.shares",
                 &format!("{}", local.cpu_shares))
             .map_err(|e| error!("Error setting cgroup limit: {}", e)).ok();
+        if let Some(limit) = local.process_limit {
+            if cgroups.has_controller(cgroup::Controller::Pids) {
+                cgroups.set_value(cgroup::Controller::Pids,
+                        "pids.max", &format!("{}", limit))
+                    .map_err(|e| error!("Error setting cgroup limit: {}", e))
+                    .ok();
+            } else {
+                error!("Can't set process-limit: \
+                    pids cgroup controller is not enabled");
+            }
+        }
+        try!(devices::extra_devices(&sandbox, &local)
+            .and_then(|devices| devices::allow_devices(cgroups, &devices))
+            .map_err(|e| format!("Error allowing devices: {}", e)));
+    }
+    if !local.io_limits.is_empty() {
+        // the limits protect neighbours, so they are not best-effort
+        let cgroup_parent = try!(master.cgroup_parent()
+            .ok_or("io-limits require cgroups to be enabled".to_string()));
+        try!(io_limits::setup(cgroups.as_ref(),
+            &(cgroup_parent + "/" +
+              &cgroup::scope_name(&options.name, options.generation)),
+            &local.io_limits)
+            .map_err(|e| format!("Error setting io-limits: {}", e)));
     }
+    timings.stage("cgroups");
+    if local.spread == Spread::Numa {
+        match master.cgroup_parent() {
+            Some(cgroup_parent) => {
+                spread::pin_to_node(cgroups.as_ref(),
+                    &(cgroup_parent + "/" +
+                      &cgroup::scope_name(&options.name, options.generation)),
+                    &options.name)
+                .map_err(|e| error!("Error spreading instance: {}", e)).ok();
+            }
+            None => error!("Can't spread instance: cgroups are disabled"),
+        }
+    }
+    if let Some(ref policy) = local.egress_policy {
+        let cgroup_parent = try!(master.cgroup_parent()
+            .ok_or("egress-policy requires cgroups to be enabled".to_string()));
+        try!(setup_egress::setup(policy,
+            &(cgroup_parent + "/" +
+              &cgroup::scope_name(&options.name, options.generation))));
+    }
+    if let Some(ref policy) = sandbox.host_network_policy {
+        if sandbox.bridged_network.is_some() {
+            return Err(format!("host-network-policy and bridged-network \
+                settings are mutually exclusive"));
+        }
+        // rules match uid as seen in the host namespace
+        let host_uid = host_uid(&sandbox, &local, user_id)
+            .ok_or_else(|| format!(
+                "User {} is not mapped to the host", user_id))?;
+        setup_firewall::setup(policy, host_uid, &local.tcp_ports, state_dir)?;
+    }
+    timings.stage("network");
 
     let has_secrets = container.secret_environ_file.is_some() ||
                       !container.secret_environ.is_empty();
-    let keys = if has_secrets {
-        Some(secrets::read_keys(&sandbox)
-            .map_err(|e| format!("Error decoding private keys: {}", e))?)
-    } else {
-        None
-    };
-
-    if let Some(ref keys) = keys {
-        temporary_change_root(&mount_dir, || {
-            let senv = if let Some(ref path) = container.secret_environ_file {
+    if has_secrets {
+        let senv = temporary_change_root(&mount_dir, || {
+            if let Some(ref path) = container.secret_environ_file {
                 if !container.secret_environ.is_empty() {
                     return Err(format!("secret-environ and \
                         secret-environ-file \
@@ -237,121 +858,152 @@ fn run(options: &Options) -> Result<i32, String>
 
                 let path = Path::new(&options.config.config).parent()
                     .expect("file always have parent path").join(path);
-                Some(secrets::parse_file(&path)
+                Ok(Some(secrets::parse_file(&path)
                     .map_err(|e| format!("Can't read secret \
                                          environ file {:?}: {}",
-                                         path, e))?)
+                                         path, e))?))
             } else {
-                None
-            };
-            let secrets = secrets::decode(keys, &sandbox, &options.config,
-                senv.as_ref().unwrap_or(&container.secret_environ))
-                .map_err(|e| format!("Error decoding secrets: {}", e))?;
-            local.environ.extend(secrets);
-            Ok(())
+                Ok(None)
+            }
         })?;
+        let secrets = secrets::decode_in_helper(&sandbox, &options.name,
+            &options.config,
+            senv.as_ref().unwrap_or(&container.secret_environ))?;
+        local.environ.extend(secrets);
     }
-    drop(keys);
-
-    try!(set_fileno_limit(local.fileno_limit)
-        .map_err(|e| format!("Error setting file limit: {}", e)));
-
-    // This is needed for unshare to properly initialize user namespace
-    mount_pseudo(&Path::new("/proc"), "proc", "", false)?;
+    timings.stage("secrets");
 
-    let mut cmd = Command::new(&local.executable);
-    cmd.uid(user_id);
-    cmd.gid(group_id);
-    if sandbox.bridged_network.is_some() {
-        cmd.keep_caps(&[
-            Capability::CAP_NET_BIND_SERVICE,
-        ]);
+    let seccomp = match local.seccomp_profile {
+        Some(ref profile) => Some(
+            seccomp::compile(profile, &mount_dir, state_dir)
+            .map_err(|e| format!("Error in seccomp-profile: {}", e))?),
+        None => None,
+    };
+    let drain = match local.drain_file {
+        Some(ref cfg) => Some(drain::Marker::open(&mount_dir, cfg)?),
+        None => None,
+    };
+    let notify_socket = match local.sd_notify {
+        Some(ref cfg) => {
+            let owner = host_uid(&sandbox, &local, user_id)
+                .and_then(|uid| host_gid(&sandbox, &local, group_id)
+                    .map(|gid| (uid, gid)));
+            Some(notify::Socket::bind(&mount_dir, cfg, owner)?)
+        }
+        None => None,
+    };
+    if let Some(ref socket) = notify_socket {
+        local.environ.extend(socket.environ());
     }
-    cmd.current_dir(&local.workdir);
+    // checked again with secrets and variables added by knot
+    local.check_exec_size(sandbox.max_environ_size)
+        .map_err(|e| format!("Process can't be started: {}", e.join("; ")))?;
 
-    // Should we propagate TERM?
-    cmd.env_clear();
-    cmd.env("TERM", env::var("TERM").unwrap_or("dumb".to_string()));
-    for (k, v) in local.environ.iter() {
-        cmd.env(k, v);
-    }
-    cmd.env("LITHOS_NAME", &options.name);
-    cmd.env("LITHOS_CONFIG", &options.config.config);
-    for var in &local.pid_env_vars {
-        cmd.env_var_with_pid(var);
+    match set_fileno_limit(local.fileno_limit) {
+        Ok(()) => {}
+        // plain user can't raise the hard limit
+        Err(ref e) if master.dev_mode => {
+            warn!("Can't set file limit: {}", e);
+        }
+        Err(e) => return Err(format!("Error setting file limit: {}", e)),
     }
 
-    cmd.args(&local.arguments);
-    cmd.args(&options.args);
-    if sandbox.uid_map.len() > 0 || sandbox.gid_map.len() > 0 {
-        cmd.set_id_maps(
-            sandbox.uid_map.iter().map(|u| unshare::UidMap {
-                inside_uid: u.inside,
-                outside_uid: u.outside,
-                count: u.count,
-            }).collect(),
-            sandbox.gid_map.iter().map(|g| unshare::GidMap {
-                inside_gid: g.inside,
-                outside_gid: g.outside,
-                count: g.count,
-            }).collect());
-    } else if local.uid_map.len() > 0 || local.gid_map.len() > 0 {
-        cmd.set_id_maps(
-            local.uid_map.iter().map(|u| unshare::UidMap {
-                inside_uid: u.inside,
-                outside_uid: u.outside,
-                count: u.count,
-            }).collect(),
-            local.gid_map.iter().map(|g| unshare::GidMap {
-                inside_gid: g.inside,
-                outside_gid: g.outside,
-                count: g.count,
-            }).collect());
+    // This is needed for unshare to properly initialize user namespace
+    if master.nested || master.dev_mode {
+        // flags of the proc mount are locked in the outer container
+        mount_proc_as_current(&Path::new("/proc"))?;
+    } else {
+        mount_pseudo(&Path::new("/proc"), "proc", "", false)?;
     }
 
-    let mount_dir = master.runtime_dir.join(&master.mount_dir);
-    let child_setup = move |_pid| {
-        change_root(&mount_dir, &mount_dir.join("tmp"))?;
-        unmount(Path::new("/tmp"))?;
-        Ok(())
-    };
-    if let Some(ref net) = sandbox.bridged_network {
-        cmd.unshare(&[Namespace::Net]);
-
-        let net = net.clone();
-        let child = options.config.clone();
-        cmd.before_unfreeze(move |pid| {
-            setup_network::setup(pid, &net, &child)?;
-            child_setup(pid)?;
-            Ok(())
-        });
-        let sockets = local.tcp_ports.iter()
-            .filter(|(_, v)| !v.external)
-            .map(|(port, cfg)| {
-                let addr = SockAddr::new_inet(InetAddr::from_std(
-                    &SocketAddr::new(cfg.host.0, *port)));
-                (cfg.clone(), addr)
-            })
-            .collect::<Vec<_>>();
-        cmd.before_exec(move || {
-            for &(ref cfg, ref addr) in &sockets {
-                unsafe {
-                    setup_network::open_socket(cfg, addr)?;
-                }
-            }
-            Ok(())
-        });
+    // command with decrypted secrets must not get into the logs
+    // (contents of `state-files` and environment are never logged)
+    let has_secret = |value: &str| value.contains("@{secret:");
+    let hide_executable = has_secret(&container.executable);
+    let hide_arguments = hide_executable ||
+        container.arguments.iter().any(|arg| has_secret(arg));
+    let executable_name = if hide_executable {
+        "<executable hidden>".to_string()
     } else {
-        cmd.before_unfreeze(child_setup);
-    }
+        format!("{:?}", local.executable)
+    };
     let rtimeo = Duration::from_millis((local.restart_timeout*1000.0) as u64);
+    // where the output of the process goes, for crash reports
+    let output_file = match local.stdout_stderr_file {
+        Some(ref path) if !local.interactive => Some(path.as_path()),
+        _ => None,
+    };
 
-    let mut trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD]);
+    let check_interval = local.restart_on_fd_usage
+        .map(|_| FD_CHECK_INTERVAL).into_iter()
+        .chain(local.health_check.as_ref().map(|hc| hc.interval))
+        .chain(local.readiness.as_ref().map(|rc| rc.interval))
+        .chain(local.sd_notify.as_ref().map(|_| notify::POLL_INTERVAL))
+        .fold(None, |min: Option<f32>, x| Some(min.map_or(x, |m| m.min(x))));
+    let mut trap = if let Some(interval) = check_interval {
+        start_check_timer(interval)?;
+        Trap::trap(&[SIGINT, SIGTERM, SIGCHLD, SIGALRM])
+    } else {
+        Trap::trap(&[SIGINT, SIGTERM, SIGCHLD])
+    };
     let mut should_exit = local.kind != Daemon || !local.restart_process_only;
     // only successful code on SIGTERM
     let mut exit_code = 2;
+    let mut stopped = false;
+    let mut report = ExitReport::CleanExit;
+    let mut network = Network {
+        netns: None,
+        hooks: NetworkHooks::new(
+            sandbox.bridged_network.as_ref()
+                .map(|net| &net.network_hooks[..]).unwrap_or(&[]),
+            &options.name, state_dir, &host_facts),
+        dhcp: DhcpClient::new(),
+    };
+    // runs cleanup part of hooks on any exit from this function
+    let _cleanup = network.hooks.cleanup_guard();
+    let mut restart = false;
+    // output of the hooks goes to the same log as output of the process
+    let hook_log = stderr_file.try_clone()
+        .map_err(|e| format!("Duplicating file descriptor: {}", e))?;
+    let spawn_hook = |executable: &str, arguments: &[String],
+                      netns: Option<&File>|
+    {
+        let mut cmd = prepare_hook(options, &sandbox, &local,
+            executable, arguments, user_id, group_id, netns)?;
+        let dup = |f: &File| Stdio::dup_file(f).map_err(|e| {
+            format!("Duplicating file descriptor: {}", e)
+        });
+        cmd.stdout(dup(&hook_log)?);
+        cmd.stderr(dup(&hook_log)?);
+        cmd.spawn()
+            .map_err(|e| format!("Error running {:?}: {}", executable, e))
+    };
+    let start_pre_stop = |pid: libc::pid_t| {
+        let hook = local.pre_stop.as_ref()?;
+        let result = open_netns(&sandbox, pid).and_then(|netns| {
+            spawn_hook(&hook.executable, &hook.arguments, netns.as_ref())
+        });
+        match result {
+            Ok(child) => {
+                info!("[{}] Running pre-stop hook (pid: {})",
+                    options.name, child.pid());
+                Some((child, hook.timeout))
+            }
+            Err(e) => {
+                error!("[{}] {}", options.name, e);
+                None
+            }
+        }
+    };
+    let mut first_start = true;
     loop {
+        if network.netns.is_none() {
+            // namespace of the previous process is not reused
+            network.dhcp.stop();
+        }
         let start = Instant::now();
+        let mut cmd = prepare_command(options, master, &sandbox, &local,
+            user_id, group_id, &network, seccomp.as_ref())?;
         let mut killed = false;
         let mut dead = false;
 
@@ -377,19 +1029,141 @@ fn run(options: &Options) -> Result<i32, String>
             };
         }
 
-        warn!("Starting {:?}: {}", options.name,
-            cmd.display(&Style::short().path(true)));
+        if let Some(ref cgroups) = cgroups {
+            // so that crash report contains peak of this very process
+            cgroups.set_value_if_exists(cgroup::Controller::Memory,
+                "memory.max_usage_in_bytes", "0")
+                .map_err(|e| debug!("Can't reset memory peak: {}", e)).ok();
+        }
+        if let Some(ref marker) = drain {
+            marker.clear()?;
+        }
+        if !first_start {
+            // `started` time of the restarted process
+            prepare_info_file(state_fd, &options.name, &options.config,
+                &sandbox).map_err(|e| warn!("{}", e)).ok();
+        }
+        first_start = false;
+        let cmdline = if hide_arguments {
+            format!("{} <arguments hidden>", executable_name)
+        } else {
+            cmd.display(&Style::short().path(true)).to_string()
+        };
+        warn!("Starting {:?}: {}", options.name, cmdline);
         stderr_file.write_all(
             format!("{}: ----- Starting {:?}: {} -----\n",
                 format_rfc3339_seconds(SystemTime::now()), options.name,
-                cmd.display(&Style::short().path(true)))
+                cmdline)
             .as_bytes()
         ).ok();
-        let child = try!(cmd.spawn().map_err(|e|
-            format!("Error running {:?}: {}", options.name, e)));
+        let child = try!(cmd.spawn().map_err(|e| {
+            let hint = match e {
+                // hint contains the path of the executable
+                unshare::Error::Exec(errno) if !hide_executable => {
+                    exec_error::explain(&mount_dir,
+                        Path::new(&local.executable), errno)
+                }
+                _ => None,
+            };
+            match hint {
+                Some(hint) => format!("Error running {:?}: {}. {}",
+                    options.name, e, hint),
+                None => format!("Error running {:?}: {}", options.name, e),
+            }
+        }));
+        write_container_pid(state_fd, child.pid());
+        // only the first start is measured, restarts skip most of the stages
+        if local.ready_wait().is_some() {
+            timings.stage("exec");
+        } else {
+            timings.finish("exec", state_fd);
+        }
+        if let Some(ref c) = counters {
+            c.incr(Slot::Starts);
+            if restart {
+                c.incr(Slot::Restarts);
+            }
+        }
+        // checks join the namespace even if it's not kept for restarts
+        let netns = open_netns(&sandbox, child.pid())?;
+        if network.netns.is_none() && !should_exit {
+            network.netns = open_netns(&sandbox, child.pid())?;
+        }
+        // unmounts leftover FUSE filesystems when process is dead
+        let _fuse_cleanup = if sandbox.allow_fuse {
+            Some(fuse::Cleanup::new(child.pid())?)
+        } else {
+            None
+        };
 
+        // daemons are never terminated by `max-runtime`
+        let max_runtime = options.config.max_runtime
+            .filter(|_| local.kind != Daemon);
+        let mut timed_out = false;
+        let mut unhealthy = None;
+        let mut health = local.health_check.as_ref().map(|hc| {
+            health_check::Checker::new(&options.name, hc, counters.as_ref())
+        });
+        // health checks are only run when the process is ready
+        let mut readiness = local.readiness.as_ref().map(|rc| {
+            health_check::Readiness::new(&options.name, rc)
+        });
+        let mut notify = notify_socket.as_ref().map(|socket| {
+            notify::State::new(&options.name, socket)
+        });
+        let mut watchdog_missed = false;
+        let mut draining = false;
+        let mut pre_stop = None;
         let mut iter = SignalIter::new(&mut trap);
-        while let Some(signal) = iter.next() {
+        if let Some(max_runtime) = max_runtime {
+            iter.set_deadline(start + duration(max_runtime));
+        }
+        loop {
+            let signal = match iter.next() {
+                Some(signal) => signal,
+                None if draining && !dead => {
+                    draining = false;
+                    let (_, deadline) = terminate(&child,
+                        &mut pre_stop, &start_pre_stop,
+                        container.kill_timeout);
+                    iter.set_deadline(deadline);
+                    continue;
+                }
+                None if pre_stop.is_some() && !dead => {
+                    warn!("Pre-stop hook of {:?} exceeded its timeout. \
+                        Terminating the process...", options.name);
+                    if let Some(mut hook) = pre_stop.take() {
+                        kill_pre_stop(&mut hook);
+                    }
+                    child.signal(SIGTERM).ok();
+                    iter.set_deadline(
+                        Instant::now() + duration(container.kill_timeout));
+                    continue;
+                }
+                None if max_runtime.is_some() && !killed && !dead => {
+                    let uptime = Instant::now() - start;
+                    error!("Process {:?} exceeded max-runtime, \
+                        uptime {}s. Terminating...",
+                        options.name, uptime.as_secs());
+                    stderr_file.write_all(
+                        format!("{}: ----- \
+                            Process {:?} exceeded max-runtime, \
+                            terminating -----\n",
+                            format_rfc3339_seconds(SystemTime::now()),
+                            options.name,
+                        ).as_bytes()
+                    ).ok();
+                    timed_out = true;
+                    should_exit = true;
+                    let (started, deadline) = begin_stop(&child,
+                        drain.as_ref(), &mut draining, &mut pre_stop,
+                        &start_pre_stop, container.kill_timeout);
+                    killed = started;
+                    iter.set_deadline(deadline);
+                    continue;
+                }
+                None => break,
+            };
             match signal {
                 SIGINT => {
                     // SIGINT is usually a Ctrl+C so it's sent to whole
@@ -403,29 +1177,163 @@ fn run(options: &Options) -> Result<i32, String>
                     debug!("Received SIGTERM signal, propagating");
                     should_exit = true;
                     exit_code = 0;
+                    stopped = true;
                     if !killed {
-                        if let Ok(()) = child.signal(SIGTERM) {
-                            killed = true;
+                        let (started, deadline) = begin_stop(&child,
+                            drain.as_ref(), &mut draining, &mut pre_stop,
+                            &start_pre_stop, container.kill_timeout);
+                        killed = started;
+                        iter.set_deadline(deadline);
+                    }
+                }
+                SIGALRM => {
+                    let exceeded = local.restart_on_fd_usage
+                        .filter(|&threshold| !killed && fd_usage_exceeded(
+                            child.pid(), threshold, local.fileno_limit));
+                    if let Some(threshold) = exceeded {
+                        warn!("Process {:?} uses more than {}% of \
+                            file descriptors. Restarting...",
+                            options.name, (threshold * 100.) as u32);
+                        stderr_file.write_all(
+                            format!("{}: ----- \
+                                Process {:?} uses too many file descriptors, \
+                                restarting -----\n",
+                                format_rfc3339_seconds(SystemTime::now()),
+                                options.name,
+                            ).as_bytes()
+                        ).ok();
+                        let (started, deadline) = begin_stop(&child,
+                            drain.as_ref(), &mut draining, &mut pre_stop,
+                            &start_pre_stop, container.kill_timeout);
+                        killed = started;
+                        iter.set_deadline(deadline);
+                    }
+                    let now = Instant::now();
+                    let mut became_ready = match readiness {
+                        Some(ref mut readiness)
+                        if !killed && !dead && readiness.is_due(now) => {
+                            readiness.run(netns.as_ref(),
+                                &|check, netns| spawn_hook(&check.executable,
+                                    &check.arguments, netns))
+                        }
+                        _ => false,
+                    };
+                    if let Some(ref mut notify) = notify {
+                        became_ready |= notify.poll(now);
+                    }
+                    if became_ready {
+                        info!("[{}] Process is ready", options.name);
+                        readiness = None;
+                        timings.finish("ready", state_fd);
+                    } else if readiness.as_ref()
+                        .map(|r| r.expired(now)) == Some(true)
+                    {
+                        // still not ready, but health checks may restart it
+                        readiness = None;
+                    }
+                    let check_failed = match health {
+                        Some(ref mut health) if !killed && !dead &&
+                            readiness.is_none() &&
+                            !notify.as_ref().map_or(false, |n| n.waiting(now))
+                            && health.is_due(now) =>
+                        {
+                            health.run(netns.as_ref(),
+                                &|check, netns| spawn_hook(&check.executable,
+                                    &check.arguments, netns),
+                                counters.as_ref())
+                        }
+                        _ => false,
+                    };
+                    if check_failed {
+                        let failures = health.as_ref()
+                            .map(|h| h.failures()).unwrap_or(0);
+                        error!("Process {:?} failed {} health checks in a \
+                            row. Restarting...", options.name, failures);
+                        stderr_file.write_all(
+                            format!("{}: ----- \
+                                Process {:?} is unhealthy, \
+                                restarting -----\n",
+                                format_rfc3339_seconds(SystemTime::now()),
+                                options.name,
+                            ).as_bytes()
+                        ).ok();
+                        if let Some(ref c) = counters {
+                            c.incr(Slot::HealthRestarts);
                         }
-                        iter.set_deadline(
-                            Instant::now() + duration(container.kill_timeout));
+                        unhealthy = Some(failures);
+                        let (started, deadline) = begin_stop(&child,
+                            drain.as_ref(), &mut draining, &mut pre_stop,
+                            &start_pre_stop, container.kill_timeout);
+                        killed = started;
+                        iter.set_deadline(deadline);
+                    }
+                    let watchdog_expired = notify.as_ref()
+                        .map_or(false, |n| n.watchdog_expired(now));
+                    if watchdog_expired && !killed && !dead {
+                        error!("Process {:?} missed its watchdog keep-alive. \
+                            Restarting...", options.name);
+                        stderr_file.write_all(
+                            format!("{}: ----- \
+                                Process {:?} missed watchdog, \
+                                restarting -----\n",
+                                format_rfc3339_seconds(SystemTime::now()),
+                                options.name,
+                            ).as_bytes()
+                        ).ok();
+                        if let Some(ref c) = counters {
+                            c.incr(Slot::HealthRestarts);
+                        }
+                        watchdog_missed = true;
+                        let (started, deadline) = begin_stop(&child,
+                            drain.as_ref(), &mut draining, &mut pre_stop,
+                            &start_pre_stop, container.kill_timeout);
+                        killed = started;
+                        iter.set_deadline(deadline);
                     }
                 }
                 SIGCHLD => {
                     for (pid, status) in reap_zombies() {
-                        if pid == child.pid() {
+                        if pre_stop.as_ref().map(|h| h.pid()) == Some(pid) {
+                            info!("[{}] Pre-stop hook {}",
+                                options.name, status);
+                            pre_stop = None;
+                            if !dead {
+                                child.signal(SIGTERM).ok();
+                                iter.set_deadline(Instant::now() +
+                                    duration(container.kill_timeout));
+                            }
+                        } else if network.dhcp.exited(pid) {
+                            error!("[{}] DHCP client {}, \
+                                address lease will not be renewed",
+                                options.name, status);
+                        } else if pid == child.pid() {
                             dead = true;
-                            if status.signal() == Some(SIGTERM as i32) ||
+                            let normal = status.signal() ==
+                                Some(SIGTERM as i32) ||
                                 status.code().map(|c| {
                                     if container.normal_exit_codes.is_empty() {
                                         local.kind != Daemon && c == 0
                                     } else {
                                         container.normal_exit_codes.contains(&c)
                                     }
-                                }).unwrap_or(false)
-                            {
+                                }).unwrap_or(false);
+                            if normal {
                                 exit_code = 0;
                             }
+                            report = match (status.code(), status.signal()) {
+                                _ if normal => ExitReport::CleanExit,
+                                (Some(code), _) => ExitReport::Crash { code },
+                                (_, Some(signal)) => {
+                                    ExitReport::Signal { signal }
+                                }
+                                (None, None) => unreachable!(),
+                            };
+                            if let Some(ref c) = counters {
+                                c.incr(Slot::Deaths);
+                                if !normal {
+                                    c.incr(Slot::Failures);
+                                }
+                            }
                             let uptime = Instant::now() - start;
                             error!("Process {:?} {}, uptime {}s",
                                 options.name, status, uptime.as_secs());
@@ -437,6 +1345,19 @@ fn run(options: &Options) -> Result<i32, String>
                                     options.name, status, uptime.as_secs(),
                                 ).as_bytes()
                             ).ok();
+                            match crash_reports {
+                                Some(ref reports) if !normal && !killed => {
+                                    reports.write(&Crash {
+                                        name: &options.name,
+                                        status: &status,
+                                        uptime,
+                                        output_file,
+                                        cgroups: cgroups.as_ref(),
+                                        config: &options.config,
+                                    });
+                                }
+                                _ => {}
+                            }
                             iter.interrupt();
                         }
                     }
@@ -444,6 +1365,10 @@ fn run(options: &Options) -> Result<i32, String>
                 _ => unreachable!(),
             }
         }
+        if let Some(mut hook) = pre_stop.take() {
+            // process exited by itself while the hook was running
+            kill_pre_stop(&mut hook);
+        }
         if !dead {
             let uptime = Instant::now() - start;
             error!("Process {:?} \
@@ -458,7 +1383,22 @@ fn run(options: &Options) -> Result<i32, String>
                     options.name, container.kill_timeout, uptime.as_secs(),
                 ).as_bytes()
             ).ok();
-            return Ok(3);
+            if timed_out {
+                return Ok((EXIT_MAX_RUNTIME, ExitReport::MaxRuntime));
+            }
+            return Ok((3, ExitReport::KilledByTimeout));
+        }
+        if timed_out {
+            return Ok((EXIT_MAX_RUNTIME, ExitReport::MaxRuntime));
+        }
+        if let Some(failures) = unhealthy {
+            // process usually exits cleanly on SIGTERM, but it's a failure
+            exit_code = 2;
+            report = ExitReport::Unhealthy { failures };
+        }
+        if watchdog_missed {
+            exit_code = 2;
+            report = ExitReport::WatchdogTimeout;
         }
 
         if should_exit {
@@ -468,9 +1408,13 @@ fn run(options: &Options) -> Result<i32, String>
         if left > Duration::new(0, 0) {
             sleep(left);
         }
+        restart = true;
     }
 
-    Ok(exit_code)
+    if stopped {
+        report = ExitReport::Stopped;
+    }
+    Ok((exit_code, report))
 }
 
 
@@ -482,6 +1426,16 @@ fn main() {
             exit(x);
         }
     };
+    if let Some(ref target) = options.debug {
+        match debug_run::run(&options, target) {
+            Ok(code) => exit(code),
+            Err(e) => {
+                write!(&mut stderr(), "Fatal error: {}\n", e).ok();
+                exit(1);
+            }
+        }
+    }
+    set_process_title(&options.name);
     match run(&options)
     {
         Ok(code) => {
@@ -494,3 +1448,18 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::process_title;
+
+    #[test]
+    fn title() {
+        assert_eq!(process_title("web/app.0"), "web/app.0");
+        assert_eq!(process_title("frontend/worker.12"), "worker.12");
+        assert_eq!(process_title("web/very-long-worker.12"),
+                   "-long-worker.12");
+        assert_eq!(process_title("web/процесс-воркер.1"), "-воркер.1");
+        assert_eq!(process_title("web/тест-воркеры.1"), "оркеры.1");
+    }
+}
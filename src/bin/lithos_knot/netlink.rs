@@ -0,0 +1,328 @@
+//! Minimal rtnetlink client for setting up bridged network
+//!
+//! Only the handful of requests needed by `setup_network` are implemented.
+//! Socket is bound to the network namespace it was created in, so a new
+//! `Netlink` must be opened after each namespace switch.
+use std::ffi::CString;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+
+use libc::{self, c_void, sockaddr_nl, AF_INET, AF_INET6, AF_NETLINK};
+use libc::{AF_UNSPEC, SOCK_CLOEXEC, SOCK_RAW, NETLINK_ROUTE};
+
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_NET_NS_FD: u16 = 28;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const VETH_INFO_PEER: u16 = 1;
+const IFF_UP: u32 = 1;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+const RTA_GATEWAY: u16 = 5;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+const NLMSG_HDRLEN: usize = 16;
+
+
+#[derive(Fail, Debug)]
+#[fail(display="netlink: {} failed: {}", operation, error)]
+pub struct NetlinkError {
+    pub operation: String,
+    #[cause] pub error: io::Error,
+}
+
+pub struct Netlink {
+    fd: RawFd,
+    seq: u32,
+}
+
+/// Request being built, attributes are appended in place
+struct Message {
+    buf: Vec<u8>,
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(ip: &IpAddr) -> u8 {
+    match *ip {
+        IpAddr::V4(_) => AF_INET as u8,
+        IpAddr::V6(_) => AF_INET6 as u8,
+    }
+}
+
+fn ip_bytes(ip: &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn link_index(name: &str) -> Result<u32, io::Error> {
+    let cname = CString::new(name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(cname.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        idx => Ok(idx),
+    }
+}
+
+impl Message {
+    fn new(kind: u16, flags: u16) -> Message {
+        let mut msg = Message { buf: Vec::with_capacity(256) };
+        msg.u32(0);  // length, filled in `finish`
+        msg.u16(kind);
+        msg.u16(flags | NLM_F_REQUEST | NLM_F_ACK);
+        msg.u32(0);  // sequence number, filled in `finish`
+        msg.u32(0);  // port id, kernel fills it
+        msg
+    }
+    fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+    fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_ne_bytes());
+    }
+    fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_ne_bytes());
+    }
+    fn pad(&mut self) {
+        let len = align(self.buf.len());
+        self.buf.resize(len, 0);
+    }
+    fn ifinfo(&mut self, index: u32, flags: u32, change: u32) {
+        self.u8(AF_UNSPEC as u8);
+        self.u8(0);
+        self.u16(0);  // device type
+        self.u32(index);
+        self.u32(flags);
+        self.u32(change);
+    }
+    fn attr(&mut self, kind: u16, data: &[u8]) {
+        self.u16((4 + data.len()) as u16);
+        self.u16(kind);
+        self.buf.extend_from_slice(data);
+        self.pad();
+    }
+    fn attr_u32(&mut self, kind: u16, val: u32) {
+        self.attr(kind, &val.to_ne_bytes());
+    }
+    fn attr_str(&mut self, kind: u16, val: &str) {
+        let mut data = val.as_bytes().to_vec();
+        data.push(0);
+        self.attr(kind, &data);
+    }
+    /// Starts nested attribute, returns offset for `end_nested`
+    fn start_nested(&mut self, kind: u16) -> usize {
+        let offset = self.buf.len();
+        self.u16(0);
+        self.u16(kind);
+        offset
+    }
+    fn end_nested(&mut self, offset: usize) {
+        let len = (self.buf.len() - offset) as u16;
+        self.buf[offset..offset+2].copy_from_slice(&len.to_ne_bytes());
+    }
+    fn finish(&mut self, seq: u32) -> &[u8] {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        &self.buf
+    }
+}
+
+impl Netlink {
+    /// Opens rtnetlink socket in the current network namespace
+    pub fn open() -> Result<Netlink, NetlinkError> {
+        let err = |error| NetlinkError {
+            operation: "open socket".into(), error };
+        let fd = unsafe {
+            libc::socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(err(io::Error::last_os_error()));
+        }
+        let nl = Netlink { fd, seq: 0 };
+        let mut addr: sockaddr_nl = unsafe { zeroed() };
+        addr.nl_family = AF_NETLINK as u16;
+        let rc = unsafe {
+            libc::bind(fd, &addr as *const sockaddr_nl as *const _,
+                size_of::<sockaddr_nl>() as u32)
+        };
+        if rc < 0 {
+            return Err(err(io::Error::last_os_error()));
+        }
+        Ok(nl)
+    }
+    fn request(&mut self, operation: &str, mut msg: Message)
+        -> Result<(), NetlinkError>
+    {
+        let err = |error| NetlinkError {
+            operation: operation.to_string(), error };
+        self.seq += 1;
+        let seq = self.seq;
+        let data = msg.finish(seq);
+        let rc = unsafe {
+            libc::send(self.fd, data.as_ptr() as *const c_void,
+                data.len(), 0)
+        };
+        if rc < 0 {
+            return Err(err(io::Error::last_os_error()));
+        }
+        let mut buf = [0u8; 4096];
+        loop {
+            let rc = unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut c_void,
+                    buf.len(), 0)
+            };
+            if rc < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err(e));
+            }
+            let mut data = &buf[..rc as usize];
+            while data.len() >= NLMSG_HDRLEN {
+                let mut len = [0u8; 4];
+                len.copy_from_slice(&data[0..4]);
+                let len = u32::from_ne_bytes(len) as usize;
+                let kind = u16::from_ne_bytes([data[4], data[5]]);
+                let mut mseq = [0u8; 4];
+                mseq.copy_from_slice(&data[8..12]);
+                if len < NLMSG_HDRLEN || len > data.len() {
+                    return Err(err(io::Error::new(io::ErrorKind::InvalidData,
+                        "truncated netlink message")));
+                }
+                if kind == NLMSG_ERROR && u32::from_ne_bytes(mseq) == seq &&
+                    len >= NLMSG_HDRLEN + 4
+                {
+                    let mut code = [0u8; 4];
+                    code.copy_from_slice(&data[16..20]);
+                    return match i32::from_ne_bytes(code) {
+                        0 => Ok(()),
+                        code => Err(err(io::Error::from_raw_os_error(-code))),
+                    };
+                }
+                data = &data[align(len).min(data.len())..];
+            }
+        }
+    }
+    fn index(&self, operation: &str, name: &str) -> Result<u32, NetlinkError> {
+        link_index(name).map_err(|error| NetlinkError {
+            operation: format!("{}: find link {:?}", operation, name),
+            error,
+        })
+    }
+    /// Creates veth pair `name` <-> `peer`
+    pub fn add_veth(&mut self, name: &str, peer: &str)
+        -> Result<(), NetlinkError>
+    {
+        let mut msg = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL);
+        msg.ifinfo(0, 0, 0);
+        msg.attr_str(IFLA_IFNAME, name);
+        let info = msg.start_nested(IFLA_LINKINFO);
+        msg.attr_str(IFLA_INFO_KIND, "veth");
+        let data = msg.start_nested(IFLA_INFO_DATA);
+        let peer_info = msg.start_nested(VETH_INFO_PEER);
+        msg.ifinfo(0, 0, 0);
+        msg.attr_str(IFLA_IFNAME, peer);
+        msg.end_nested(peer_info);
+        msg.end_nested(data);
+        msg.end_nested(info);
+        self.request(&format!("add veth {:?}", name), msg)
+    }
+    pub fn delete_link(&mut self, name: &str) -> Result<(), NetlinkError> {
+        let op = format!("delete link {:?}", name);
+        let mut msg = Message::new(RTM_DELLINK, 0);
+        msg.ifinfo(self.index(&op, name)?, 0, 0);
+        self.request(&op, msg)
+    }
+    /// Moves link into namespace referred by file descriptor `ns_fd`
+    pub fn move_to_netns(&mut self, name: &str, ns_fd: RawFd)
+        -> Result<(), NetlinkError>
+    {
+        let op = format!("move link {:?} to namespace", name);
+        let mut msg = Message::new(RTM_NEWLINK, 0);
+        msg.ifinfo(self.index(&op, name)?, 0, 0);
+        msg.attr_u32(IFLA_NET_NS_FD, ns_fd as u32);
+        self.request(&op, msg)
+    }
+    /// Adds link to the bridge
+    pub fn set_master(&mut self, name: &str, bridge: &str)
+        -> Result<(), NetlinkError>
+    {
+        let op = format!("add link {:?} to bridge {:?}", name, bridge);
+        let bridge_idx = self.index(&op, bridge)?;
+        let mut msg = Message::new(RTM_NEWLINK, 0);
+        msg.ifinfo(self.index(&op, name)?, 0, 0);
+        msg.attr_u32(IFLA_MASTER, bridge_idx);
+        self.request(&op, msg)
+    }
+    pub fn set_up(&mut self, name: &str) -> Result<(), NetlinkError> {
+        let op = format!("set link {:?} up", name);
+        let mut msg = Message::new(RTM_NEWLINK, 0);
+        msg.ifinfo(self.index(&op, name)?, IFF_UP, IFF_UP);
+        self.request(&op, msg)
+    }
+    pub fn add_address(&mut self, name: &str, ip: IpAddr, prefix: u8)
+        -> Result<(), NetlinkError>
+    {
+        let op = format!("add address {}/{} to {:?}", ip, prefix, name);
+        let index = self.index(&op, name)?;
+        let mut msg = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL);
+        msg.u8(family(&ip));
+        msg.u8(prefix);
+        msg.u8(0);  // flags
+        msg.u8(RT_SCOPE_UNIVERSE);
+        msg.u32(index);
+        msg.attr(IFA_LOCAL, &ip_bytes(&ip));
+        msg.attr(IFA_ADDRESS, &ip_bytes(&ip));
+        self.request(&op, msg)
+    }
+    pub fn add_default_route(&mut self, gateway: IpAddr)
+        -> Result<(), NetlinkError>
+    {
+        let op = format!("add default route via {}", gateway);
+        let mut msg = Message::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL);
+        msg.u8(family(&gateway));
+        msg.u8(0);  // destination length, zero for default route
+        msg.u8(0);  // source length
+        msg.u8(0);  // tos
+        msg.u8(RT_TABLE_MAIN);
+        msg.u8(RTPROT_BOOT);
+        msg.u8(RT_SCOPE_UNIVERSE);
+        msg.u8(RTN_UNICAST);
+        msg.u32(0);  // flags
+        msg.attr(RTA_GATEWAY, &ip_bytes(&gateway));
+        self.request(&op, msg)
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,162 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use failure::{Error, ResultExt};
+use unshare::{self, Style};
+
+use lithos::container_config::replace_vars;
+use lithos::host_facts::HostFacts;
+use lithos::sandbox_config::NetworkHook;
+
+
+/// Interface of the container as seen in the host namespace
+pub struct Link {
+    pub interface: String,
+    pub ip: IpAddr,
+}
+
+/// Network hooks of the container
+///
+/// Link is stored when network is set up, so cleanup part knows what to
+/// clean. Clones share the link.
+#[derive(Clone)]
+pub struct NetworkHooks {
+    hooks: Vec<NetworkHook>,
+    name: String,
+    state_dir: PathBuf,
+    host_facts: Rc<HostFacts>,
+    link: Rc<RefCell<Option<Link>>>,
+}
+
+/// Runs `cleanup` part of the hooks when dropped
+///
+/// If network is never set up, nothing is run.
+pub struct Cleanup(NetworkHooks);
+
+fn substitute(item: &str, link: &Link, hooks: &NetworkHooks) -> String {
+    if !item.contains('@') {
+        return item.to_string();
+    }
+    replace_vars(item, |v| {
+        match v {
+            "container_ip" => link.ip.to_string(),
+            "interface" => link.interface.clone(),
+            "container_name" => hooks.name.clone(),
+            _ if v.starts_with("fact:") => {
+                hooks.host_facts.get(&v["fact:".len()..])
+                .unwrap_or_else(|| {
+                    error!("No host fact {:?} for network-hooks. \
+                            Using empty string.", v);
+                    String::new()
+                })
+            }
+            _ => {
+                error!("No variable {:?} for network-hooks. \
+                        Using empty string.", v);
+                String::new()
+            }
+        }
+    })
+}
+
+fn run_command(command: &[String], link: &Link, hooks: &NetworkHooks)
+    -> Result<(), Error>
+{
+    let mut cmd = unshare::Command::new(&command[0]);
+    for item in &command[1..] {
+        cmd.arg(substitute(item, link, hooks));
+    }
+    debug!("Running {}", cmd.display(&Style::short()));
+    match cmd.status() {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => bail!("{} failed: {}", command[0], s),
+        Err(e) => bail!("{} failed: {}", command[0], e),
+    }
+}
+
+/// Fills in the template and loads it with `nft -f`
+fn run_nft(template: &Path, dest: &Path, link: &Link, hooks: &NetworkHooks)
+    -> Result<(), Error>
+{
+    let mut text = String::new();
+    File::open(template)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .context(format!("can't read {:?}", template))?;
+    File::create(dest)
+        .and_then(|mut f| {
+            f.write_all(substitute(&text, link, hooks).as_bytes())
+        })
+        .context(format!("can't write {:?}", dest))?;
+    run_command(&["/usr/sbin/nft".to_string(), "-f".to_string(),
+                  dest.to_string_lossy().to_string()], link, hooks)
+}
+
+impl NetworkHooks {
+    pub fn new(hooks: &[NetworkHook], name: &str, state_dir: &Path,
+        host_facts: &HostFacts)
+        -> NetworkHooks
+    {
+        NetworkHooks {
+            hooks: hooks.to_vec(),
+            name: name.to_string(),
+            state_dir: state_dir.to_path_buf(),
+            host_facts: Rc::new(host_facts.clone()),
+            link: Rc::new(RefCell::new(None)),
+        }
+    }
+    /// Returns guard which runs cleanup part of the hooks on drop
+    pub fn cleanup_guard(&self) -> Cleanup {
+        Cleanup(self.clone())
+    }
+    /// Runs setup part of the hooks, in the host network namespace
+    pub fn setup(&self, link: Link) -> Result<(), Error> {
+        // stored first, so partially applied hooks are cleaned up too
+        *self.link.borrow_mut() = Some(link);
+        let link = self.link.borrow();
+        let link = link.as_ref().expect("link is just set");
+        for (idx, hook) in self.hooks.iter().enumerate() {
+            if let Some(ref template) = hook.nft_setup {
+                run_nft(template,
+                    &self.state_dir.join(format!("network-hook-{}.nft", idx)),
+                    link, self)
+                .context(format!("network hook {}", idx))?;
+            }
+            if !hook.setup.is_empty() {
+                run_command(&hook.setup, link, self)
+                    .context(format!("network hook {}", idx))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        let hooks = &self.0;
+        let link = match hooks.link.borrow_mut().take() {
+            Some(link) => link,
+            None => return,
+        };
+        // in reverse order, and every hook is cleaned up even if some fail
+        for (idx, hook) in hooks.hooks.iter().enumerate().rev() {
+            if !hook.cleanup.is_empty() {
+                run_command(&hook.cleanup, &link, hooks)
+                    .map_err(|e| error!("Network hook {} cleanup: {}",
+                        idx, e))
+                    .ok();
+            }
+            if let Some(ref template) = hook.nft_cleanup {
+                let dest = hooks.state_dir
+                    .join(format!("network-hook-{}-cleanup.nft", idx));
+                run_nft(template, &dest, &link, hooks)
+                    .map_err(|e| error!("Network hook {} cleanup: {}",
+                        idx, e))
+                    .ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Systemd notification protocol for the process (`sd-notify`)
+//!
+//! The socket is bound in the image before the first start and is kept
+//! open across restarts of the process. Messages are read on `SIGALRM`,
+//! only `READY=1`, `STATUS=` and `WATCHDOG=1` are interpreted, others are
+//! ignored.
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::str::from_utf8;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use lithos::container_config::SdNotify;
+use lithos::utils::open_dir_nofollow;
+
+
+/// Interval of reading the socket
+pub const POLL_INTERVAL: f32 = 1.;
+
+/// Maximum size of the notification, as in systemd
+const MAX_MESSAGE: usize = 4096;
+
+pub struct Socket {
+    socket: UnixDatagram,
+    config: SdNotify,
+}
+
+/// Notifications of a single start of the process
+pub struct State<'a> {
+    name: &'a str,
+    socket: &'a Socket,
+    ready: bool,
+    gave_up: bool,
+    give_up: Instant,
+    watchdog_deadline: Option<Instant>,
+    status: Option<String>,
+}
+
+fn duration(inp: f32) -> Duration {
+    Duration::from_millis((inp * 1000.) as u64)
+}
+
+impl Socket {
+    /// Binds the socket at `path` in the image mounted at `root`
+    ///
+    /// Socket is owned by `owner` (user and group in the host namespace),
+    /// so the process can send to it. The directory may be writable by the
+    /// container, so symlinks are not followed.
+    pub fn bind(root: &Path, config: &SdNotify, owner: Option<(u32, u32)>)
+        -> Result<Socket, String>
+    {
+        let (dir, name) = match (config.path.parent(), config.path.file_name())
+        {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(format!("Invalid notify socket path {:?}",
+                config.path)),
+        };
+        let dir = open_dir_nofollow(root, dir)
+            .map_err(|e| format!("Can't open directory of notify socket \
+                {:?}: {}", config.path, e))?;
+        let cname = CString::new(name.as_bytes()).expect("path has no nulls");
+        let rc = unsafe {
+            libc::unlinkat(dir.as_raw_fd(), cname.as_ptr(), 0)
+        };
+        if rc != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(format!("Can't remove stale notify socket {:?}: {}",
+                    config.path, e));
+            }
+        }
+        // bind(2) has no `at` variant, so the path is resolved via the
+        // already opened directory
+        let path = Path::new("/proc/self/fd")
+            .join(dir.as_raw_fd().to_string()).join(name);
+        let socket = UnixDatagram::bind(&path)
+            .map_err(|e| format!("Can't bind notify socket {:?}: {}",
+                config.path, e))?;
+        socket.set_nonblocking(true)
+            .map_err(|e| format!("Can't set notify socket nonblocking: {}",
+                e))?;
+        if let Some((uid, gid)) = owner {
+            let rc = unsafe {
+                libc::fchownat(dir.as_raw_fd(), cname.as_ptr(), uid, gid,
+                    libc::AT_SYMLINK_NOFOLLOW)
+            };
+            if rc != 0 {
+                return Err(format!("Can't chown notify socket: {}",
+                    io::Error::last_os_error()));
+            }
+        }
+        Ok(Socket { socket, config: config.clone() })
+    }
+    /// Environment variables passed to the process
+    pub fn environ(&self) -> Vec<(String, String)> {
+        let mut result = vec![
+            ("NOTIFY_SOCKET".to_string(),
+             self.config.path.display().to_string()),
+        ];
+        if let Some(watchdog) = self.config.watchdog {
+            result.push(("WATCHDOG_USEC".to_string(),
+                format!("{}", (watchdog * 1e6) as u64)));
+        }
+        result
+    }
+    fn receive(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        let mut buf = [0u8; MAX_MESSAGE];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(len) => {
+                    if let Ok(msg) = from_utf8(&buf[..len]) {
+                        result.push(msg.to_string());
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Error reading notify socket: {}", e);
+                    break;
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<'a> State<'a> {
+    /// Starts waiting for `READY=1` from a freshly started process
+    ///
+    /// Messages left by the previous process are discarded.
+    pub fn new(name: &'a str, socket: &'a Socket) -> State<'a> {
+        socket.receive();
+        State {
+            name,
+            socket,
+            ready: false,
+            gave_up: false,
+            give_up: Instant::now() + duration(socket.config.max_wait),
+            watchdog_deadline: None,
+            status: None,
+        }
+    }
+    /// Process is neither ready nor `max-wait` has passed yet
+    pub fn waiting(&self, now: Instant) -> bool {
+        !self.ready && now < self.give_up
+    }
+    /// Reads pending messages, returns true if process has just become ready
+    ///
+    /// Watchdog is armed when process is ready or on `max-wait`.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let was_ready = self.ready;
+        let mut ping = false;
+        for msg in self.socket.receive() {
+            for line in msg.lines() {
+                match line {
+                    "READY=1" => self.ready = true,
+                    "WATCHDOG=1" => ping = true,
+                    _ if line.starts_with("STATUS=") => {
+                        let status = &line["STATUS=".len()..];
+                        if self.status.as_ref().map(|s| &s[..])
+                            != Some(status)
+                        {
+                            info!("[{}] Status: {}", self.name, status);
+                            self.status = Some(status.to_string());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(watchdog) = self.socket.config.watchdog {
+            if !self.waiting(now) &&
+                (ping || self.watchdog_deadline.is_none())
+            {
+                self.watchdog_deadline = Some(now + duration(watchdog));
+            }
+        }
+        if !self.ready && !self.gave_up && now >= self.give_up {
+            self.gave_up = true;
+            error!("[{}] Process is not ready in {}s, \
+                giving up waiting for READY=1",
+                self.name, self.socket.config.max_wait);
+        }
+        !was_ready && self.ready
+    }
+    /// Returns true if process didn't send `WATCHDOG=1` in time
+    pub fn watchdog_expired(&self, now: Instant) -> bool {
+        self.watchdog_deadline.map(|d| d <= now).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use lithos::container_config::SdNotify;
+    use super::{Socket, State};
+
+    fn socket(watchdog: Option<f32>) -> (Socket, UnixDatagram) {
+        let (socket, client) = UnixDatagram::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        (Socket {
+            socket,
+            config: SdNotify {
+                path: PathBuf::from("/run/notify"),
+                max_wait: 10.,
+                watchdog,
+            },
+        }, client)
+    }
+
+    #[test]
+    fn ready() {
+        let (sock, client) = socket(None);
+        let mut state = State::new("test", &sock);
+        let now = Instant::now();
+        client.send(b"STATUS=starting").unwrap();
+        assert!(!state.poll(now));
+        assert_eq!(state.status.as_ref().map(|x| &x[..]), Some("starting"));
+        assert!(state.waiting(now));
+        client.send(b"STATUS=serving\nREADY=1\n").unwrap();
+        assert!(state.poll(now));
+        assert_eq!(state.status.as_ref().map(|x| &x[..]), Some("serving"));
+        assert!(!state.waiting(now));
+        // already ready
+        client.send(b"READY=1").unwrap();
+        assert!(!state.poll(now));
+    }
+
+    #[test]
+    fn unknown_and_stale() {
+        let (sock, client) = socket(None);
+        client.send(b"READY=1").unwrap();
+        // left by the previous process
+        let mut state = State::new("test", &sock);
+        let now = Instant::now();
+        client.send(b"MAINPID=1\nREADY=0\nSTATUS").unwrap();
+        assert!(!state.poll(now));
+        assert!(state.status.is_none());
+        assert!(state.waiting(now));
+        assert!(!state.waiting(now + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn watchdog() {
+        let (sock, client) = socket(Some(1.));
+        let mut state = State::new("test", &sock);
+        let now = Instant::now();
+        assert!(!state.poll(now));
+        // not armed until ready
+        assert!(!state.watchdog_expired(now + Duration::from_secs(5)));
+        client.send(b"READY=1").unwrap();
+        assert!(state.poll(now));
+        assert!(!state.watchdog_expired(now));
+        assert!(state.watchdog_expired(now + Duration::from_secs(1)));
+        let later = now + Duration::from_millis(500);
+        client.send(b"WATCHDOG=1").unwrap();
+        assert!(!state.poll(later));
+        assert!(!state.watchdog_expired(now + Duration::from_secs(1)));
+        assert!(state.watchdog_expired(later + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,48 @@
+//! Checks of the `requires` section of the container config
+//!
+//! This is done right after the config is read from the image, so that a
+//! container built for another architecture or a newer kernel is reported
+//! as a config error instead of failing somewhere in the middle of setup.
+use nix::sys::utsname::uname;
+
+use lithos::cgroup;
+use lithos::container_config::{Requirements, CgroupVersion};
+use lithos::kernel_features::kernel_version;
+
+
+/// Same as `cgroup-v2` host feature, so hybrid hierarchy is `v2` too
+fn cgroup_version() -> CgroupVersion {
+    if cgroup::unified_base().is_some() {
+        CgroupVersion::V2
+    } else {
+        CgroupVersion::V1
+    }
+}
+
+pub fn check(req: &Requirements) -> Result<(), String> {
+    let uts = uname();
+    if let Some(ref arch) = req.arch {
+        if arch != uts.machine() {
+            return Err(format!("container requires {} architecture, \
+                host is {}", arch, uts.machine()));
+        }
+    }
+    if let Some(ref min_kernel) = req.min_kernel {
+        let required = kernel_version(min_kernel);
+        if required.is_empty() {
+            return Err(format!("invalid min-kernel {:?}", min_kernel));
+        }
+        if kernel_version(uts.release()) < required {
+            return Err(format!("container requires kernel {} or newer, \
+                host has {}", min_kernel, uts.release()));
+        }
+    }
+    if let Some(cgroup) = req.cgroup {
+        let host = cgroup_version();
+        if cgroup != host {
+            return Err(format!("container requires cgroup {}, \
+                host has {}", cgroup.name(), host.name()));
+        }
+    }
+    Ok(())
+}
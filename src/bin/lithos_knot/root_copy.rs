@@ -0,0 +1,142 @@
+use std::ffi::CString;
+use std::fs::{copy, create_dir, read_dir, read_link, symlink_metadata};
+use std::fs::{set_permissions, Permissions};
+use std::io;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::ptr::null_mut;
+
+use failure::{Error, ResultExt};
+use libc;
+use libmount::Tmpfs;
+
+use lithos::utils::cpath;
+
+
+fn set_owner(path: &Path, uid: u32, gid: u32) -> Result<(), io::Error> {
+    // doesn't follow symlinks
+    let rc = unsafe { libc::lchown(cpath(path).as_ptr(), uid, gid) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Names of extended attributes of the file (doesn't follow symlinks)
+fn list_xattrs(path: &Path) -> Result<Vec<CString>, io::Error> {
+    let c_path = cpath(path);
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), null_mut(), 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; size as usize];
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(),
+        buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(size as usize);
+    Ok(buf.split(|&c| c == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| CString::new(name).expect("no zeros in name"))
+        .collect())
+}
+
+/// Copies extended attributes, i.e. file capabilities and ACLs
+///
+/// Must be called after chown, as it drops `security.capability`.
+fn copy_xattrs(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    let names = match list_xattrs(src) {
+        Ok(names) => names,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let c_src = cpath(src);
+    let c_dest = cpath(dest);
+    for name in names {
+        let size = unsafe { libc::lgetxattr(c_src.as_ptr(), name.as_ptr(),
+            null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe { libc::lgetxattr(c_src.as_ptr(), name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let rc = unsafe { libc::lsetxattr(c_dest.as_ptr(), name.as_ptr(),
+            value.as_ptr() as *const libc::c_void, size as usize, 0) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOTSUP) {
+                // i.e. `user.*` attributes on tmpfs of older kernels
+                warn!("Attribute {:?} of {:?} is not supported by tmpfs, \
+                    skipping", name, src);
+                continue;
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn copy_dir(src: &Path, dest: &Path) -> Result<(), Error> {
+    for entry in read_dir(src).context(format!("can't list {:?}", src))? {
+        let entry = entry?;
+        let spath = entry.path();
+        let dpath = dest.join(entry.file_name());
+        let meta = symlink_metadata(&spath)
+            .context(format!("can't stat {:?}", spath))?;
+        let typ = meta.file_type();
+        if typ.is_dir() {
+            create_dir(&dpath).context(format!("can't create {:?}", dpath))?;
+            copy_dir(&spath, &dpath)?;
+        } else if typ.is_symlink() {
+            symlink(read_link(&spath)?, &dpath)
+                .context(format!("can't create {:?}", dpath))?;
+        } else if typ.is_file() {
+            copy(&spath, &dpath)
+                .context(format!("can't copy {:?}", spath))?;
+        } else {
+            warn!("Skipping special file {:?} of the image", spath);
+            continue;
+        }
+        set_owner(&dpath, meta.uid(), meta.gid())
+            .context(format!("can't chown {:?}", dpath))?;
+        if typ.is_symlink() {
+            continue;
+        }
+        // chown clears setuid and setgid bits, so mode is set after it
+        // (and after the contents for directories, which may be read-only)
+        set_permissions(&dpath, Permissions::from_mode(meta.mode() & 0o7777))
+            .context(format!("can't chmod {:?}", dpath))?;
+        copy_xattrs(&spath, &dpath)
+            .context(format!("can't copy attributes of {:?}", spath))?;
+    }
+    Ok(())
+}
+
+/// Mounts tmpfs of the limited size at `dest` and copies image into it
+///
+/// Unlike bind-mounted image, the copy is writable, and changes are dropped
+/// when the container exits.
+pub fn mount_copy(image: &Path, dest: &Path, size: usize)
+    -> Result<(), String>
+{
+    _mount_copy(image, dest, size)
+    .map_err(|e| format!("Error copying image {:?} to tmpfs: {}", image, e))
+}
+
+fn _mount_copy(image: &Path, dest: &Path, size: usize) -> Result<(), Error> {
+    let meta = symlink_metadata(image)
+        .context(format!("can't stat {:?}", image))?;
+    Tmpfs::new(dest).size_bytes(size).mode(meta.mode() & 0o7777)
+        .mount().map_err(|e| format_err!("{}", e))?;
+    copy_dir(image, dest)?;
+    set_owner(dest, meta.uid(), meta.gid())
+        .context(format!("can't chown {:?}", dest))?;
+    Ok(())
+}
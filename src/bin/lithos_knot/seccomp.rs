@@ -0,0 +1,403 @@
+//! Seccomp filter of the process (`seccomp-profile`)
+//!
+//! The filter is compiled by libseccomp in the knot, and only the resulting
+//! BPF program is loaded in the child right before exec. Library is loaded
+//! at runtime, so it's only required on hosts where containers have
+//! seccomp profiles. The `minimal` filter of the knot's own helpers is
+//! built without the library.
+use std::ffi::CString;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::mem::transmute;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use libc::{self, c_char, c_int, c_uint, c_void};
+use serde_json;
+
+use lithos::container_config::{SeccompProfile, SeccompRules, SeccompRule};
+use lithos::container_config::{SeccompArg, SeccompAction, SeccompOp};
+use lithos::utils::temporary_change_root;
+
+
+const LIBRARY: &str = "libseccomp.so.2";
+/// Size of `struct sock_filter`
+const INSN_SIZE: usize = 8;
+/// `BPF_MAXINSNS`
+const MAX_INSNS: usize = 4096;
+
+/// Compiled BPF program
+#[derive(Clone)]
+pub struct Filter(Vec<u8>);
+
+// classic BPF opcodes used by the minimal filter
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+// offsets in `struct seccomp_data`
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+const RET_KILL: u32 = 0x0000_0000;
+const RET_EPERM: u32 = 0x0005_0000 | libc::EPERM as u32;
+const RET_ALLOW: u32 = 0x7fff_0000;
+
+#[cfg(target_arch="x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch="aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch="x86_64", target_arch="aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscalls needed to compute something in memory and write it to a pipe
+const MINIMAL_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_read, libc::SYS_write, libc::SYS_close,
+    libc::SYS_brk, libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mremap,
+    libc::SYS_madvise, libc::SYS_futex, libc::SYS_getrandom,
+    libc::SYS_rt_sigreturn, libc::SYS_rt_sigprocmask,
+    libc::SYS_exit, libc::SYS_exit_group,
+];
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const u8,
+}
+
+#[repr(C)]
+struct ArgCmp {
+    arg: c_uint,
+    op: c_int,
+    datum_a: u64,
+    datum_b: u64,
+}
+
+type Init = extern "C" fn(u32) -> *mut c_void;
+type ResolveName = extern "C" fn(*const c_char) -> c_int;
+type RuleAddArray = extern "C" fn(*mut c_void, u32, c_int, c_uint,
+                                  *const ArgCmp) -> c_int;
+type ExportBpf = extern "C" fn(*mut c_void, c_int) -> c_int;
+type Release = extern "C" fn(*mut c_void);
+
+struct Library {
+    init: Init,
+    resolve_name: ResolveName,
+    rule_add_array: RuleAddArray,
+    export_bpf: ExportBpf,
+    release: Release,
+}
+
+/// Profile in docker/OCI format
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+struct JsonProfile {
+    default_action: SeccompAction,
+    #[serde(default="eperm")]
+    default_errno_ret: u32,
+    #[serde(default)]
+    syscalls: Vec<JsonRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+struct JsonRule {
+    names: Vec<String>,
+    action: SeccompAction,
+    #[serde(default="eperm")]
+    errno_ret: u32,
+    #[serde(default)]
+    args: Vec<JsonArg>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+struct JsonArg {
+    index: u32,
+    value: u64,
+    #[serde(default)]
+    value_two: u64,
+    op: SeccompOp,
+}
+
+fn eperm() -> u32 { libc::EPERM as u32 }
+
+impl From<JsonProfile> for SeccompRules {
+    fn from(p: JsonProfile) -> SeccompRules {
+        SeccompRules {
+            default_action: p.default_action,
+            default_errno: p.default_errno_ret,
+            syscalls: p.syscalls.into_iter().map(|r| SeccompRule {
+                names: r.names,
+                action: r.action,
+                errno: r.errno_ret,
+                args: r.args.into_iter().map(|a| SeccompArg {
+                    index: a.index,
+                    value: a.value,
+                    value_two: a.value_two,
+                    op: a.op,
+                }).collect(),
+            }).collect(),
+        }
+    }
+}
+
+fn action(action: SeccompAction, errno: u32) -> u32 {
+    use lithos::container_config::SeccompAction::*;
+    match action {
+        Kill => 0x0000_0000,
+        KillProcess => 0x8000_0000,
+        Trap => 0x0003_0000,
+        Errno => 0x0005_0000 | (errno & 0xffff),
+        Log => 0x7ffc_0000,
+        Allow => 0x7fff_0000,
+    }
+}
+
+fn op(op: SeccompOp) -> c_int {
+    use lithos::container_config::SeccompOp::*;
+    match op {
+        NotEqual => 1,
+        Less => 2,
+        LessOrEqual => 3,
+        Equal => 4,
+        GreaterOrEqual => 5,
+        Greater => 6,
+        MaskedEqual => 7,
+    }
+}
+
+unsafe fn symbol(lib: *mut c_void, name: &str) -> Result<*mut c_void, String>
+{
+    let cname = CString::new(name).unwrap();
+    let sym = libc::dlsym(lib, cname.as_ptr());
+    if sym.is_null() {
+        return Err(format!("{} has no symbol {}", LIBRARY, name));
+    }
+    Ok(sym)
+}
+
+impl Library {
+    fn open() -> Result<Library, String> {
+        let name = CString::new(LIBRARY).unwrap();
+        unsafe {
+            let lib = libc::dlopen(name.as_ptr(), libc::RTLD_NOW);
+            if lib.is_null() {
+                return Err(format!("Can't load {}, \
+                    is libseccomp installed?", LIBRARY));
+            }
+            // library is never unloaded, knot loads it once
+            Ok(Library {
+                init: transmute(symbol(lib, "seccomp_init")?),
+                resolve_name: transmute(
+                    symbol(lib, "seccomp_syscall_resolve_name")?),
+                rule_add_array: transmute(
+                    symbol(lib, "seccomp_rule_add_array")?),
+                export_bpf: transmute(symbol(lib, "seccomp_export_bpf")?),
+                release: transmute(symbol(lib, "seccomp_release")?),
+            })
+        }
+    }
+}
+
+fn parse_profile<R: Read>(reader: R, path: &Path)
+    -> Result<SeccompRules, String>
+{
+    let profile: JsonProfile = serde_json::from_reader(reader)
+        .map_err(|e| format!("Can't parse seccomp profile {:?}: {}",
+            path, e))?;
+    Ok(profile.into())
+}
+
+fn read_profile(profile: &SeccompProfile, root: &Path)
+    -> Result<SeccompRules, String>
+{
+    match *profile {
+        SeccompProfile::Inline(ref rules) => Ok(rules.clone()),
+        SeccompProfile::File(ref path) => {
+            // in the chroot, so that symlinks of the image can't point to
+            // the host files
+            temporary_change_root(root, || {
+                let file = File::open(path)
+                    .map_err(|e| format!("Can't open seccomp profile \
+                        {:?}: {}", path, e))?;
+                parse_profile(file, path)
+            })
+        }
+    }
+}
+
+fn export(lib: &Library, ctx: *mut c_void, rules: &SeccompRules,
+    tmp: &Path)
+    -> Result<Filter, String>
+{
+    let default = action(rules.default_action, rules.default_errno);
+    for rule in &rules.syscalls {
+        let act = action(rule.action, rule.errno);
+        if act == default {
+            // libseccomp refuses rules matching the default action
+            continue;
+        }
+        let args = rule.args.iter().map(|a| ArgCmp {
+            arg: a.index,
+            op: op(a.op),
+            datum_a: a.value,
+            datum_b: a.value_two,
+        }).collect::<Vec<_>>();
+        for name in &rule.names {
+            let cname = CString::new(&name[..])
+                .map_err(|_| format!("Bad syscall name {:?}", name))?;
+            let nr = (lib.resolve_name)(cname.as_ptr());
+            if nr < 0 {
+                // profiles usually list syscalls of newer kernels
+                debug!("Unknown syscall {:?}, skipping", name);
+                continue;
+            }
+            let rc = (lib.rule_add_array)(ctx, act, nr,
+                args.len() as c_uint,
+                if args.is_empty() { ptr::null() } else { args.as_ptr() });
+            if rc < 0 {
+                return Err(format!("Can't add seccomp rule for {:?}: {}",
+                    name, io::Error::from_raw_os_error(-rc)));
+            }
+        }
+    }
+    let mut file = OpenOptions::new()
+        .read(true).write(true).create(true).truncate(true)
+        .open(tmp)
+        .map_err(|e| format!("Can't create {:?}: {}", tmp, e))?;
+    remove_file(tmp).ok();
+    let rc = (lib.export_bpf)(ctx, file.as_raw_fd());
+    if rc < 0 {
+        return Err(format!("Can't export seccomp filter: {}",
+            io::Error::from_raw_os_error(-rc)));
+    }
+    let mut buf = Vec::new();
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_end(&mut buf))
+        .map_err(|e| format!("Can't read seccomp filter: {}", e))?;
+    if buf.is_empty() || buf.len() % INSN_SIZE != 0 ||
+        buf.len() / INSN_SIZE > MAX_INSNS
+    {
+        return Err(format!("Seccomp filter has invalid size {}",
+            buf.len()));
+    }
+    Ok(Filter(buf))
+}
+
+/// Reads the profile (from the image mounted at `root` for a file profile)
+/// and compiles it, `state_dir` is used for a temporary file
+pub fn compile(profile: &SeccompProfile, root: &Path, state_dir: &Path)
+    -> Result<Filter, String>
+{
+    let rules = read_profile(profile, root)?;
+    let lib = Library::open()?;
+    let ctx = (lib.init)(action(rules.default_action, rules.default_errno));
+    if ctx.is_null() {
+        return Err(format!("Can't initialize seccomp filter"));
+    }
+    let result = export(&lib, ctx, &rules, &state_dir.join("seccomp.bpf"));
+    (lib.release)(ctx);
+    result
+}
+
+/// Appends `struct sock_filter`
+fn insn(buf: &mut Vec<u8>, code: u16, jt: u8, jf: u8, k: u32) {
+    buf.extend_from_slice(&code.to_ne_bytes());
+    buf.push(jt);
+    buf.push(jf);
+    buf.extend_from_slice(&k.to_ne_bytes());
+}
+
+/// Filter which denies anything except memory allocation, reading and
+/// writing already open file descriptors and exit
+///
+/// Denied syscalls fail with `EPERM` rather than kill the process, as
+/// libraries may probe something (e.g. when capturing a backtrace).
+/// Returns `None` on architectures which aren't supported yet.
+pub fn minimal() -> Option<Filter> {
+    let arch = AUDIT_ARCH?;
+    let num = MINIMAL_SYSCALLS.len();
+    let mut buf = Vec::with_capacity((num + 6) * INSN_SIZE);
+    insn(&mut buf, BPF_LD_W_ABS, 0, 0, DATA_ARCH);
+    insn(&mut buf, BPF_JEQ_K, 1, 0, arch);
+    insn(&mut buf, BPF_RET_K, 0, 0, RET_KILL);
+    insn(&mut buf, BPF_LD_W_ABS, 0, 0, DATA_NR);
+    for (idx, &nr) in MINIMAL_SYSCALLS.iter().enumerate() {
+        // jump over the rest of the list and the deny to the allow
+        insn(&mut buf, BPF_JEQ_K, (num - idx) as u8, 0, nr as u32);
+    }
+    insn(&mut buf, BPF_RET_K, 0, 0, RET_EPERM);
+    insn(&mut buf, BPF_RET_K, 0, 0, RET_ALLOW);
+    Some(Filter(buf))
+}
+
+/// Loads the filter into the current process
+///
+/// Called in the child right before exec. Sets `no_new_privs`, as it's
+/// required to load a filter without `CAP_SYS_ADMIN`.
+pub fn apply(filter: &Filter) -> io::Result<()> {
+    // bare syscalls, as it runs in the child after fork
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let prog = SockFprog {
+        len: (filter.0.len() / INSN_SIZE) as u16,
+        filter: filter.0.as_ptr(),
+    };
+    let rc = unsafe {
+        libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER,
+                    &prog as *const SockFprog)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use lithos::container_config::{SeccompAction, SeccompOp};
+    use super::{parse_profile, minimal, INSN_SIZE, MINIMAL_SYSCALLS};
+
+    #[test]
+    fn docker_profile() {
+        let rules = parse_profile(&br#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [
+                {"names": ["read", "write"], "action": "SCMP_ACT_ALLOW"},
+                {"names": ["personality"], "action": "SCMP_ACT_ALLOW",
+                 "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}]},
+                {"names": ["ptrace"], "action": "SCMP_ACT_ERRNO",
+                 "errnoRet": 1}
+            ]
+        }"#[..], Path::new("/profile.json")).unwrap();
+        assert_eq!(rules.default_action, SeccompAction::Errno);
+        assert_eq!(rules.default_errno, 1);
+        assert_eq!(rules.syscalls.len(), 3);
+        assert_eq!(rules.syscalls[0].names, vec!["read", "write"]);
+        assert!(rules.syscalls[0].args.is_empty());
+        let arg = &rules.syscalls[1].args[0];
+        assert_eq!((arg.index, arg.value, arg.value_two), (0, 8, 0));
+        assert_eq!(arg.op, SeccompOp::Equal);
+        assert_eq!(rules.syscalls[2].action, SeccompAction::Errno);
+        assert_eq!(rules.syscalls[2].errno, 1);
+    }
+
+    #[test]
+    fn bad_profile() {
+        let err = parse_profile(&br#"{"defaultAction": "ALLOW"}"#[..],
+            Path::new("/profile.json")).unwrap_err();
+        assert!(err.starts_with(
+            "Can't parse seccomp profile \"/profile.json\""));
+    }
+
+    #[test]
+    fn minimal_size() {
+        if let Some(filter) = minimal() {
+            assert_eq!(filter.0.len(),
+                (MINIMAL_SYSCALLS.len() + 6) * INSN_SIZE);
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::{BTreeMap, HashSet};
-use std::io::Read;
+use std::io::{Read, Write};
 use std::fs::{File};
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
 use std::path::Path;
 use std::str::from_utf8;
 
@@ -9,6 +10,11 @@ use base64;
 use blake2::{Blake2b, digest::VariableOutput, digest::Input};
 use failure::{Error, ResultExt};
 use quire::{parse_config, Options};
+use libc;
+use nix::fcntl::OFlag;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, pipe2, ForkResult};
+use serde_json;
 use ssh_keys::{PrivateKey, openssh};
 
 use lithos::nacl;
@@ -16,6 +22,8 @@ use lithos::sandbox_config::SandboxConfig;
 use lithos::child_config::ChildInstance;
 use lithos::container_config::{environ_validator};
 
+use seccomp;
+
 
 fn parse_private_key(filename: &Path) -> Result<Vec<PrivateKey>, Error> {
     let mut buf = String::with_capacity(1024);
@@ -42,7 +50,28 @@ fn b2_short_hash(data: &[u8]) -> String {
     return base64::encode(&buf[..])
 }
 
-fn decrypt(key: &PrivateKey, namespaces: &HashSet<&str>, value: &str)
+/// Sandbox and process names `v3` secrets may be bound to
+///
+/// Process name is the name of the child without the instance number, e.g.
+/// `sandbox/process` for the `sandbox/process.0`.
+fn scopes(name: &str) -> Vec<String> {
+    let mut pair = name.splitn(2, '/');
+    let sandbox = pair.next().unwrap();
+    let mut result = vec![sandbox.to_string()];
+    if let Some(process) = pair.next() {
+        let process = match process.rfind('.') {
+            Some(dot) if process[dot+1..].parse::<usize>().is_ok() => {
+                &process[..dot]
+            }
+            _ => process,
+        };
+        result.push(format!("{}/{}", sandbox, process));
+    }
+    return result;
+}
+
+fn decrypt(key: &PrivateKey, namespaces: &HashSet<&str>, scopes: &[String],
+    value: &str)
     -> Result<String, Error>
 {
     let key_bytes = match *key {
@@ -50,27 +79,35 @@ fn decrypt(key: &PrivateKey, namespaces: &HashSet<&str>, value: &str)
         _ => bail!("Only ed25519 keys are supported"),
     };
     let (private_key, public_key) = key_bytes.split_at(32);
-    if !value.starts_with("v2:") {
-        bail!("Only v2 secrets are supported");
-    }
-    let mut it = value.split(":");
-    it.next(); // skip version
-    let (key_hash, ns_hash, secr_hash, cipher) = {
-        match (it.next(), it.next(), it.next(), it.next(), it.next()) {
-            (Some(key), Some(ns), Some(secr), Some(cipher), None) => {
-                (key, ns, secr, base64::decode(cipher)?)
-            }
-            _ => bail!("invalid key format"),
+    let parts = value.split(":").collect::<Vec<_>>();
+    // v3 secrets are bound to the name of the sandbox or process
+    let (key_hash, ns_hash, scope_hash, secr_hash, cipher) = match parts[..] {
+        ["v2", key, ns, secr, cipher] => (key, ns, None, secr, cipher),
+        ["v3", key, ns, scope, secr, cipher] => {
+            (key, ns, Some(scope), secr, cipher)
         }
+        ["v2", ..] | ["v3", ..] => bail!("invalid key format"),
+        _ => bail!("Only v2 and v3 secrets are supported"),
     };
+    let cipher = base64::decode(cipher)?;
 
     let plain = nacl::crypto_box_edwards_seal_open(
         &cipher, public_key, private_key)?;
 
-    let mut pair = plain.splitn(2, |&x| x == b':');
-    let namespace = from_utf8(pair.next().unwrap())
+    let nparts = if scope_hash.is_some() { 3 } else { 2 };
+    let mut items = plain.splitn(nparts, |&x| x == b':');
+    let namespace = from_utf8(items.next().unwrap())
         .map_err(|_| format_err!("can't decode namespace from utf-8"))?;
-    let secret = pair.next().ok_or(format_err!("decrypted data is invalid"))?;
+    let scope = if scope_hash.is_some() {
+        let scope = items.next()
+            .ok_or(format_err!("decrypted data is invalid"))?;
+        Some(from_utf8(scope)
+            .map_err(|_| format_err!("can't decode scope from utf-8"))?)
+    } else {
+        None
+    };
+    let secret = items.next()
+        .ok_or(format_err!("decrypted data is invalid"))?;
 
     if b2_short_hash(public_key) != key_hash {
         bail!("invalid key hash");
@@ -78,6 +115,15 @@ fn decrypt(key: &PrivateKey, namespaces: &HashSet<&str>, value: &str)
     if b2_short_hash(namespace.as_bytes()) != ns_hash {
         bail!("invalid namespace hash");
     }
+    if let (Some(scope), Some(scope_hash)) = (scope, scope_hash) {
+        if b2_short_hash(scope.as_bytes()) != scope_hash {
+            bail!("invalid scope hash");
+        }
+        if !scopes.iter().any(|s| s == scope) {
+            bail!("secret is bound to {:?}, not to {:?}",
+                scope, scopes.last().unwrap());
+        }
+    }
     if b2_short_hash(&secret) != secr_hash {
         bail!("invalid secret hash");
     }
@@ -92,15 +138,16 @@ fn decrypt(key: &PrivateKey, namespaces: &HashSet<&str>, value: &str)
         .map_err(|_| format_err!("Can't decode secret as utf-8"))
 }
 
-fn decrypt_pair(keys: &[PrivateKey], namespaces: &HashSet<&str>,
-    values: &[String])
-    -> Result<String, Vec<Error>>
+/// Returns the secret and the index of the key file it's decrypted with
+fn decrypt_pair(keys: &[(usize, PrivateKey)], namespaces: &HashSet<&str>,
+    scopes: &[String], values: &[String])
+    -> Result<(String, usize), Vec<Error>>
 {
     let mut errs = Vec::new();
-    for key in keys {
+    for &(file, ref key) in keys {
         for value in values {
-            match decrypt(key, namespaces, value) {
-                Ok(value) => return Ok(value),
+            match decrypt(key, namespaces, scopes, value) {
+                Ok(value) => return Ok((value, file)),
                 Err(e) => errs.push(e),
             }
         }
@@ -108,14 +155,20 @@ fn decrypt_pair(keys: &[PrivateKey], namespaces: &HashSet<&str>,
     Err(errs)
 }
 
+/// Reads keys of all the `secrets-private-key` files, in order
+///
+/// Each key is paired with the index of the file it's read from.
 pub fn read_keys(sandbox: &SandboxConfig)
-    -> Result<Vec<PrivateKey>, Error>
+    -> Result<Vec<(usize, PrivateKey)>, Error>
 {
-    let keys = if let Some(ref filename) = sandbox.secrets_private_key {
-        parse_private_key(&filename)?
-    } else {
+    if sandbox.secrets_private_key.is_empty() {
         bail!("No secrets key file defined to decode secrets");
-    };
+    }
+    let mut keys = Vec::new();
+    for (idx, filename) in sandbox.secrets_private_key.iter().enumerate() {
+        keys.extend(parse_private_key(filename)?
+            .into_iter().map(|key| (idx, key)));
+    }
     return Ok(keys);
 }
 
@@ -125,9 +178,13 @@ pub fn parse_file(path: &Path) -> Result<BTreeMap<String, Vec<String>>, String>
         .map_err(|e| e.to_string())
 }
 
-pub fn decode(keys: &Vec<PrivateKey>, sandbox: &SandboxConfig,
-    child_config: &ChildInstance, secrets: &BTreeMap<String, Vec<String>>)
-    -> Result<BTreeMap<String, String>, Error>
+/// Decrypts secrets of the process `name` (i.e. `sandbox/process.0`)
+///
+/// Each value is returned along with the index of the key file used.
+pub fn decode(keys: &[(usize, PrivateKey)], sandbox: &SandboxConfig,
+    name: &str, child_config: &ChildInstance,
+    secrets: &BTreeMap<String, Vec<String>>)
+    -> Result<BTreeMap<String, (String, usize)>, Error>
 {
     let mut all_namespaces = HashSet::new();
     if sandbox.secrets_namespaces.len() == 0 {
@@ -139,10 +196,12 @@ pub fn decode(keys: &Vec<PrivateKey>, sandbox: &SandboxConfig,
     all_namespaces.extend(
         child_config.extra_secrets_namespaces.iter().map(|x| &x[..]));
 
+    let scopes = scopes(name);
     let mut res = BTreeMap::new();
 
     for (name, values) in secrets {
-        res.insert(name.clone(), decrypt_pair(&keys, &all_namespaces, values)
+        res.insert(name.clone(),
+            decrypt_pair(&keys, &all_namespaces, &scopes, values)
             .map_err(|e| {
                 format_err!("Can't decrypt secret {:?}, errors: {}", name,
                     e.iter().map(|x| x.to_string())
@@ -152,3 +211,185 @@ pub fn decode(keys: &Vec<PrivateKey>, sandbox: &SandboxConfig,
 
     Ok(res)
 }
+
+/// Runs in the helper process, returns decrypted secrets or an error
+fn helper(sandbox: &SandboxConfig, name: &str, child_config: &ChildInstance,
+    secrets: &BTreeMap<String, Vec<String>>,
+    filter: Option<&seccomp::Filter>)
+    -> Result<BTreeMap<String, (String, usize)>, String>
+{
+    let keys = read_keys(sandbox)
+        .map_err(|e| format!("Error decoding private keys: {}", e))?;
+    if let Some(filter) = filter {
+        seccomp::apply(filter)
+            .map_err(|e| format!("Can't restrict syscalls: {}", e))?;
+    }
+    decode(&keys, sandbox, name, child_config, secrets)
+        .map_err(|e| format!("Error decoding secrets: {}", e))
+}
+
+/// Decrypts secrets in a short-lived child process
+///
+/// Private keys are read by the child, so they are never in the memory of
+/// the knot itself. After reading keys the child can only allocate memory
+/// and write to the pipe. Result is passed back as JSON.
+pub fn decode_in_helper(sandbox: &SandboxConfig, name: &str,
+    child_config: &ChildInstance, secrets: &BTreeMap<String, Vec<String>>)
+    -> Result<BTreeMap<String, String>, String>
+{
+    let filter = seccomp::minimal();
+    if filter.is_none() {
+        warn!("Syscalls of the secrets helper can't be restricted \
+            on this architecture");
+    }
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)
+        .map_err(|e| format!("Can't create pipe: {}", e))?;
+    let mut output = unsafe { File::from_raw_fd(read_fd) };
+    let input = unsafe { File::from_raw_fd(write_fd) };
+    match fork().map_err(|e| format!("Can't fork secrets helper: {}", e))? {
+        ForkResult::Child => {
+            drop(output);
+            let mut input = input;
+            let result = helper(sandbox, name, child_config, secrets,
+                filter.as_ref());
+            let code = match serde_json::to_vec(&result) {
+                Ok(data) if input.write_all(&data).is_ok() => 0,
+                _ => 1,
+            };
+            // no destructors, syscalls they may use are not allowed
+            unsafe { libc::_exit(code) };
+        }
+        ForkResult::Parent { child } => {
+            drop(input);
+            let mut buf = Vec::new();
+            let read = output.read_to_end(&mut buf);
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, 0)) => {}
+                Ok(status) => {
+                    return Err(format!("Secrets helper failed: {:?}",
+                        status));
+                }
+                Err(e) => {
+                    return Err(format!("Can't wait secrets helper: {}", e));
+                }
+            }
+            read.map_err(|e| format!("Can't read secrets: {}", e))?;
+            let secrets = serde_json::from_slice::<
+                    Result<BTreeMap<String, (String, usize)>, String>
+                >(&buf)
+                .map_err(|e| format!("Bad reply of secrets helper: {}", e))??;
+            // helper can't log, so keys used are logged here
+            Ok(secrets.into_iter().map(|(var, (value, file))| {
+                info!("[{}] Secret {:?} is decrypted with key {:?}",
+                    name, var, sandbox.secrets_private_key[file]);
+                (var, value)
+            }).collect())
+        }
+    }
+}
+
+/// Decrypts the value of `@{secret:...}` substitution
+///
+/// Value is one or more encrypted secrets separated by whitespace (e.g. for
+/// different keys), like a list in `secret-environ`.
+pub fn decode_value(sandbox: &SandboxConfig, name: &str,
+    child_config: &ChildInstance, value: &str)
+    -> Result<String, String>
+{
+    let key = "@{secret:...}";
+    let mut secrets = BTreeMap::new();
+    secrets.insert(key.to_string(),
+        value.split_whitespace().map(|x| x.to_string()).collect());
+    let mut result = decode_in_helper(sandbox, name, child_config,
+        &secrets)?;
+    Ok(result.remove(key).expect("all secrets are decoded"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use base64;
+    use crypto::ed25519::keypair;
+    use ssh_keys::PrivateKey;
+
+    use lithos::nacl;
+    use super::{scopes, decrypt, b2_short_hash};
+
+    fn key() -> (PrivateKey, [u8; 32]) {
+        let (private, public) = keypair(&[7u8; 32]);
+        (PrivateKey::Ed25519(private), public)
+    }
+
+    /// Same format as produced by `lithos_crypt encrypt`
+    fn encrypt(public: &[u8], ns: &str, scope: Option<&str>, data: &str)
+        -> String
+    {
+        let plain = match scope {
+            Some(scope) => format!("{}:{}:{}", ns, scope, data),
+            None => format!("{}:{}", ns, data),
+        };
+        let cipher = base64::encode(
+            &nacl::crypto_box_edwards_seal(plain.as_bytes(), public));
+        let scope_hash = scope
+            .map(|s| format!("{}:", b2_short_hash(s.as_bytes())))
+            .unwrap_or_default();
+        format!("{}:{}:{}:{}{}:{}",
+            if scope.is_some() { "v3" } else { "v2" },
+            b2_short_hash(public), b2_short_hash(ns.as_bytes()),
+            scope_hash, b2_short_hash(data.as_bytes()), cipher)
+    }
+
+    fn check(value: &str, name: &str) -> Result<String, String> {
+        let namespaces = vec!["ns"].into_iter().collect::<HashSet<_>>();
+        decrypt(&key().0, &namespaces, &scopes(name), value)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn scopes_of_name() {
+        assert_eq!(scopes("web/worker.0"), vec!["web", "web/worker"]);
+        assert_eq!(scopes("web/worker.12"), vec!["web", "web/worker"]);
+        assert_eq!(scopes("web/cmd"), vec!["web", "web/cmd"]);
+        assert_eq!(scopes("web/worker.v2"), vec!["web", "web/worker.v2"]);
+        assert_eq!(scopes("web"), vec!["web"]);
+    }
+
+    #[test]
+    fn v2() {
+        let value = encrypt(&key().1, "ns", None, "secret");
+        assert_eq!(check(&value, "web/worker.0").unwrap(), "secret");
+        let value = encrypt(&key().1, "other", None, "secret");
+        assert!(check(&value, "web/worker.0").is_err());
+    }
+
+    #[test]
+    fn v3_sandbox() {
+        let value = encrypt(&key().1, "ns", Some("web"), "secret");
+        assert_eq!(check(&value, "web/worker.0").unwrap(), "secret");
+        assert_eq!(check(&value, "web/cron").unwrap(), "secret");
+        assert_eq!(check(&value, "api/worker.0").unwrap_err(),
+            "secret is bound to \"web\", not to \"api/worker\"");
+    }
+
+    #[test]
+    fn v3_process() {
+        let value = encrypt(&key().1, "ns", Some("web/worker"), "secret");
+        assert_eq!(check(&value, "web/worker.3").unwrap(), "secret");
+        assert!(check(&value, "web/worker2.0").is_err());
+        assert!(check(&value, "web/cron").is_err());
+    }
+
+    #[test]
+    fn v3_tampered_scope() {
+        let value = encrypt(&key().1, "ns", Some("web"), "secret");
+        let mut parts = value.split(':').map(String::from)
+            .collect::<Vec<_>>();
+        parts[3] = b2_short_hash(b"api");
+        assert_eq!(check(&parts.join(":"), "web/worker.0").unwrap_err(),
+            "invalid scope hash");
+        parts.remove(3);
+        assert_eq!(check(&parts.join(":"), "web/worker.0").unwrap_err(),
+            "invalid key format");
+    }
+}
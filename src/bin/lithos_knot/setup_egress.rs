@@ -0,0 +1,122 @@
+use ipnetwork::IpNetwork;
+
+use lithos::bpf::{Asm, Cond, Size, Insn, Program, FN_SKB_LOAD_BYTES};
+use lithos::cgroup;
+use lithos::container_config::EgressPolicy;
+
+
+// registers preserved across helper calls
+const CTX: u8 = 6;
+const PORT: u8 = 7;
+const PROTO: u8 = 8;
+const DADDR: u8 = 9;
+const FP: u8 = 10;
+
+const ETH_P_IP: u16 = 0x0800;
+const IPPROTO_TCP: i32 = 6;
+const IPPROTO_UDP: i32 = 17;
+
+// offset of `protocol` field in `struct __sk_buff`
+const SKB_PROTOCOL: i16 = 16;
+// stack buffers
+const IP_HEADER: i16 = -24;
+const L4_HEADER: i16 = -32;
+
+
+/// Compiles policy into a `cgroup/skb` program
+///
+/// Program passes IPv4 packets which destination matches any of the rules
+/// (and destination port is in the list, if any), everything else is
+/// dropped. For non-tcp/udp packets port is assumed to be zero.
+fn compile(policy: &EgressPolicy) -> Result<Vec<Insn>, String> {
+    let mut asm = Asm::new();
+    let drop = asm.label();
+    let allow = asm.label();
+    let rules = asm.label();
+    let load_port = asm.label();
+
+    asm.mov_reg(CTX, 1);
+    asm.load(Size::Word, 0, CTX, SKB_PROTOCOL);
+    asm.jump_imm(Cond::Ne, 0, ETH_P_IP.to_be() as i32, drop);
+
+    asm.mov_reg(1, CTX);
+    asm.mov_imm(2, 0);
+    asm.mov_reg(3, FP);
+    asm.add_imm(3, IP_HEADER as i32);
+    asm.mov_imm(4, 20);
+    asm.call(FN_SKB_LOAD_BYTES);
+    asm.jump_imm(Cond::Ne, 0, 0, drop);
+    // header length, to find transport header
+    asm.load(Size::Byte, PORT, FP, IP_HEADER);
+    asm.and_imm(PORT, 0x0f);
+    asm.lsh_imm(PORT, 2);
+    asm.load(Size::Byte, PROTO, FP, IP_HEADER + 9);
+    asm.load(Size::Word, DADDR, FP, IP_HEADER + 16);
+    asm.from_be(DADDR, 32);
+
+    asm.jump_imm(Cond::Eq, PROTO, IPPROTO_TCP, load_port);
+    asm.jump_imm(Cond::Eq, PROTO, IPPROTO_UDP, load_port);
+    asm.mov_imm(PORT, 0);
+    asm.jump(rules);
+
+    asm.bind(load_port);
+    asm.mov_reg(1, CTX);
+    asm.mov_reg(2, PORT);
+    asm.mov_reg(3, FP);
+    asm.add_imm(3, L4_HEADER as i32);
+    asm.mov_imm(4, 4);
+    asm.call(FN_SKB_LOAD_BYTES);
+    asm.jump_imm(Cond::Ne, 0, 0, drop);
+    asm.load(Size::Half, PORT, FP, L4_HEADER + 2);
+    asm.from_be(PORT, 16);
+
+    asm.bind(rules);
+    for rule in &policy.allow {
+        let net = match rule.network {
+            IpNetwork::V4(net) => net,
+            IpNetwork::V6(net) => {
+                return Err(format!("IPv6 networks are not supported \
+                    in egress-policy: {}", net));
+            }
+        };
+        let next = asm.label();
+        let mask = u32::from(net.mask());
+        asm.mov_reg(1, DADDR);
+        asm.and_imm(1, mask as i32);
+        asm.ld_imm64(2, (u32::from(net.ip()) & mask) as u64);
+        asm.jump_reg(Cond::Ne, 1, 2, next);
+        if rule.ports.is_empty() {
+            asm.jump(allow);
+        }
+        for rng in &rule.ports {
+            if rng.start > rng.end || rng.end > 65535 {
+                return Err(format!("Bad port range {}-{} in egress-policy",
+                    rng.start, rng.end));
+            }
+            let next_range = asm.label();
+            asm.jump_imm(Cond::Gt, PORT, rng.end as i32, next_range);
+            asm.jump_imm(Cond::Ge, PORT, rng.start as i32, allow);
+            asm.bind(next_range);
+        }
+        asm.bind(next);
+    }
+
+    asm.bind(drop);
+    asm.mov_imm(0, 0);
+    asm.exit();
+    asm.bind(allow);
+    asm.mov_imm(0, 1);
+    asm.exit();
+    Ok(asm.finish())
+}
+
+/// Moves knot to the unified cgroup `name` and attaches egress filter
+///
+/// Process spawned afterwards inherits the cgroup.
+pub fn setup(policy: &EgressPolicy, name: &str) -> Result<(), String> {
+    let insns = compile(policy)?;
+    let dir = cgroup::ensure_in_unified_group(name)?;
+    let prog = Program::load_cgroup_skb(&insns)?;
+    prog.attach_egress(&dir)
+        .map_err(|e| format!("Error attaching egress policy: {}", e))
+}
@@ -1,23 +1,34 @@
 use std::io;
 use std::io::{Write, BufWriter};
-use std::fs::{File};
+use std::fs::{File, OpenOptions, Permissions};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::fs::{create_dir_all, copy, metadata, symlink_metadata};
 use std::path::{Path, PathBuf};
 use std::collections::BTreeMap;
+use std::time::SystemTime;
 
+use humantime::format_rfc3339_seconds;
 use libmount::{self, BindMount};
 use failure::{Error, ResultExt, err_msg};
 
 use lithos::mount::{mount_ro_recursive};
-use lithos::mount::{mount_pseudo, mount_pts};
+use lithos::mount::{mount_pseudo, mount_proc_as_current, mount_pts};
 use lithos::network::{get_host_ip, get_host_name};
 use lithos::master_config::MasterConfig;
-use lithos::sandbox_config::SandboxConfig;
+use lithos::host_facts::HostFacts;
+use lithos::sandbox_config::{SandboxConfig, InfoFile};
+use lithos::child_config::ChildInstance;
 use lithos::container_config::{InstantiatedConfig, Volume};
 use lithos::container_config::Volume::{Statedir, Readonly, Persistent, Tmpfs};
 use lithos::utils::{set_file_mode, set_file_owner};
-use lithos::utils::{relative};
+use lithos::utils::{relative, temporary_change_root, create_at};
+use lithos::id_map::IdMapExt;
 
+use devices;
+use idmap_mount;
+
+/// Name of the `info-file` in the state dir
+const INFO_FILE: &str = "lithos-info";
 
 fn map_dir(dir: &Path, dirs: &BTreeMap<PathBuf, PathBuf>) -> Option<PathBuf> {
     assert!(dir.is_absolute());
@@ -41,14 +52,14 @@ fn prepare_resolv_conf(state_dir: &Path, local: &InstantiatedConfig,
 }
 
 fn prepare_hosts_file(state_dir: &Path, local: &InstantiatedConfig,
-    tree: &SandboxConfig)
+    tree: &SandboxConfig, pool_hosts: Option<&Path>)
     -> Result<(), Error>
 {
     let copy_hosts = local.hosts_file.copy_from_host;
     let add_localhost = local.hosts_file.localhost.unwrap_or(!copy_hosts);
     let add_hostname = local.hosts_file.public_hostname.unwrap_or(!copy_hosts);
     if add_localhost || add_hostname || copy_hosts
-        || tree.additional_hosts.len() > 0
+        || tree.additional_hosts.len() > 0 || pool_hosts.is_some()
     {
         let fname = state_dir.join("hosts");
         let mut file = BufWriter::new(
@@ -73,40 +84,238 @@ fn prepare_hosts_file(state_dir: &Path, local: &InstantiatedConfig,
         for (ref host, ref ip) in tree.additional_hosts.iter() {
             writeln!(&mut file, "{} {}", ip, host)?;
         }
+        if let Some(path) = pool_hosts {
+            // addresses allocated from the `ip-pool` of the sandbox
+            match File::open(path) {
+                Ok(mut source) => {
+                    io::copy(&mut source, &mut file)?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => bail!("error reading {:?}: {}", path, e),
+            }
+        }
         set_file_mode(&fname, 0o644).ok(); // TODO(tailhook) check error?
     }
     Ok(())
 }
 
-pub fn prepare_state_dir(dir: &Path, local: &InstantiatedConfig,
+/// Writes the `info-file` of the sandbox, mounted by `setup_filesystem`
+///
+/// The file is rewritten in place on each start of the process, so the
+/// mounted file is updated too.
+pub fn prepare_info_file(state_dir: &File, name: &str, child: &ChildInstance,
     tree: &SandboxConfig)
     -> Result<(), String>
 {
-    _prepare_state_dir(dir, local, tree)
-    .map_err(|e| format!("state dir: {}", e))
+    let info = match tree.info_file {
+        Some(ref info) => info,
+        None => return Ok(()),
+    };
+    _prepare_info_file(state_dir, name, child, info)
+    .map_err(|e| format!("error preparing info file: {}", e))
 }
 
-fn _prepare_state_dir(dir: &Path, local: &InstantiatedConfig,
-    tree: &SandboxConfig)
+fn _prepare_info_file(state_dir: &File, name: &str, child: &ChildInstance,
+    info: &InfoFile)
     -> Result<(), Error>
 {
-    // TODO(tailhook) chown files
+    let file = create_at(state_dir, INFO_FILE)?;
+    file.set_permissions(Permissions::from_mode(0o644))?;
+    let mut file = BufWriter::new(file);
+    writeln!(&mut file, "# This container is run by lithos")?;
+    writeln!(&mut file, "name: {}", name)?;
+    writeln!(&mut file, "image: {}", child.image)?;
+    writeln!(&mut file, "config-hash: {}", child.hash())?;
+    writeln!(&mut file, "started: {}",
+        format_rfc3339_seconds(SystemTime::now()))?;
+    if let Some(ref contact) = info.contact {
+        writeln!(&mut file, "contact: {}", contact)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Creates state dir if it doesn't exist yet and opens it
+///
+/// Files written after the process is started (exit report, timings) are
+/// written relative to the descriptor, as the root of the knot changes on
+/// the first start.
+pub fn open_state_dir(dir: &Path) -> Result<File, String> {
     if metadata(dir).is_err() {
         create_dir_all(dir)
-            .map_err(|e| format_err!(
-                "Couldn't create state directory: {}", e))?;
+            .map_err(|e| format!("Couldn't create state directory: {}", e))?;
         set_file_mode(dir, 0o1777)
+            .map_err(|e| format!("Couldn't set chmod for state dir: {}", e))?;
+    }
+    File::open(dir)
+        .map_err(|e| format!("Couldn't open state dir {:?}: {}", dir, e))
+}
+
+pub fn prepare_state_dir(dir: &Path, local: &InstantiatedConfig,
+    tree: &SandboxConfig, pool_hosts: Option<&Path>)
+    -> Result<(), String>
+{
+    _prepare_state_dir(dir, local, tree, pool_hosts)
+    .map_err(|e| format!("state dir: {}", e))
+}
+
+fn _prepare_state_dir(dir: &Path, local: &InstantiatedConfig,
+    tree: &SandboxConfig, pool_hosts: Option<&Path>)
+    -> Result<(), Error>
+{
+    // TODO(tailhook) chown files
+    // owned by root of the container, otherwise it's `nobody` inside the
+    // user namespace. Fixed on every start, as uid mapping might change
+    if let (Some(user), Some(group)) =
+        (host_uid(tree, local, 0), host_gid(tree, local, 0))
+    {
+        set_file_owner(dir, user, group)
             .map_err(|e| format_err!(
-                "Couldn't set chmod for state dir: {}", e))?;
+                "Couldn't chown state dir: {}", e))?;
     }
 
     prepare_resolv_conf(dir, local, tree)
         .map_err(|e| format_err!("error preparing resolf.conf: {}", e))?;
-    prepare_hosts_file(dir, local, tree)
+    prepare_hosts_file(dir, local, tree, pool_hosts)
         .map_err(|e| format_err!("error preparing hosts: {}", e))?;
     return Ok(());
 }
 
+/// Writes `state-files`, readable only by the user of the process
+pub fn prepare_state_files(dir: &Path, local: &InstantiatedConfig,
+    tree: &SandboxConfig, user_id: u32, group_id: u32)
+    -> Result<(), String>
+{
+    _prepare_state_files(dir, local, tree, user_id, group_id)
+    .map_err(|e| format!("state files: {}", e))
+}
+
+fn _prepare_state_files(dir: &Path, local: &InstantiatedConfig,
+    tree: &SandboxConfig, user_id: u32, group_id: u32)
+    -> Result<(), Error>
+{
+    if local.state_files.is_empty() {
+        return Ok(());
+    }
+    let user = host_uid(tree, local, user_id)
+        .ok_or(format_err!("Non-mapped user {}", user_id))?;
+    let group = host_gid(tree, local, group_id)
+        .ok_or(format_err!("Non-mapped group {}", group_id))?;
+    // contents may have decrypted secrets, so they are never in the errors
+    for (name, content) in &local.state_files {
+        let path = dir.join(name);
+        OpenOptions::new().write(true).create(true).truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .context(format!("can't write {:?}", name))?;
+        // file may exist from the previous start with other mode or owner
+        set_file_mode(&path, 0o600)
+            .context(format!("can't chmod {:?}", name))?;
+        set_file_owner(&path, user, group)
+            .context(format!("can't chown {:?}", name))?;
+    }
+    Ok(())
+}
+
+/// Maps user id of the container to the user id in the host system
+pub fn host_uid(tree: &SandboxConfig, local: &InstantiatedConfig, uid: u32)
+    -> Option<u32>
+{
+    if !tree.uid_map.is_empty() {
+        tree.uid_map.map_id(uid)
+    } else {
+        local.map_uid(uid)
+    }
+}
+
+/// Maps group id of the container to the group id in the host system
+pub fn host_gid(tree: &SandboxConfig, local: &InstantiatedConfig, gid: u32)
+    -> Option<u32>
+{
+    if !tree.gid_map.is_empty() {
+        tree.gid_map.map_id(gid)
+    } else {
+        local.map_gid(gid)
+    }
+}
+
+pub fn prepare_log_dirs(tree: &SandboxConfig, local: &InstantiatedConfig)
+    -> Result<(), String>
+{
+    _prepare_log_dirs(tree, local)
+    .map_err(|e| format!("log dirs: {}", e))
+}
+
+fn _prepare_log_dirs(tree: &SandboxConfig, local: &InstantiatedConfig)
+    -> Result<(), Error>
+{
+    for (path, dir) in &tree.log_dirs {
+        if !path.is_absolute() {
+            bail!("log dir {:?} must be absolute", path);
+        }
+        let user = host_uid(tree, local, dir.user)
+            .ok_or(format_err!("Non-mapped user {} for log dir {:?}",
+                dir.user, path))?;
+        let group = host_gid(tree, local, dir.group)
+            .ok_or(format_err!("Non-mapped group {} for log dir {:?}",
+                dir.group, path))?;
+        create_dir_all(path)
+            .map_err(|e| format_err!("Error creating {:?}: {}", path, e))?;
+        // ownership is fixed on every start, as uid mapping might change
+        set_file_owner(path, user, group)
+            .map_err(|e| format_err!("Error chowning {:?}: {}", path, e))?;
+        set_file_mode(path, dir.mode)
+            .map_err(|e| format_err!("Can't chmod {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// Checks that working directory exists inside the container
+///
+/// With `create-workdir` the directory is created and owned by the container
+/// user. It works only if the path is on a writable volume, as the image
+/// itself is mounted read-only.
+pub fn prepare_workdir(tree: &SandboxConfig, local: &InstantiatedConfig,
+    mount_dir: &Path, user_id: u32, group_id: u32)
+    -> Result<(), String>
+{
+    temporary_change_root(mount_dir, || {
+        _prepare_workdir(tree, local, user_id, group_id)
+        .map_err(|e| format!("workdir {:?}: {}", local.workdir, e))
+    })
+}
+
+fn _prepare_workdir(tree: &SandboxConfig, local: &InstantiatedConfig,
+    user_id: u32, group_id: u32)
+    -> Result<(), Error>
+{
+    let path = &local.workdir;
+    if !path.is_absolute() {
+        bail!("must be absolute");
+    }
+    match metadata(path) {
+        Ok(ref meta) if meta.is_dir() => return Ok(()),
+        Ok(_) => bail!("is not a directory"),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => bail!("can't stat: {}", e),
+    }
+    if !local.create_workdir {
+        bail!("doesn't exist in the container \
+            (set `create-workdir: true` to create it)");
+    }
+    let user = host_uid(tree, local, user_id)
+        .ok_or(format_err!("non-mapped user {}", user_id))?;
+    let group = host_gid(tree, local, group_id)
+        .ok_or(format_err!("non-mapped group {}", group_id))?;
+    create_dir_all(path)
+        .map_err(|e| format_err!("error creating (the path must be on a \
+            writable volume): {}", e))?;
+    set_file_owner(path, user, group)
+        .map_err(|e| format_err!("error chowning: {}", e))?;
+    Ok(())
+}
+
 fn check_file(root: &Path, file: &str)
     -> Result<bool, Error>
 {
@@ -178,6 +387,119 @@ fn mount_resolv_conf(root: &Path, local: &InstantiatedConfig,
     .mount().map_err(|e| format_err!("{}", e))
 }
 
+/// Mounts `trust-bundle` of the master config, if the image has the file
+fn mount_trust_bundle(root: &Path, master: &MasterConfig)
+    -> Result<(), Error>
+{
+    let bundle = match master.trust_bundle {
+        Some(ref bundle) => bundle,
+        None => return Ok(()),
+    };
+    if !bundle.mount_point.is_absolute() {
+        bail!("trust-bundle mount point must be absolute");
+    }
+    let dest = root.join(relative(&bundle.mount_point, Path::new("/")));
+    match symlink_metadata(&dest) {
+        Ok(ref m) if m.is_file() => {}
+        Ok(_) => bail!("trust-bundle mount point {:?} is not a file",
+            bundle.mount_point),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("No {:?} in image, trust-bundle is not mounted",
+                bundle.mount_point);
+            return Ok(());
+        }
+        Err(e) => bail!("can't check {:?}: {}", bundle.mount_point, e),
+    }
+    BindMount::new(&bundle.path, &dest).mount()
+        .map_err(|e| format_err!("{}", e))?;
+    mount_ro_recursive(&dest).map_err(err_msg)?;
+    Ok(())
+}
+
+/// Mounts host facts file, if the image has `host-facts-mount-point`
+fn mount_host_facts(root: &Path, master: &MasterConfig)
+    -> Result<(), Error>
+{
+    let mount_point = &master.host_facts_mount_point;
+    if !mount_point.is_absolute() {
+        bail!("host-facts-mount-point must be absolute");
+    }
+    let dest = root.join(relative(mount_point, Path::new("/")));
+    match symlink_metadata(&dest) {
+        Ok(ref m) if m.is_file() => {}
+        Ok(_) => bail!("host facts mount point {:?} is not a file",
+            mount_point),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("No {:?} in image, host facts are not mounted",
+                mount_point);
+            return Ok(());
+        }
+        Err(e) => bail!("can't check {:?}: {}", mount_point, e),
+    }
+    let source = HostFacts::mounted_path(&master.host_facts_path());
+    if !source.exists() {
+        // written by lithos_tree, might be absent for `lithos_knot --debug`
+        warn!("No {:?}, host facts are not mounted", source);
+        return Ok(());
+    }
+    BindMount::new(&source, &dest).mount()
+        .map_err(|e| format_err!("{}", e))?;
+    mount_ro_recursive(&dest).map_err(err_msg)?;
+    Ok(())
+}
+
+/// Mounts `info-file` of the sandbox, if the image has a file at its path
+///
+/// The file is also in the state dir, so it's visible in the `!Statedir`
+/// volume anyway.
+fn mount_info_file(root: &Path, tree: &SandboxConfig, state_dir: &Path)
+    -> Result<(), Error>
+{
+    let info = match tree.info_file {
+        Some(ref info) => info,
+        None => return Ok(()),
+    };
+    if !info.path.is_absolute() {
+        bail!("info-file path must be absolute");
+    }
+    let dest = root.join(relative(&info.path, Path::new("/")));
+    match symlink_metadata(&dest) {
+        Ok(ref m) if m.is_file() => {}
+        Ok(_) => bail!("info file mount point {:?} is not a file",
+            info.path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            // lithos_check reports this too
+            warn!("No {:?} in image, info file is only available as {:?} \
+                in the state dir", info.path, INFO_FILE);
+            return Ok(());
+        }
+        Err(e) => bail!("can't check {:?}: {}", info.path, e),
+    }
+    let source = state_dir.join(INFO_FILE);
+    BindMount::new(&source, &dest).mount()
+        .map_err(|e| format_err!("{}", e))?;
+    mount_ro_recursive(&dest).map_err(err_msg)?;
+    Ok(())
+}
+
+/// Creates user namespace with the same mapping as the container has
+fn id_namespace(tree: &SandboxConfig, local: &InstantiatedConfig)
+    -> Result<File, Error>
+{
+    let (uid_map, gid_map) = if
+        !tree.uid_map.is_empty() || !tree.gid_map.is_empty()
+    {
+        (&tree.uid_map, &tree.gid_map)
+    } else {
+        (&local.uid_map, &local.gid_map)
+    };
+    if uid_map.is_empty() || gid_map.is_empty() {
+        bail!("idmapped volumes require `uid-map` and `gid-map` \
+            in either sandbox or container config");
+    }
+    idmap_mount::user_namespace(uid_map, gid_map)
+}
+
 pub fn setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
     local: &InstantiatedConfig, state_dir: &Path)
     -> Result<(), String>
@@ -195,16 +517,37 @@ fn _setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
     assert!(mntdir.is_absolute());
 
     let mut volumes: Vec<(&String, &Volume)> = local.volumes.iter().collect();
+    // created on demand for idmapped volumes
+    let mut userns = None;
     volumes.sort_by(|&(mp1, _), &(mp2, _)| mp1.len().cmp(&mp2.len()));
 
     let devdir = mntdir.join("dev");
-    BindMount::new(&master.devfs_dir, &devdir).mount()
-        .map_err(|e| format_err!("{}", e))?;
+    let extra_devices = devices::extra_devices(tree, local)?;
+    if !extra_devices.is_empty() {
+        devices::mount_dev(&master.devfs_dir, &devdir, &extra_devices)?;
+    } else {
+        BindMount::new(&master.devfs_dir, &devdir).mount()
+            .map_err(|e| format_err!("{}", e))?;
+    }
     mount_ro_recursive(&devdir).map_err(err_msg)?;
 
     mount_pts(&mntdir.join("dev/pts")).map_err(err_msg)?;
-    mount_pseudo(&mntdir.join("sys"), "sysfs", "", true).map_err(err_msg)?;
-    mount_pseudo(&mntdir.join("proc"), "proc", "", false).map_err(err_msg)?;
+    if master.nested || master.dev_mode {
+        // sysfs can only be mounted by the owner of the network namespace
+        BindMount::new("/sys", mntdir.join("sys")).mount()
+            .map_err(|e| format_err!("{}", e))?;
+        mount_ro_recursive(&mntdir.join("sys")).map_err(err_msg)?;
+    } else {
+        mount_pseudo(&mntdir.join("sys"), "sysfs", "", true)
+            .map_err(err_msg)?;
+    }
+    if master.nested || master.dev_mode {
+        // flags of the proc mount are locked in the outer container
+        mount_proc_as_current(&mntdir.join("proc")).map_err(err_msg)?;
+    } else {
+        mount_pseudo(&mntdir.join("proc"), "proc", "", false)
+            .map_err(err_msg)?;
+    }
 
     for &(mp_str, volume) in volumes.iter() {
         let tmp_mp = PathBuf::from(&mp_str[..]);
@@ -240,11 +583,18 @@ fn _setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
                         create_dir_all(&path)
                             .map_err(|e| format_err!("Error creating \
                                 persistent volume: {}", e))?;
-                        let user = local.map_uid(opt.user)
+                        // idmapped volume stores ids of the container as is
+                        let (user, group) = if opt.idmap {
+                            (Some(opt.user), Some(opt.group))
+                        } else {
+                            (host_uid(tree, local, opt.user),
+                             host_gid(tree, local, opt.group))
+                        };
+                        let user = user
                             .ok_or(format_err!(
                                 "Non-mapped user {} for volume {}",
                                 opt.user, mp_str))?;
-                        let group = local.map_gid(opt.group)
+                        let group = group
                             .ok_or(format_err!(
                                 "Non-mapped group {} for volume {}",
                                 opt.group, mp_str))?;
@@ -256,8 +606,17 @@ fn _setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
                                 volume: {}", e))?;
                     }
                 }
-                BindMount::new(&path, &dest).mount()
-                    .map_err(|e| format_err!("{}", e))?;
+                if opt.idmap {
+                    if userns.is_none() {
+                        userns = Some(id_namespace(tree, local)?);
+                    }
+                    idmap_mount::bind_mount(&path, &dest,
+                        userns.as_ref().expect("namespace is just created"))
+                        .context(format!("idmapped volume {}", mp_str))?;
+                } else {
+                    BindMount::new(&path, &dest).mount()
+                        .map_err(|e| format_err!("{}", e))?;
+                }
             }
             &Tmpfs(ref opt) => {
                 libmount::Tmpfs::new(&dest)
@@ -272,10 +631,10 @@ fn _setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
                     create_dir_all(&dir)
                         .map_err(|e| format_err!("Error creating \
                             persistent volume: {}", e))?;
-                    let user = local.map_uid(opt.user)
+                    let user = host_uid(tree, local, opt.user)
                         .ok_or(format_err!("Non-mapped user {} for volume {}",
                             opt.user, mp_str))?;
-                    let group = local.map_gid(opt.group)
+                    let group = host_gid(tree, local, opt.group)
                         .ok_or(format_err!("Non-mapped group {} for volume {}",
                             opt.group, mp_str))?;
                     set_file_owner(&dir, user, group)
@@ -291,6 +650,21 @@ fn _setup_filesystem(master: &MasterConfig, tree: &SandboxConfig,
         }
     }
 
+    if let Some(dest) = local.gpus.as_ref()
+        .and_then(|g| g.driver_libraries.as_ref())
+    {
+        let src = tree.gpu_driver_dir.as_ref()
+            .ok_or(format_err!("gpus.driver-libraries requires \
+                gpu-driver-dir in sandbox config"))?;
+        let dest = mntdir.join(relative(dest, &root));
+        BindMount::new(src, &dest).mount()
+            .map_err(|e| format_err!("{}", e))?;
+        mount_ro_recursive(&dest).map_err(err_msg)?;
+    }
+
+    mount_trust_bundle(&mntdir, master)?;
+    mount_host_facts(&mntdir, master)?;
+    mount_info_file(&mntdir, tree, state_dir)?;
     mount_resolv_conf(&mntdir, local, state_dir)?;
     mount_hosts_file(&mntdir, local, state_dir)?;
 
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Write as IoWrite;
+use std::path::Path;
+
+use failure::{Error, ResultExt};
+use unshare::{self, Style};
+
+use lithos::container_config::TcpPort;
+use lithos::range::Range;
+use lithos::sandbox_config::HostNetworkPolicy;
+use lithos::setup::NFTABLES_FILE;
+
+
+fn port_set<I: Iterator<Item=String>>(items: I) -> String {
+    let items = items.collect::<Vec<_>>();
+    format!("{{ {} }}", items.join(", "))
+}
+
+fn range_str(rng: &Range) -> String {
+    if rng.start == rng.end {
+        rng.start.to_string()
+    } else {
+        format!("{}-{}", rng.start, rng.end)
+    }
+}
+
+/// Generates nftables ruleset for the processes running as `uid`
+///
+/// Only packets from listening ports in `tcp_ports` and to whitelisted
+/// outgoing ports pass, so process can neither serve nor connect anything
+/// else. Table is recreated atomically on each start, and removed by
+/// `clean_child` when the process is stopped.
+fn ruleset(policy: &HostNetworkPolicy, uid: u32,
+    tcp_ports: &HashMap<u16, TcpPort>)
+    -> String
+{
+    let table = format!("inet lithos_uid{}", uid);
+    let mut buf = String::with_capacity(512);
+    // declaring table first makes deleting it never fail
+    writeln!(buf, "table {}", table).unwrap();
+    writeln!(buf, "delete table {}", table).unwrap();
+    writeln!(buf, "table {} {{", table).unwrap();
+    writeln!(buf, "  chain output {{").unwrap();
+    writeln!(buf, "    type filter hook output priority 0; policy accept;")
+        .unwrap();
+    writeln!(buf, "    meta skuid != {} accept", uid).unwrap();
+    if policy.allow_loopback {
+        writeln!(buf, "    oif \"lo\" accept").unwrap();
+    }
+    if !tcp_ports.is_empty() {
+        let mut ports = tcp_ports.keys().cloned().collect::<Vec<_>>();
+        ports.sort();
+        writeln!(buf, "    tcp sport {} accept",
+            port_set(ports.iter().map(|p| p.to_string()))).unwrap();
+    }
+    if !policy.allow_outgoing_ports.is_empty() {
+        let set = port_set(policy.allow_outgoing_ports.iter().map(range_str));
+        writeln!(buf, "    tcp dport {} accept", set).unwrap();
+        writeln!(buf, "    udp dport {} accept", set).unwrap();
+    }
+    writeln!(buf, "    drop").unwrap();
+    writeln!(buf, "  }}").unwrap();
+    writeln!(buf, "}}").unwrap();
+    buf
+}
+
+pub fn setup(policy: &HostNetworkPolicy, uid: u32,
+    tcp_ports: &HashMap<u16, TcpPort>, state_dir: &Path)
+    -> Result<(), String>
+{
+    _setup(policy, uid, tcp_ports, state_dir)
+    .map_err(|e| format!("error setting up host network policy: {}", e))
+}
+
+fn _setup(policy: &HostNetworkPolicy, uid: u32,
+    tcp_ports: &HashMap<u16, TcpPort>, state_dir: &Path)
+    -> Result<(), Error>
+{
+    let path = state_dir.join(NFTABLES_FILE);
+    File::create(&path)
+        .and_then(|mut f| f.write_all(
+            ruleset(policy, uid, tcp_ports).as_bytes()))
+        .context("can't write nftables.conf")?;
+    let mut cmd = unshare::Command::new("/usr/sbin/nft");
+    cmd.arg("-f").arg(&path);
+    debug!("Running {}", cmd.display(&Style::short()));
+    match cmd.status() {
+        Ok(s) if s.success() => {}
+        Ok(s) => bail!("nft failed: {}", s),
+        Err(e) => bail!("nft failed: {}", e),
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::fs::{read_dir, symlink_metadata};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+
+use lithos::container_config::{GpuConfig, GpuVendor};
+
+
+/// Returns host paths of the device nodes needed to use the GPUs
+///
+/// Paths are relative to `/dev`. Devices that don't exist on the host are
+/// skipped for shared nodes (which depend on the driver version) and are
+/// an error for GPUs themselves.
+pub fn gpu_devices(gpus: &GpuConfig) -> Result<Vec<PathBuf>, Error> {
+    let dev = Path::new("/dev");
+    let mut result = Vec::new();
+    let mut indices = gpus.devices.clone();
+    if indices.is_empty() {
+        indices = all_gpus(gpus.vendor)?;
+    }
+    match gpus.vendor {
+        GpuVendor::Nvidia => {
+            for name in &["nvidiactl", "nvidia-uvm", "nvidia-uvm-tools",
+                          "nvidia-modeset"]
+            {
+                if dev.join(name).exists() {
+                    result.push(PathBuf::from(name));
+                }
+            }
+            for idx in indices {
+                result.push(PathBuf::from(format!("nvidia{}", idx)));
+            }
+        }
+        GpuVendor::Amd => {
+            if dev.join("kfd").exists() {
+                result.push(PathBuf::from("kfd"));
+            }
+            for idx in indices {
+                result.push(PathBuf::from(format!("dri/card{}", idx)));
+                let render = PathBuf::from(format!("dri/renderD{}", 128+idx));
+                if dev.join(&render).exists() {
+                    result.push(render);
+                }
+            }
+        }
+    }
+    for path in &result {
+        let meta = symlink_metadata(dev.join(path))
+            .context(format!("GPU device /dev/{}", path.display()))?;
+        if !meta.file_type().is_char_device() {
+            bail!("/dev/{} is not a character device", path.display());
+        }
+    }
+    Ok(result)
+}
+
+fn all_gpus(vendor: GpuVendor) -> Result<Vec<u32>, Error> {
+    let (dir, prefix) = match vendor {
+        GpuVendor::Nvidia => ("/dev", "nvidia"),
+        GpuVendor::Amd => ("/dev/dri", "card"),
+    };
+    let mut result = Vec::new();
+    for entry in read_dir(dir).context(format!("can't list {}", dir))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if let Some(Ok(idx)) = name.strip_prefix(prefix).map(|x| x.parse()) {
+            result.push(idx);
+        }
+    }
+    result.sort();
+    if result.is_empty() {
+        bail!("no GPUs found in {}", dir);
+    }
+    Ok(result)
+}
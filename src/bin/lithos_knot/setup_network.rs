@@ -1,24 +1,35 @@
+use std::cell::Cell;
 use std::fs::File;
 use std::io;
 use std::net::{IpAddr};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::mem::{self, size_of};
-use std::process;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use blake2::{self, Digest};
 use failure::{Error, ResultExt};
-use ipnetwork::IpNetwork;
-use libc::{close};
+use libc::{close, pid_t};
 use nix::sched::{setns};
 use nix::sched::CloneFlags;
-use nix::sys::socket::{SockAddr};
+use nix::sys::socket::{SockAddr, InetAddr};
 use nix::ifaddrs::getifaddrs;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
 use serde_json::to_vec;
 use unshare::{self, Style};
 
 use lithos::child_config::ChildInstance;
 use lithos::container_config::{TcpPort, replace_vars};
 use lithos::sandbox_config::{BridgedNetwork};
+use lithos::socket_stats::somaxconn;
+
+use netlink::Netlink;
+use network_hooks::Link;
+
+const DHCP_POLL: Duration = Duration::from_millis(100);
 
 
 struct NsGuard {
@@ -49,16 +60,25 @@ impl Drop for NsGuard {
 }
 
 
-pub fn setup(pid: u32, net: &BridgedNetwork, child: &ChildInstance)
-    -> Result<(), String>
+/// Sets up network namespace of the child
+///
+/// Returns host side of the link for bridged containers.
+pub fn setup(pid: u32, net: &BridgedNetwork, child: &ChildInstance,
+    name: &str, dhcp: &DhcpClient)
+    -> Result<Option<Link>, String>
 {
-    if let Some(ip) = child.ip_address {
-        _setup_bridged(pid, net, ip)
-        .map_err(|e| e.to_string())
+    let interface = if let Some(ip) = child.ip_address {
+        interface_name(net, &ip)
+    } else if !net.dhcp_command.is_empty() {
+        dhcp_interface_name(net, name)
     } else {
-        _setup_isolated(pid)
+        return _setup_isolated(pid)
+            .map(|()| None)
+            .map_err(|e| e.to_string());
+    };
+    _setup_bridged(pid, net, &interface, child.ip_address, dhcp)
+        .map(|ip| Some(Link { interface, ip }))
         .map_err(|e| e.to_string())
-    }
 }
 
 
@@ -84,146 +104,190 @@ fn interface_name(network: &BridgedNetwork, ip: &IpAddr) -> String {
     return name;
 }
 
-fn _setup_bridged(pid: u32, net: &BridgedNetwork, ip: IpAddr)
-    -> Result<(), Error>
+/// Interface name for containers which get address via DHCP
+///
+/// Address is unknown beforehand, so name is derived from the process name
+fn dhcp_interface_name(network: &BridgedNetwork, name: &str) -> String {
+    #[derive(Serialize)]
+    struct HashSource<'a> {
+        bridge: &'a str,
+        name: &'a str,
+    }
+    let name = format!("li_{:.10}",
+        // double formatting because of a bug in generic array
+        format!("{:010x}", blake2::Blake2b::digest(&to_vec(&HashSource {
+            bridge: &network.bridge,
+            name,
+        }).expect("can always serialize"))));
+    assert!(name.len() <= 15);
+    return name;
+}
+
+fn replace_command_vars(title: &str, item: &str, vars: &[(&str, &str)])
+    -> String
+{
+    if !item.contains('@') {
+        return item.to_string();
+    }
+    replace_vars(item, |v| {
+        match vars.iter().find(|&&(name, _)| name == v) {
+            Some(&(_, value)) => value.to_string(),
+            None => {
+                error!("No variable {:?} for {}. Using empty string.",
+                       v, title);
+                String::new()
+            }
+        }
+    })
+}
+
+struct DhcpPid(Cell<Option<pid_t>>);
+
+/// DHCP client serving network namespace of the process
+///
+/// Shared between the knot and the callback setting up the network.
+/// Client is stopped when the namespace is not used any more, and when the
+/// last reference is dropped. It also gets `SIGTERM` if knot dies.
+#[derive(Clone)]
+pub struct DhcpClient(Rc<DhcpPid>);
+
+impl DhcpClient {
+    pub fn new() -> DhcpClient {
+        DhcpClient(Rc::new(DhcpPid(Cell::new(None))))
+    }
+    /// Stops the client, so it doesn't hold the old namespace
+    pub fn stop(&self) {
+        self.0.stop()
+    }
+    /// Returns true if `pid` is the client (which is forgotten then)
+    pub fn exited(&self, pid: pid_t) -> bool {
+        if (self.0).0.get() == Some(pid) {
+            (self.0).0.set(None);
+            return true;
+        }
+        false
+    }
+}
+
+impl DhcpPid {
+    fn stop(&self) {
+        if let Some(pid) = self.0.take() {
+            kill(Pid::from_raw(pid), Signal::SIGTERM)
+                .map_err(|e| warn!("Can't stop DHCP client: {}", e))
+                .ok();
+        }
+    }
+}
+
+impl Drop for DhcpPid {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Runs DHCP client in the container's namespace and waits for the address
+///
+/// Client is left running to renew the lease until the namespace is
+/// abandoned or the knot exits (see `DhcpClient`).
+fn run_dhcp(net: &BridgedNetwork, interface: &str, client: &DhcpClient)
+    -> Result<IpAddr, Error>
+{
+    client.stop();
+    let mut cmd = unshare::Command::new(&net.dhcp_command[0]);
+    for item in &net.dhcp_command[1..] {
+        cmd.arg(replace_command_vars("dhcp-command", item,
+            &[("interface", interface)]));
+    }
+    cmd.set_parent_death_signal(Signal::SIGTERM);
+    debug!("Running {}", cmd.display(&Style::short()));
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => bail!("dhcp-command failed: {}", e),
+    };
+    let deadline = Instant::now() + Duration::from_millis(
+        (net.dhcp_timeout * 1000.0) as u64);
+    loop {
+        let addr = getifaddrs()?
+            .filter(|x| x.interface_name == interface)
+            .filter_map(|x| match x.address {
+                Some(SockAddr::Inet(addr)) => Some(addr.to_std().ip()),
+                _ => None,
+            })
+            .find(|ip| ip.is_ipv4());
+        if let Some(ip) = addr {
+            info!("Got address {} via DHCP", ip);
+            (client.0).0.set(Some(child.pid()));
+            return Ok(ip);
+        }
+        match waitpid(Pid::from_raw(child.pid()), Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {}
+            Ok(status) => {
+                bail!("dhcp-command exited before getting lease: {:?}",
+                    status);
+            }
+            Err(e) => bail!("dhcp-command failed: {}", e),
+        }
+        if Instant::now() > deadline {
+            child.kill().ok();
+            bail!("no DHCP lease in {}s", net.dhcp_timeout);
+        }
+        sleep(DHCP_POLL);
+    }
+}
+
+fn _setup_bridged(pid: u32, net: &BridgedNetwork, interface: &str,
+    ip: Option<IpAddr>, dhcp: &DhcpClient)
+    -> Result<IpAddr, Error>
 {
-    let interface = interface_name(net, &ip);
+    let interface = interface.to_string();
     let iinterface = interface.replace("_", "-");
     assert!(iinterface != interface);
 
     if getifaddrs()?.any(|x| x.interface_name == interface) {
-        let mut cmd = unshare::Command::new("/bin/ip");
-        cmd.arg("link");
-        cmd.arg("del").arg(&interface);
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link del failed: {}", s),
-            Err(e) => bail!("ip link del failed: {}", e),
-        }
+        Netlink::open()?.delete_link(&interface)?;
     }
     {
         // Create interface in the child namespace
         // This helps to keep parent namespace clean if this process crashes
         // for some reason.
         let ns = NsGuard::enter(pid)?;
-
-        let mut cmd = unshare::Command::new("/bin/ip");
-        cmd.arg("link").arg("add");
-        cmd.arg(&interface);
-        cmd.arg("type").arg("veth");
-        cmd.arg("peer").arg("name").arg(&iinterface);
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link add failed: {}", s),
-            Err(e) => bail!("ip link add failed: {}", e),
-        }
-
+        let mut nl = Netlink::open()?;
+        nl.add_veth(&interface, &iinterface)?;
         // The move just external part of the interface to the parent namespace
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("link").arg("set");
-        cmd.arg("dev").arg(&interface);
-        cmd.arg("netns")
-            .arg(&format!("/proc/{}/fd/{}",
-                          process::id(), ns.parent_raw_fd()));
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link set failed: {}", s),
-            Err(e) => bail!("ip link set failed: {}", e),
-        }
+        nl.move_to_netns(&interface, ns.parent_raw_fd())?;
     }  // return into parent namespace to add to bridge and up the interface
 
-    let mut cmd = unshare::Command::new("/sbin/brctl");
-    cmd.arg("addif").arg(&net.bridge).arg(&interface);
-    debug!("Running {}", cmd.display(&Style::short()));
-    match cmd.status() {
-        Ok(s) if s.success() => {}
-        Ok(s) => bail!("brctl failed: {}", s),
-        Err(e) => bail!("brctl failed: {}", e),
-    }
-
-    let mut cmd = unshare::Command::new("/sbin/ip");
-    cmd.arg("link").arg("set");
-    cmd.arg(&interface);
-    cmd.arg("up");
-    debug!("Running {}", cmd.display(&Style::short()));
-    match cmd.status() {
-        Ok(s) if s.success() => {}
-        Ok(s) => bail!("ip link up failed: {}", s),
-        Err(e) => bail!("ip link up failed: {}", e),
-    }
+    let mut nl = Netlink::open()?;
+    nl.set_master(&interface, &net.bridge)?;
+    nl.set_up(&interface)?;
 
     {
         // and again to the child to setup internal part and routing
         let _ns = NsGuard::enter(pid)?;
-
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("link").arg("set");
-        cmd.arg("lo").arg("up");
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link up lo failed: {}", s),
-            Err(e) => bail!("ip link up lo failed: {}", e),
-        }
-
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("addr").arg("add");
-        cmd.arg(&format!("{}",
-            IpNetwork::new(ip, net.network.prefix())
-            .expect("network asways valid")));
-        cmd.arg("dev").arg(&iinterface);
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link addr failed: {}", s),
-            Err(e) => bail!("ip link addr failed: {}", e),
-        }
-
-        let mut cmd = unshare::Command::new("/sbin/ip");
-        cmd.arg("link").arg("set");
-        cmd.arg(&iinterface);
-        cmd.arg("up");
-        debug!("Running {}", cmd.display(&Style::short()));
-        match cmd.status() {
-            Ok(s) if s.success() => {}
-            Ok(s) => bail!("ip link child up failed: {}", s),
-            Err(e) => bail!("ip link child up failed: {}", e),
-        }
-
-        if let Some(gw) = net.default_gateway {
-            let mut cmd = unshare::Command::new("/sbin/ip");
-            cmd.arg("route").arg("add");
-            cmd.arg("default");
-            cmd.arg("via").arg(&format!("{}", gw));
-            debug!("Running {}", cmd.display(&Style::short()));
-            match cmd.status() {
-                Ok(s) if s.success() => {}
-                Ok(s) => bail!("ip route failed: {}", s),
-                Err(e) => bail!("ip route failed: {}", e),
+        let mut nl = Netlink::open()?;
+        nl.set_up("lo")?;
+        let ip = match ip {
+            Some(ip) => {
+                nl.add_address(&iinterface, ip, net.network.prefix())?;
+                nl.set_up(&iinterface)?;
+                if let Some(gw) = net.default_gateway {
+                    nl.add_default_route(gw)?;
+                }
+                ip
             }
-        }
+            None => {
+                // address and routes are configured by the DHCP client
+                nl.set_up(&iinterface)?;
+                run_dhcp(net, &iinterface, dhcp)?
+            }
+        };
 
         if net.after_setup_command.len() > 0 {
             let mut cmd = unshare::Command::new(&net.after_setup_command[0]);
+            let ip = ip.to_string();
             for item in &net.after_setup_command[1..] {
-                if item.contains('@') {
-                    cmd.arg(&replace_vars(item, |v| {
-                        match v {
-                            "container_ip" => ip.to_string(),
-                            _ => {
-                                error!("No variable {:?} \
-                                        for after-setup-command. \
-                                        Using empty string.", v);
-                                String::new()
-                            }
-                        }
-                    }));
-                } else {
-                    cmd.arg(item);
-                }
+                cmd.arg(replace_command_vars("after-setup-command", item,
+                    &[("container_ip", &ip)]));
             }
             debug!("Running {}", cmd.display(&Style::short()));
             match cmd.status() {
@@ -232,22 +296,13 @@ fn _setup_bridged(pid: u32, net: &BridgedNetwork, ip: IpAddr)
                 Err(e) => bail!("after-setup-command failed: {}", e),
             }
         }
+        Ok(ip)
     }
-
-    Ok(())
 }
 
 fn _setup_isolated(child: u32) -> Result<(), Error> {
     let _ns = NsGuard::enter(child)?;
-    let mut cmd = unshare::Command::new("/sbin/ip");
-    cmd.arg("link").arg("set");
-    cmd.arg("lo").arg("up");
-    debug!("Running {}", cmd.display(&Style::short()));
-    match cmd.status() {
-        Ok(s) if s.success() => {}
-        Ok(s) => bail!("ip link up lo failed: {}", s),
-        Err(e) => bail!("ip link up lo failed: {}", e),
-    }
+    Netlink::open()?.set_up("lo")?;
     Ok(())
 }
 
@@ -255,19 +310,36 @@ fn _setup_isolated(child: u32) -> Result<(), Error> {
 /// This function is executed in restricted child environment
 ///
 /// No allocations, logging, etc. Just bare sys calls
-pub unsafe fn open_socket(cfg: &TcpPort, addr: &SockAddr)
+pub unsafe fn open_socket(cfg: &TcpPort, addr: &SockAddr, fd: RawFd)
     -> Result<(), io::Error>
 {
-    use libc::{socket, setsockopt, bind, listen, dup2, AF_INET, SOCK_STREAM};
+    use libc::{socket, setsockopt, bind, listen, dup2, SOCK_STREAM};
+    use libc::{AF_INET, AF_INET6, IPPROTO_IPV6, IPV6_V6ONLY};
     use libc::{SOL_SOCKET, SO_REUSEADDR, SO_REUSEPORT};
     use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK, EINTR};
-    let s = match socket(AF_INET, SOCK_STREAM, 0) {
+    use libc::{IPPROTO_TCP, TCP_SYNCNT};
+    let ipv6 = match *addr {
+        SockAddr::Inet(InetAddr::V6(_)) => true,
+        _ => false,
+    };
+    let s = match socket(if ipv6 { AF_INET6 } else { AF_INET },
+                         SOCK_STREAM, 0) {
         -1 => return Err(io::Error::last_os_error()),
         s => s,
     };
     // NOTE: we don't close socket on error here, because process will die
     // after any error
 
+    // so that `::` and `0.0.0.0` can be listened at the same time
+    if ipv6 {
+        let v6only = cfg.v6only as u32;
+        if setsockopt(s, IPPROTO_IPV6, IPV6_V6ONLY,
+                      mem::transmute(&v6only), size_of::<u32>() as u32) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
     if cfg.reuse_addr {
         if setsockopt(s, SOL_SOCKET, SO_REUSEADDR,
                       mem::transmute(&1u32), size_of::<u32>() as u32) == -1
@@ -282,6 +354,14 @@ pub unsafe fn open_socket(cfg: &TcpPort, addr: &SockAddr)
             return Err(io::Error::last_os_error());
         }
     }
+    if let Some(count) = cfg.syn_count {
+        if setsockopt(s, IPPROTO_TCP, TCP_SYNCNT,
+                      mem::transmute(&(count as u32)),
+                      size_of::<u32>() as u32) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
     if cfg.set_non_block {
         let fl = loop {
             let fl = fcntl(s, F_GETFL);
@@ -305,11 +385,31 @@ pub unsafe fn open_socket(cfg: &TcpPort, addr: &SockAddr)
     if listen(s, cfg.listen_backlog as i32) == -1 {
         return Err(io::Error::last_os_error());
     }
-    if s != cfg.fd {
-        if dup2(s, cfg.fd) == -1 {
+    if s != fd {
+        if dup2(s, fd) == -1 {
             return Err(io::Error::last_os_error());
         }
         close(s);
     }
     Ok(())
 }
+
+/// Warns about sockets whose listen backlog is clamped by the kernel
+///
+/// Called before spawning the process, because `open_socket` runs in the
+/// forked child where neither files nor logging can be used.
+pub fn check_backlog(sockets: &[(TcpPort, SockAddr, RawFd)]) {
+    let limit = match somaxconn() {
+        Ok(limit) => limit,
+        Err(e) => {
+            debug!("Can't read net.core.somaxconn: {}", e);
+            return;
+        }
+    };
+    for &(ref cfg, ref addr, _) in sockets {
+        if limit < cfg.listen_backlog {
+            warn!("Listen backlog of {:?} is {}, but it's clamped to {} \
+                by net.core.somaxconn", addr, cfg.listen_backlog, limit);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Pins instances to NUMA nodes for `spread: numa`
+//!
+//! CPUs and memory of the node are set in the cpuset of the container
+//! cgroup, so they apply to every process of the container, including the
+//! ones started after a restart, and the process can't widen them. Uses
+//! `cpuset` controller if it's enabled in `cgroup-controllers`, and the
+//! unified hierarchy otherwise.
+use lithos::cgroup::{self, CGroups, Controller};
+use lithos::host_topology::{discover, node_for_instance, Node};
+
+
+/// Instance number from the `sandbox/child.N` name
+fn instance_number(name: &str) -> usize {
+    name.rfind('.')
+        .and_then(|idx| name[idx+1..].parse().ok())
+        .unwrap_or(0)
+}
+
+fn cpu_list(node: &Node) -> String {
+    node.cpus.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn set_unified(name: &str, node: &Node) -> Result<(), String> {
+    cgroup::ensure_in_unified_group(name)?;
+    cgroup::enable_unified_controller(name, "cpuset")?;
+    cgroup::set_unified_value(name, "cpuset.cpus", &cpu_list(node))?;
+    cgroup::set_unified_value(name, "cpuset.mems", &node.id.to_string())?;
+    Ok(())
+}
+
+/// Restricts the cgroup of the knot to the node of the instance
+///
+/// `cgroup_name` is the path of the cgroup relative to the root of the
+/// unified hierarchy, it's only used if there is no `cpuset` controller.
+pub fn pin_to_node(cgroups: Option<&CGroups>, cgroup_name: &str,
+    name: &str)
+    -> Result<(), String>
+{
+    let nodes = discover()?;
+    if nodes.len() < 2 {
+        debug!("[{}] Host has {} NUMA node(s), not spreading",
+            name, nodes.len());
+        return Ok(());
+    }
+    let node = node_for_instance(&nodes, instance_number(name))
+        .expect("nodes are not empty");
+    match cgroups {
+        Some(cgroups) if cgroups.has_controller(Controller::Cpuset) => {
+            cgroups.set_value(Controller::Cpuset, "cpuset.cpus",
+                &cpu_list(node))?;
+            cgroups.set_value(Controller::Cpuset, "cpuset.mems",
+                &node.id.to_string())?;
+        }
+        _ if cgroup::unified_base().is_some() => {
+            set_unified(cgroup_name, node)?;
+        }
+        _ => return Err(format!("neither cpuset cgroup controller nor \
+            unified cgroup hierarchy is available")),
+    }
+    info!("[{}] Pinned to NUMA node {}", name, node.id);
+    Ok(())
+}
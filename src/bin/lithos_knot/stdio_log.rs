@@ -0,0 +1,58 @@
+//! Shared `<sandbox>.log` in `stdio-log-dir` (`stdio-log-max-size`)
+//!
+//! When the file is larger than the limit at the start of the container,
+//! it's renamed to `<sandbox>.log.1` (replacing the previous one) and a new
+//! file is created. Processes which are already running keep writing to
+//! the rotated file until they are restarted, as the file descriptor is
+//! passed to them directly.
+use std::fs::{File, OpenOptions, metadata, rename};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use nix::fcntl::{flock, FlockArg};
+
+
+fn open_file(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true).append(true).write(true).open(path)
+        .map_err(|e| format!("Error opening stderr file {:?}: {}", path, e))
+}
+
+/// Opens stdio log of the sandbox, rotating it if it's too large
+pub fn open(path: &Path, max_size: Option<u64>) -> Result<File, String> {
+    let file = open_file(path)?;
+    let max_size = match max_size {
+        Some(max_size) => max_size,
+        None => return Ok(file),
+    };
+    // other containers of the sandbox may be starting at the same time
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)
+        .map_err(|e| format!("Can't lock stderr file {:?}: {}", path, e))?;
+    let meta = file.metadata()
+        .map_err(|e| format!("Can't stat stderr file {:?}: {}", path, e))?;
+    let rotated = match metadata(path) {
+        Ok(ref cur) => cur.dev() != meta.dev() || cur.ino() != meta.ino(),
+        Err(_) => true,
+    };
+    let result = if rotated {
+        // by other container while we were waiting for the lock
+        open_file(path)
+    } else if meta.len() > max_size {
+        let old = path.with_extension("log.1");
+        rename(path, &old)
+            .map_err(|e| format!("Can't rotate stderr file {:?}: {}",
+                path, e))?;
+        info!("Stderr file {:?} is rotated, it was {} bytes",
+            path, meta.len());
+        open_file(path)
+    } else {
+        flock(file.as_raw_fd(), FlockArg::Unlock)
+            .map_err(|e| format!("Can't unlock stderr file {:?}: {}",
+                path, e))?;
+        return Ok(file);
+    };
+    // lock of the old file is released when it's closed here
+    drop(file);
+    result
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::time::{Instant, Duration};
+
+use serde_json::to_writer;
+
+use lithos::metrics::STARTUP_TIMINGS_FILE;
+use lithos::utils::create_at;
+
+/// Startup stages in the order they are run
+const STAGES: &[&str] = &[
+    "config", "mounts", "cgroups", "network", "secrets", "exec",
+];
+
+/// Measures duration of the container startup stages
+pub struct Timings {
+    last: Instant,
+    stages: BTreeMap<&'static str, u64>,
+    finished: bool,
+}
+
+fn millis(dur: Duration) -> u64 {
+    dur.as_secs() * 1000 + dur.subsec_millis() as u64
+}
+
+impl Timings {
+    pub fn start() -> Timings {
+        Timings {
+            last: Instant::now(),
+            stages: BTreeMap::new(),
+            finished: false,
+        }
+    }
+    /// Finishes stage `name` which started at the end of the previous one
+    pub fn stage(&mut self, name: &'static str) {
+        let now = Instant::now();
+        let ms = millis(now - self.last);
+        debug!("Startup stage {:?} took {}ms", name, ms);
+        *self.stages.entry(name).or_insert(0) += ms;
+        self.last = now;
+    }
+    /// Returns the stage which is currently running
+    ///
+    /// After startup is finished, the process is restarted in `exec` stage.
+    pub fn current_stage(&self) -> &'static str {
+        STAGES.iter().cloned()
+            .find(|name| !self.stages.contains_key(name))
+            .unwrap_or("exec")
+    }
+    /// Finishes the last stage and writes timings into the state dir
+    ///
+    /// File is picked up by `lithos_tree`. Subsequent calls do nothing.
+    /// Directory is passed as a descriptor, as this runs after the root of
+    /// the knot is changed.
+    pub fn finish(&mut self, name: &'static str, state_dir: &File) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.stage(name);
+        create_at(state_dir, STARTUP_TIMINGS_FILE)
+            .map_err(|e| e.to_string())
+            .and_then(|f| to_writer(f, &self.stages)
+                .map_err(|e| e.to_string()))
+            .map_err(|e| warn!("Can't write {}: {}", STARTUP_TIMINGS_FILE, e))
+            .ok();
+    }
+}
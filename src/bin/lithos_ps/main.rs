@@ -24,12 +24,13 @@ use std::default::Default;
 use std::process::exit;
 use std::collections::{BTreeMap, BTreeSet};
 
-use argparse::{ArgumentParser, StoreConst, Print};
+use argparse::{StoreConst, Print};
 use libc::{pid_t, _SC_CLK_TCK, sysconf};
 
 use lithos::utils::get_time;
 use lithos::knot_options;
 use lithos::tree_options;
+use lithos::cli;
 use ascii::Column;
 use self::LithosInfo::*;
 use self::Action::*;
@@ -675,7 +676,7 @@ fn main() {
     };
 
     {
-        let mut ap = ArgumentParser::new();
+        let mut ap = cli::Parser::new();
         ap.refer(&mut action)
             .add_option(&["--json"], StoreConst(PrintJson),
                 "Print big json instead human-readable tree")
@@ -692,7 +693,7 @@ fn main() {
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "Show version");
         ap.set_description("Displays tree of processes");
-        match ap.parse_args() {
+        match cli::parse_args(&ap) {
             Ok(()) => {}
             Err(x) => {
                 exit(x);
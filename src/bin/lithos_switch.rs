@@ -17,13 +17,15 @@ use std::fs::{File};
 use std::fs::{copy, rename};
 use std::process::{Command, Stdio};
 
-use argparse::{ArgumentParser, Parse, StoreTrue, Print};
+use argparse::{Parse, StoreTrue, Print};
 use quire::{parse_config, Options};
 use nix::sys::signal::{SIGQUIT, kill};
 use nix::unistd::Pid;
 
 use lithos::master_config::MasterConfig;
+use lithos::cli;
 use lithos::sandbox_config::SandboxConfig;
+use lithos::sandbox_dirs;
 
 
 fn switch_config(master_cfg: &Path, sandbox_name: String, config_file: &Path)
@@ -62,9 +64,7 @@ fn switch_config(master_cfg: &Path, sandbox_name: String, config_file: &Path)
             return Err(format!("Can't parse master config: {}", e));
         }
     };
-    let sandbox_fn = master_cfg.parent().unwrap()
-        .join(&master.sandboxes_dir)
-        .join(&(sandbox_name.clone() + ".yaml"));
+    let sandbox_fn = sandbox_dirs::find(&master, master_cfg, &sandbox_name);
     let sandbox: SandboxConfig = match parse_config(&sandbox_fn,
         &SandboxConfig::validator(), &Options::default())
     {
@@ -87,12 +87,13 @@ fn switch_config(master_cfg: &Path, sandbox_name: String, config_file: &Path)
         .map_err(|e| format!("Error replacing file: {}", e)));
 
     info!("Done. Sending SIGQUIT to lithos_tree");
-    let pid_file = master.runtime_dir.join("master.pid");
+    let pid_file = master.pid_file();
     let mut buf = String::with_capacity(50);
     let read_pid = File::open(&pid_file)
             .and_then(|mut f| f.read_to_string(&mut buf))
             .ok()
-            .and_then(|_| FromStr::from_str(buf[..].trim()).ok())
+            .and_then(|_| buf.lines().next()
+                .and_then(|line| FromStr::from_str(line.trim()).ok()))
             .map(Pid::from_raw);
     match read_pid {
         Some(pid) if kill(pid, None).is_ok() => {
@@ -123,7 +124,7 @@ fn main() {
     let mut config_file = PathBuf::from("");
     let mut sandbox_name = "".to_string();
     {
-        let mut ap = ArgumentParser::new();
+        let mut ap = cli::Parser::new();
         ap.set_description("Checks if lithos configuration is ok");
         ap.refer(&mut master_config)
           .add_option(&["--master"], Parse,
@@ -148,7 +149,7 @@ fn main() {
         ap.add_option(&["--version"],
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "Show version");
-        match ap.parse_args() {
+        match cli::parse_args(&ap) {
             Ok(()) => {}
             Err(x) => {
                 exit(x);
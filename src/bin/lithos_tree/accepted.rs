@@ -0,0 +1,29 @@
+//! Last accepted process config of each sandbox
+//!
+//! With `max-total-instances` process config is copied to
+//! `<runtime-dir>/accepted` as `<sandbox>.yaml` each time it's accepted.
+//! When a new config is refused (i.e. it exceeds the limit), the copy is
+//! read instead, so processes keep running with the previous config.
+use std::fs::{copy, create_dir_all, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use lithos::master_config::MasterConfig;
+
+
+pub fn path(master: &MasterConfig, sandbox: &str) -> PathBuf {
+    master.accepted_configs_path().join(format!("{}.yaml", sandbox))
+}
+
+/// Stores a copy of the process config `source` of the sandbox
+pub fn save(master: &MasterConfig, sandbox: &str, source: &Path)
+    -> Result<(), io::Error>
+{
+    let dest = path(master, sandbox);
+    if let Some(dir) = dest.parent() {
+        create_dir_all(dir)?;
+    }
+    let tmp = dest.with_extension("yaml.tmp");
+    copy(source, &tmp)?;
+    rename(&tmp, &dest)
+}
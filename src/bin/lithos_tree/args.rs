@@ -6,14 +6,41 @@ use std::time::{Instant, Duration};
 
 use nix::unistd::Pid;
 
+use lithos::child_config::INSTANCE_CONFIG_FILE;
+
 pub enum Child {
-    Normal { name: String, config: String },
+    /// Config is `None` when it's not saved by knot, so the process can only
+    /// be identified by the generation of the config
+    Normal { name: String, config: Option<String>, generation: Option<u64> },
     Zombie,
     Unidentified,
     Error,
 }
 
-pub fn read(pid: Pid, global_config: &Path) -> Child {
+/// Checks arguments after `--config`
+///
+/// `--generation N` is optional as it's not passed by older versions.
+fn valid_tail(tail: &str) -> bool {
+    if tail.is_empty() {
+        return true;
+    }
+    let items: Vec<&str> = tail.split('\0').collect();
+    items.len() == 3 && items[0] == "--generation" &&
+        items[1].parse::<u64>().is_ok() && items[2].is_empty()
+}
+
+fn generation(tail: &str) -> Option<u64> {
+    tail.split('\0').nth(1).and_then(|x| x.parse().ok())
+}
+
+fn read_config(state_path: &Path, name: &str) -> Result<String, io::Error> {
+    let mut buf = String::with_capacity(4096);
+    File::open(state_path.join(name).join(INSTANCE_CONFIG_FILE))
+        .and_then(|mut f| f.read_to_string(&mut buf))?;
+    Ok(buf)
+}
+
+pub fn read(pid: Pid, global_config: &Path, state_path: &Path) -> Child {
     use self::Child::*;
     let start = Instant::now();
     loop {
@@ -54,14 +81,44 @@ pub fn read(pid: Pid, global_config: &Path) -> Child {
            || args[1] != "--name"
            || args[3] != "--master"
            || Path::new(args[4]) != global_config
-           || args[5] != "--config"
-           || args[7] != ""
+           || (args[5] != "--config" && args[5] != "--config-fd")
+           || !valid_tail(args[7])
         {
             return Unidentified;
         }
-        return Normal {
-            name: args[2].to_string(),
-            config: args[6].to_string(),
-        };
+        if args[5] == "--config" {
+            // passed by older versions of lithos
+            return Normal {
+                name: args[2].to_string(),
+                config: Some(args[6].to_string()),
+                generation: generation(args[7]),
+            };
+        }
+        // config is saved by knot shortly after start
+        match read_config(state_path, args[2]) {
+            Ok(config) => {
+                return Normal {
+                    name: args[2].to_string(),
+                    config: Some(config),
+                    generation: generation(args[7]),
+                };
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound &&
+                start + Duration::new(1, 0) > Instant::now() =>
+            {
+                sleep(Duration::from_millis(2));
+                continue;
+            }
+            Err(e) => {
+                // knot may fail before saving config (or it's removed),
+                // then only generation passed on the command-line is known
+                debug!("Can't read config of {:?}: {}", args[2], e);
+                return Normal {
+                    name: args[2].to_string(),
+                    config: None,
+                    generation: generation(args[7]),
+                };
+            }
+        }
     }
 }
@@ -0,0 +1,57 @@
+//! Runs slow helper commands off the main loop
+//!
+//! Compression of config logs, firewall and systemd updates are run in
+//! threads, which wait for their commands themselves. The main loop only
+//! reaps children of the main thread (`__WNOTHREAD`), so it doesn't steal
+//! exit statuses of the helper commands.
+use std::thread;
+
+use libc::pid_t;
+use nix::errno::Errno::{EINTR, ECHILD};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::Error;
+use unshare::ExitStatus;
+
+
+/// Non-blocking iteration over zombie processes of the main thread
+pub struct Zombies;
+
+impl Iterator for Zombies {
+    type Item = (pid_t, ExitStatus);
+
+    fn next(&mut self) -> Option<(pid_t, ExitStatus)> {
+        use self::WaitStatus::*;
+        loop {
+            let flags = WaitPidFlag::WNOHANG | WaitPidFlag::__WNOTHREAD;
+            match waitpid(None, Some(flags)) {
+                Ok(Exited(pid, status)) => {
+                    return Some((pid.into(),
+                        ExitStatus::Exited(status as i8)));
+                }
+                Ok(Signaled(pid, sig, core)) => {
+                    return Some((pid.into(),
+                        ExitStatus::Signaled(sig, core)));
+                }
+                Ok(StillAlive) => return None,
+                Ok(_) => continue,
+                Err(Error::Sys(EINTR)) => continue,
+                Err(Error::Sys(ECHILD)) => return None,
+                Err(e) => panic!("Unexpected waitpid error: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Same as `unshare::reap_zombies`, but skips commands of the helpers
+pub fn reap_zombies() -> Zombies {
+    Zombies
+}
+
+/// Runs `fun` in a background thread
+pub fn spawn<F>(name: &str, fun: F)
+    where F: FnOnce() + Send + 'static
+{
+    thread::Builder::new().name(name.to_string()).spawn(fun)
+        .map_err(|e| error!("Can't start {} thread: {}", name, e))
+        .ok();
+}
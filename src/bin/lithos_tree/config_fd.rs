@@ -0,0 +1,79 @@
+//! Passing config from `lithos_tree` to `lithos_knot`
+//!
+//! Config is written into a sealed memfd (or an unlinked temporary file in
+//! the state dir on kernels without memfd), which is inherited by the knot
+//! as `--config-fd`. Unlike command-line, it isn't visible in `ps` and has
+//! no size limit.
+use std::ffi::CString;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::cmp::max;
+
+use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+
+use lithos::container_config::InstantiatedConfig;
+use lithos::master_config::MasterConfig;
+
+
+/// Chooses file descriptor for the config which doesn't clash with
+/// descriptors passed to the container
+pub fn choose(cfg: &InstantiatedConfig) -> RawFd {
+    cfg.tcp_ports.values()
+        // port with multiple hosts uses a descriptor per host
+        .flat_map(|p| {
+            Some(p.fd + p.host.0.len().saturating_sub(1) as RawFd)
+            .into_iter().chain(p.accept_before_exec)
+        })
+        .chain(cfg.unix_sockets.values().map(|s| s.fd))
+        .max()
+        .map(|fd| max(fd + 1, 3))
+        .unwrap_or(3)
+}
+
+fn memfd(name: &str, config: &str) -> Result<File, io::Error> {
+    let cname = CString::new(format!("lithos:{}", name))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let fd = memfd_create(&cname,
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING)
+        .map_err(io::Error::other)?;
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(config.as_bytes())?;
+    fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(
+        SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW |
+        SealFlag::F_SEAL_WRITE | SealFlag::F_SEAL_SEAL))
+        .map_err(io::Error::other)?;
+    Ok(file)
+}
+
+fn tmpfile(master: &MasterConfig, name: &str, config: &str)
+    -> Result<File, io::Error>
+{
+    let path = master.state_path()
+        .join(format!(".config.{}.tmp", name.replace("/", ":")));
+    let mut file = OpenOptions::new()
+        .read(true).write(true).create(true).truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    remove_file(&path)?;
+    file.write_all(config.as_bytes())?;
+    Ok(file)
+}
+
+/// Creates a file with config of the process, positioned at the start
+pub fn create(master: &MasterConfig, name: &str, config: &str)
+    -> Result<File, io::Error>
+{
+    let mut file = match memfd(name, config) {
+        Ok(file) => file,
+        Err(e) => {
+            debug!("Can't create memfd for {:?}: {}, using a file",
+                name, e);
+            tmpfile(master, name, config)?
+        }
+    };
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
@@ -0,0 +1,288 @@
+//! Log of the process configs of each sandbox in `config-log-dir`
+//!
+//! Log is rotated when it's larger than `CONFIG_LOG_SIZE`, keeping
+//! `MAX_CONFIG_LOGS` files. With `config-log-encryption` rotated files are
+//! compressed by `gzip` and encrypted by `age` in a background thread, so
+//! only the current file is kept in plain text. Total size of the directory
+//! may be limited by `config-log-max-size`, in this case oldest rotated
+//! files of all sandboxes are removed first.
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, read_dir, remove_file, rename};
+use std::io::{self, Write};
+use std::mem::replace;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use humantime::format_rfc3339_seconds;
+use regex::RegexSet;
+use serde_json::to_string;
+
+use lithos::{MAX_CONFIG_LOGS, ENCRYPTED_LOG_SUFFIX};
+use lithos::child_config::{ChildConfig, InlineContainer};
+use lithos::child_config::parse_inline_container;
+use lithos::master_config::{MasterConfig, ConfigLogEncryption};
+use lithos::sandbox_config::{SandboxConfig, ConfigLog};
+
+use background;
+
+
+pub const CONFIG_LOG_SIZE: u64 = 10_485_760;
+/// Value written instead of the redacted variable
+const REDACTED: &str = "<redacted>";
+
+
+fn encrypted(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(ENCRYPTED_LOG_SUFFIX);
+    return name.into();
+}
+
+fn rename_log(from: &Path, to: &Path) {
+    match rename(from, to) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            error!("Can't rename log file {:?}: {}", from, e);
+        }
+        Ok(()) => {
+            debug!("Renamed {:?}", from);
+        }
+    };
+}
+
+fn remove_log(path: &Path) {
+    match remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            error!("Can't remove log file {:?}: {}", path, e);
+        }
+        Ok(()) => {
+            debug!("Removed {:?}", path);
+        }
+    };
+}
+
+/// Compresses and encrypts the file, plain file is removed on success
+fn encrypt(path: &Path, cfg: &ConfigLogEncryption) -> Result<(), String> {
+    let target = encrypted(path);
+    let input = File::open(path)
+        .map_err(|e| format!("can't open {:?}: {}", path, e))?;
+    let mut gzip = Command::new(&cfg.gzip)
+        .arg("-c")
+        .stdin(input)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't run {:?}: {}", cfg.gzip, e))?;
+    let mut age = Command::new(&cfg.age);
+    for recipient in &cfg.recipients {
+        age.arg("--recipient").arg(recipient);
+    }
+    age.arg("--output").arg(&target);
+    age.stdin(gzip.stdout.take().expect("gzip stdout is piped"));
+    let age_status = age.status()
+        .map_err(|e| format!("can't run {:?}: {}", cfg.age, e));
+    let gzip_status = gzip.wait()
+        .map_err(|e| format!("can't wait {:?}: {}", cfg.gzip, e))?;
+    let age_status = age_status?;
+    if !gzip_status.success() || !age_status.success() {
+        remove_log(&target);
+        return Err(format!("gzip {}, age {}", gzip_status, age_status));
+    }
+    remove_log(path);
+    Ok(())
+}
+
+/// Opens log file, rotating it if it's too large
+pub fn open(base: &Path, name: &str,
+    encryption: Option<&ConfigLogEncryption>)
+    -> Result<File, io::Error>
+{
+    let target_name = base.join(name);
+    let file = OpenOptions::new().create(true).write(true).append(true)
+        .open(&target_name)?;
+    let logmeta = file.metadata()?;
+    if logmeta.len() > CONFIG_LOG_SIZE {
+        let lastname = base.join(format!("{}.{}", name, MAX_CONFIG_LOGS));
+        remove_log(&lastname);
+        remove_log(&encrypted(&lastname));
+        let mut prevname = lastname;
+        for i in (1..MAX_CONFIG_LOGS).rev() {
+            let curname = base.join(format!("{}.{}", name, i));
+            rename_log(&curname, &prevname);
+            rename_log(&encrypted(&curname), &encrypted(&prevname));
+            prevname = curname;
+        }
+        rename_log(&target_name, &prevname);
+        if let Some(cfg) = encryption {
+            let cfg = cfg.clone();
+            background::spawn("config_log", move || {
+                encrypt(&prevname, &cfg)
+                    .map_err(|e| error!("Can't encrypt log file {:?}: {}. \
+                        File is kept unencrypted", prevname, e))
+                    .ok();
+            });
+        }
+        // reopen same path
+        OpenOptions::new().create(true).write(true).append(true)
+           .open(target_name)
+    } else {
+        Ok(file)
+    }
+}
+
+/// Replaces values of variables with names matching `patterns`
+///
+/// All variables are redacted if `patterns` is empty. Rendered container
+/// config is redacted too, see `redact_container`.
+fn redact(cfg: &BTreeMap<String, ChildConfig>, patterns: &[String])
+    -> BTreeMap<String, ChildConfig>
+{
+    let set = match RegexSet::new(patterns) {
+        Ok(set) => Some(set),
+        Err(e) => {
+            // checked by lithos_check, but better be safe
+            error!("Invalid redact-variables: {}. \
+                Redacting all variables", e);
+            None
+        }
+    };
+    let matches = |name: &str| match set {
+        Some(ref set) => patterns.is_empty() || set.is_match(name),
+        None => true,
+    };
+    let mut cfg = cfg.clone();
+    for child in cfg.values_mut() {
+        let mut secrets = Vec::new();
+        for (name, value) in child.variables.iter_mut() {
+            if matches(name) {
+                secrets.push(replace(value, REDACTED.to_string()));
+            }
+        }
+        child.container = child.container.as_ref()
+            .and_then(|c| redact_container(c, &secrets, &matches));
+    }
+    return cfg;
+}
+
+/// Redacts environment of the inline (or rendered) container config
+///
+/// Environment variables are redacted by name, like variables of the
+/// process. Values of the redacted variables are also hidden in arguments
+/// and environment, as a template may have substituted them there.
+/// Container is omitted altogether if it can't be parsed.
+fn redact_container<F>(container: &InlineContainer, secrets: &[String],
+    matches: F)
+    -> Option<InlineContainer>
+    where F: Fn(&str) -> bool
+{
+    let mut cfg = parse_inline_container(container.as_json())
+        .map_err(|e| error!("Can't redact container config: {}", e))
+        .ok()?;
+    let hide = |value: &mut String| {
+        for secret in secrets.iter().filter(|s| !s.is_empty()) {
+            if value.contains(&secret[..]) {
+                *value = value.replace(&secret[..], REDACTED);
+            }
+        }
+    };
+    for (name, value) in cfg.environ.iter_mut() {
+        if matches(name) {
+            *value = REDACTED.to_string();
+        } else {
+            hide(value);
+        }
+    }
+    for arg in cfg.arguments.iter_mut() {
+        hide(arg);
+    }
+    hide(&mut cfg.executable);
+    for value in cfg.state_files.values_mut() {
+        hide(value);
+    }
+    InlineContainer::new(&cfg)
+        .map_err(|e| error!("Can't redact container config: {}", e))
+        .ok()
+}
+
+/// Appends config of the sandbox to its log, according to `config-log`
+pub fn write(master: &MasterConfig, sandbox_name: &str,
+    sandbox: &SandboxConfig, cfg: &BTreeMap<String, ChildConfig>)
+{
+    let config_log_dir = match master.config_log_dir {
+        Some(ref dir) => dir,
+        None => return,
+    };
+    let data = match sandbox.config_log {
+        ConfigLog::Off => return,
+        ConfigLog::Redacted => {
+            to_string(&redact(cfg, &sandbox.redact_variables)).unwrap()
+        }
+        ConfigLog::Full => to_string(cfg).unwrap(),
+    };
+    open(config_log_dir, &format!("{}.log", sandbox_name),
+        master.config_log_encryption.as_ref())
+    .and_then(|mut f| {
+        // we want as atomic writes as possible,
+        // so format into a buf
+        let buf = format!("{} {}\n",
+            format_rfc3339_seconds(SystemTime::now()), data);
+        f.write_all(buf.as_bytes())
+    })
+    .map_err(|e| error!("Error writing config log: {}", e))
+    .ok();
+}
+
+/// Removes oldest rotated logs until size of the directory fits `max_size`
+///
+/// Current logs (`<sandbox>.log`) are never removed. Returns the size of
+/// the directory after cleanup.
+pub fn enforce_size(dir: &Path, max_size: Option<u64>)
+    -> Result<u64, io::Error>
+{
+    let mut total = 0;
+    let mut rotated = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            // removed concurrently, probably by lithos_clean
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if !meta.is_file() {
+            continue;
+        }
+        total += meta.len();
+        let name = entry.file_name();
+        if !name.to_str().map(|n| n.ends_with(".log")).unwrap_or(false) {
+            let mtime = meta.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            rotated.push((mtime, entry.path(), meta.len()));
+        }
+    }
+    let max_size = match max_size {
+        Some(max_size) if total > max_size => max_size,
+        _ => return Ok(total),
+    };
+    rotated.sort();
+    for (_, path, size) in rotated {
+        if total <= max_size {
+            break;
+        }
+        match remove_file(&path) {
+            Ok(()) => {
+                info!("Removed config log {:?} to fit config-log-max-size",
+                    path);
+                total -= size;
+            }
+            Err(e) => error!("Can't remove config log {:?}: {}", path, e),
+        }
+    }
+    if total > max_size {
+        warn!("Config logs in {:?} take {} bytes, which is more than \
+            config-log-max-size, but only current logs are left",
+            dir, total);
+    }
+    Ok(total)
+}
@@ -0,0 +1,165 @@
+use std::fs::{remove_file, set_permissions, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{kill, SIGIO};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+use nix::sys::uio::IoVec;
+use nix::unistd::getpid;
+
+/// Time to wait for a client to send the command
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// Command received on the control socket
+#[derive(Debug)]
+pub enum Request {
+    GroupStart(String),
+    GroupStop(String),
+    GroupRestart(String),
+    Drain,
+    Undrain,
+    /// Lend sockets of the stopped daemon to `lithos_cmd`
+    BorrowSockets(String, String),
+    /// List containers with their state (reply is JSON)
+    List,
+    /// Start, stop or restart `sandbox` or `sandbox/child`
+    Start(String),
+    Stop(String),
+    Restart(String),
+    /// Reread configs of the sandbox
+    Reread(String),
+}
+
+/// Client connection waiting for the reply
+pub struct Connection {
+    stream: UnixStream,
+}
+
+impl Request {
+    fn parse(line: &str) -> Result<Request, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match &words[..] {
+            ["group-start", name] => Ok(Request::GroupStart(name.to_string())),
+            ["group-stop", name] => Ok(Request::GroupStop(name.to_string())),
+            ["group-restart", name] => {
+                Ok(Request::GroupRestart(name.to_string()))
+            }
+            ["drain"] => Ok(Request::Drain),
+            ["undrain"] => Ok(Request::Undrain),
+            ["borrow-sockets", sandbox, child] => {
+                Ok(Request::BorrowSockets(sandbox.to_string(),
+                                          child.to_string()))
+            }
+            ["list"] => Ok(Request::List),
+            ["start", name] => Ok(Request::Start(name.to_string())),
+            ["stop", name] => Ok(Request::Stop(name.to_string())),
+            ["restart", name] => Ok(Request::Restart(name.to_string())),
+            ["reread", sandbox] => Ok(Request::Reread(sandbox.to_string())),
+            _ => Err(format!("invalid command {:?}", line.trim())),
+        }
+    }
+}
+
+impl Connection {
+    /// Sends result of the command to the client and closes connection
+    pub fn reply(mut self, result: Result<String, String>) {
+        let line = match result {
+            Ok(text) => format!("ok: {}\n", text),
+            Err(text) => format!("error: {}\n", text),
+        };
+        self.stream.write_all(line.as_bytes())
+            .map_err(|e| debug!("Error replying to control client: {}", e))
+            .ok();
+    }
+    /// Sends file descriptors along with the list of their target numbers
+    ///
+    /// File descriptors are passed in the same message as the reply line,
+    /// so the client receives them with the first read.
+    pub fn reply_with_fds(self, result: Result<Vec<(RawFd, RawFd)>, String>)
+    {
+        let pairs = match result {
+            Ok(pairs) => pairs,
+            Err(e) => return self.reply(Err(e)),
+        };
+        let targets = pairs.iter().map(|&(target, _)| target.to_string())
+            .collect::<Vec<_>>();
+        let fds = pairs.iter().map(|&(_, fd)| fd).collect::<Vec<_>>();
+        let line = format!("ok: {}\n", targets.join(" "));
+        let iov = [IoVec::from_slice(line.as_bytes())];
+        let cmsgs = if fds.is_empty() {
+            Vec::new()
+        } else {
+            vec![ControlMessage::ScmRights(&fds)]
+        };
+        sendmsg(self.stream.as_raw_fd(), &iov, &cmsgs,
+                MsgFlags::empty(), None)
+            .map_err(|e| debug!("Error replying to control client: {}", e))
+            .ok();
+    }
+}
+
+fn read_request(stream: &UnixStream) -> Result<Request, String> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let mut line = String::with_capacity(100);
+    BufReader::new(stream).read_line(&mut line)
+        .map_err(|e| format!("error reading command: {}", e))?;
+    Request::parse(&line)
+}
+
+/// Listens on the control socket and sends `SIGIO` to the current process
+/// when a command is received
+///
+/// Like `image_watch`, listening is done in a thread which lives until the
+/// process exits (or is re-executed on reload). Main loop takes commands
+/// from the returned channel and replies to them.
+pub fn start(path: &Path)
+    -> Result<Receiver<(Request, Connection)>, io::Error>
+{
+    match remove_file(path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    set_permissions(path, Permissions::from_mode(0o600))?;
+    let (tx, rx) = channel();
+    let me = getpid();
+    thread::Builder::new().name("control".into()).spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Error accepting control connection: {}", e);
+                    continue;
+                }
+            };
+            let conn = Connection { stream };
+            match read_request(&conn.stream) {
+                Ok(request) => {
+                    info!("Control command {:?}", request);
+                    if tx.send((request, conn)).is_err() {
+                        return;
+                    }
+                    kill(me, SIGIO).ok();
+                }
+                Err(e) => conn.reply(Err(e)),
+            }
+        }
+    })?;
+    Ok(rx)
+}
+
+/// Removes the socket file when `lithos_tree` exits
+pub fn cleanup(path: &Path) {
+    remove_file(path)
+        .map_err(|e| debug!("Can't remove control socket {:?}: {}", path, e))
+        .ok();
+}
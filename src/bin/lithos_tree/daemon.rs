@@ -0,0 +1,92 @@
+use std::fs::{File, OpenOptions, canonicalize, read_link};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::str::FromStr;
+
+use libc::{_exit, STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO};
+use nix::fcntl::{fcntl, FdFlag, F_GETFD, F_SETFD};
+use nix::unistd::{ForkResult, fork, setsid, dup2, chdir};
+use scan_dir;
+
+
+/// Finds locked pid file inherited from the previous image of the process
+///
+/// Pid file descriptor is kept open across `exec`, when we are re-executed
+/// (i.e. configuration is reloaded or metrics have just been enabled) the
+/// lock is still held and we are already in background.
+pub fn inherited_pid_file(pid_file: &Path) -> Option<File> {
+    inherited_fd(pid_file).map(|fd| unsafe { File::from_raw_fd(fd) })
+}
+
+/// Returns true if pid file is inherited, i.e. we are re-executed
+pub fn is_inherited(pid_file: &Path) -> bool {
+    inherited_fd(pid_file).is_some()
+}
+
+fn inherited_fd(pid_file: &Path) -> Option<RawFd> {
+    let pid_file = canonicalize(pid_file).ok()?;
+    let mut found = None;
+    scan_dir::ScanDir::all().read("/proc/self/fd", |iter| {
+        for (entry, name) in iter {
+            let fd: RawFd = match FromStr::from_str(&name) {
+                Ok(fd) if fd >= 3 => fd,
+                _ => continue,
+            };
+            if read_link(entry.path()).ok().as_ref() == Some(&pid_file) {
+                found = Some(fd);
+                break;
+            }
+        }
+    }).ok()?;
+    found
+}
+
+fn set_cloexec(file: &File, value: bool) -> Result<(), String> {
+    let fd = file.as_raw_fd();
+    fcntl(fd, F_GETFD)
+        .and_then(|flags| {
+            let mut flags = FdFlag::from_bits_truncate(flags);
+            flags.set(FdFlag::FD_CLOEXEC, value);
+            fcntl(fd, F_SETFD(flags))
+        })
+        .map(|_| ())
+        .map_err(|e| format!("Can't change cloexec flag: {}", e))
+}
+
+/// Keeps the descriptor (and so the lock) open across `exec`
+///
+/// Must be called just before `exec` of `lithos_tree` itself, otherwise
+/// every spawned process would hold the lock.
+pub fn inherit_on_exec(file: &File) -> Result<(), String> {
+    set_cloexec(file, false)
+}
+
+/// Restores the cloexec flag of the descriptor inherited across `exec`
+pub fn close_on_exec(file: &File) -> Result<(), String> {
+    set_cloexec(file, true)
+}
+
+/// Detaches from the controlling terminal using the classic double fork
+///
+/// Only the grandchild returns from this function, stdio is redirected to
+/// `/dev/null` and working directory is changed to `/`.
+pub fn daemonize() -> Result<(), String> {
+    match fork().map_err(|e| format!("Can't fork: {}", e))? {
+        ForkResult::Parent { .. } => unsafe { _exit(0) },
+        ForkResult::Child => {}
+    }
+    setsid().map_err(|e| format!("Can't create session: {}", e))?;
+    // Second fork makes sure we never reacquire a controlling terminal
+    match fork().map_err(|e| format!("Can't fork: {}", e))? {
+        ForkResult::Parent { .. } => unsafe { _exit(0) },
+        ForkResult::Child => {}
+    }
+    chdir("/").map_err(|e| format!("Can't chdir to root: {}", e))?;
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")
+        .map_err(|e| format!("Can't open /dev/null: {}", e))?;
+    for &fd in &[STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+        dup2(null.as_raw_fd(), fd)
+            .map_err(|e| format!("Can't redirect stdio: {}", e))?;
+    }
+    Ok(())
+}
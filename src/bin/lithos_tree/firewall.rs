@@ -0,0 +1,313 @@
+//! Opens ports of the running processes in the host firewall
+//!
+//! Enabled by `manage-firewall` of the master config. Port is opened when
+//! the first process listening it in the host network is started, and is
+//! closed when the last such process stops. Commands are run in a
+//! background thread, failed updates are retried after `RETRY_INTERVAL`.
+//!
+//! For `nft` the whole set of ports is written into `nft-port-set`
+//! atomically. For `firewalld` ports are added and removed by a single
+//! command for each direction (in runtime configuration only), and ports
+//! opened so far are kept in a file in the runtime dir, so they're closed
+//! after in-place restart too.
+use std::collections::BTreeSet;
+use std::fs::{read_to_string, remove_file, write};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use lithos::master_config::{MasterConfig, ManageFirewall};
+
+use background;
+
+
+const NFT: &str = "/usr/sbin/nft";
+const FIREWALL_CMD: &str = "/usr/bin/firewall-cmd";
+/// Delay before the failed update is retried
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Settings needed to run the firewall commands
+#[derive(Clone)]
+struct Target {
+    kind: ManageFirewall,
+    nft_set: String,
+    zone: Option<String>,
+    state_file: PathBuf,
+}
+
+struct State {
+    /// Ports which are open now
+    open: BTreeSet<u16>,
+    /// Whether `open` is known to be applied to the firewall
+    synced: bool,
+    /// Update is running in a background thread
+    running: bool,
+    /// When the last update has failed
+    failed: Option<Instant>,
+}
+
+pub struct Firewall {
+    target: Target,
+    state: Arc<Mutex<State>>,
+}
+
+impl Firewall {
+    pub fn new(master: &MasterConfig) -> Option<Firewall> {
+        let kind = master.manage_firewall?;
+        let state_file = master.firewall_ports_path();
+        let open = match read_to_string(&state_file) {
+            Ok(data) => data.split_whitespace()
+                .filter_map(|x| x.parse().ok())
+                .collect(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                BTreeSet::new()
+            }
+            Err(e) => {
+                error!("Can't read {:?}: {}", state_file, e);
+                BTreeSet::new()
+            }
+        };
+        Some(Firewall {
+            target: Target {
+                kind,
+                nft_set: master.nft_port_set.clone(),
+                zone: master.firewalld_zone.clone(),
+                state_file,
+            },
+            state: Arc::new(Mutex::new(State {
+                open,
+                synced: false,
+                running: false,
+                failed: None,
+            })),
+        })
+    }
+
+    /// Makes `ports` the only ones opened by us
+    ///
+    /// Firewall commands are run in a background thread, this is a no-op
+    /// while the previous update is still running. Ports which failed to
+    /// open or close are retried after `RETRY_INTERVAL`, as this is called
+    /// on every iteration of the main loop.
+    pub fn update(&mut self, ports: BTreeSet<u16>) {
+        let (open, synced) = {
+            let mut state = self.state.lock().expect("firewall state");
+            if state.running || state.synced && ports == state.open {
+                return;
+            }
+            if state.failed.map_or(false, |t| t.elapsed() < RETRY_INTERVAL) {
+                return;
+            }
+            state.running = true;
+            (state.open.clone(), state.synced)
+        };
+        let target = self.target.clone();
+        let state = self.state.clone();
+        background::spawn("firewall", move || {
+            let (open, ok) = target.apply(open, synced, ports);
+            finish(&target, &state, open, ok);
+        });
+    }
+
+    /// Closes all the ports, waiting for the update in progress
+    ///
+    /// Used on shutdown, so the ports are closed before we exit.
+    pub fn close_all(&mut self) {
+        loop {
+            let mut state = self.state.lock().expect("firewall state");
+            if state.running {
+                drop(state);
+                sleep(Duration::from_millis(100));
+                continue;
+            }
+            let (open, ok) = self.target.apply(state.open.clone(),
+                state.synced, BTreeSet::new());
+            state.open = open;
+            state.synced = ok;
+            self.target.save(&state.open);
+            return;
+        }
+    }
+}
+
+/// Records result of the background update
+fn finish(target: &Target, state: &Mutex<State>, open: BTreeSet<u16>,
+    ok: bool)
+{
+    let mut state = state.lock().expect("firewall state");
+    state.synced = ok;
+    state.failed = if state.synced { None } else { Some(Instant::now()) };
+    if !state.synced {
+        warn!("Firewall is not updated completely, \
+            retrying in {}s", RETRY_INTERVAL.as_secs());
+    }
+    state.open = open;
+    state.running = false;
+    target.save(&state.open);
+}
+
+impl Target {
+    /// Applies `ports`, returns ports which are open after that and
+    /// whether all the commands succeeded
+    fn apply(&self, mut open: BTreeSet<u16>, synced: bool,
+        ports: BTreeSet<u16>)
+        -> (BTreeSet<u16>, bool)
+    {
+        match self.kind {
+            ManageFirewall::Nft => {
+                match run_nft(&nft_script(&self.nft_set, &ports)) {
+                    Ok(()) => return (ports, true),
+                    Err(e) => {
+                        error!("Can't update {:?}: {}", self.nft_set, e);
+                        // set is replaced atomically, so it's unchanged
+                        return (open, false);
+                    }
+                }
+            }
+            ManageFirewall::Firewalld => {
+                let closing = open.difference(&ports).cloned()
+                    .collect::<Vec<_>>();
+                // adding already open port is just a warning
+                let opening = ports.iter()
+                    .filter(|&&port| !synced || !open.contains(&port))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let (closed, closed_all) = self.change_ports("--remove-port",
+                    &closing);
+                for port in closed {
+                    open.remove(&port);
+                }
+                let (opened, opened_all) = self.change_ports("--add-port",
+                    &opening);
+                open.extend(opened);
+                let ok = closed_all && opened_all;
+                return (open, ok);
+            }
+        }
+    }
+
+    fn save(&self, open: &BTreeSet<u16>) {
+        save_ports(&self.state_file, open)
+            .map_err(|e| error!("Can't write {:?}: {}", self.state_file, e))
+            .ok();
+    }
+
+    /// Runs a single `firewall-cmd` for all `ports`
+    ///
+    /// If it fails, ports are changed one by one, to find out which ones
+    /// have failed. Returns changed ports and whether all of them are.
+    fn change_ports(&self, action: &str, ports: &[u16]) -> (Vec<u16>, bool) {
+        if ports.is_empty() {
+            return (Vec::new(), true);
+        }
+        info!("Firewall {} {:?}", action, ports);
+        match self.firewall_cmd(action, ports) {
+            Ok(()) => return (ports.to_vec(), true),
+            Err(ref e) if ports.len() == 1 => {
+                error!("Firewall {} {} failed: {}", action, ports[0], e);
+                return (Vec::new(), false);
+            }
+            Err(e) => {
+                warn!("Firewall {} {:?} failed: {}. Retrying one by one",
+                    action, ports, e);
+            }
+        }
+        let mut changed = Vec::new();
+        for &port in ports {
+            match self.firewall_cmd(action, &[port]) {
+                Ok(()) => changed.push(port),
+                Err(e) => error!("Firewall {} {} failed: {}",
+                    action, port, e),
+            }
+        }
+        let all = changed.len() == ports.len();
+        (changed, all)
+    }
+
+    fn firewall_cmd(&self, action: &str, ports: &[u16])
+        -> Result<(), String>
+    {
+        let mut cmd = Command::new(FIREWALL_CMD);
+        cmd.arg("--quiet");
+        if let Some(ref zone) = self.zone {
+            cmd.arg(format!("--zone={}", zone));
+        }
+        for port in ports {
+            cmd.arg(format!("{}={}/tcp", action, port));
+        }
+        cmd.stdin(Stdio::null());
+        let output = cmd.output()
+            .map_err(|e| format!("can't run {}: {}", FIREWALL_CMD, e))?;
+        if !output.status.success() {
+            return Err(format!("firewall-cmd {}: {}", output.status,
+                String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+}
+
+fn save_ports(path: &Path, open: &BTreeSet<u16>) -> Result<(), io::Error> {
+    if open.is_empty() {
+        return match remove_file(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        };
+    }
+    let mut data = String::with_capacity(6*open.len());
+    for port in open {
+        data.push_str(&port.to_string());
+        data.push('\n');
+    }
+    write(path, data)
+}
+
+/// Replaces elements of the nftables `set` with `ports` atomically
+fn nft_script(set: &str, ports: &BTreeSet<u16>) -> String {
+    let mut buf = format!("flush set {}\n", set);
+    if !ports.is_empty() {
+        let ports = ports.iter().map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        buf.push_str(&format!("add element {} {{ {} }}\n",
+            set, ports.join(", ")));
+    }
+    buf
+}
+
+fn run_nft(script: &str) -> Result<(), String> {
+    let mut child = Command::new(NFT)
+        .arg("-f").arg("-")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't run {}: {}", NFT, e))?;
+    child.stdin.take().expect("stdin is piped")
+        .write_all(script.as_bytes())
+        .map_err(|e| format!("can't write to nft: {}", e))?;
+    let output = child.wait_with_output()
+        .map_err(|e| format!("can't wait for nft: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("nft {}: {}", output.status,
+            String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use super::nft_script;
+
+    #[test]
+    fn nft() {
+        let set = "inet filter lithos_ports";
+        assert_eq!(nft_script(set, &BTreeSet::new()),
+            "flush set inet filter lithos_ports\n");
+        let ports = vec![8080, 80].into_iter().collect();
+        assert_eq!(nft_script(set, &ports),
+            "flush set inet filter lithos_ports\n\
+             add element inet filter lithos_ports { 80, 8080 }\n");
+    }
+}
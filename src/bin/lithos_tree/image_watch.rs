@@ -0,0 +1,69 @@
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::thread;
+
+use libc::{self, c_int, c_char, uint32_t};
+use nix::sys::signal::{kill, SIGIO};
+use nix::unistd::getpid;
+
+// not in the libc crate we use
+extern "C" {
+    fn inotify_init1(flags: c_int) -> c_int;
+    fn inotify_add_watch(fd: c_int, path: *const c_char, mask: uint32_t)
+        -> c_int;
+}
+
+const IN_MOVED_TO: uint32_t = 0x0000_0080;
+const IN_CREATE: uint32_t = 0x0000_0100;
+const IN_ONLYDIR: uint32_t = 0x0100_0000;
+
+
+/// Watches image directories and sends `SIGIO` to the current process when
+/// anything is created in them
+///
+/// Watching is done in a thread, which lives until the process exits (or is
+/// re-executed on reload). The signal is caught by the main loop, which
+/// rechecks pending images immediately.
+pub fn start(dirs: HashSet<PathBuf>) -> Result<(), io::Error> {
+    let fd = unsafe { inotify_init1(libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for dir in &dirs {
+        let cpath = CString::new(dir.as_os_str().as_bytes())
+            .expect("path has no nulls");
+        let rc = unsafe {
+            inotify_add_watch(fd, cpath.as_ptr(),
+                IN_CREATE | IN_MOVED_TO | IN_ONLYDIR)
+        };
+        if rc < 0 {
+            warn!("Can't watch images in {:?}: {}",
+                dir, io::Error::last_os_error());
+        }
+    }
+    let me = getpid();
+    thread::Builder::new().name("image_watch".into()).spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe {
+                libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len())
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Error watching images: {}", err);
+                return;
+            }
+            // event details are not interesting, all pending images are
+            // checked anyway
+            kill(me, SIGIO).ok();
+        }
+    })?;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use std::fs::{File, read_link, canonicalize};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use nix::unistd::{Pid, getpid};
+use quire::{parse_config, Options as COptions};
+use scan_dir;
+
+use lithos::master_config::MasterConfig;
+use lithos::tree_options::Options;
+
+
+struct Instance {
+    pid: Pid,
+    config_file: PathBuf,
+}
+
+/// Checks whether the process sees the same filesystem as we do
+///
+/// Paths of a `lithos_tree` running in a container (see `nested`), i.e.
+/// its config and pid file, are in another mount namespace, so it can't
+/// conflict with us.
+fn same_mount_namespace(pid: Pid) -> bool {
+    match (read_link(format!("/proc/{}/ns/mnt", pid)),
+           read_link("/proc/self/ns/mnt"))
+    {
+        (Ok(theirs), Ok(ours)) => theirs == ours,
+        // can't compare, so check the process as before
+        _ => true,
+    }
+}
+
+fn read_instance(pid: Pid) -> Option<Instance> {
+    let mut buf = String::with_capacity(256);
+    File::open(format!("/proc/{}/cmdline", pid))
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .ok()?;
+    let mut args: Vec<String> = buf.split('\0')
+        .map(|x| x.to_string()).collect();
+    if args.last().map(|x| x.is_empty()).unwrap_or(false) {
+        args.pop();
+    }
+    if args.is_empty() || Path::new(&args[0]).file_name()
+        .and_then(|x| x.to_str()) != Some("lithos_tree")
+    {
+        return None;
+    }
+    if !same_mount_namespace(pid) {
+        debug!("Skipping lithos_tree (pid {}) in other mount namespace", pid);
+        return None;
+    }
+    let options = Options::parse_specific_args(args,
+        &mut io::sink(), &mut io::sink()).ok()?;
+    // relative config path is relative to the working dir of the process
+    let cwd = read_link(format!("/proc/{}/cwd", pid)).ok()?;
+    Some(Instance { pid, config_file: cwd.join(&options.config_file) })
+}
+
+fn sandboxes_dir(master: &MasterConfig, config_file: &Path) -> PathBuf {
+    let dir = config_file.parent().unwrap_or(Path::new("/"))
+        .join(&master.sandboxes_dir);
+    canonicalize(&dir).unwrap_or(dir)
+}
+
+/// Refuses to start if other `lithos_tree` uses the same sandboxes
+///
+/// Two supervisors sharing sandbox configs would fight for the same
+/// containers, state dirs and cgroups.
+pub fn check_overlapping(master: &MasterConfig, config_file: &Path)
+    -> Result<(), String>
+{
+    let mypid = getpid();
+    let my_dir = sandboxes_dir(master, config_file);
+    let mut others = Vec::new();
+    scan_dir::ScanDir::dirs().read("/proc", |iter| {
+        for (_, name) in iter {
+            let pid = match FromStr::from_str(&name) {
+                Ok(pid) => Pid::from_raw(pid),
+                Err(_) => continue,
+            };
+            if pid == mypid {
+                continue;
+            }
+            if let Some(inst) = read_instance(pid) {
+                others.push(inst);
+            }
+        }
+    }).map_err(|e| format!("Can't read /proc: {}", e))?;
+    for inst in others {
+        let other: MasterConfig = match parse_config(&inst.config_file,
+            &MasterConfig::validator(), &COptions::default())
+        {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                warn!("Can't read config {:?} of lithos_tree (pid {}): {}",
+                    inst.config_file, inst.pid, e);
+                continue;
+            }
+        };
+        let dir = sandboxes_dir(&other, &inst.config_file);
+        if dir.starts_with(&my_dir) || my_dir.starts_with(&dir) {
+            return Err(format!("Other lithos_tree (pid {}, config {:?}) \
+                uses overlapping sandboxes dir {:?}",
+                inst.pid, inst.config_file, dir));
+        }
+        if other.pid_file() == master.pid_file() {
+            return Err(format!("Other lithos_tree (pid {}, config {:?}) \
+                uses the same pid file {:?}, set distinct `instance-name`",
+                inst.pid, inst.config_file, master.pid_file()));
+        }
+    }
+    Ok(())
+}
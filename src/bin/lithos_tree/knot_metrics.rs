@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::remove_file;
+use std::io;
+
+use scan_dir;
+
+use lithos::master_config::MasterConfig;
+use lithos::metrics::Process;
+use lithos::shared_metrics::{SharedCounters, Slot, ALL_SLOTS};
+
+
+/// Aggregates counters maintained by `lithos_knot` processes
+///
+/// Counters in files are cumulative, so we remember last seen values and
+/// add the difference to the per-process metrics.
+pub struct KnotMetrics {
+    last: HashMap<String, Vec<u64>>,
+}
+
+/// Returns values of the counters and whether the process is unhealthy
+fn read(master: &MasterConfig, name: &str) -> Option<(Vec<u64>, bool)> {
+    let path = master.knot_metrics_path().join(name);
+    match SharedCounters::open(&path) {
+        Ok(counters) => {
+            Some((ALL_SLOTS.iter().map(|&s| counters.get(s)).collect(),
+                  counters.get(Slot::Unhealthy) != 0))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            debug!("Can't read counters {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn metric(m: &Process, slot: Slot) -> &::libcantal::Counter {
+    match slot {
+        Slot::Starts => &m.process_starts,
+        Slot::Deaths => &m.process_deaths,
+        Slot::Failures => &m.process_failures,
+        Slot::Restarts => &m.process_restarts,
+        Slot::HealthChecks => &m.health_checks,
+        Slot::HealthCheckFailures => &m.health_check_failures,
+        Slot::HealthRestarts => &m.health_restarts,
+        Slot::Unhealthy => unreachable!("gauge is not in ALL_SLOTS"),
+    }
+}
+
+impl KnotMetrics {
+    pub fn new() -> KnotMetrics {
+        KnotMetrics { last: HashMap::new() }
+    }
+    /// Remembers current values, so that they are not counted again
+    ///
+    /// Called for recovered processes and just before starting knot.
+    pub fn baseline(&mut self, master: &MasterConfig, name: &str) {
+        let values = read(master, name).map(|(values, _)| values)
+            .unwrap_or_else(|| vec![0; ALL_SLOTS.len()]);
+        self.last.insert(name.to_string(), values);
+    }
+    /// Adds counters incremented since the last call to metrics
+    ///
+    /// Returns true if the last health check of the process failed.
+    pub fn collect(&mut self, master: &MasterConfig, name: &str,
+        metrics: &Process)
+        -> bool
+    {
+        let (values, unhealthy) = match read(master, name) {
+            Some(result) => result,
+            None => return false,
+        };
+        let last = self.last.entry(name.to_string())
+            .or_insert_with(|| values.clone());
+        for (idx, &slot) in ALL_SLOTS.iter().enumerate() {
+            // file is recreated if value is less than before
+            let delta = if values[idx] >= last[idx] {
+                values[idx] - last[idx]
+            } else {
+                values[idx]
+            };
+            metric(metrics, slot).incr(delta);
+        }
+        *last = values;
+        unhealthy
+    }
+}
+
+/// Removes counters of the processes that are not configured any more
+pub fn remove_dangling(names: &HashSet<&str>, master: &MasterConfig) {
+    let base = master.knot_metrics_path();
+    scan_dir::ScanDir::dirs().read(&base, |iter| {
+        for (entry, sandbox_name) in iter {
+            scan_dir::ScanDir::files().read(entry.path(), |iter| {
+                for (entry, proc_name) in iter {
+                    let name = format!("{}/{}", sandbox_name, proc_name);
+                    if !names.contains(&name[..]) {
+                        remove_file(entry.path())
+                            .map_err(|e| warn!("Can't remove {:?}: {}",
+                                entry.path(), e))
+                            .ok();
+                    }
+                }
+            }).map_err(|e| debug!("Can't read {:?}: {}", entry.path(), e))
+            .ok();
+        }
+    }).map_err(|e| debug!("Can't read {:?}: {}", base, e)).ok();
+}
@@ -8,7 +8,7 @@ extern crate nix;
 extern crate quire;
 extern crate regex;
 extern crate scan_dir;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 extern crate signal;
 extern crate syslog;
 extern crate unshare;
@@ -18,27 +18,31 @@ extern crate unshare;
 
 use std::env;
 use std::mem::replace;
-use std::fs::{File, OpenOptions, metadata, remove_file, rename};
-use std::io::{self, stderr, Read, Write};
+use std::cmp::max;
+use std::fs::{File, OpenOptions, metadata, remove_file, write};
+use std::io::{self, stderr, Read, Write, Seek, SeekFrom};
 use std::str::{FromStr};
-use std::fs::{remove_dir, read_dir};
-use std::net::SocketAddr;
+use std::fs::{remove_dir, read_dir, canonicalize};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, Instant, Duration};
+use std::rc::Rc;
+use std::time::{Instant, Duration};
 use std::process::exit;
-use std::collections::{HashMap, BTreeMap, HashSet};
-use std::os::unix::io::{RawFd, AsRawFd};
+use std::sync::mpsc::Receiver;
+use std::collections::{HashMap, BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
 
 use failure::Error;
-use humantime::format_rfc3339_seconds;
 use libc::{close};
 use nix::fcntl::{fcntl, FdFlag, OFlag, F_GETFD, F_SETFD, F_GETFL, F_SETFL};
-use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD};
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::{SIGINT, SIGTERM, SIGCHLD, SIGIO, SIGHUP};
 use nix::sys::signal::{kill, Signal};
 use nix::sys::socket::{getsockname, SockAddr};
 use nix::sys::socket::{setsockopt, bind, listen};
 use nix::sys::socket::{socket, AddressFamily, SockType, InetAddr};
-use nix::sys::socket::{SockFlag};
+use nix::sys::socket::{SockFlag, accept4};
 use nix::sys::socket::sockopt::{ReuseAddr, ReusePort};
 use nix::unistd::{Pid, getpid};
 use quire::{parse_config, Options as COptions};
@@ -46,31 +50,80 @@ use regex::Regex;
 use serde_json::to_string;
 use signal::exec_handler;
 use signal::trap::Trap;
-use unshare::{Command, reap_zombies, Namespace, Fd, Stdio};
+use unshare::{Command, Namespace, Fd, Stdio};
 
-use lithos::MAX_CONFIG_LOGS;
 use lithos::cgroup;
-use lithos::child_config::ChildConfig;
-use lithos::child_config::ChildKind::Daemon;
+use lithos::command_slots;
+use lithos::socket_loans;
+use lithos::child_config::{ChildConfig, ChildInstance};
+use lithos::child_config::parse_inline_container;
+use lithos::child_config::ChildKind::{self, Daemon};
 use lithos::container_config::{ContainerConfig, TcpPort, DEFAULT_KILL_TIMEOUT};
 use lithos::container_config::{InstantiatedConfig, Variables};
+use lithos::container_config::RestartPolicy;
 use lithos::id_map::IdMapExt;
-use lithos::master_config::{MasterConfig, create_master_dirs};
+use lithos::master_config::{MasterConfig, ContainerDefaults, RunGroup};
+use lithos::master_config::create_master_dirs;
 use lithos::metrics;
 use lithos::sandbox_config::SandboxConfig;
 use lithos::setup::{clean_child, init_logging};
+use lithos::ipam::Ipam;
+use lithos::instance_ids::InstanceIds;
+use lithos::child_generation::Generations;
+use lithos::socket_stats;
+use lithos::sandbox_dirs;
+use lithos::host_facts::HostFacts;
+use lithos::proc_stats;
+use lithos::statsd::Statsd;
 use lithos::timer_queue::Queue;
 use lithos::utils::{clean_dir, relative, ABNORMAL_TERM_SIGNALS};
-use lithos::utils::{temporary_change_root};
+use lithos::utils::{read_container_config};
+use lithos::templates::render_child;
 use lithos::utils;
 use lithos::tree_options::Options;
+use lithos::version;
+use lithos::reason::Reason;
+use lithos::exit_report::{self, ExitReport};
+use lithos::generation::{self, ConfigDirs};
+use lithos::events;
+
+use knot_metrics::KnotMetrics;
+use control::{Request, Connection};
+use watchdog::Watchdog;
+use firewall::Firewall;
+use background::reap_zombies;
 
 use self::Timeout::*;
 
 mod args;
-
-
-pub const CONFIG_LOG_SIZE: u64 = 10_485_760;
+mod knot_metrics;
+mod daemon;
+mod instances;
+mod image_watch;
+mod config_fd;
+mod control;
+mod systemd;
+mod watchdog;
+mod config_log;
+mod retention;
+mod accepted;
+mod unix_sockets;
+mod firewall;
+mod background;
+
+
+pub const SAMPLE_INTERVAL: u64 = 5;
+/// Process is not counted as starting after this time, even if `lithos_knot`
+/// didn't report that it's done
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay of the start if `max-concurrent-starts` limit is reached
+const START_RETRY: Duration = Duration::from_millis(100);
+/// Interval of checking whether missing image has appeared
+const IMAGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Delay of the start when address isn't available (`wait-for-address`)
+const ADDRESS_RETRY: Duration = Duration::from_secs(1);
+/// Delay of the start while sockets are lent to a command
+const LOAN_RETRY: Duration = Duration::from_secs(1);
 
 struct Process {
     restart_min: Instant,
@@ -80,12 +133,55 @@ struct Process {
     config: String,
     inner_config: InstantiatedConfig,
     addresses: Vec<InetAddr>,
+    unix_sockets: Vec<unix_sockets::Spec>,
     socket_cred: (u32, u32),
     bridged_network: bool,
+    /// Set when we stop the process on purpose
+    stop_reason: Option<Reason>,
+    /// Number of consecutive setup failures reported by `lithos_knot`
+    setup_failures: u32,
+    /// Generation of the child config
+    generation: u64,
+    /// File descriptor where knot reads config from
+    config_fd: RawFd,
+    /// Until when start is retried if the address is not available yet
+    address_deadline: Option<Instant>,
+    /// Sockets may be lent to a command (`inherit-sockets`) while stopped
+    lends_sockets: bool,
+    /// Process passed its `readiness` check or sent `READY=1` (or has
+    /// neither), only such processes are counted in `running` metrics
+    ready: bool,
+    /// Unknown for processes started before `lithos_tree` was reloaded
+    started: Option<Instant>,
+    /// Number of restarts since `lithos_tree` is started
+    restarts: u32,
+    /// `max-parallel-restarts` of the child config
+    rolling: Option<RollingRestart>,
+    /// Config is changed, process waits for its batch of rolling restart
+    restart_pending: bool,
+    /// Restarts within `restart-window`, for `max-restarts`
+    recent_restarts: VecDeque<Instant>,
+}
+
+/// Instances of the child with changed config are restarted in batches
+#[derive(Clone, Copy)]
+struct RollingRestart {
+    max_parallel: usize,
+    delay: Duration,
+}
+
+/// Bind failed with `EADDRNOTAVAIL`, i.e. address isn't on the host yet
+#[derive(Fail, Debug)]
+#[fail(display="address {} is not available", addr)]
+struct AddressNotAvailable {
+    addr: InetAddr,
+    /// `wait-for-address` of the port
+    wait: Option<f32>,
 }
 
 struct Socket {
     fd: RawFd,
+    last_owned: Instant,
 }
 
 enum Child {
@@ -93,9 +189,63 @@ enum Child {
     Unidentified(String),
 }
 
+/// Things needed to make processes out of the child configs
+struct Reader {
+    bin: Binaries,
+    master_file: PathBuf,
+    options: Options,
+    host_facts: HostFacts,
+    container_defaults: ContainerDefaults,
+    cgroup_controllers: Vec<String>,
+    /// Knot waits until it's put into systemd scope, see `systemd`
+    systemd_scope: bool,
+}
+
+/// Where configs are read from, to reread a sandbox (`reread` command)
+struct ConfigSource {
+    reader: Rc<Reader>,
+    dirs: ConfigDirs,
+    sandbox_paths: Vec<PathBuf>,
+}
+
+/// Child which is not run because its image doesn't exist (yet)
+///
+/// Usually this means image is not synced to this host yet. Existence of the
+/// image is rechecked every `IMAGE_CHECK_INTERVAL`.
+struct PendingImage {
+    reader: Rc<Reader>,
+    sandbox_name: String,
+    sandbox: Rc<SandboxConfig>,
+    child_name: String,
+    child: ChildConfig,
+    reserved_ips: Vec<IpAddr>,
+    lends_sockets: bool,
+}
+
+impl PendingImage {
+    /// Checks that image and its container config (unless inline) exist
+    fn image_exists(&self) -> bool {
+        let image = self.sandbox.image_dir.join(&self.child.image);
+        if self.child.container.is_some() {
+            return image.is_dir();
+        }
+        let config = Path::new(&self.child.config);
+        image.join(config.strip_prefix("/").unwrap_or(config)).is_file()
+    }
+}
+
+/// Process stopped via the control socket, it isn't restarted until its
+/// run group is started again
+enum Held {
+    /// Process is not exited yet, sandbox and child name are kept
+    Stopping(String, String),
+    Stopped(Process),
+}
+
 enum Timeout {
-    Start(Process),
-    Kill(Pid),
+    Start(Process, Reason),
+    Kill(Pid, Reason),
+    CheckImage(PendingImage),
 }
 
 impl Child {
@@ -107,6 +257,15 @@ impl Child {
     }
 }
 
+impl Held {
+    fn base_name(&self) -> (&str, &str) {
+        match *self {
+            Held::Stopping(ref sandbox, ref child) => (sandbox, child),
+            Held::Stopped(ref p) => (&p.base_name.0, &p.base_name.1),
+        }
+    }
+}
+
 impl AsRawFd for Socket {
     fn as_raw_fd(&self) -> RawFd {
         self.fd
@@ -115,7 +274,8 @@ impl AsRawFd for Socket {
 
 
 fn new_child(bin: &Binaries, name: &str, master_fn: &Path,
-    cfg: &str, options: &Options, _sandbox: &SandboxConfig)
+    config_fd: RawFd, generation: u64, options: &Options,
+    _sandbox: &SandboxConfig, systemd_scope: bool)
     -> Command
 {
     let mut cmd = Command::new(&bin.lithos_knot);
@@ -124,8 +284,15 @@ fn new_child(bin: &Binaries, name: &str, master_fn: &Path,
     cmd.arg(name);
     cmd.arg("--master");
     cmd.arg(master_fn);
-    cmd.arg("--config");
-    cmd.arg(cfg);
+    // config itself is passed via memfd, see `config_fd`
+    cmd.arg("--config-fd");
+    cmd.arg(config_fd.to_string());
+    cmd.arg("--generation");
+    cmd.arg(generation.to_string());
+    if systemd_scope {
+        cmd.arg("--scope-fd");
+        cmd.arg(systemd::scope_fd(config_fd).to_string());
+    }
     if options.log_stderr {
         cmd.arg("--log-stderr");
     }
@@ -151,11 +318,30 @@ fn check_master_config(cfg: &MasterConfig) -> Result<(), String> {
             "Devfs dir ({:?}) must exist and contain device nodes",
             cfg.devfs_dir));
     }
+    if let Some(ref path) = cfg.knot_binary {
+        if !path.is_absolute() {
+            return Err(format!("Knot binary path ({:?}) must be absolute",
+                path));
+        }
+    }
+    if let Some(ref name) = cfg.instance_name {
+        if name.is_empty() || !name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "Instance name {:?} must only contain alphanumeric \
+                 characters, underscores and dashes", name));
+        }
+    }
+    if cfg.systemd_scope.is_some() && cfg.cgroup_name.is_some() {
+        return Err(format!("Cgroups are managed by systemd when \
+            `systemd-scope` is enabled, set `cgroup-name: null`"));
+    }
     return Ok(());
 }
 
 fn global_init(master: &MasterConfig, options: &Options)
-    -> Result<(), String>
+    -> Result<File, String>
 {
     try!(create_master_dirs(&master));
     try!(init_logging(&master, &master.log_file, &master.syslog_app_name,
@@ -163,16 +349,27 @@ fn global_init(master: &MasterConfig, options: &Options)
           options.log_level
             .or_else(|| FromStr::from_str(&master.log_level).ok())
             .unwrap_or(log::LogLevel::Warn)));
-    try!(check_process(&master));
-    if let Some(ref name) = master.cgroup_name {
-        try!(cgroup::ensure_in_group(name, &master.cgroup_controllers));
+    let pid_file = try!(check_process(&master, &options.config_file,
+                                      options.daemonize));
+    if let Some(ref name) = master.cgroup_parent() {
+        match cgroup::ensure_in_group(name, &master.cgroup_controllers) {
+            Ok(_) => {}
+            // cgroup hierarchy is usually not writable in a container
+            Err(ref e) if master.nested => {
+                warn!("Running without cgroups: {}", e);
+            }
+            Err(e) => return Err(e),
+        }
     }
-    return Ok(());
+    return Ok(pid_file);
 }
 
 fn global_cleanup(master: &MasterConfig) {
-    clean_dir(&master.runtime_dir.join(&master.state_dir), false)
+    clean_dir(&master.state_path(), false)
         .unwrap_or_else(|e| error!("Error removing state dir: {}", e));
+    if let Some(path) = master.control_socket_path() {
+        control::cleanup(&path);
+    }
 }
 
 fn _is_child(pid: Pid, ppid: Pid) -> bool {
@@ -192,41 +389,60 @@ fn _is_child(pid: Pid, ppid: Pid) -> bool {
 }
 
 
-fn check_process(cfg: &MasterConfig) -> Result<(), String> {
-    let mypid = getpid();
-    let pid_file = cfg.runtime_dir.join("master.pid");
-    if metadata(&pid_file).is_ok() {
-        let mut buf = String::with_capacity(50);
-        match File::open(&pid_file)
-            .and_then(|mut f| f.read_to_string(&mut buf))
-            .map_err(|_| ())
-            .and_then(|_| FromStr::from_str(&buf[..].trim())
-                            .map_err(|_| ()))
-            .map(Pid::from_raw)
-        {
-            Ok(pid) if pid == mypid => {
-                return Ok(());
-            }
-            Ok(pid) => {
-                if kill(pid, None).is_ok() {
-                    return Err(format!(concat!("Master pid is {}. ",
-                        "And there is alive process with ",
-                        "that pid."), pid));
-
-                }
-            }
-            _ => {
-                warn!("Pid file exists, but cannot be read");
-            }
-        }
+fn check_process(cfg: &MasterConfig, config_file: &Path, daemonize: bool)
+    -> Result<File, String>
+{
+    let pid_file = cfg.pid_file();
+    if let Some(mut file) = daemon::inherited_pid_file(&pid_file) {
+        debug!("Pid file {:?} is inherited, lock is already held", pid_file);
+        daemon::close_on_exec(&file)?;
+        // binary might be upgraded on reload
+        write_pid_file(&mut file, &pid_file, config_file)?;
+        return Ok(file);
     }
-    try!(File::create(&pid_file)
-        .and_then(|mut f| write!(f, "{}\n", getpid()))
-        .map_err(|e| format!("Can't write file {:?}: {}", pid_file, e)));
-    return Ok(());
+    // children must not inherit the lock
+    let mut file = try!(OpenOptions::new()
+        .read(true).write(true).create(true).truncate(false)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(&pid_file)
+        .map_err(|e| format!("Can't open file {:?}: {}", pid_file, e)));
+    if let Err(e) = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        let mut buf = String::with_capacity(256);
+        file.read_to_string(&mut buf).ok();
+        let mut lines = buf.lines();
+        let pid = lines.next().unwrap_or("<unknown>");
+        let config = lines.next().unwrap_or("<unknown>");
+        let version = lines.next().unwrap_or("<unknown>");
+        return Err(format!("Pid file {:?} is locked ({}): \
+            master pid is {}, config {}, version {}",
+            pid_file, e, pid, config, version));
+    }
+    // Lock is inherited by the forked process, so we fork only when it's
+    // already held
+    if daemonize {
+        try!(daemon::daemonize());
+    }
+    write_pid_file(&mut file, &pid_file, config_file)?;
+    return Ok(file);
 }
 
-fn recover_sockets(sockets: &mut HashMap<InetAddr, Socket>) {
+/// Writes pid, config and version of the running `lithos_tree`
+fn write_pid_file(file: &mut File, pid_file: &Path, config_file: &Path)
+    -> Result<(), String>
+{
+    let config_file = canonicalize(config_file)
+        .unwrap_or_else(|_| config_file.to_path_buf());
+    file.set_len(0)
+        .and_then(|()| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| write!(file, "{}\n{}\n{}\n",
+                             getpid(), config_file.display(),
+                             version::build_info()))
+        .map_err(|e| format!("Can't write file {:?}: {}", pid_file, e))
+}
+
+fn recover_sockets(sockets: &mut HashMap<InetAddr, Socket>,
+                   unix: &mut HashMap<PathBuf, Socket>)
+{
     scan_dir::ScanDir::all().read("/proc/self/fd", |iter| {
         let fds = iter
             .filter_map(|(_, name)| FromStr::from_str(&name).ok())
@@ -236,6 +452,7 @@ fn recover_sockets(sockets: &mut HashMap<InetAddr, Socket>) {
                 Ok(SockAddr::Inet(addr)) => {
                     let sock = Socket {
                         fd: fd,
+                        last_owned: Instant::now(),
                     };
                     match sockets.insert(addr, sock) {
                         None => {
@@ -248,6 +465,27 @@ fn recover_sockets(sockets: &mut HashMap<InetAddr, Socket>) {
                         }
                     }
                 }
+                Ok(SockAddr::Unix(_)) => {
+                    let path = match unix_sockets::recover(fd) {
+                        Some(path) => path,
+                        None => {
+                            debug!("Fd {} is not a listening unix socket",
+                                fd);
+                            continue;
+                        }
+                    };
+                    let sock = Socket {
+                        fd: fd,
+                        last_owned: Instant::now(),
+                    };
+                    if let Some(old) = unix.insert(path.clone(), sock) {
+                        error!("Path {:?} has two sockets: \
+                            fd={} and fd={}, discarding latter.",
+                            path, fd, old.fd);
+                    } else {
+                        info!("Recovered fd {} as {:?}", fd, path);
+                    }
+                }
                 Ok(_) => {
                     debug!("Fd {} is different kind of socket", fd);
                 }
@@ -268,12 +506,28 @@ fn list_proc(pids: &mut HashSet<Pid>) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Compares configs structurally, so that serialization differences
+/// between lithos versions don't restart processes
+fn same_config(current: &str, running: &str) -> bool {
+    if current == running {
+        return true;
+    }
+    match (ChildInstance::config_hash(current),
+           ChildInstance::config_hash(running))
+    {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
 fn recover_processes(children: &mut HashMap<Pid, Child>,
     configs: &mut HashMap<String, Process>,
-    queue: &mut Queue<Timeout>, metrics: &metrics::Metrics, config_file: &Path)
+    queue: &mut Queue<Timeout>, metrics: &metrics::Metrics, config_file: &Path,
+    master: &MasterConfig, options: &Options)
 {
     use args::Child::*;
     let mypid = getpid();
+    let state_path = master.state_path();
     let now = Instant::now();
 
     let mut pids = HashSet::new();
@@ -287,17 +541,34 @@ fn recover_processes(children: &mut HashMap<Pid, Child>,
         if !_is_child(pid, mypid) {
             continue;
         }
-        match args::read(pid, config_file) {
-            Normal { name, config } => match configs.remove(&name) {
-                Some(child) => {
-                    if &child.config[..] != &config[..] {
+        match args::read(pid, config_file, &state_path) {
+            Normal { name, config, generation } => match configs.remove(&name)
+            {
+                Some(mut child) => {
+                    let same = match config {
+                        Some(ref config) => same_config(&child.config, config),
+                        None => generation == Some(child.generation),
+                    };
+                    if same {
+                        // nothing to do
+                    } else if options.ignore_config_drift {
+                        warn!("Config mismatch: {}, pid: {}. Keeping it \
+                            running (--ignore-config-drift)", name, pid);
+                    } else if child.rolling.is_some() {
+                        warn!("Config mismatch: {}, pid: {}. Upgrading \
+                            in the rolling restart...", name, pid);
+                        child.restart_pending = true;
+                    } else {
                         warn!("Config mismatch: {}, pid: {}. Upgrading...",
                               name, pid);
+                        child.stop_reason = Some(Reason::ConfigChange);
                         kill(pid, Signal::SIGTERM)
                         .map_err(|e|
                             error!("Error sending TERM to {}: {:?}",
                                 pid, e)).ok();
                     }
+                    // we can't know whether it was ready, assume it is
+                    child.ready = true;
                     metrics.processes[&child.base_name].running.incr(1);
                     metrics.running.incr(1);
                     children.insert(pid, Child::Process(child));
@@ -310,6 +581,7 @@ fn recover_processes(children: &mut HashMap<Pid, Child>,
                     .map_err(|e| error!("Error sending TERM to {}: {:?}",
                         pid, e)).ok();
                     metrics.unknown.incr(1);
+                    metrics.stops[&Reason::Retired].incr(1);
                 }
             }
             Zombie => {
@@ -327,8 +599,9 @@ fn recover_processes(children: &mut HashMap<Pid, Child>,
                         pid, e)).ok();
                 queue.add(
                     now + duration(DEFAULT_KILL_TIMEOUT),
-                    Kill(pid));
+                    Kill(pid, Reason::Retired));
                 metrics.unknown.incr(1);
+                metrics.stops[&Reason::Retired].incr(1);
             }
         }
     }
@@ -337,7 +610,7 @@ fn recover_processes(children: &mut HashMap<Pid, Child>,
 fn remove_dangling_state_dirs(names: &HashSet<&str>, master: &MasterConfig)
 {
     let pid_regex = Regex::new(r"\.(\d+)$").unwrap();
-    let master = master.runtime_dir.join(&master.state_dir);
+    let master = master.state_path();
     scan_dir::ScanDir::dirs().read(&master, |iter| {
         for (entry, sandbox_name) in iter {
             let path = entry.path();
@@ -400,9 +673,10 @@ fn _rm_cgroup(dir: &Path) {
 
 fn remove_dangling_cgroups(names: &HashSet<&str>, master: &MasterConfig)
 {
-    if master.cgroup_name.is_none() {
-        return;
-    }
+    let cgroup_parent = match master.cgroup_parent() {
+        Some(name) => name,
+        None => return,
+    };
     let cgroups = match cgroup::parse_cgroups(None) {
         Ok(cgroups) => cgroups,
         Err(e) => {
@@ -413,11 +687,11 @@ fn remove_dangling_cgroups(names: &HashSet<&str>, master: &MasterConfig)
     // TODO(tailhook) need to customize cgroup mount point?
     let cgroup_base = Path::new("/sys/fs/cgroup");
     let root_path = Path::new("/");
-    let child_group_regex = Regex::new(r"^([\w-]+):([\w-]+\.\d+)\.scope$")
+    let child_group_regex = Regex::new(r"^([\w-]+):([\w-]+\.\d+)(?:\.g\d+)?\.scope$")
         .unwrap();
     let cmd_group_regex = Regex::new(r"^([\w-]+):cmd\.[\w-]+\.(\d+)\.scope$")
         .unwrap();
-    let cgroup_filename = master.cgroup_name.as_ref().map(|x| &x[..]);
+    let cgroup_filename = Some(&cgroup_parent[..]);
 
     // Loop over all controllers in case someone have changed config
     for cgrp in cgroups.all_groups.iter() {
@@ -463,37 +737,85 @@ fn run(config_file: &Path, options: &Options)
         &MasterConfig::validator(), &COptions::default())
         .map_err(|e| format!("Error reading master config: {}", e)));
     try!(check_master_config(&master));
-    try!(global_init(&master, &options));
+    try!(instances::check_overlapping(&master, config_file));
+    // pid file is inherited only when configuration is reloaded
+    let reloaded = daemon::is_inherited(&master.pid_file());
+    let pid_file = try!(global_init(&master, &options));
 
-    let bin = match get_binaries() {
+    let bin = match get_binaries(&master) {
         Some(bin) => bin,
         None => {
             exit(127);
         }
     };
 
-    force_cantal(&bin, &master);
+    force_cantal(&bin, &master, config_file, options, &pid_file);
 
-    let mut trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD]);
+    let mut trap = Trap::trap(&[SIGINT, SIGTERM, SIGCHLD, SIGIO, SIGHUP]);
     let config_file = config_file.to_owned();
 
-    let mut metrics = metrics::Metrics::new();
-    let (mut configs, sandboxes) = read_sandboxes(&master, &bin, &config_file,
-        options);
+    let dirs = generation::resolve_dirs(&master, &config_file)?;
+    if let Some(generation) = dirs.generation() {
+        warn!("Using config generation {:?}", generation);
+    }
+    let sandbox_paths = sandbox_dirs::dirs(&master, &config_file,
+        &dirs.sandboxes.path);
+
+    let host_facts = HostFacts::gather()?;
+    host_facts.write(&master.host_facts_path())
+        .map_err(|e| error!("{}", e)).ok();
+    let missing = host_facts.kernel_features.missing();
+    if !missing.is_empty() {
+        warn!("Kernel features not available: {}. Containers which need \
+            them will not be started", missing.join(", "));
+    }
 
-    for (_, pro) in &configs {
+    let reader = Rc::new(Reader {
+        bin,
+        master_file: config_file.clone(),
+        options: options.clone(),
+        host_facts,
+        container_defaults: master.container_defaults.clone(),
+        cgroup_controllers: master.cgroup_controllers.clone(),
+        systemd_scope: master.systemd_scope.is_some(),
+    });
+    let mut metrics = metrics::Metrics::new();
+    let (mut configs, sandboxes, pending, refused) = read_sandboxes(&master,
+        &reader, &dirs, &sandbox_paths);
+    for item in &pending {
         metrics.processes.insert(
-            pro.base_name.clone(),
+            (item.sandbox_name.clone(), item.child_name.clone()),
             metrics::Process::new());
     }
 
+    for (_, pro) in &configs {
+        metrics.processes.entry(pro.base_name.clone())
+            .or_insert_with(metrics::Process::new)
+            .generation.set(pro.generation as i64);
+        metrics.stdio_log_size.entry(pro.base_name.0.clone())
+            .or_insert_with(libcantal::Integer::new);
+        register_sockets(pro, &mut metrics);
+    }
+
     // read counters so that we don't miss events in case lithos restarts
     // too often
-    let _metrics = libcantal::start_with_reading(&metrics);
+    let _metrics = if master.metrics {
+        libcantal::start_with_reading(&metrics)
+            .map_err(|e| error!("Can't initialize metrics: {}", e)).ok()
+    } else {
+        None
+    };
     // then overwrite things that are possibly out of date
     metrics.restarts.incr(1);
+    metrics.build_info.set(1);
+    metrics.config_errors.incr(refused as u64);
     metrics.containers.set(configs.len() as i64);
+    retention::cleanup_removed(&master, &sandbox_paths, &metrics);
+    enforce_config_log_size(&master, &metrics);
     metrics.sandboxes.set(sandboxes as i64);
+    metrics.config_generation.set(dirs.generation()
+        .and_then(generation::generation_number)
+        .unwrap_or(0) as i64);
     metrics.running.set(0);
     for (_, pro) in &metrics.processes {
         pro.running.set(0);
@@ -502,12 +824,14 @@ fn run(config_file: &Path, options: &Options)
     info!("Recovering Sockets");
     let mut queue = Queue::new();
     let mut sockets = HashMap::new();
-    recover_sockets(&mut sockets);
+    let mut unix_sockets = HashMap::new();
+    recover_sockets(&mut sockets, &mut unix_sockets);
     info!("Recovering Processes");
     let mut children = HashMap::new();
     recover_processes(&mut children, &mut configs, &mut queue,
-        &metrics, &config_file);
-    close_unused_sockets(&mut sockets, &mut children);
+        &metrics, &config_file, &master, options);
+    close_unused_sockets(&mut sockets, &mut unix_sockets, &mut children,
+        &HashMap::new());
 
     {
         let recovered = children.values()
@@ -532,17 +856,47 @@ fn run(config_file: &Path, options: &Options)
 
         info!("Removing Dangling CGroups");
         remove_dangling_cgroups(&keep_cgroups, &master);
+        knot_metrics::remove_dangling(&keep_cgroups, &master);
     }
 
     info!("Starting Processes");
-    schedule_new_workers(configs, &mut queue);
+    schedule_new_workers(configs, &mut queue,
+        if reloaded { Reason::Rollout } else { Reason::Initial });
+    metrics.pending_image.set(pending.len() as i64);
+    if !pending.is_empty() {
+        let dirs = pending.iter()
+            .map(|item| item.sandbox.image_dir.clone())
+            .collect();
+        image_watch::start(dirs)
+            .map_err(|e| error!("Can't watch image dirs: {}. \
+                Images will be rechecked periodically", e))
+            .ok();
+    }
+    for item in pending {
+        queue.add(Instant::now() + IMAGE_CHECK_INTERVAL, CheckImage(item));
+    }
+
+    let control = master.control_socket_path().and_then(|path| {
+        control::start(&path)
+            .map_err(|e| error!("Can't listen control socket {:?}: {}",
+                path, e))
+            .ok()
+    });
+
+    let mut source = ConfigSource { reader, dirs, sandbox_paths };
+
+    let mut firewall = Firewall::new(&master);
 
     metrics.queue.set(queue.len() as i64);
-    normal_loop(&mut queue, &mut children, &mut sockets, &mut trap,
-        &metrics, &master);
+    normal_loop(&mut queue, &mut children, &mut sockets, &mut unix_sockets,
+        &mut trap, &metrics, &master, control.as_ref(), &mut source,
+        &mut firewall);
     if children.len() > 0 {
-        shutdown_loop(&mut children, &mut sockets, &mut trap,
-            &metrics, &master);
+        shutdown_loop(&mut children, &mut sockets, &mut unix_sockets,
+            &mut trap, &metrics, &master, &mut firewall);
+    }
+    if let Some(ref mut firewall) = firewall {
+        firewall.close_all();
     }
 
     global_cleanup(&master);
@@ -550,47 +904,454 @@ fn run(config_file: &Path, options: &Options)
     return Ok(());
 }
 
+/// Ports which running processes listen in the host network
+///
+/// I.e. all ports of processes in the host network and `external` ones of
+/// processes in the bridged network. Opened by `manage-firewall`.
+fn host_ports(children: &HashMap<Pid, Child>) -> BTreeSet<u16> {
+    children.values()
+        .filter_map(|child| match *child {
+            Child::Process(ref p) => Some(p),
+            Child::Unidentified(_) => None,
+        })
+        .flat_map(|p| p.inner_config.tcp_ports.iter()
+            .filter(move |&(_, item)| !p.bridged_network || item.external)
+            .map(|(&port, _)| port))
+        .collect()
+}
+
+/// Closes sockets which are not used by any running process
+///
+/// Sockets of stopped processes which can be lent to a command are kept.
 fn close_unused_sockets(sockets: &mut HashMap<InetAddr, Socket>,
-                        children: &HashMap<Pid, Child>)
+                        unix: &mut HashMap<PathBuf, Socket>,
+                        children: &HashMap<Pid, Child>,
+                        held: &HashMap<String, Held>)
 {
     let empty = Vec::new();
+    let lenders = held.values().filter_map(|h| match *h {
+        Held::Stopped(ref p) if p.lends_sockets => Some(p),
+        _ => None,
+    }).collect::<Vec<_>>();
     let used_addresses: HashSet<InetAddr> = children.values().flat_map(|ch| {
         match ch {
             &Child::Process(ref p) => p.addresses.iter().cloned(),
             &Child::Unidentified(_) => empty.iter().cloned(),
         }
-    }).collect();
+    }).chain(lenders.iter().flat_map(|p| p.addresses.iter().cloned()))
+    .collect();
+    let now = Instant::now();
     *sockets = replace(sockets, HashMap::new())
-        .into_iter().filter(|&(p, ref s)| {
+        .into_iter().filter_map(|(p, mut s)| {
             if used_addresses.contains(&p) {
-                true
+                s.last_owned = now;
+                Some((p, s))
             } else {
                 info!("Closing fd {} addr {}", s.fd, p);
                 unsafe { close(s.fd) };
-                false
+                None
             }
         }).collect();
+    let used_paths: HashSet<&Path> = children.values().flat_map(|ch| {
+        match ch {
+            &Child::Process(ref p) => &p.unix_sockets[..],
+            &Child::Unidentified(_) => &[],
+        }
+    }).chain(lenders.iter().flat_map(|p| &p.unix_sockets[..]))
+    .map(|spec| spec.path.as_path()).collect();
+    unix.retain(|path, s| {
+        if used_paths.contains(path.as_path()) {
+            s.last_owned = now;
+            true
+        } else {
+            unix_sockets::close_socket(path, s.fd);
+            false
+        }
+    });
+}
+
+/// Registers metrics of the sockets of the process
+///
+/// Metrics are registered on start only, so sockets which are added on
+/// reload get their metrics after the in-place restart.
+fn register_sockets(pro: &Process, metrics: &mut metrics::Metrics) {
+    for (&port, item) in &pro.inner_config.tcp_ports {
+        for (addr, _) in item.sockets(port) {
+            metrics.addresses.entry(addr.to_string())
+                .or_insert_with(metrics::Socket::new)
+                .configured_backlog.set(item.listen_backlog as i64);
+        }
+    }
+    for spec in &pro.unix_sockets {
+        metrics.addresses.entry(spec.path.display().to_string())
+            .or_insert_with(metrics::Socket::new)
+            .configured_backlog.set(spec.cfg.listen_backlog as i64);
+    }
+}
+
+fn sample_sockets(sockets: &HashMap<InetAddr, Socket>,
+                  unix_sockets: &HashMap<PathBuf, Socket>,
+                  children: &HashMap<Pid, Child>,
+                  last_overflows: &mut Option<u64>,
+                  metrics: &metrics::Metrics)
+{
+    let now = Instant::now();
+    let mut owners = HashMap::new();
+    for child in children.values() {
+        if let &Child::Process(ref p) = child {
+            let names = p.addresses.iter().map(|a| a.to_string())
+                .chain(p.unix_sockets.iter()
+                    .map(|s| s.path.display().to_string()));
+            for name in names {
+                *owners.entry(name).or_insert(0) += 1;
+            }
+        }
+    }
+    metrics.sockets.set((sockets.len() + unix_sockets.len()) as i64);
+    // Kernel only has a host-wide counter of dropped connections, so we
+    // attribute drops to the sockets which have full queue right now
+    let overflows = socket_stats::listen_overflows()
+        .map_err(|e| debug!("Can't read listen overflows: {}", e)).ok();
+    let dropped = match (overflows, *last_overflows) {
+        (Some(value), Some(last)) => value.saturating_sub(last),
+        _ => 0,
+    };
+    if overflows.is_some() {
+        *last_overflows = overflows;
+    }
+    metrics.listen_overflows.incr(dropped);
+    let update_owners = |name: &str, s: &Socket| {
+        let m = match metrics.addresses.get(name) {
+            Some(m) => m,
+            None => return None,
+        };
+        let num_owners = owners.get(name).cloned().unwrap_or(0);
+        m.owners.set(num_owners);
+        if num_owners > 0 {
+            m.unowned_time.set(0);
+        } else {
+            m.unowned_time.set((now - s.last_owned).as_secs() as i64);
+        }
+        Some(m)
+    };
+    for (path, s) in unix_sockets {
+        update_owners(&path.display().to_string(), s);
+    }
+    for (addr, s) in sockets {
+        let m = match update_owners(&addr.to_string(), s) {
+            Some(m) => m,
+            None => continue,
+        };
+        match socket_stats::listen_queue(s.fd) {
+            Ok(q) => {
+                m.queue.set(q.length as i64);
+                m.backlog.set(q.backlog as i64);
+                if q.backlog > 0 && q.length >= q.backlog {
+                    warn!("Accept queue of {} is full ({} connections)",
+                        addr, q.length);
+                    m.queue_full.incr(1);
+                    m.overflows.incr(dropped);
+                }
+            }
+            Err(e) => debug!("Can't get TCP_INFO of {}: {}", addr, e),
+        }
+    }
+    for (addr, m) in &metrics.addresses {
+        // Socket is not open at all
+        if !sockets.keys().any(|a| a.to_string() == *addr) &&
+           !unix_sockets.keys().any(|p| p.display().to_string() == *addr)
+        {
+            m.owners.set(0);
+            m.queue.set(0);
+            m.backlog.set(0);
+            m.unowned_time.set(0);
+        }
+    }
+}
+
+fn sample_stdio_logs(master: &MasterConfig, metrics: &metrics::Metrics) {
+    for (sandbox, size) in &metrics.stdio_log_size {
+        let path = master.stdio_log_dir.join(format!("{}.log", sandbox));
+        match metadata(&path) {
+            Ok(meta) => size.set(meta.len() as i64),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => size.set(0),
+            Err(e) => debug!("Can't stat {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Samples resource usage of `lithos_tree` itself
+fn sample_self(children: &HashMap<Pid, Child>, metrics: &metrics::Metrics) {
+    match proc_stats::read_usage(i32::from(getpid())) {
+        Ok(usage) => {
+            metrics.rss_bytes.set(usage.rss_bytes as i64);
+            metrics.cpu_ms.set(usage.cpu_ms as i64);
+        }
+        Err(e) => debug!("Can't read own resource usage: {}", e),
+    }
+    metrics.children.set(children.len() as i64);
+}
+
+/// Samples resource usage of the processes run by `lithos_knot`
+///
+/// Metrics are per process name: threads, fds and fd usage are maximums
+/// among the instances (to see a leak in any of them), context switches
+/// are summed up.
+fn sample_processes(children: &HashMap<Pid, Child>,
+                    metrics: &metrics::Metrics, master: &MasterConfig)
+{
+    let mut values = HashMap::new();
+    for (pid, child) in children {
+        let p = match *child {
+            Child::Process(ref p) => p,
+            Child::Unidentified(_) => continue,
+        };
+        let state_dir = master.state_path().join(&p.name);
+        let stats = match proc_stats::container_process(i32::from(*pid),
+                                                        &state_dir)
+            .map(proc_stats::read)
+        {
+            Some(Ok(stats)) => stats,
+            Some(Err(e)) => {
+                debug!("Can't read stats of {:?}: {}", p.name, e);
+                continue;
+            }
+            // not started yet or restarting
+            None => continue,
+        };
+        let v = values.entry(&p.base_name).or_insert([0i64; 5]);
+        v[0] = v[0].max(stats.threads as i64);
+        v[1] = v[1].max(stats.open_fds as i64);
+        v[2] = v[2].max(stats.fd_usage_percent().unwrap_or(0) as i64);
+        v[3] += stats.voluntary_ctxt_switches as i64;
+        v[4] += stats.nonvoluntary_ctxt_switches as i64;
+    }
+    for (name, m) in &metrics.processes {
+        let v = values.get(name).cloned().unwrap_or([0; 5]);
+        m.threads.set(v[0]);
+        m.open_fds.set(v[1]);
+        m.fd_usage_percent.set(v[2]);
+        m.voluntary_ctxt_switches.set(v[3]);
+        m.nonvoluntary_ctxt_switches.set(v[4]);
+    }
+}
+
+/// Reads startup timings left by `lithos_knot` of recently started children
+///
+/// Processes which report readiness are counted as running here, as knot
+/// only writes the timings when the process is ready.
+fn collect_startup_timings(children: &mut HashMap<Pid, Child>,
+    starting: &mut HashMap<Pid, Instant>,
+    metrics: &metrics::Metrics, master: &MasterConfig)
+{
+    let state_path = master.state_path();
+    for (pid, child) in children {
+        let p = match *child {
+            Child::Process(ref mut p) => p,
+            _ => continue,
+        };
+        let path = state_path.join(&p.name)
+            .join(metrics::STARTUP_TIMINGS_FILE);
+        let mut buf = String::with_capacity(256);
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut buf)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                debug!("Can't read {:?}: {}", path, e);
+                continue;
+            }
+        }
+        remove_file(&path)
+            .map_err(|e| warn!("Can't remove {:?}: {}", path, e)).ok();
+        starting.remove(pid);
+        if !p.ready {
+            p.ready = true;
+            metrics.processes[&p.base_name].running.incr(1);
+            metrics.running.incr(1);
+        }
+        let stages: BTreeMap<String, u64> = match serde_json::from_str(&buf) {
+            Ok(stages) => stages,
+            Err(e) => {
+                warn!("Bad startup timings of {:?}: {}", p.name, e);
+                continue;
+            }
+        };
+        for (stage, ms) in stages {
+            if let Some(hist) = metrics.startup.get(&stage[..]) {
+                hist.observe(ms);
+            }
+        }
+    }
+}
+
+/// Stops the next batch of instances waiting for the rolling restart
+///
+/// Batch is started when all the instances of the child restarted in the
+/// previous batch are running and ready, and `restart-batch-delay` has
+/// passed since then. `batches` keeps when the last batch of each child
+/// was seen in progress. Returns when the loop should wake up to start the
+/// next batch.
+fn rolling_restarts(children: &mut HashMap<Pid, Child>,
+    queue: &Queue<Timeout>,
+    batches: &mut HashMap<(String, String), Instant>, now: Instant)
+    -> Option<Instant>
+{
+    let mut pending = HashMap::new();
+    for (&pid, child) in children.iter() {
+        if let Child::Process(ref p) = *child {
+            if p.restart_pending && p.stop_reason.is_none() {
+                if let Some(rolling) = p.rolling {
+                    pending.entry(p.base_name.clone())
+                        .or_insert_with(|| (rolling, Vec::new()))
+                        .1.push((p.name.clone(), pid));
+                }
+            }
+        }
+    }
+    batches.retain(|name, _| pending.contains_key(name));
+    if pending.is_empty() {
+        return None;
+    }
+    let mut busy = HashSet::new();
+    for child in children.values() {
+        if let Child::Process(ref p) = *child {
+            if !p.ready || p.stop_reason == Some(Reason::ConfigChange) {
+                busy.insert(&p.base_name);
+            }
+        }
+    }
+    // includes instances failing to start after the restart
+    for timeout in queue.iter() {
+        if let Start(ref p, _) = *timeout {
+            busy.insert(&p.base_name);
+        }
+    }
+    let mut stop = Vec::new();
+    let mut wake_up = None::<Instant>;
+    for (name, (rolling, mut instances)) in pending {
+        if busy.contains(&name) {
+            batches.insert(name, now);
+            continue;
+        }
+        if let Some(&last) = batches.get(&name) {
+            if last + rolling.delay > now {
+                let next = last + rolling.delay;
+                wake_up = Some(wake_up.map_or(next, |x| x.min(next)));
+                continue;
+            }
+        }
+        info!("Rolling restart of {}/{}: restarting {} of {} instances",
+            name.0, name.1, instances.len().min(rolling.max_parallel),
+            instances.len());
+        instances.sort_by(|a, b| a.0.cmp(&b.0));
+        instances.truncate(rolling.max_parallel);
+        stop.extend(instances.into_iter().map(|(_, pid)| pid));
+        batches.insert(name, now);
+    }
+    for pid in stop {
+        if let Some(&mut Child::Process(ref mut p)) = children.get_mut(&pid) {
+            p.restart_pending = false;
+            p.stop_reason = Some(Reason::ConfigChange);
+        }
+        kill(pid, Signal::SIGTERM)
+            .map_err(|e| error!("Error sending TERM to {}: {:?}", pid, e))
+            .ok();
+    }
+    wake_up
+}
+
+/// Forgets processes which are done with the startup
+///
+/// Startup is finished when `lithos_knot` reports its timings (i.e. the
+/// process is spawned), when it's dead, or on `START_TIMEOUT`.
+fn update_starting(starting: &mut HashMap<Pid, Instant>,
+    children: &HashMap<Pid, Child>, metrics: &metrics::Metrics,
+    master: &MasterConfig, now: Instant)
+{
+    let state_path = master.state_path();
+    starting.retain(|pid, &mut deadline| {
+        match children.get(pid) {
+            Some(&Child::Process(ref p)) => {
+                deadline > now && metadata(state_path.join(&p.name)
+                    .join(metrics::STARTUP_TIMINGS_FILE)).is_err()
+            }
+            _ => false,
+        }
+    });
+    metrics.starting.set(starting.len() as i64);
+}
+
+/// Makes IPv6 socket not to accept IPv4 connections, so that `::` and
+/// `0.0.0.0` can be listened at the same time
+///
+/// Always set explicitly, as the default depends on `bindv6only` sysctl.
+fn set_v6only(sock: RawFd, v6only: bool) -> Result<(), nix::Error> {
+    let value = v6only as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(sock, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t)
+    };
+    nix::errno::Errno::result(res).map(|_| ())
+}
+
+fn set_syn_count(sock: RawFd, count: u8) -> Result<(), nix::Error> {
+    let value = count as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(sock, libc::IPPROTO_TCP, libc::TCP_SYNCNT,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t)
+    };
+    nix::errno::Errno::result(res).map(|_| ())
+}
+
+/// Warns if kernel uses lower backlog than configured in `listen-backlog`
+fn check_backlog(addr: &InetAddr, cfg: &TcpPort) {
+    match socket_stats::somaxconn() {
+        Ok(limit) if limit < cfg.listen_backlog => {
+            warn!("Listen backlog of {} is {}, but it's clamped to {} \
+                by net.core.somaxconn", addr, cfg.listen_backlog, limit);
+        }
+        Ok(_) => {}
+        Err(e) => debug!("Can't read net.core.somaxconn: {}", e),
+    }
 }
 
 fn open_socket(addr: InetAddr, cfg: &TcpPort, uid: u32, gid: u32)
     -> Result<RawFd, Error>
 {
-
+    let family = match addr {
+        InetAddr::V4(_) => AddressFamily::Inet,
+        InetAddr::V6(_) => AddressFamily::Inet6,
+    };
     let sock = {
         let _fsuid_guard = utils::FsUidGuard::set(uid, gid);
-        try!(socket(AddressFamily::Inet, SockType::Stream,
+        try!(socket(family, SockType::Stream,
                     SockFlag::SOCK_CLOEXEC, None)
             .map_err(|e| format_err!("Can't create socket: {:?}", e)))
     };
 
     let mut result = Ok(());
+    if family == AddressFamily::Inet6 {
+        result = set_v6only(sock, cfg.v6only);
+    }
     if cfg.reuse_addr {
         result = result.and_then(|_| setsockopt(sock, ReuseAddr, &true));
     }
     if cfg.reuse_port {
         result = result.and_then(|_| setsockopt(sock, ReusePort, &true));
     }
+    if let Some(count) = cfg.syn_count {
+        result = result.and_then(|_| set_syn_count(sock, count));
+    }
     result = result.and_then(|_| bind(sock, &SockAddr::Inet(addr)));
+    if result == Err(nix::Error::Sys(nix::errno::Errno::EADDRNOTAVAIL)) {
+        unsafe { close(sock) };
+        return Err(AddressNotAvailable {
+            addr,
+            wait: cfg.wait_for_address,
+        }.into());
+    }
     result = result.and_then(|_| listen(sock, cfg.listen_backlog));
     result = result.and_then(|_| listen(sock, cfg.listen_backlog));
     // Only reset cloexec flag when socket is fully ready
@@ -613,57 +1374,128 @@ fn open_socket(addr: InetAddr, cfg: &TcpPort, uid: u32, gid: u32)
         Err(format_err!("Socket option error: {:?}", e))
     } else {
         info!("Socket {} open as {}", addr, sock);
+        check_backlog(&addr, cfg);
         Ok(sock)
     }
 }
 
+/// Accepts pending connection on behalf of the starting process
+///
+/// Only done for non-blocking sockets, as we can't afford to block
+/// the main loop when there is no connection.
+fn accept_pending(addr: &InetAddr, sock: &Socket) -> Option<File> {
+    let flags = fcntl(sock.fd, F_GETFL)
+        .map(OFlag::from_bits_truncate)
+        .unwrap_or(OFlag::empty());
+    if !flags.contains(OFlag::O_NONBLOCK) {
+        warn!("Socket {} is blocking, can't accept before exec. \
+               Use `set-non-block: true`", addr);
+        return None;
+    }
+    match accept4(sock.fd, SockFlag::SOCK_CLOEXEC) {
+        Ok(fd) => Some(unsafe { File::from_raw_fd(fd) }),
+        Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => None,
+        Err(e) => {
+            warn!("Error accepting connection on {}: {}", addr, e);
+            None
+        }
+    }
+}
+
 fn open_sockets_for(socks: &mut HashMap<InetAddr, Socket>,
                     ports: &HashMap<u16, TcpPort>,
+                    unix_socks: &mut HashMap<PathBuf, Socket>,
+                    unix: &[unix_sockets::Spec],
                     cmd: &mut Command,
                     uid: u32, gid: u32,
-                    external_only: bool)
+                    external_only: bool,
+                    metrics: &metrics::Metrics)
     -> Result<(), Error>
 {
     for (&port, item) in ports {
         if external_only == true || item.external {
-            let addr = InetAddr::from_std(&SocketAddr::new(item.host.0, port));
-            if !socks.contains_key(&addr) {
-                if !item.reuse_port {
-                    let sock = open_socket(addr, item, uid, gid)?;
-                    socks.insert(addr, Socket {
-                        fd: sock,
-                    });
+            for (sock_addr, _) in item.sockets(port) {
+                let addr = InetAddr::from_std(&sock_addr);
+                if !socks.contains_key(&addr) {
+                    if !item.reuse_port {
+                        let sock = open_socket(addr, item, uid, gid)?;
+                        socks.insert(addr, Socket {
+                            fd: sock,
+                            last_owned: Instant::now(),
+                        });
+                    }
                 }
             }
         }
     }
 
+    for spec in unix {
+        if !unix_socks.contains_key(&spec.path) {
+            let sock = unix_sockets::open(spec)?;
+            unix_socks.insert(spec.path.clone(), Socket {
+                fd: sock,
+                last_owned: Instant::now(),
+            });
+        }
+    }
+
     cmd.reset_fds();
+    let all_fds = socks.values().chain(unix_socks.values()).map(|x| x.fd);
+    if let (Some(lo), Some(hi)) = (all_fds.clone().min(), all_fds.max()) {
+        cmd.close_fds(lo..hi + 1);
+    }
+    for spec in unix {
+        let fd = Fd::dup_file(unix_socks.get(&spec.path).unwrap())
+            .map_err(|e| format_err!("Can't dup file descriptor: {}", e))?;
+        cmd.file_descriptor(spec.cfg.fd, fd);
+    }
     if socks.len() > 0 {
-        cmd.close_fds(socks.values().map(|x| x.fd).min().unwrap()
-                      ..(socks.values().map(|x| x.fd).max().unwrap() + 1));
         for (&port, item) in ports {
             if external_only == false && !item.external {
                 continue;
             }
-            let addr = InetAddr::from_std(&SocketAddr::new(item.host.0, port));
-            match item.fd {
-                0 => {
-                    let fd = Stdio::dup_file(socks.get(&addr).unwrap())
-                        .map_err(|e| {
-                            format_err!("Can't dup file descriptor: {}", e)
-                        })?;
-                    cmd.stdin(fd);
+            let sockets = item.sockets(port);
+            for (idx, &(sock_addr, target)) in sockets.iter().enumerate() {
+                let addr = InetAddr::from_std(&sock_addr);
+                match target {
+                    0 => {
+                        let fd = Stdio::dup_file(socks.get(&addr).unwrap())
+                            .map_err(|e| {
+                                format_err!("Can't dup file descriptor: {}",
+                                    e)
+                            })?;
+                        cmd.stdin(fd);
+                    }
+                    // TODO(tailhook) these is dangerous to pass to lithos
+                    // knot as stdout and stderr, we need to map them somehow
+                    1|2 => {
+                        bail!("passing fd 1 and fd 2 is not supported yet")
+                    }
+                    _ => {
+                        let fd = Fd::dup_file(socks.get(&addr).unwrap())
+                            .map_err(|e| {
+                                format_err!("Can't dup file descriptor: {}",
+                                    e)
+                            })?;
+                        cmd.file_descriptor(target, fd);
+                    }
+                }
+                // early accepted connection is from the first address only
+                if idx > 0 {
+                    continue;
                 }
-                // TODO(tailhook) these is dangerous to pass to lithos knot
-                // as stdout and stderr, we need to map them somehow
-                1|2 => bail!("passing fd 1 and fd 2 is not supported yet"),
-                _ => {
-                    let fd = Fd::dup_file(socks.get(&addr).unwrap())
-                        .map_err(|e| {
-                            format_err!("Can't dup file descriptor: {}", e)
-                        })?;
-                    cmd.file_descriptor(item.fd, fd);
+                if let Some(conn_fd) = item.accept_before_exec {
+                    let conn = socks.get(&addr)
+                        .and_then(|sock| accept_pending(&addr, sock));
+                    if let Some(conn) = conn {
+                        debug!("Passing early accepted connection \
+                            on {} as {}", addr, conn_fd);
+                        cmd.file_descriptor(conn_fd, Fd::from_file(conn));
+                        let name = addr.to_string();
+                        if let Some(m) = metrics.addresses.get(&name) {
+                            m.early_accepts.incr(1);
+                        }
+                    }
                 }
             }
         }
@@ -678,47 +1510,223 @@ fn duration(inp: f32) -> Duration {
 fn normal_loop(queue: &mut Queue<Timeout>,
     children: &mut HashMap<Pid, Child>,
     sockets: &mut HashMap<InetAddr, Socket>,
+    unix_sockets: &mut HashMap<PathBuf, Socket>,
     trap: &mut Trap,
     metrics: &metrics::Metrics,
-    master: &MasterConfig)
+    master: &MasterConfig,
+    control: Option<&Receiver<(Request, Connection)>>,
+    source: &mut ConfigSource,
+    firewall: &mut Option<Firewall>)
 {
+    let mut next_sample = Instant::now();
+    let mut next_retention = next_sample + retention::CHECK_INTERVAL;
+    let mut held = HashMap::new();
+    // new configs of running processes, applied when they exit
+    let mut replaced = HashMap::new();
+    let mut draining = metadata(&master.drain_marker()).is_ok();
+    let mut drained = false;
+    let mut waves = StopWaves::new();
+    let mut group_starts = Vec::<GroupStart>::new();
+    if draining {
+        warn!("Host is drained, processes are not started until undrained");
+        metrics.draining.set(1);
+        // processes recovered after restart of lithos_tree
+        let stopped = waves.drain(master, queue, children, &mut held,
+            metrics);
+        if stopped > 0 {
+            warn!("Draining {} recovered processes", stopped);
+        }
+    }
+    let mut statsd = master.statsd.as_ref()
+        .map(|cfg| (Statsd::new(cfg), duration(cfg.interval), Instant::now()));
+    let mut starting = HashMap::new();
+    let mut knot_metrics = KnotMetrics::new();
+    for child in children.values() {
+        if let Child::Process(ref p) = *child {
+            knot_metrics.baseline(master, &p.name);
+        }
+    }
+    let mut woke_up: Option<Instant> = None;
+    let mut watchdog = Watchdog::from_env();
+    let mut batches = HashMap::new();
+    let mut listen_overflows = None;
     loop {
         let now = Instant::now();
 
+        if master.max_concurrent_starts.is_some() {
+            update_starting(&mut starting, children, metrics, master, now);
+        }
+        let next_batch = rolling_restarts(children, queue, &mut batches,
+            now);
+        if next_sample <= now {
+            sample_sockets(sockets, unix_sockets, children,
+                &mut listen_overflows, metrics);
+            sample_processes(children, metrics, master);
+            sample_self(children, metrics);
+            sample_stdio_logs(master, metrics);
+            if master.max_concurrent_commands.is_some() {
+                metrics.command_queue.set(
+                    command_slots::queue_length(master) as i64);
+            }
+            collect_startup_timings(children, &mut starting, metrics, master);
+            let mut unhealthy = HashMap::new();
+            for child in children.values() {
+                if let Child::Process(ref p) = *child {
+                    let failing = knot_metrics.collect(master, &p.name,
+                        &metrics.processes[&p.base_name]);
+                    *unhealthy.entry(&p.base_name).or_insert(0) +=
+                        failing as i64;
+                }
+            }
+            for (name, m) in &metrics.processes {
+                m.unhealthy.set(unhealthy.get(name).cloned().unwrap_or(0));
+            }
+            if next_retention <= now {
+                retention::cleanup_removed(master, &source.sandbox_paths,
+                    metrics);
+                next_retention = now + retention::CHECK_INTERVAL;
+            }
+            next_sample = now + Duration::from_secs(SAMPLE_INTERVAL);
+        }
+        if let Some((ref mut statsd, interval, ref mut next_push)) = statsd {
+            if *next_push <= now {
+                statsd.push(metrics)
+                    .map_err(|e| warn!("Error pushing to statsd: {}", e))
+                    .ok();
+                *next_push = now + interval;
+            }
+            if *next_push < next_sample {
+                next_sample = *next_push;
+            }
+        }
+
         let mut buf = Vec::new();
+        let mut recheck = Vec::new();
         for timeout in queue.pop_until(now) {
             match timeout {
-                Start(mut child) => {
+                Start(mut child, reason) => {
+                    if draining {
+                        debug!("Host is drained, holding {:?}", child.name);
+                        metrics.held.incr(1);
+                        held.insert(child.name.clone(), Held::Stopped(child));
+                        continue;
+                    }
+                    if child.lends_sockets && socket_loans::is_lent(master,
+                        &child.base_name.0, &child.base_name.1)
+                    {
+                        debug!("Sockets of {:?} are lent to a command, \
+                            delaying start", child.name);
+                        buf.push((now + LOAN_RETRY, child, reason));
+                        continue;
+                    }
+                    if let Some(limit) = master.max_concurrent_starts {
+                        if starting.len() >= limit {
+                            debug!("Too many processes starting, \
+                                delaying {:?}", child.name);
+                            buf.push((now + START_RETRY, child, reason));
+                            continue;
+                        }
+                    }
                     let restart_min = now +
                         duration(child.inner_config.restart_timeout);
                     match open_sockets_for(
                         sockets, &child.inner_config.tcp_ports,
+                        unix_sockets, &child.unix_sockets,
                         &mut child.cmd,
                         child.socket_cred.0, child.socket_cred.1,
-                        !child.bridged_network)
+                        !child.bridged_network, metrics)
                     {
-                        Ok(()) => {}
+                        Ok(()) => {
+                            child.address_deadline = None;
+                        }
                         Err(e) => {
+                            let wait = e.downcast_ref::<AddressNotAvailable>()
+                                .and_then(|e| e.wait);
+                            if let Some(wait) = wait {
+                                let deadline = *child.address_deadline
+                                    .get_or_insert(now + duration(wait));
+                                if now < deadline {
+                                    info!("Can't start {:?} yet: {}. \
+                                        Retrying...", child.name, e);
+                                    buf.push((now + ADDRESS_RETRY,
+                                              child, reason));
+                                    continue;
+                                }
+                            }
                             error!("Error starting {:?}, \
                                 error opening sockets: {}",
                                 child.name, e);
-                            buf.push((restart_min, child));
+                            child.address_deadline = None;
+                            buf.push((restart_min, child, reason));
                             continue;
                         }
                     }
-                    metrics.processes[&child.base_name].started.incr(1);
-                    metrics.started.incr(1);
-                    let result = child.cmd.spawn();
-                    // need to drop referenced duplicated sockets
-                    child.cmd.reset_fds();
+                    match config_fd::create(master, &child.name,
+                                            &child.config)
+                    {
+                        Ok(file) => {
+                            child.cmd.file_descriptor(child.config_fd,
+                                Fd::from_file(file));
+                        }
+                        Err(e) => {
+                            error!("Error starting {:?}, \
+                                can't pass config: {}",
+                                child.name, e);
+                            child.cmd.reset_fds();
+                            buf.push((restart_min, child, reason));
+                            continue;
+                        }
+                    }
+                    let mut scope_gate = None;
+                    if master.systemd_scope.is_some() {
+                        match systemd::set_gate(&mut child.cmd,
+                                                child.config_fd)
+                        {
+                            Ok(gate) => scope_gate = Some(gate),
+                            Err(e) => {
+                                error!("Error starting {:?}, \
+                                    can't pass scope pipe: {}",
+                                    child.name, e);
+                                child.cmd.reset_fds();
+                                buf.push((restart_min, child, reason));
+                                continue;
+                            }
+                        }
+                    }
+                    knot_metrics.baseline(master, &child.name);
+                    metrics.processes[&child.base_name].started.incr(1);
+                    metrics.started.incr(1);
+                    metrics.starts[&reason].incr(1);
+                    let result = child.cmd.spawn();
+                    // need to drop referenced duplicated sockets
+                    child.cmd.reset_fds();
                     match result {
                         Ok(c) => {
-                            info!("Forked {:?} (pid: {})",
-                                child.name, c.pid());
-                            metrics.processes[&child.base_name]
-                                .running.incr(1);
-                            metrics.running.incr(1);
+                            info!("Forked {:?} (pid: {}, reason: {})",
+                                child.name, c.pid(), reason);
+                            if let (Some(scope), Some(gate)) =
+                                (master.systemd_scope.as_ref(), scope_gate)
+                            {
+                                systemd::start_scope_in_background(scope,
+                                    systemd::unit_name(master, &child.name),
+                                    c.pid(), gate);
+                            }
+                            let readiness = child.inner_config.ready_wait();
+                            child.ready = readiness.is_none();
+                            if child.ready {
+                                metrics.processes[&child.base_name]
+                                    .running.incr(1);
+                                metrics.running.incr(1);
+                            }
                             child.restart_min = restart_min;
+                            child.started = Some(now);
+                            if master.max_concurrent_starts.is_some() {
+                                // knot reports when process is ready
+                                let wait = START_TIMEOUT + readiness
+                                    .map(duration).unwrap_or_default();
+                                starting.insert(Pid::from_raw(c.pid()),
+                                                now + wait);
+                            }
                             children.insert(Pid::from_raw(c.pid()),
                                             Child::Process(child));
                         }
@@ -730,30 +1738,98 @@ fn normal_loop(queue: &mut Queue<Timeout>,
                                 .deaths.incr(1);
                             metrics.deaths.incr(1);
                             error!("Error starting {:?}: {}", child.name, e);
-                            buf.push((restart_min, child));
+                            buf.push((restart_min, child, Reason::Crash));
                         }
                     }
                 }
-                Kill(pid) => {
+                Kill(pid, reason) => {
                     if children.contains_key(&pid) {  // if not already dead
-                        error!("Process {:?} looks like hanging. \
-                            Sending kill...",
-                            pid);
+                        error!("Process {:?} (stopped, reason: {}) \
+                            looks like hanging. Sending kill...",
+                            pid, reason);
                         kill(pid, Signal::SIGKILL).ok();
                     }
                 }
+                CheckImage(item) => {
+                    if !item.image_exists() {
+                        recheck.push(item);
+                        continue;
+                    }
+                    let processes = read_pending(master, &item);
+                    if processes.is_empty() && item.child.instances > 0 {
+                        // image is probably still being synced
+                        info!("Image {:?} of {}/{} is not readable yet, \
+                            will retry", item.child.image, item.sandbox_name,
+                            item.child_name);
+                        recheck.push(item);
+                        continue;
+                    }
+                    info!("Image {:?} of {}/{} has appeared",
+                        item.child.image, item.sandbox_name,
+                        item.child_name);
+                    metrics.pending_image.decr(1);
+                    for (_, process) in processes {
+                        metrics.processes[&process.base_name]
+                            .generation.set(process.generation as i64);
+                        buf.push((now, process, Reason::Rollout));
+                    }
+                }
             }
         }
-        for (restart_min, v) in buf.into_iter() {
-            queue.add(restart_min, Start(v));
+        for (restart_min, v, reason) in buf.into_iter() {
+            queue.add(restart_min, Start(v, reason));
+        }
+        for item in recheck.drain(..) {
+            queue.add(now + IMAGE_CHECK_INTERVAL, CheckImage(item));
         }
         metrics.queue.set(queue.len() as i64);
 
-        close_unused_sockets(sockets, children);
-        let next_signal = match queue.peek_time() {
-            Some(deadline) => trap.wait(deadline),
-            None => trap.next(),
+        close_unused_sockets(sockets, unix_sockets, children, &held);
+        if let Some(ref mut firewall) = *firewall {
+            firewall.update(host_ports(children));
+        }
+        waves.advance(children, &held, master);
+        let mut idx = 0;
+        while idx < group_starts.len() {
+            if group_starts[idx].advance(queue, children, &mut held,
+                metrics, master)
+            {
+                idx += 1;
+            } else {
+                group_starts.remove(idx);
+            }
+        }
+        if !draining {
+            drained = false;
+        } else if !drained && !children.values()
+            .any(|c| matches!(*c, Child::Process(..)))
+        {
+            warn!("Host is drained");
+            events::emit(master, "drained", json!({}));
+            write_drain_marker(master, "drained");
+            drained = true;
+        }
+        // queue is processed, so the loop is not stuck
+        if let Some(ref mut watchdog) = watchdog {
+            watchdog.ping(Instant::now());
+        }
+        let mut deadline = match queue.peek_time() {
+            Some(deadline) if deadline < next_sample => deadline,
+            _ => next_sample,
         };
+        if let Some(ref watchdog) = watchdog {
+            deadline = deadline.min(watchdog.deadline());
+        }
+        if let Some(next_batch) = next_batch {
+            deadline = deadline.min(next_batch);
+        }
+        if let Some(woke_up) = woke_up {
+            let spent = Instant::now() - woke_up;
+            metrics.loop_latency.observe(
+                spent.as_secs() * 1000 + spent.subsec_millis() as u64);
+        }
+        let next_signal = trap.wait(deadline);
+        woke_up = Some(Instant::now());
         match next_signal {
             None => {
                 continue;
@@ -776,27 +1852,145 @@ fn normal_loop(queue: &mut Queue<Timeout>,
             Some(SIGCHLD) => {
                 for (pid, status) in reap_zombies() {
                     match children.remove(&Pid::from_raw(pid)) {
-                        Some(Child::Process(child)) => {
-                            error!("Container {:?} (pid: {}) {}",
-                                child.name, pid, status);
+                        Some(Child::Process(mut child)) => {
+                            let report = exit_report::read(
+                                &master.state_path().join(&child.name));
+                            // lithos_knot transforms valid exits to exit 0,
+                            // older knots don't write exit report
+                            let failure = report.as_ref()
+                                .map(|r| r.is_failure())
+                                .unwrap_or(status.code() != Some(0));
+                            let stopped_by_tree = child.stop_reason.is_some();
+                            let reason = child.stop_reason.take()
+                                .unwrap_or(if failure {
+                                    Reason::Crash
+                                } else {
+                                    Reason::Exit
+                                });
+                            match report {
+                                Some(ref report) => {
+                                    error!("Container {:?} (pid: {}) {}, \
+                                        reason: {}, report: {}",
+                                        child.name, pid, status, reason,
+                                        report);
+                                }
+                                None => {
+                                    error!("Container {:?} (pid: {}) {}, \
+                                        reason: {}",
+                                        child.name, pid, status, reason);
+                                }
+                            }
+                            metrics.stops[&reason].incr(1);
                             metrics.processes
                                 [&child.base_name].deaths.incr(1);
                             metrics.deaths.incr(1);
-                            // lithos_knot transforms valid exits to exit 0
-                            if status.code() != Some(0) {
+                            if status.code() ==
+                                Some(version::EXIT_SCHEMA_MISMATCH)
+                            {
+                                error!("Container {:?} can't understand \
+                                    config of this lithos_tree, check \
+                                    version of lithos_knot binary",
+                                    child.name);
+                                metrics.schema_mismatches.incr(1);
+                            }
+                            if failure {
                                 metrics.processes[&child.base_name]
                                     .failures.incr(1);
                                 metrics.failures.incr(1);
                             }
-                            metrics.processes[&child.base_name]
-                                .running.decr(1);
-                            metrics.running.decr(1);
-                            clean_child(&child.name, &master, true);
-                            queue.add(child.restart_min, Start(child));
+                            if child.ready {
+                                metrics.processes[&child.base_name]
+                                    .running.decr(1);
+                                metrics.running.decr(1);
+                            }
+                            knot_metrics.collect(master, &child.name,
+                                &metrics.processes[&child.base_name]);
+                            clean_child(&child.name, &master, true, reason);
+                            if reason == Reason::Retired {
+                                // removed from the config on SIGHUP
+                                continue;
+                            }
+                            if let Some(new) = replaced.remove(&child.name) {
+                                child = Process {
+                                    restarts: child.restarts,
+                                    recent_restarts: child.recent_restarts,
+                                    .. new
+                                };
+                            }
+                            child.restart_pending = false;
+                            if let Some(slot) = held.get_mut(&child.name) {
+                                info!("Container {:?} is held until started \
+                                    via control socket", child.name);
+                                metrics.held.incr(1);
+                                *slot = Held::Stopped(child);
+                                continue;
+                            }
+                            // by the tree or by an operator, the restart
+                            // policy only applies to the exits by itself
+                            let restart_requested = stopped_by_tree ||
+                                matches!(report, Some(ExitReport::Stopped));
+                            let restart_at = match report {
+                                // host is not going to change by itself
+                                Some(ExitReport::RequirementsNotMet {
+                                    ref message })
+                                => {
+                                    error!("Container {:?} can't run on \
+                                        this host: {}. Not restarting it \
+                                        until lithos_tree is reloaded",
+                                        child.name, message);
+                                    metrics.config_errors.incr(1);
+                                    continue;
+                                }
+                                // restart requested by operator, no backoff
+                                Some(ExitReport::Stopped)
+                                if !stopped_by_tree => Instant::now(),
+                                // setup failures are usually deterministic,
+                                // so they have their own (longer) backoff
+                                Some(ExitReport::SetupFailure {..}) => {
+                                    metrics.setup_failures.incr(1);
+                                    child.setup_failures += 1;
+                                    let policy = &master.setup_failure_policy;
+                                    if policy.give_up_after.map(|limit| {
+                                        child.setup_failures >= limit
+                                    }).unwrap_or(false) {
+                                        error!("Container {:?} failed to \
+                                            set up {} times in a row, not \
+                                            restarting it until lithos_tree \
+                                            is reloaded",
+                                            child.name, child.setup_failures);
+                                        metrics.setup_give_ups.incr(1);
+                                        continue;
+                                    }
+                                    max(child.restart_min, Instant::now() +
+                                        duration(policy.restart_delay(
+                                            child.setup_failures)))
+                                }
+                                _ => {
+                                    child.setup_failures = 0;
+                                    child.restart_min
+                                }
+                            };
+                            if !restart_requested {
+                                let now = Instant::now();
+                                if let Err(e) = check_restart_policy(
+                                    &mut child, failure, now)
+                                {
+                                    warn!("Container {:?} {}. It's held \
+                                        until started via control socket",
+                                        child.name, e);
+                                    metrics.held.incr(1);
+                                    held.insert(child.name.clone(),
+                                        Held::Stopped(child));
+                                    continue;
+                                }
+                            }
+                            child.restarts += 1;
+                            queue.add(restart_at, Start(child, reason));
                             metrics.queue.set(queue.len() as i64);
                         }
                         Some(Child::Unidentified(name)) => {
-                            clean_child(&name, &master, false);
+                            clean_child(&name, &master, false,
+                                Reason::Retired);
                             metrics.unknown.decr(1);
                         }
                         None => {
@@ -805,16 +1999,1032 @@ fn normal_loop(queue: &mut Queue<Timeout>,
                     }
                 }
             }
+            Some(SIGIO) => {
+                // image watcher noticed something new in image dirs
+                queue.expedite(Instant::now(),
+                    |t| matches!(*t, CheckImage(..)));
+                // or a command is received on the control socket
+                let requests = control.iter().flat_map(|c| c.try_iter());
+                for (request, conn) in requests {
+                    match request {
+                        Request::BorrowSockets(sandbox, child) => {
+                            conn.reply_with_fds(lend_sockets(
+                                &sandbox, &child, children, &held,
+                                sockets, unix_sockets, master));
+                        }
+                        Request::List => {
+                            conn.reply(Ok(list_containers(queue, children,
+                                &held, Instant::now())));
+                        }
+                        Request::Reread(sandbox) => {
+                            conn.reply(reread_sandbox(&sandbox, source,
+                                queue, children, &mut held, &mut replaced,
+                                metrics, master));
+                        }
+                        request => {
+                            conn.reply(handle_request(request, queue,
+                                children, &mut held, &mut draining,
+                                &mut waves, &mut group_starts,
+                                metrics, master));
+                        }
+                    }
+                }
+                metrics.queue.set(queue.len() as i64);
+            }
+            Some(SIGHUP) => {
+                info!("Received SIGHUP, reloading configs");
+                reload_configs(source, queue, children, &mut held,
+                    &mut replaced, metrics, master);
+                metrics.queue.set(queue.len() as i64);
+            }
             _ => unreachable!(),
         }
     }
 }
 
+/// Checks `restart-policy` and `max-restarts` of the process which
+/// exited by itself, and records the restart
+fn check_restart_policy(child: &mut Process, failure: bool, now: Instant)
+    -> Result<(), String>
+{
+    let cfg = &child.inner_config;
+    match cfg.restart_policy {
+        RestartPolicy::Always => {}
+        RestartPolicy::OnFailure if failure => {}
+        RestartPolicy::OnFailure => {
+            return Err(format!("exited successfully \
+                (restart-policy: on-failure)"));
+        }
+        RestartPolicy::Never => {
+            return Err(format!("exited (restart-policy: never)"));
+        }
+    }
+    if let Some(max) = cfg.max_restarts {
+        let window = duration(cfg.restart_window);
+        while child.recent_restarts.front()
+            .map(|&time| time + window <= now).unwrap_or(false)
+        {
+            child.recent_restarts.pop_front();
+        }
+        if child.recent_restarts.len() >= max as usize {
+            return Err(format!("was restarted {} times within {} seconds \
+                (max-restarts)", child.recent_restarts.len(),
+                cfg.restart_window));
+        }
+        child.recent_restarts.push_back(now);
+    }
+    Ok(())
+}
+
+fn handle_request(request: Request, queue: &mut Queue<Timeout>,
+    children: &mut HashMap<Pid, Child>,
+    held: &mut HashMap<String, Held>,
+    draining: &mut bool, waves: &mut StopWaves,
+    group_starts: &mut Vec<GroupStart>,
+    metrics: &metrics::Metrics, master: &MasterConfig)
+    -> Result<String, String>
+{
+    let (name, stop, start, is_group) = match request {
+        Request::GroupStart(name) => (name, false, true, true),
+        Request::GroupStop(name) => (name, true, false, true),
+        Request::GroupRestart(name) => (name, true, true, true),
+        Request::Start(name) => (name, false, true, false),
+        Request::Stop(name) => (name, true, false, false),
+        Request::Restart(name) => (name, true, true, false),
+        Request::Drain => {
+            if !*draining {
+                *draining = true;
+                metrics.draining.set(1);
+                write_drain_marker(master, "draining");
+            }
+            group_starts.clear();
+            let stopped = waves.drain(master, queue, children, held,
+                metrics);
+            warn!("Draining host, {} processes are stopping", stopped);
+            events::emit(master, "drain", json!({"processes": stopped}));
+            return Ok(format!("{} stopped", stopped));
+        }
+        Request::Undrain => {
+            *draining = false;
+            waves.clear(children, held);
+            metrics.draining.set(0);
+            remove_file(&master.drain_marker())
+                .map_err(|e| warn!("Can't remove drain marker: {}", e))
+                .ok();
+            let started = start_processes(|_, _| Some(0), Reason::Drain,
+                queue, held, metrics);
+            warn!("Host is undrained, {} processes started", started);
+            events::emit(master, "undrain", json!({"processes": started}));
+            return Ok(format!("{} started", started));
+        }
+        Request::BorrowSockets(..) | Request::List | Request::Reread(..)
+        => unreachable!(),
+    };
+    if start && *draining {
+        return Err(format!("host is drained, undrain it first"));
+    }
+    if is_group {
+        let group = master.run_groups.get(&name)
+            .ok_or_else(|| format!("no run group {:?}", name))?;
+        // the new command overrides the start in progress
+        group_starts.retain(|item| item.name != name);
+        let mut stopped = 0;
+        if stop {
+            // processes are held, so restarted ones are started in order
+            stopped = waves.stop(|sandbox, child| {
+                group.position(sandbox, child)
+            }, Reason::Operator, queue, children, held, metrics, master);
+        }
+        let mut started = 0;
+        if start {
+            let (mut item, num) = GroupStart::new(&name, group, held);
+            if item.advance(queue, children, held, metrics, master) {
+                group_starts.push(item);
+            }
+            started = num;
+        }
+        info!("Run group {:?}: {} stopped, {} started",
+            name, stopped, started);
+        return Ok(format!("{} stopped, {} started", stopped, started));
+    }
+    let position = |sandbox: &str, child: &str| {
+        if name_matches(&name, sandbox, child) { Some(0) } else { None }
+    };
+    let mut stopped = 0;
+    if stop {
+        stopped = stop_processes(&position, !start, Reason::Operator,
+            queue, children, held, metrics);
+    }
+    let mut started = 0;
+    if start {
+        started = start_processes(&position, Reason::Operator,
+            queue, held, metrics);
+    }
+    info!("Containers {:?}: {} stopped, {} started",
+        name, stopped, started);
+    Ok(format!("{} stopped, {} started", stopped, started))
+}
+
+/// Checks `sandbox` or `sandbox/child` name given to the control commands
+fn name_matches(name: &str, sandbox: &str, child: &str) -> bool {
+    let mut pair = name.splitn(2, '/');
+    pair.next() == Some(sandbox) && pair.next().map_or(true, |c| c == child)
+}
+
+/// Cgroup of the process as seen by the kernel, so it's correct for both
+/// `cgroup-name` and `systemd-scope`
+fn cgroup_path(pid: Pid) -> Option<String> {
+    let groups = cgroup::parse_cgroups(Some(i32::from(pid))).ok()?;
+    groups.by_name.get("memory")
+        // cgroup v2 has a single hierarchy without controller names
+        .or_else(|| groups.by_name.get(""))
+        .or_else(|| groups.all_groups.first())
+        .map(|group| group.1.display().to_string())
+}
+
+fn process_status(p: &Process, pid: Option<Pid>, state: &str, now: Instant)
+    -> serde_json::Value
+{
+    let addresses = p.addresses.iter().map(|a| a.to_string())
+        .chain(p.unix_sockets.iter().map(|s| s.path.display().to_string()))
+        .collect::<Vec<_>>();
+    json!({
+        "name": p.name,
+        "pid": pid.map(i32::from),
+        "state": state,
+        "uptime": pid.and(p.started).map(|s| (now - s).as_secs()),
+        "restarts": p.restarts,
+        "generation": p.generation,
+        "config_hash": ChildInstance::config_hash(&p.config),
+        "addresses": addresses,
+        "cgroup": pid.and_then(cgroup_path),
+    })
+}
+
+/// Describes containers as a JSON list, for the `list` command
+fn list_containers(queue: &Queue<Timeout>, children: &HashMap<Pid, Child>,
+    held: &HashMap<String, Held>, now: Instant)
+    -> String
+{
+    let mut items = Vec::new();
+    for (&pid, child) in children {
+        let item = match *child {
+            Child::Process(ref p) => {
+                let state = if p.stop_reason.is_some() {
+                    "stopping"
+                } else if !p.ready {
+                    "starting"
+                } else {
+                    "running"
+                };
+                process_status(p, Some(pid), state, now)
+            }
+            Child::Unidentified(ref name) => json!({
+                "name": name,
+                "pid": i32::from(pid),
+                "state": "unidentified",
+                "uptime": null,
+                "restarts": null,
+                "generation": null,
+                "config_hash": null,
+                "addresses": [],
+                "cgroup": cgroup_path(pid),
+            }),
+        };
+        items.push(item);
+    }
+    let waiting = queue.iter()
+        .filter_map(|t| match *t {
+            Start(ref p, _) => Some((p, "queued")),
+            _ => None,
+        })
+        // processes which are still stopping are listed above
+        .chain(held.values().filter_map(|h| match *h {
+            Held::Stopped(ref p) => Some((p, "held")),
+            Held::Stopping(..) => None,
+        }));
+    for (p, state) in waiting {
+        items.push(process_status(p, None, state, now));
+    }
+    items.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    serde_json::Value::Array(items).to_string()
+}
+
+/// Rereads configs of the sandbox, for the `reread` command
+///
+/// Running processes with changed config are restarted, queued and held
+/// ones just get the new config. Processes can't be added or removed
+/// without reloading `lithos_tree`, as their metrics are registered on
+/// start, so such changes are left until reload.
+fn reread_sandbox(name: &str, source: &ConfigSource,
+    queue: &mut Queue<Timeout>,
+    children: &mut HashMap<Pid, Child>,
+    held: &mut HashMap<String, Held>,
+    replaced: &mut HashMap<String, Process>,
+    metrics: &metrics::Metrics, master: &MasterConfig)
+    -> Result<String, String>
+{
+    let path = sandbox_dirs::list(&source.sandbox_paths)
+        .map_err(|e| format!("can't list sandboxes: {}", e))?
+        .remove(name)
+        .ok_or_else(|| format!("no sandbox {:?}", name))?;
+    let sandbox: SandboxConfig = parse_config(&path,
+        &SandboxConfig::validator(), &COptions::default())
+        .map_err(|e| format!("can't read {:?}: {}", path, e))?;
+    // processes aren't added, so the limit of instances can't be exceeded,
+    // but the config isn't saved as accepted either, as only a part of it
+    // is applied until reload
+    let cfg = read_process_config(master, &source.dirs, name.to_string(),
+        Rc::new(sandbox));
+    let mut pending = Vec::new();
+    let mut configs = read_subtree(master, &source.reader, cfg, &mut pending)
+        .into_iter()
+        .filter(|&(_, ref p)| metrics.processes.contains_key(&p.base_name))
+        .collect::<HashMap<_, _>>();
+    enforce_config_log_size(master, metrics);
+    let (restarted, updated, unchanged) = update_processes(&mut configs,
+        queue, children, held, replaced, metrics);
+    // i.e. new processes, or ones waiting for the image
+    let skipped = configs.len() + pending.len();
+    if skipped > 0 {
+        warn!("Sandbox {:?}: changes of {} processes are left until \
+            lithos_tree is reloaded", name, skipped);
+    }
+    info!("Sandbox {:?} is reread: {} restarted, {} updated, {} unchanged",
+        name, restarted, updated, unchanged);
+    Ok(format!("{} restarted, {} updated, {} unchanged, {} need reload",
+        restarted, updated, unchanged, skipped))
+}
+
+fn enforce_config_log_size(master: &MasterConfig,
+    metrics: &metrics::Metrics)
+{
+    if let Some(ref dir) = master.config_log_dir {
+        config_log::enforce_size(dir, master.config_log_max_size)
+            .map(|size| metrics.config_log_size.set(size as i64))
+            .map_err(|e| error!("Can't check size of config logs \
+                in {:?}: {}", dir, e))
+            .ok();
+    }
+}
+
+/// Rereads all the configs on `SIGHUP`, without restarting `lithos_tree`
+///
+/// Works like the in-place restart (`QUIT`): processes removed from the
+/// config are stopped, added ones are started and the ones with changed
+/// config are restarted. Metrics of the processes are registered on
+/// start, so children which are not known to this `lithos_tree` at all
+/// are left until the in-place restart.
+fn reload_configs(source: &mut ConfigSource,
+    queue: &mut Queue<Timeout>,
+    children: &mut HashMap<Pid, Child>,
+    held: &mut HashMap<String, Held>,
+    replaced: &mut HashMap<String, Process>,
+    metrics: &metrics::Metrics, master: &MasterConfig)
+{
+    match generation::resolve_dirs(master, &source.reader.master_file) {
+        Ok(dirs) => {
+            if dirs.generation() != source.dirs.generation() {
+                warn!("Using config generation {:?}", dirs.generation());
+            }
+            source.sandbox_paths = sandbox_dirs::dirs(master,
+                &source.reader.master_file, &dirs.sandboxes.path);
+            source.dirs = dirs;
+        }
+        Err(e) => {
+            error!("Can't resolve config dirs: {}. Reading the previous \
+                ones", e);
+        }
+    }
+    metrics.config_generation.set(source.dirs.generation()
+        .and_then(generation::generation_number)
+        .unwrap_or(0) as i64);
+    let (configs, sandboxes, pending, refused) = read_sandboxes(master,
+        &source.reader, &source.dirs, &source.sandbox_paths);
+    let unregistered = configs.values()
+        .flat_map(|p| p.addresses.iter().map(|a| a.to_string())
+            .chain(p.unix_sockets.iter()
+                .map(|s| s.path.display().to_string())))
+        .filter(|name| !metrics.addresses.contains_key(name))
+        .collect::<HashSet<_>>();
+    if !unregistered.is_empty() {
+        warn!("Metrics of {} new sockets are left until lithos_tree is \
+            restarted in-place (QUIT signal)", unregistered.len());
+    }
+    metrics.config_errors.incr(refused as u64);
+    metrics.containers.set(configs.len() as i64);
+    metrics.sandboxes.set(sandboxes as i64);
+    retention::cleanup_removed(master, &source.sandbox_paths, metrics);
+    // reload may have appended to the config logs
+    enforce_config_log_size(master, metrics);
+
+    let mut retired = 0;
+    for timeout in queue.extract(|t| match *t {
+        Start(ref p, _) => !configs.contains_key(&p.name),
+        CheckImage(..) => true,
+        Kill(..) => false,
+    }) {
+        match timeout {
+            Start(p, _) => {
+                info!("Container {:?} is removed from the config", p.name);
+                metrics.stops[&Reason::Retired].incr(1);
+                retired += 1;
+            }
+            CheckImage(..) => metrics.pending_image.decr(1),
+            Kill(..) => unreachable!(),
+        }
+    }
+    let removed = held.keys()
+        .filter(|name| !configs.contains_key(*name))
+        .cloned().collect::<Vec<_>>();
+    for name in removed {
+        if let Some(Held::Stopped(_)) = held.remove(&name) {
+            info!("Container {:?} is removed from the config", name);
+            metrics.held.decr(1);
+            metrics.stops[&Reason::Retired].incr(1);
+            retired += 1;
+        }
+    }
+    for (&pid, child) in children.iter_mut() {
+        let p = match *child {
+            Child::Process(ref mut p) => p,
+            Child::Unidentified(_) => continue,
+        };
+        if configs.contains_key(&p.name) ||
+            p.stop_reason == Some(Reason::Retired)
+        {
+            continue;
+        }
+        warn!("Retired child: {}, pid: {}. Sending SIGTERM...",
+            p.name, pid);
+        replaced.remove(&p.name);
+        p.stop_reason = Some(Reason::Retired);
+        kill(pid, Signal::SIGTERM)
+            .map_err(|e| error!("Error sending TERM to {}: {:?}", pid, e))
+            .ok();
+        retired += 1;
+    }
+
+    let (mut configs, unknown) = configs.into_iter()
+        .partition::<HashMap<_, _>, _>(|&(_, ref p)| {
+            metrics.processes.contains_key(&p.base_name)
+        });
+    let (restarted, updated, unchanged) = update_processes(&mut configs,
+        queue, children, held, replaced, metrics);
+    // processes which are not running, queued or held are started,
+    // including the ones which gave up restarting
+    let added = configs.len();
+    for (_, p) in &configs {
+        metrics.processes[&p.base_name]
+            .generation.set(p.generation as i64);
+    }
+    schedule_new_workers(configs, queue, Reason::Rollout);
+    let mut skipped = unknown.len();
+    for item in pending {
+        let base_name = (item.sandbox_name.clone(), item.child_name.clone());
+        if metrics.processes.contains_key(&base_name) {
+            metrics.pending_image.incr(1);
+            queue.add(Instant::now() + IMAGE_CHECK_INTERVAL,
+                CheckImage(item));
+        } else {
+            skipped += 1;
+        }
+    }
+    if skipped > 0 {
+        warn!("{} new processes are left until lithos_tree is restarted \
+            in-place (QUIT signal)", skipped);
+    }
+    info!("Configs are reloaded: {} started, {} stopped, {} restarted, \
+        {} updated, {} unchanged", added, retired, restarted, updated,
+        unchanged);
+}
+
+/// Passes new configs to the known processes, for `reread` and `SIGHUP`
+///
+/// Running processes with changed config are restarted, queued and held
+/// ones just get the new config. Configs of the processes found are
+/// removed from `configs`. Returns numbers of restarted, updated and
+/// unchanged processes.
+fn update_processes(configs: &mut HashMap<String, Process>,
+    queue: &mut Queue<Timeout>,
+    children: &mut HashMap<Pid, Child>,
+    held: &mut HashMap<String, Held>,
+    replaced: &mut HashMap<String, Process>,
+    metrics: &metrics::Metrics)
+    -> (usize, usize, usize)
+{
+    let mut restarted = 0;
+    let mut updated = 0;
+    let mut unchanged = 0;
+    let mut update = |old: &mut Process, configs: &mut HashMap<_, Process>| {
+        match configs.remove(&old.name) {
+            Some(ref new) if same_config(&new.config, &old.config) => {
+                unchanged += 1;
+            }
+            Some(new) => {
+                metrics.processes[&new.base_name]
+                    .generation.set(new.generation as i64);
+                *old = new;
+                updated += 1;
+            }
+            None => {}
+        }
+    };
+    queue.update(|t| if let Start(ref mut p, _) = *t {
+        update(p, configs);
+    });
+    for item in held.values_mut() {
+        if let Held::Stopped(ref mut p) = *item {
+            update(p, configs);
+        }
+    }
+    for (&pid, child) in children.iter_mut() {
+        let p = match *child {
+            Child::Process(ref mut p) => p,
+            Child::Unidentified(_) => continue,
+        };
+        let new = match configs.remove(&p.name) {
+            Some(new) => new,
+            None => continue,
+        };
+        if same_config(&new.config, &p.config) {
+            unchanged += 1;
+            continue;
+        }
+        metrics.processes[&new.base_name]
+            .generation.set(new.generation as i64);
+        p.rolling = new.rolling;
+        replaced.insert(p.name.clone(), new);
+        restarted += 1;
+        if p.rolling.is_some() {
+            p.restart_pending = true;
+        } else if p.stop_reason.is_none() {
+            p.stop_reason = Some(Reason::ConfigChange);
+            kill(pid, Signal::SIGTERM)
+                .map_err(|e| error!("Error sending TERM to {}: {:?}",
+                    pid, e))
+                .ok();
+        }
+    }
+    (restarted, updated, unchanged)
+}
+
+/// Opens sockets of the stopped daemon if needed, for `lithos_cmd`
+///
+/// Returns pairs of (target fd, fd). Sockets are kept open by us, so they
+/// are passed to the daemon again when it's started after the command.
+fn lend_sockets(sandbox: &str, child: &str,
+    children: &HashMap<Pid, Child>,
+    held: &HashMap<String, Held>,
+    sockets: &mut HashMap<InetAddr, Socket>,
+    unix_sockets: &mut HashMap<PathBuf, Socket>,
+    master: &MasterConfig)
+    -> Result<Vec<(RawFd, RawFd)>, String>
+{
+    let is_child = |p: &Process| {
+        p.base_name.0 == sandbox && p.base_name.1 == child
+    };
+    let running = children.values().any(|c| match *c {
+        Child::Process(ref p) => is_child(p),
+        Child::Unidentified(_) => false,
+    });
+    if running {
+        return Err(format!("{}/{} is running, stop its run group first",
+            sandbox, child));
+    }
+    // all instances have the same ports unless configured otherwise,
+    // so sockets of the first one are lent
+    let process = held.values()
+        .filter_map(|h| match *h {
+            Held::Stopped(ref p) if is_child(p) => Some(p),
+            _ => None,
+        })
+        .min_by(|a, b| a.name.cmp(&b.name))
+        .ok_or_else(|| format!("{}/{} is not stopped", sandbox, child))?;
+    if !process.lends_sockets {
+        return Err(format!("no command has inherit-sockets: {}", child));
+    }
+    if !socket_loans::is_lent(master, sandbox, child) {
+        return Err(format!("loan of {}/{} is not locked", sandbox, child));
+    }
+    let (uid, gid) = process.socket_cred;
+    let mut result = Vec::new();
+    for (&port, item) in &process.inner_config.tcp_ports {
+        if process.bridged_network && !item.external {
+            continue;  // opened by lithos_knot in container's namespace
+        }
+        if item.reuse_port {
+            continue;  // not held by us
+        }
+        for (sock_addr, target) in item.sockets(port) {
+            if target < 3 {
+                return Err(format!("passing fd {} is not supported",
+                    target));
+            }
+            let addr = InetAddr::from_std(&sock_addr);
+            if !sockets.contains_key(&addr) {
+                let fd = open_socket(addr, item, uid, gid)
+                    .map_err(|e| format!("can't open {}: {}", addr, e))?;
+                sockets.insert(addr, Socket {
+                    fd,
+                    last_owned: Instant::now(),
+                });
+            }
+            result.push((target, sockets[&addr].fd));
+        }
+    }
+    for spec in &process.unix_sockets {
+        if !unix_sockets.contains_key(&spec.path) {
+            let fd = unix_sockets::open(spec)
+                .map_err(|e| format!("can't open {:?}: {}", spec.path, e))?;
+            unix_sockets.insert(spec.path.clone(), Socket {
+                fd,
+                last_owned: Instant::now(),
+            });
+        }
+        result.push((spec.cfg.fd, unix_sockets[&spec.path].fd));
+    }
+    info!("Lending {} sockets of {}/{} to a command",
+        result.len(), sandbox, child);
+    Ok(result)
+}
+
+fn write_drain_marker(master: &MasterConfig, state: &str) {
+    let path = master.drain_marker();
+    write(&path, format!("{}\n", state))
+        .map_err(|e| error!("Can't write drain marker {:?}: {}", path, e))
+        .ok();
+}
+
+/// Position of the process when the host is drained
+///
+/// Processes which are not in any of the `run-groups` are stopped first,
+/// then processes of the groups in the reverse order of `children` (a
+/// process in several groups is stopped as the latest of its positions).
+fn drain_position(master: &MasterConfig, sandbox: &str, child: &str)
+    -> usize
+{
+    master.run_groups.values()
+        .filter_map(|group| group.position(sandbox, child))
+        .max()
+        .unwrap_or(usize::MAX)
+}
+
+/// Processes stopped by `drain` or `group-stop`, which are sent `SIGTERM`
+/// in waves
+///
+/// Each wave is the processes with the same position in the run group.
+/// The next wave is stopped when all the processes of the previous one
+/// have exited.
+struct StopWaves {
+    /// Waves not stopped yet, the next one is the last
+    pending: Vec<Wave>,
+    /// Processes of the current wave which are still running
+    current: Vec<Pid>,
+}
+
+struct Wave {
+    pids: Vec<Pid>,
+    reason: Reason,
+}
+
+impl StopWaves {
+    fn new() -> StopWaves {
+        StopWaves { pending: Vec::new(), current: Vec::new() }
+    }
+
+    /// Stops all the running processes, returns the number of processes
+    fn drain(&mut self, master: &MasterConfig,
+        queue: &mut Queue<Timeout>,
+        children: &mut HashMap<Pid, Child>,
+        held: &mut HashMap<String, Held>,
+        metrics: &metrics::Metrics)
+        -> usize
+    {
+        self.stop(|sandbox, child| {
+            Some(drain_position(master, sandbox, child))
+        }, Reason::Drain, queue, children, held, metrics, master)
+    }
+
+    /// Holds processes for which `position` is not `None` and stops
+    /// running ones, the highest position first
+    ///
+    /// Returns the number of processes which are stopped.
+    fn stop<F>(&mut self, position: F, reason: Reason,
+        queue: &mut Queue<Timeout>,
+        children: &mut HashMap<Pid, Child>,
+        held: &mut HashMap<String, Held>,
+        metrics: &metrics::Metrics, master: &MasterConfig)
+        -> usize
+        where F: Fn(&str, &str) -> Option<usize>
+    {
+        hold_queued(&position, queue, held, metrics);
+        let waiting = self.pending.iter()
+            .flat_map(|wave| wave.pids.iter())
+            .chain(&self.current)
+            .cloned()
+            .collect::<HashSet<_>>();
+        let mut stopping = Vec::new();
+        for (&pid, child) in children.iter() {
+            let p = match *child {
+                Child::Process(ref p) if p.stop_reason.is_none() => p,
+                _ => continue,
+            };
+            if waiting.contains(&pid) {
+                continue;
+            }
+            if let Some(pos) = position(&p.base_name.0, &p.base_name.1) {
+                // held right away, so isn't restarted if exits by itself
+                held.insert(p.name.clone(), Held::Stopping(
+                    p.base_name.0.clone(), p.base_name.1.clone()));
+                stopping.push((pos, pid));
+            }
+        }
+        // the last wave is stopped first
+        stopping.sort_by_key(|&(pos, _)| pos);
+        let num = stopping.len();
+        let mut last = None;
+        for (pos, pid) in stopping {
+            if last != Some(pos) {
+                self.pending.insert(0, Wave { pids: Vec::new(), reason });
+                last = Some(pos);
+            }
+            self.pending[0].pids.push(pid);
+        }
+        self.advance(children, held, master);
+        return num;
+    }
+
+    /// Forgets the processes not stopped by `drain` yet, on `undrain`
+    fn clear(&mut self, children: &HashMap<Pid, Child>,
+        held: &mut HashMap<String, Held>)
+    {
+        for wave in &self.pending {
+            if wave.reason != Reason::Drain {
+                continue;
+            }
+            for pid in &wave.pids {
+                if let Some(&Child::Process(ref p)) = children.get(pid) {
+                    if let Some(&Held::Stopping(..)) = held.get(&p.name) {
+                        held.remove(&p.name);
+                    }
+                }
+            }
+        }
+        self.pending.retain(|wave| wave.reason != Reason::Drain);
+    }
+
+    /// Stops the next wave if all processes of the current one have exited
+    fn advance(&mut self, children: &mut HashMap<Pid, Child>,
+        held: &HashMap<String, Held>, master: &MasterConfig)
+    {
+        loop {
+            self.current.retain(|pid| children.contains_key(pid));
+            if !self.current.is_empty() {
+                return;
+            }
+            let wave = match self.pending.pop() {
+                Some(wave) => wave,
+                None => return,
+            };
+            for &pid in &wave.pids {
+                if let Some(&mut Child::Process(ref mut p)) =
+                    children.get_mut(&pid)
+                {
+                    if p.stop_reason.is_some() ||
+                        !held.contains_key(&p.name)
+                    {
+                        // started again or stopped by something else
+                        continue;
+                    }
+                    info!("Stopping {:?} (pid: {}), reason: {}",
+                        p.name, pid, wave.reason);
+                    events::emit(master, "stop", json!({
+                        "process": p.name,
+                        "pid": i32::from(pid),
+                        "reason": wave.reason.as_str(),
+                    }));
+                    p.stop_reason = Some(wave.reason);
+                    kill(pid, Signal::SIGTERM)
+                        .map_err(|e| error!("Error sending TERM to {}: {:?}",
+                            pid, e))
+                        .ok();
+                }
+            }
+            self.current = wave.pids;
+        }
+    }
+}
+
+/// Progress of the process started by `GroupStart`
+#[derive(Debug, PartialEq)]
+enum Progress {
+    Starting,
+    Ready,
+    Failed,
+}
+
+/// Run group started by `group-start` or `group-restart`
+///
+/// Processes with the same position in the group are started at once, and
+/// the next position is started when all the processes of the previous one
+/// are running and ready (see `readiness`). When any of them fails to
+/// start, the rest of the group is left held.
+struct GroupStart {
+    name: String,
+    /// Positions not started yet, the next one is the last
+    pending: Vec<usize>,
+    /// Names of the processes of the current position
+    current: Vec<String>,
+}
+
+impl GroupStart {
+    /// Starts held processes of the group, returns their number
+    fn new(name: &str, group: &RunGroup, held: &HashMap<String, Held>)
+        -> (GroupStart, usize)
+    {
+        let mut positions = held.values()
+            .filter_map(|item| {
+                let (sandbox, child) = item.base_name();
+                group.position(sandbox, child)
+            })
+            .collect::<Vec<_>>();
+        let num = positions.len();
+        positions.sort();
+        positions.dedup();
+        positions.reverse();
+        let start = GroupStart {
+            name: name.to_string(),
+            pending: positions,
+            current: Vec::new(),
+        };
+        return (start, num);
+    }
+
+    /// Starts the next position if the current one is ready
+    ///
+    /// Returns false when the group is started or has failed.
+    fn advance(&mut self, queue: &mut Queue<Timeout>,
+        children: &HashMap<Pid, Child>,
+        held: &mut HashMap<String, Held>,
+        metrics: &metrics::Metrics, master: &MasterConfig)
+        -> bool
+    {
+        let group = match master.run_groups.get(&self.name) {
+            Some(group) => group,
+            None => return false,
+        };
+        loop {
+            let mut starting = false;
+            for name in &self.current {
+                match progress(name, queue, children) {
+                    Progress::Ready => {}
+                    Progress::Starting => starting = true,
+                    Progress::Failed => {
+                        self.fail(name, group, held, metrics, master);
+                        return false;
+                    }
+                }
+            }
+            if starting {
+                return true;
+            }
+            let pos = match self.pending.last() {
+                Some(&pos) => pos,
+                None => return false,
+            };
+            let members = held.iter()
+                .filter(|&(_, item)| {
+                    let (sandbox, child) = item.base_name();
+                    group.position(sandbox, child) == Some(pos)
+                })
+                .map(|(name, item)| {
+                    (name.clone(), matches!(*item, Held::Stopping(..)))
+                })
+                .collect::<Vec<_>>();
+            if members.iter().any(|&(_, stopping)| stopping) {
+                // restarted processes have not exited yet
+                return true;
+            }
+            self.pending.pop();
+            self.current.clear();
+            let now = Instant::now();
+            for (name, _) in members {
+                if let Some(Held::Stopped(process)) = held.remove(&name) {
+                    metrics.held.decr(1);
+                    queue.add(now, Start(process, Reason::Operator));
+                    self.current.push(name);
+                }
+            }
+        }
+    }
+
+    /// Leaves the rest of the group held, because `failed` didn't start
+    fn fail(&self, failed: &str, group: &RunGroup,
+        held: &HashMap<String, Held>,
+        metrics: &metrics::Metrics, master: &MasterConfig)
+    {
+        let mut rest = held.iter()
+            .filter(|&(_, item)| {
+                let (sandbox, child) = item.base_name();
+                group.position(sandbox, child)
+                    .map_or(false, |pos| self.pending.contains(&pos))
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        rest.sort();
+        error!("Run group {:?}: {:?} failed to start, \
+            {} processes are not started",
+            self.name, failed, rest.len());
+        for name in &rest {
+            warn!("Not starting {:?}, reason: {}",
+                name, Reason::DependencyFailed);
+            metrics.stops[&Reason::DependencyFailed].incr(1);
+        }
+        events::emit(master, "group-failed", json!({
+            "group": self.name,
+            "process": failed,
+            "held": rest,
+        }));
+    }
+}
+
+/// Checks whether the process started by `GroupStart` is running and ready
+///
+/// Process is considered failed if it is restarted with any reason other
+/// than the start itself, or is stopped before it's ready.
+fn progress(name: &str, queue: &Queue<Timeout>,
+    children: &HashMap<Pid, Child>)
+    -> Progress
+{
+    for child in children.values() {
+        if let Child::Process(ref p) = *child {
+            if p.name == name {
+                return match (p.ready, p.stop_reason) {
+                    (true, _) => Progress::Ready,
+                    (false, None) => Progress::Starting,
+                    (false, Some(_)) => Progress::Failed,
+                };
+            }
+        }
+    }
+    for timeout in queue.iter() {
+        if let Start(ref p, reason) = *timeout {
+            if p.name == name {
+                return if reason == Reason::Operator {
+                    Progress::Starting
+                } else {
+                    Progress::Failed
+                };
+            }
+        }
+    }
+    Progress::Failed
+}
+
+/// Holds processes waiting for restart, for which `position` is not `None`
+fn hold_queued<F>(position: F, queue: &mut Queue<Timeout>,
+    held: &mut HashMap<String, Held>,
+    metrics: &metrics::Metrics)
+    where F: Fn(&str, &str) -> Option<usize>
+{
+    for timeout in queue.extract(|t| match *t {
+        Start(ref p, _) => {
+            position(&p.base_name.0, &p.base_name.1).is_some()
+        }
+        _ => false,
+    }) {
+        if let Start(process, _) = timeout {
+            metrics.held.incr(1);
+            held.insert(process.name.clone(), Held::Stopped(process));
+        }
+    }
+}
+
+/// Sends SIGTERM to running processes in reverse order of `position`
+///
+/// Processes for which `position` returns `None` are left intact. If `hold`
+/// is true, processes are not restarted until started again via control
+/// socket.
+fn stop_processes<F>(position: F, hold: bool, reason: Reason,
+    queue: &mut Queue<Timeout>,
+    children: &mut HashMap<Pid, Child>,
+    held: &mut HashMap<String, Held>,
+    metrics: &metrics::Metrics)
+    -> usize
+    where F: Fn(&str, &str) -> Option<usize>
+{
+    if hold {
+        // processes waiting for restart are held right away
+        hold_queued(&position, queue, held, metrics);
+    }
+    let mut stopping = Vec::new();
+    for (&pid, child) in children.iter_mut() {
+        if let Child::Process(ref mut p) = *child {
+            if p.stop_reason.is_some() {
+                continue;  // already stopping
+            }
+            if let Some(pos) = position(&p.base_name.0, &p.base_name.1) {
+                p.stop_reason = Some(reason);
+                stopping.push((pos, pid));
+                if hold {
+                    held.insert(p.name.clone(), Held::Stopping(
+                        p.base_name.0.clone(), p.base_name.1.clone()));
+                }
+            }
+        }
+    }
+    stopping.sort_by(|a, b| b.0.cmp(&a.0));
+    for &(_, pid) in &stopping {
+        kill(pid, Signal::SIGTERM)
+            .map_err(|e| error!("Error sending TERM to {}: {:?}", pid, e))
+            .ok();
+    }
+    return stopping.len();
+}
+
+/// Starts held processes in order of `position`
+///
+/// Processes which are still stopping are restarted as usual when they exit.
+fn start_processes<F>(position: F, reason: Reason,
+    queue: &mut Queue<Timeout>,
+    held: &mut HashMap<String, Held>,
+    metrics: &metrics::Metrics)
+    -> usize
+    where F: Fn(&str, &str) -> Option<usize>
+{
+    let names = held.iter()
+        .filter(|&(_, item)| {
+            let (sandbox, child) = item.base_name();
+            position(sandbox, child).is_some()
+        })
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    let mut processes = Vec::new();
+    for name in names {
+        if let Some(Held::Stopped(process)) = held.remove(&name) {
+            metrics.held.decr(1);
+            processes.push(process);
+        }
+    }
+    processes.sort_by_key(|p| position(&p.base_name.0, &p.base_name.1));
+    let now = Instant::now();
+    let num = processes.len();
+    for (idx, process) in processes.into_iter().enumerate() {
+        // distinct deadlines keep the order in the queue
+        queue.add(now + Duration::from_millis(idx as u64),
+            Start(process, reason));
+    }
+    return num;
+}
+
 fn shutdown_loop(children: &mut HashMap<Pid, Child>,
     sockets: &mut HashMap<InetAddr, Socket>,
+    unix_sockets: &mut HashMap<PathBuf, Socket>,
     trap: &mut Trap,
     metrics: &metrics::Metrics,
-    master: &MasterConfig)
+    master: &MasterConfig,
+    firewall: &mut Option<Firewall>)
 {
     for sig in trap {
         match sig {
@@ -837,8 +3047,9 @@ fn shutdown_loop(children: &mut HashMap<Pid, Child>,
                 for (pid, status) in reap_zombies() {
                     match children.remove(&Pid::from_raw(pid)) {
                         Some(Child::Process(child)) => {
-                            info!("Container {:?} (pid {}) {}",
-                                child.name, pid, status);
+                            info!("Container {:?} (pid {}) {}, reason: {}",
+                                child.name, pid, status, Reason::Shutdown);
+                            metrics.stops[&Reason::Shutdown].incr(1);
                             metrics.processes[&child.base_name]
                                 .deaths.incr(1);
                             metrics.deaths.incr(1);
@@ -847,13 +3058,17 @@ fn shutdown_loop(children: &mut HashMap<Pid, Child>,
                                     .failures.incr(1);
                                 metrics.failures.incr(1);
                             }
-                            metrics.processes[&child.base_name]
-                                .running.decr(1);
-                            metrics.running.decr(1);
-                            clean_child(&child.name, &master, false);
+                            if child.ready {
+                                metrics.processes[&child.base_name]
+                                    .running.decr(1);
+                                metrics.running.decr(1);
+                            }
+                            clean_child(&child.name, &master, false,
+                                Reason::Shutdown);
                         }
                         Some(Child::Unidentified(name)) => {
-                            clean_child(&name, &master, false);
+                            clean_child(&name, &master, false,
+                                Reason::Retired);
                             metrics.unknown.decr(1);
                         }
                         None => {
@@ -864,215 +3079,508 @@ fn shutdown_loop(children: &mut HashMap<Pid, Child>,
                 // In case we will wait for some process for the long time
                 // we want to close tcp ports as fast as possible, so that
                 // our upstream/monitoring notice the socket is closed
-                close_unused_sockets(sockets, children);
+                close_unused_sockets(sockets, unix_sockets, children,
+                    &HashMap::new());
+                if let Some(ref mut firewall) = *firewall {
+                    firewall.update(host_ports(children));
+                }
                 if children.len() == 0 {
                     return;
                 }
             }
+            SIGIO | SIGHUP => {
+                // images and configs are not interesting any more
+                continue;
+            }
             _ => unreachable!(),
         }
     }
 }
 
-fn read_sandboxes(master: &MasterConfig, bin: &Binaries,
-    master_file: &Path, options: &Options)
-    -> (HashMap<String, Process>, usize)
+/// Process config of a sandbox which is not instantiated yet
+struct SandboxChildren {
+    name: String,
+    sandbox: Rc<SandboxConfig>,
+    /// Process config file
+    path: PathBuf,
+    children: BTreeMap<String, ChildConfig>,
+    /// New config is read successfully and is not refused
+    accepted: bool,
+}
+
+fn read_sandboxes(master: &MasterConfig, reader: &Rc<Reader>,
+    dirs: &ConfigDirs, sandbox_paths: &[PathBuf])
+    -> (HashMap<String, Process>, usize, Vec<PendingImage>, usize)
 {
-    let mut sandboxes = 0;
-    let dirpath = master_file.parent().unwrap().join(&master.sandboxes_dir);
-    info!("Reading sandboxes from {:?}", dirpath);
+    let mut pending = Vec::new();
+    info!("Reading sandboxes from {:?}", sandbox_paths);
     let sandbox_validator = SandboxConfig::validator();
-    let result = scan_dir::ScanDir::files().read(&dirpath, |iter| {
-        let yamls = iter.filter(|&(_, ref name)| name.ends_with(".yaml"));
-        yamls.filter_map(|(entry, name)| {
-            let sandbox_config = entry.path();
-            let sandbox_name = name[..name.len()-5].to_string();
+    let mut configs = sandbox_dirs::list(sandbox_paths).map(|configs| {
+        configs.into_iter().filter_map(|(sandbox_name, sandbox_config)| {
+            if !reader.options.sandbox_selected(&sandbox_name) {
+                info!("Skipping sandbox {:?} (filtered out by command-line)",
+                    sandbox_name);
+                return None;
+            }
             debug!("Reading config: {:?}", sandbox_config);
             parse_config(&sandbox_config, &sandbox_validator, &COptions::default())
                 .map_err(|e| error!("Can't read config {:?}: {}",
                                     sandbox_config, e))
-                .map(|cfg: SandboxConfig| (sandbox_name, cfg))
+                .map(|cfg: SandboxConfig| (sandbox_name, Rc::new(cfg)))
                 .ok()
-        }).flat_map(|(name, sandbox)| {
-            sandboxes += 1;
-            read_subtree(master, bin, master_file, &name, &sandbox, options)
-            .into_iter()
-        }).collect()
+        }).map(|(name, sandbox)| {
+            read_process_config(master, dirs, name, sandbox)
+        }).collect::<Vec<_>>()
     })
     .map_err(|e| error!("Error reading sandboxes directory: {}", e))
-    .unwrap_or(HashMap::new());
-    (result, sandboxes)
-}
-
-fn open_config_log(base: &Path, name: &str) -> Result<File, io::Error> {
-    let target_name = base.join(name);
-    let file = OpenOptions::new().create(true).write(true).append(true)
-        .open(&target_name)?;
-    let logmeta = file.metadata()?;
-    if logmeta.len() > CONFIG_LOG_SIZE {
-        let lastname = base.join(format!("{}.{}", name, MAX_CONFIG_LOGS));
-        match remove_file(&lastname) {
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-            Err(e) => {
-                error!("Can't remove log file {:?}: {}", lastname, e);
-            }
-            Ok(()) => {
-                debug!("Removed {:?}", lastname);
-            }
+    .unwrap_or(Vec::new());
+    let refused = check_budget(master, reader, dirs, &mut configs);
+    if master.max_total_instances.is_some() {
+        for cfg in configs.iter().filter(|cfg| cfg.accepted) {
+            accepted::save(master, &cfg.name, &cfg.path)
+                .map_err(|e| error!("Can't save accepted config \
+                    of sandbox {:?}: {}", cfg.name, e))
+                .ok();
+        }
+    }
+    let sandboxes = configs.len();
+    let result = configs.into_iter().flat_map(|cfg| {
+        read_subtree(master, reader, cfg, &mut pending).into_iter()
+    }).collect();
+    (result, sandboxes, pending, refused)
+}
+
+fn read_children(master: &MasterConfig, dirs: &ConfigDirs,
+    sandbox_name: &String, sandbox: &SandboxConfig, cfg: &Path)
+    -> Result<BTreeMap<String, ChildConfig>, String>
+{
+    let validator = ChildConfig::mapping_validator_with(
+        &master.container_defaults);
+    parse_config(cfg, &validator, &COptions::default())
+        .map(|cfg: BTreeMap<String, ChildConfig>| {
+            cfg.into_iter().filter_map(|(name, mut child)| {
+                render_child(&mut child, &dirs.processes.path,
+                    &sandbox.template_values, &master.container_defaults)
+                .map_err(|e| error!("Can't make config of {}/{}: {}",
+                    sandbox_name, name, e))
+                .ok()
+                .map(|()| (name, child))
+            }).collect()
+        })
+        .map_err(|e| e.to_string())
+}
+
+/// Reads the current process config of the sandbox
+fn read_process_config(master: &MasterConfig, dirs: &ConfigDirs,
+    name: String, sandbox: Rc<SandboxConfig>)
+    -> SandboxChildren
+{
+    let path = dirs.processes.path
+        .join(sandbox.config_file.as_ref().map(Path::new)
+            .unwrap_or(Path::new(&(name.clone() + ".yaml"))));
+    debug!("Reading child config {:?}", path);
+    let (children, accepted) = match read_children(master, dirs,
+        &name, &sandbox, &path)
+    {
+        Ok(children) => (children, true),
+        Err(e) => {
+            warn!("Can't read config {:?}: {}", path, e);
+            (BTreeMap::new(), false)
+        }
+    };
+    SandboxChildren { name, sandbox, path, children, accepted }
+}
+
+/// Number of instances of the daemons which are run by this tree
+fn instance_count(reader: &Reader, sandbox_name: &str,
+    children: &BTreeMap<String, ChildConfig>)
+    -> usize
+{
+    children.iter()
+        .filter(|&(name, child)| {
+            child.kind == Daemon &&
+            reader.options.child_selected(sandbox_name, name)
+        })
+        .map(|(_, child)| child.instances)
+        .sum()
+}
+
+fn total_instances(reader: &Reader, configs: &[SandboxChildren]) -> usize {
+    configs.iter()
+        .map(|cfg| instance_count(reader, &cfg.name, &cfg.children))
+        .sum()
+}
+
+/// Checks `max-total-instances`, returns the number of refused configs
+///
+/// If new configs of all the sandboxes fit the limit, all of them are
+/// accepted. Otherwise every config which has more instances than the last
+/// accepted config of the same sandbox is refused and the accepted one is
+/// used instead, so the result doesn't depend on the order of sandboxes.
+/// If even the previous configs don't fit, refused sandboxes are not run.
+fn check_budget(master: &MasterConfig, reader: &Reader, dirs: &ConfigDirs,
+    configs: &mut [SandboxChildren])
+    -> usize
+{
+    let max = match master.max_total_instances {
+        Some(max) => max,
+        None => return 0,
+    };
+    let total = total_instances(reader, configs);
+    if total <= max {
+        return 0;
+    }
+    let mut refused = Vec::new();
+    for (idx, cfg) in configs.iter_mut().enumerate() {
+        if !cfg.accepted {
+            continue;
+        }
+        let previous = accepted::path(master, &cfg.name);
+        let children = if previous.exists() {
+            read_children(master, dirs, &cfg.name, &cfg.sandbox, &previous)
+            .map_err(|e| error!("Can't read previous config {:?}: {}",
+                previous, e))
+            .unwrap_or(BTreeMap::new())
+        } else {
+            BTreeMap::new()
         };
-        let mut prevname = lastname;
-        for i in (1..MAX_CONFIG_LOGS).rev() {
-            let curname = base.join(format!("{}.{}", name, i));
-            match rename(&curname, &prevname) {
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                Err(e) => {
-                    error!("Can't rename log file {:?}: {}", curname, e);
-                }
-                Ok(()) => {
-                    debug!("Renamed {:?}", curname);
-                }
-            };
-            prevname = curname;
+        let count = instance_count(reader, &cfg.name, &cfg.children);
+        let old_count = instance_count(reader, &cfg.name, &children);
+        if count <= old_count {
+            continue;
         }
-        match rename(&target_name, &prevname) {
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-            Err(e) => {
-                error!("Can't rename log file {:?}: {}", target_name, e);
-            }
-            Ok(()) => {
-                debug!("Renamed {:?}", target_name);
+        error!("Config of sandbox {:?} has {} instances ({} before), \
+            total number of instances would be {}, which is more than \
+            max-total-instances ({}). Refusing it and keeping the previous \
+            config", cfg.name, count, old_count, total, max);
+        if !previous.exists() {
+            error!("No previous config of sandbox {:?}, \
+                not running it", cfg.name);
+        }
+        events::emit(master, "config-refused", json!({
+            "sandbox": cfg.name,
+            "instances": count,
+            "previous_instances": old_count,
+            "total_instances": total,
+            "max_total_instances": max,
+        }));
+        cfg.children = children;
+        cfg.accepted = false;
+        refused.push(idx);
+    }
+    let total = total_instances(reader, configs);
+    if total > max {
+        for &idx in &refused {
+            let cfg = &mut configs[idx];
+            if !cfg.children.is_empty() {
+                error!("Previous config of sandbox {:?} doesn't fit \
+                    max-total-instances ({}) either, not running it",
+                    cfg.name, max);
+                cfg.children.clear();
             }
+        }
+        let total = total_instances(reader, configs);
+        if total > max {
+            error!("Sandboxes which configs didn't grow have {} instances, \
+                which is more than max-total-instances ({}). \
+                Running them anyway", total, max);
+        }
+    }
+    return refused.len();
+}
+
+fn read_subtree(master: &MasterConfig, reader: &Rc<Reader>,
+    cfg: SandboxChildren, pending: &mut Vec<PendingImage>)
+    -> Vec<(String, Process)>
+{
+    let SandboxChildren { name, sandbox, children, accepted, .. } = cfg;
+    let (sandbox_name, sandbox) = (&name, &sandbox);
+    if accepted {
+        // refused configs are not logged, the previous one is logged
+        // when it was accepted
+        config_log::write(master, sandbox_name, sandbox, &children);
+    }
+    let reserved_ips = reserved_ips(sandbox, &children);
+    let mut ipam = open_ipam(master, sandbox_name, sandbox,
+        reserved_ips.clone());
+    let mut ids = InstanceIds::load(master, sandbox_name);
+    ids.retain(|name| children.contains_key(name));
+    let mut gens = Generations::load(master, sandbox_name);
+    gens.retain(|name| children.contains_key(name));
+    let lenders = children.values()
+        .filter(|child| child.kind == ChildKind::Command)
+        .filter_map(|child| child.inherit_sockets.clone())
+        .collect::<HashSet<_>>();
+    let mut result = Vec::new();
+    for (child_name, child) in children {
+        if child.kind != Daemon {
+            continue;
+        }
+        if !reader.options.child_selected(sandbox_name, &child_name) {
+            info!("Skipping {}/{} (filtered out by command-line)",
+                sandbox_name, child_name);
+            continue;
+        }
+        let item = PendingImage {
+            reader: reader.clone(),
+            sandbox_name: sandbox_name.clone(),
+            sandbox: sandbox.clone(),
+            lends_sockets: lenders.contains(&child_name),
+            child_name,
+            child,
+            reserved_ips: reserved_ips.clone(),
         };
-        // reopen same path
-        OpenOptions::new().create(true).write(true).append(true)
-           .open(target_name)
-    } else {
-        Ok(file)
+        if !item.image_exists() {
+            warn!("Image {:?} of {}/{} doesn't exist, waiting for it",
+                item.child.image, sandbox_name, item.child_name);
+            pending.push(item);
+            continue;
+        }
+        let generation = gens.get(&item.child_name, &item.child.config_hash());
+        result.extend(read_child(&item, &mut ipam, &mut ids, generation));
     }
+    save_instance_ids(&ids, sandbox_name);
+    save_generations(&gens, sandbox_name);
+    if let Some(ref ipam) = ipam {
+        ipam.save(master, sandbox_name)
+            .map_err(|e| error!("Can't save address allocations \
+                of sandbox {:?}: {}", sandbox_name, e))
+            .ok();
+    }
+    result
 }
 
-fn read_subtree<'x>(master: &MasterConfig,
-    bin: &Binaries, master_file: &Path,
-    sandbox_name: &String, sandbox: &SandboxConfig,
-    options: &Options)
+/// Makes processes of all instances of the child
+fn read_child(item: &PendingImage,
+    ipam: &mut Option<Ipam>, ids: &mut InstanceIds, generation: u64)
     -> Vec<(String, Process)>
 {
     let now = Instant::now();
-    let cfg = master_file.parent().unwrap()
-        .join(&master.processes_dir)
-        .join(sandbox.config_file.as_ref().map(Path::new)
-            .unwrap_or(Path::new(&(sandbox_name.clone() + ".yaml"))));
-    debug!("Reading child config {:?}", cfg);
-    parse_config(&cfg, &ChildConfig::mapping_validator(), &COptions::default())
-        .map(|cfg: BTreeMap<String, ChildConfig>| {
-            if let Some(ref config_log_dir) = master.config_log_dir {
-                open_config_log(config_log_dir,
-                                &format!("{}.log", sandbox_name))
-                .and_then(|mut f| {
-                    // we want as atomic writes as possible,
-                    // so format into a buf
-                    let buf = format!("{} {}\n",
-                        format_rfc3339_seconds(SystemTime::now()),
-                        to_string(&cfg).unwrap());
-                    f.write_all(buf.as_bytes())
-                })
-                .map_err(|e| error!("Error writing config log: {}", e))
-                .ok();
+    let PendingImage {
+        ref reader, ref sandbox_name, ref sandbox, ref child_name, ref child,
+        lends_sockets, ..
+    } = *item;
+    let image_dir = sandbox.image_dir.join(&child.image);
+    let cfg_res = match child.container {
+        Some(ref inline) => parse_inline_container(inline.as_json())
+            .map_err(|e| format!("{} (process {:?} of sandbox {:?})",
+                e, child_name, sandbox_name)),
+        None => read_container_config(&image_dir, Path::new(&child.config),
+                sandbox.default_container_config.as_deref(),
+                &reader.container_defaults)
+            .map_err(|e| format!("Error reading {:?} \
+                of sandbox {:?} of image {:?}: {}",
+                &child.config, sandbox_name, child.image,  e)),
+    };
+    let cfg: ContainerConfig = match cfg_res {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("{}", e);
+            return Vec::new();
+        }
+    };
+    if let Err(e) = reader.host_facts.kernel_features
+        .check(&reader.cgroup_controllers, sandbox, &cfg)
+    {
+        error!("Process {:?} of sandbox {:?} can't run on this host: {}",
+            child_name, sandbox_name, e);
+        return Vec::new();
+    }
+    if !cfg.unix_sockets.is_empty() && sandbox.unix_sockets_dir.is_none() {
+        error!("Process {:?} of sandbox {:?} has unix-sockets, but \
+            sandbox has no unix-sockets-dir", child_name, sandbox_name);
+        return Vec::new();
+    }
+    // ids as seen from the host, for sockets opened by us
+    let host_ids = |uid: u32, gid: u32| {
+        if sandbox.uid_map.len() > 0 {
+            (sandbox.uid_map.map_id(uid).unwrap_or(0),
+             sandbox.gid_map.map_id(gid).unwrap_or(0))
+        } else if cfg.uid_map.len() > 0 {
+            (cfg.uid_map.map_id(uid).unwrap_or(0),
+             cfg.gid_map.map_id(gid).unwrap_or(0))
+        } else {
+            (uid, gid)
+        }
+    };
+    let (sock_uid, sock_gid) = host_ids(
+        cfg.user_id.or(sandbox.default_user)
+            // don't care sock_uid so much
+            .unwrap_or(0),
+        cfg.group_id.or(sandbox.default_group)
+            // don't care sock_gid so much
+            .unwrap_or(0));
+
+    let rolling = child.max_parallel_restarts.map(|max_parallel| {
+        RollingRestart {
+            max_parallel,
+            delay: duration(child.restart_batch_delay.unwrap_or(0.)),
+        }
+    });
+    let mut items = Vec::<(String, Process)>::new();
+    for i in ids.allocate(child_name, child.instances) {
+        let name = format!("{}/{}.{}", sandbox_name, child_name, i);
+        let mut child = match child.instantiate(i) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Error instantiating child {:?} \
+                        of sandbox {:?}: {}",
+                        child_name, sandbox_name, e);
+                continue;
             }
-            cfg
-        })
-        .map_err(|e| warn!("Can't read config {:?}: {}", cfg, e))
-        .unwrap_or(BTreeMap::new())
-        .into_iter()
-        .filter(|&(_, ref child)| child.kind == Daemon)
-        .flat_map(|(child_name, child)| {
-            let instances = child.instances;
-
-            let image_dir = sandbox.image_dir.join(&child.image);
-            let cfg_res = temporary_change_root(&image_dir, || {
-                parse_config(&child.config,
-                    &ContainerConfig::validator(), &COptions::default())
-                .map_err(|e| format!("Error reading {:?} \
-                    of sandbox {:?} of image {:?}: {}",
-                    &child.config, sandbox_name, child.image,  e))
-            });
-            let cfg: ContainerConfig = match cfg_res {
-                Ok(cfg) => cfg,
-                Err(e) => {
-                    error!("{}", e);
-                    return Vec::new().into_iter();
+        };
+        if let Some(ref mut ipam) = *ipam {
+            if child.ip_address.is_none() {
+                child.ip_address = ipam.allocate(&name);
+                if child.ip_address.is_none() {
+                    error!("No free addresses in the pool \
+                        for {:?}", name);
+                    continue;
                 }
+            }
+        }
+        let cfg = match cfg.instantiate(&Variables {
+                user_vars: &child.variables,
+                lithos_name: &name,
+                lithos_config_filename: &child.config,
+                host_facts: Some(&reader.host_facts),
+                secrets: None,
+            }) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Variable substitution error {:?} \
+                    of sandbox {:?} of image {:?}: {}",
+                    &child.config, sandbox_name, child.image,
+                    e.join("; "));
+                continue;
+            }
+        };
+        if let Err(e) = cfg.check_exec_size(sandbox.max_environ_size) {
+            error!("Process {:?} of sandbox {:?} of image {:?} can't be \
+                started: {}", &child.config, sandbox_name, child.image,
+                e.join("; "));
+            continue;
+        }
+        let unix_sockets = cfg.unix_sockets.iter().map(|(name, sock)| {
+            let (uid, gid) = match (sock.user, sock.group) {
+                (None, None) => (sock_uid, sock_gid),
+                (user, group) => host_ids(
+                    user.or(cfg.user_id).or(sandbox.default_user)
+                        .unwrap_or(0),
+                    group.or(cfg.group_id).or(sandbox.default_group)
+                        .unwrap_or(0)),
             };
-            let mut sock_uid = cfg.user_id.or(sandbox.default_user)
-                // don't care sock_uid so much
-                .unwrap_or(0);
-            let mut sock_gid = cfg.group_id.or(sandbox.default_group)
-                // don't care sock_gid so much
-                .unwrap_or(0);
-            if sandbox.uid_map.len() > 0 {
-                sock_uid = sandbox.uid_map.map_id(sock_uid).unwrap_or(0);
-                sock_gid = sandbox.gid_map.map_id(sock_gid).unwrap_or(0);
-            } else if cfg.uid_map.len() > 0 {
-                sock_uid = cfg.uid_map.map_id(sock_uid).unwrap_or(0);
-                sock_gid = cfg.gid_map.map_id(sock_gid).unwrap_or(0);
-            }
-
-            let mut items = Vec::<(String, Process)>::new();
-            for i in 0..instances {
-                let name = format!("{}/{}.{}", sandbox_name, child_name, i);
-                let child = match child.instantiate(i) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("Error instantiating child {:?} \
-                                of sandbox {:?}: {}",
-                                child_name, sandbox_name, e);
-                        continue;
-                    }
-                };
-                let cfg = match cfg.instantiate(&Variables {
-                        user_vars: &child.variables,
-                        lithos_name: &name,
-                        lithos_config_filename: &child.config,
-                    }) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("Variable substitution error {:?} \
-                            of sandbox {:?} of image {:?}: {}",
-                            &child.config, sandbox_name, child.image,
-                            e.join("; "));
-                        continue;
-                    }
-                };
-                let child_string = to_string(&child)
-                    .expect("can always serialize child config");
-                let cmd = new_child(bin, &name, master_file,
-                    &child_string, options, &sandbox);
-                let restart_min = now + duration(cfg.restart_timeout);
-                let process = Process {
-                    cmd: cmd,
-                    name: name.clone(),
-                    base_name: (sandbox_name.clone(), child_name.clone()),
-                    restart_min: restart_min,
-                    config: child_string,
-                    addresses: cfg.tcp_ports.iter().map(|(&port, item)| {
-                            InetAddr::from_std(
-                                &SocketAddr::new(item.host.0, port))
-                        }).collect(),
-                    inner_config: cfg,
-                    socket_cred: (sock_uid, sock_gid),
-                    bridged_network: sandbox.bridged_network.is_some(),
-                };
-                items.push((name, process));
+            unix_sockets::Spec {
+                path: sandbox.unix_sockets_dir.as_ref()
+                    .expect("checked above").join(name),
+                uid, gid,
+                cfg: sock.clone(),
             }
-            items.into_iter()
-        }).collect()
+        }).collect();
+        let child_string = to_string(&child)
+            .expect("can always serialize child config");
+        let config_fd = config_fd::choose(&cfg);
+        let cmd = new_child(&reader.bin, &name, &reader.master_file,
+            config_fd, generation, &reader.options, &sandbox,
+            reader.systemd_scope);
+        let restart_min = now + duration(cfg.restart_timeout);
+        let process = Process {
+            cmd: cmd,
+            name: name.clone(),
+            base_name: (sandbox_name.clone(), child_name.clone()),
+            restart_min: restart_min,
+            config: child_string,
+            addresses: cfg.tcp_ports.iter().flat_map(|(&port, item)| {
+                    item.sockets(port).into_iter()
+                    .map(|(addr, _)| InetAddr::from_std(&addr))
+                }).collect(),
+            inner_config: cfg,
+            unix_sockets,
+            socket_cred: (sock_uid, sock_gid),
+            bridged_network: sandbox.bridged_network.is_some(),
+            stop_reason: None,
+            setup_failures: 0,
+            address_deadline: None,
+            generation,
+            config_fd,
+            lends_sockets,
+            ready: false,
+            started: None,
+            restarts: 0,
+            rolling,
+            restart_pending: false,
+            recent_restarts: VecDeque::new(),
+        };
+        items.push((name, process));
+    }
+    items
+}
+
+/// Makes processes of the child which image has appeared
+fn read_pending(master: &MasterConfig, item: &PendingImage)
+    -> Vec<(String, Process)>
+{
+    let mut ipam = open_ipam(master, &item.sandbox_name, &item.sandbox,
+        item.reserved_ips.clone());
+    let mut ids = InstanceIds::load(master, &item.sandbox_name);
+    let mut gens = Generations::load(master, &item.sandbox_name);
+    let generation = gens.get(&item.child_name, &item.child.config_hash());
+    let result = read_child(item, &mut ipam, &mut ids, generation);
+    save_instance_ids(&ids, &item.sandbox_name);
+    save_generations(&gens, &item.sandbox_name);
+    if let Some(ref ipam) = ipam {
+        ipam.save(master, &item.sandbox_name)
+            .map_err(|e| error!("Can't save address allocations \
+                of sandbox {:?}: {}", item.sandbox_name, e))
+            .ok();
+    }
+    result
+}
+
+/// Addresses which can't be allocated from the pool of the sandbox
+fn reserved_ips(sandbox: &SandboxConfig,
+    children: &BTreeMap<String, ChildConfig>)
+    -> Vec<IpAddr>
+{
+    let mut reserved = Vec::new();
+    if let Some(ref net) = sandbox.bridged_network {
+        reserved.extend(net.default_gateway);
+    }
+    for child in children.values() {
+        reserved.extend(child.ip_addresses.iter().cloned());
+    }
+    reserved
+}
+
+fn save_instance_ids(ids: &InstanceIds, sandbox_name: &str) {
+    ids.save()
+        .map_err(|e| error!("Can't save instance numbers \
+            of sandbox {:?}: {}", sandbox_name, e))
+        .ok();
+}
+
+fn save_generations(gens: &Generations, sandbox_name: &str) {
+    gens.save()
+        .map_err(|e| error!("Can't save config generations \
+            of sandbox {:?}: {}", sandbox_name, e))
+        .ok();
+}
+
+/// Opens address allocations if sandbox has an address pool
+fn open_ipam(master: &MasterConfig, sandbox_name: &str,
+    sandbox: &SandboxConfig, reserved: Vec<IpAddr>)
+    -> Option<Ipam>
+{
+    let net = sandbox.bridged_network.as_ref()?;
+    let pool = net.ip_pool?;
+    Some(Ipam::load(master, sandbox_name, pool, reserved))
 }
 
 fn schedule_new_workers(configs: HashMap<String, Process>,
-    queue: &mut Queue<Timeout>)
+    queue: &mut Queue<Timeout>, reason: Reason)
 {
     for (_, item) in configs.into_iter() {
-        queue.add(Instant::now(), Start(item));
+        queue.add(Instant::now(), Start(item, reason));
     }
 }
 
@@ -1081,36 +3589,55 @@ struct Binaries {
     lithos_knot: PathBuf,
 }
 
-fn get_binaries() -> Option<Binaries> {
-    let dir = match env::current_exe().ok()
-        .and_then(|x| x.parent().map(|y| y.to_path_buf()))
-    {
-        Some(dir) => dir,
-        None => return None,
-    };
+fn get_binaries(master: &MasterConfig) -> Option<Binaries> {
     let bin = Binaries {
-        lithos_tree: dir.join("lithos_tree"),
-        lithos_knot: dir.join("lithos_knot"),
+        lithos_tree: env::current_exe().ok()?,
+        lithos_knot: master.knot_binary()?,
     };
     if !metadata(&bin.lithos_tree).map(|x| x.is_file()).unwrap_or(false) {
         write!(&mut stderr(), "Can't find lithos_tree binary").unwrap();
         return None;
     }
     if !metadata(&bin.lithos_knot).map(|x| x.is_file()).unwrap_or(false) {
-        write!(&mut stderr(), "Can't find lithos_knot binary").unwrap();
+        write!(&mut stderr(), "Can't find lithos_knot binary at {:?}",
+            bin.lithos_knot).unwrap();
+        return None;
+    }
+    if let Err(e) = version::check_knot_binary(&bin.lithos_knot) {
+        write!(&mut stderr(), "{}", e).unwrap();
         return None;
     }
     return Some(bin);
 }
 
-fn force_cantal(bin: &Binaries, conf: &MasterConfig) {
+fn force_cantal(bin: &Binaries, conf: &MasterConfig, config_file: &Path,
+    options: &Options, pid_file: &File)
+{
     use std::ffi::CString;
     use std::os::unix::ffi::OsStringExt;
+    if !conf.metrics {
+        debug!("Metrics are disabled");
+        return;
+    }
+    let current = env::var_os("CANTAL_PATH").map(PathBuf::from);
+    // Explicit path always wins, otherwise we keep CANTAL_PATH from the
+    // environment and only pick the default if there is none
+    let target = match (&options.metrics_path, &conf.metrics_path) {
+        (&Some(ref path), _) => conf.runtime_dir.join(path),
+        (&None, &Some(ref path)) => conf.runtime_dir.join(path),
+        (&None, &None) => match current {
+            Some(ref path) => path.clone(),
+            None => conf.default_metrics_path(config_file),
+        },
+    };
     // Migration between v0.10.6 and v0.11.0 should enable metrics without
     // stop/start cycle, which is usually needed to add environment variables
     // to the config.
-    if env::var_os("CANTAL_PATH").is_none() {
-        env::set_var("CANTAL_PATH", conf.runtime_dir.join("metrics"));
+    if current.as_ref() != Some(&target) {
+        env::set_var("CANTAL_PATH", &target);
+        // lock is kept, so the new image finds itself re-executed
+        daemon::inherit_on_exec(pid_file)
+            .map_err(|e| error!("Can't keep pid file lock: {}", e)).ok();
         nix::unistd::execve(
             &CString::new(bin.lithos_tree.clone()
                 .into_os_string().into_vec())
@@ -1145,3 +3672,90 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    use nix::unistd::Pid;
+    use serde_json::{Value, from_str};
+
+    use lithos::master_config::RunGroup;
+    use lithos::timer_queue::Queue;
+    use super::{Child, Held, GroupStart, Progress};
+    use super::{name_matches, list_containers, progress};
+
+    #[test]
+    fn names() {
+        assert!(name_matches("web", "web", "worker"));
+        assert!(name_matches("web/worker", "web", "worker"));
+        assert!(!name_matches("web/worker", "web", "worker2"));
+        assert!(!name_matches("web/", "web", "worker"));
+        assert!(!name_matches("we", "web", "worker"));
+        assert!(!name_matches("web/worker/x", "web", "worker"));
+    }
+
+    #[test]
+    fn group_start_order() {
+        let group = RunGroup {
+            children: vec!["db".into(), "web/worker".into(), "web".into()],
+        };
+        let mut held = HashMap::new();
+        for &(sandbox, child) in &[("web", "main"), ("web", "worker"),
+            ("db", "main"), ("other", "main")]
+        {
+            held.insert(format!("{}/{}.0", sandbox, child),
+                Held::Stopping(sandbox.into(), child.into()));
+        }
+        let (start, num) = GroupStart::new("shop", &group, &held);
+        assert_eq!(num, 3);
+        assert_eq!(start.pending, vec![2, 1, 0]);
+        assert!(start.current.is_empty());
+    }
+
+    #[test]
+    fn missing_process_failed() {
+        assert_eq!(progress("web/main.0", &Queue::new(), &HashMap::new()),
+            Progress::Failed);
+    }
+
+    #[test]
+    fn list_unidentified() {
+        let mut children = HashMap::new();
+        // pids which don't exist, so there is no cgroup
+        children.insert(Pid::from_raw(0x3ffffff0),
+            Child::Unidentified("b/main.0".into()));
+        children.insert(Pid::from_raw(0x3ffffff1),
+            Child::Unidentified("a/main.0".into()));
+        let list: Value = from_str(&list_containers(&Queue::new(),
+            &children, &HashMap::new(), Instant::now())).unwrap();
+        assert_eq!(list, json!([{
+            "name": "a/main.0",
+            "pid": 0x3ffffff1,
+            "state": "unidentified",
+            "uptime": null,
+            "restarts": null,
+            "generation": null,
+            "config_hash": null,
+            "addresses": [],
+            "cgroup": null,
+        }, {
+            "name": "b/main.0",
+            "pid": 0x3ffffff0,
+            "state": "unidentified",
+            "uptime": null,
+            "restarts": null,
+            "generation": null,
+            "config_hash": null,
+            "addresses": [],
+            "cgroup": null,
+        }]));
+    }
+
+    #[test]
+    fn list_empty() {
+        assert_eq!(list_containers(&Queue::new(), &HashMap::new(),
+            &HashMap::new(), Instant::now()), "[]");
+    }
+}
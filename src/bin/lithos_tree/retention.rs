@@ -0,0 +1,187 @@
+//! Cleanup of the logs of removed sandboxes (`sandbox-retention`)
+//!
+//! Sandbox is considered removed when there is no `<sandbox>.yaml` in the
+//! sandboxes dir (nor in `sandboxes-include` dirs), but its config log or
+//! stdio log still exist. This is checked each time `lithos_tree` starts
+//! or reloads configuration, and every `CHECK_INTERVAL` in between.
+//! Archiving and removal are done in a background thread, as `tar` may
+//! take a while.
+use std::collections::BTreeSet;
+use std::fs::{canonicalize, create_dir_all, read_dir, remove_file};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lithos::master_config::{MasterConfig, SandboxRetention};
+use lithos::master_config::RetentionArchive;
+use lithos::metrics::Metrics;
+use lithos::sandbox_dirs;
+use lithos::events;
+
+use background;
+
+
+/// Interval of the checks for removed sandboxes between reloads
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Whether the background thread cleaning up logs is still running
+static CLEANING: AtomicBool = AtomicBool::new(false);
+
+
+/// Names of the files in `dir` ending with `suffix` (with suffix stripped)
+fn names(dir: &Path, suffix: &str) -> Result<BTreeSet<String>, io::Error> {
+    let mut result = BTreeSet::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if name.ends_with(suffix) {
+                result.insert(name[..name.len()-suffix.len()].to_string());
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Current and rotated logs of the sandbox in `dir`
+fn log_files(dir: &Path, sandbox: &str) -> Vec<PathBuf> {
+    let current = format!("{}.log", sandbox);
+    let rotated = format!("{}.log.", sandbox);
+    let mut result = Vec::new();
+    read_dir(dir).map(|entries| {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let matches = entry.file_name().to_str()
+                .map(|n| n == current || n.starts_with(&rotated))
+                .unwrap_or(false);
+            if matches {
+                result.push(entry.path());
+            }
+        }
+    }).map_err(|e| error!("Can't read dir {:?}: {}", dir, e)).ok();
+    result.sort();
+    return result;
+}
+
+fn archive(sandbox: &str, files: &[PathBuf], cfg: &RetentionArchive)
+    -> Result<PathBuf, String>
+{
+    create_dir_all(&cfg.dir)
+        .map_err(|e| format!("can't create dir {:?}: {}", cfg.dir, e))?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs()).unwrap_or(0);
+    let target = cfg.dir.join(format!("{}.{}.tar.zst", sandbox, timestamp));
+    let mut cmd = Command::new(&cfg.tar);
+    cmd.arg("--zstd").arg("--create").arg("--file").arg(&target);
+    // paths are stored relative to the root, so config and stdio logs
+    // having same names don't clash
+    cmd.arg("--directory").arg("/");
+    for path in files {
+        let path = canonicalize(path)
+            .map_err(|e| format!("can't resolve {:?}: {}", path, e))?;
+        cmd.arg(path.strip_prefix("/").unwrap_or(&path));
+    }
+    cmd.stdin(Stdio::null());
+    let output = cmd.output()
+        .map_err(|e| format!("can't run {:?}: {}", cfg.tar, e))?;
+    if !output.status.success() {
+        remove_file(&target).ok();
+        return Err(format!("{:?} {}: {}", cfg.tar, output.status,
+            String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(target)
+}
+
+/// Archives (if configured) and removes logs of the removed sandboxes
+pub fn cleanup_removed(master: &MasterConfig, sandbox_paths: &[PathBuf],
+    metrics: &Metrics)
+{
+    let policy = match master.sandbox_retention {
+        Some(ref policy) => policy,
+        None => return,
+    };
+    let existing = match sandbox_dirs::list(sandbox_paths) {
+        Ok(configs) => configs.into_iter().map(|(name, _)| name)
+            .collect::<BTreeSet<_>>(),
+        Err(e) => {
+            error!("Can't read sandboxes dirs {:?}: {}. \
+                Skipping cleanup of removed sandboxes", sandbox_paths, e);
+            return;
+        }
+    };
+    if existing.is_empty() {
+        // probably dir isn't mounted or deployed yet, better be safe
+        warn!("No sandboxes found in {:?}. \
+            Skipping cleanup of removed sandboxes", sandbox_paths);
+        return;
+    }
+    let mut log_dirs = vec![&master.stdio_log_dir];
+    log_dirs.extend(master.config_log_dir.as_ref());
+    let mut removed = BTreeSet::new();
+    for dir in &log_dirs {
+        match names(dir, ".log") {
+            Ok(names) => removed.extend(names),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("Can't read dir {:?}: {}", dir, e),
+        }
+    }
+    let removed = removed.difference(&existing)
+        .map(|sandbox| {
+            let files = log_dirs.iter()
+                .flat_map(|dir| log_files(dir, sandbox))
+                .collect::<Vec<_>>();
+            (sandbox.clone(), files)
+        })
+        .collect::<Vec<_>>();
+    if removed.is_empty() {
+        return;
+    }
+    if CLEANING.swap(true, Ordering::SeqCst) {
+        debug!("Previous cleanup of removed sandboxes is still running");
+        return;
+    }
+    metrics.sandboxes_removed.incr(removed.len() as u64);
+    let policy = policy.clone();
+    let event_log = master.event_log_path();
+    background::spawn("retention", move || {
+        for (sandbox, files) in removed {
+            cleanup(&sandbox, &files, &policy, event_log.as_ref()
+                .map(|p| p.as_path()));
+        }
+        CLEANING.store(false, Ordering::SeqCst);
+    });
+}
+
+fn cleanup(sandbox: &str, files: &[PathBuf], policy: &SandboxRetention,
+    event_log: Option<&Path>)
+{
+    let mut archive_path = None;
+    if let SandboxRetention::Archive(ref cfg) = *policy {
+        match archive(sandbox, files, cfg) {
+            Ok(path) => {
+                info!("Logs of sandbox {:?} are archived into {:?}",
+                    sandbox, path);
+                archive_path = Some(path);
+            }
+            Err(e) => {
+                error!("Can't archive logs of sandbox {:?}: {}. \
+                    Logs are kept", sandbox, e);
+                return;
+            }
+        }
+    }
+    for path in files {
+        remove_file(path)
+            .map_err(|e| error!("Can't remove {:?}: {}", path, e))
+            .ok();
+    }
+    warn!("Sandbox {:?} is removed, {} log files cleaned up",
+        sandbox, files.len());
+    if let Some(event_log) = event_log {
+        events::write(event_log, "sandbox-removed", json!({
+            "sandbox": sandbox,
+            "files": files.len(),
+            "archive": archive_path,
+        }));
+    }
+}
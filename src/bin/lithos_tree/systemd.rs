@@ -0,0 +1,138 @@
+//! Experimental `systemd-scope` spawn backend
+//!
+//! Each `lithos_knot` is put into a transient systemd scope before it
+//! does anything. Registration is done by calling `StartTransientUnit` on
+//! the systemd manager via `busctl` in a background thread, while the knot
+//! waits on a pipe passed as `--scope-fd`. The pipe is written to when the
+//! unit is registered, and closed without writing if registration failed.
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Write as IoWrite;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::process::{Command as StdCommand, Stdio};
+
+use libc::pid_t;
+use nix::fcntl::OFlag;
+use nix::unistd::pipe2;
+use unshare::{Command, Fd};
+
+use lithos::master_config::{MasterConfig, SystemdScope};
+
+use background;
+
+
+/// Escapes process name the way `systemd-escape` does
+///
+/// Slashes are turned into dashes, and all other characters which aren't
+/// allowed in unit names (including dashes) are `\xNN`-escaped.
+pub fn escape(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for (idx, b) in name.bytes().enumerate() {
+        match b {
+            b'/' => result.push('-'),
+            b'.' if idx == 0 => result.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => {
+                result.push(b as char);
+            }
+            _ => write!(&mut result, "\\x{:02x}", b).unwrap(),
+        }
+    }
+    return result;
+}
+
+/// Name of the scope unit of the process
+pub fn unit_name(master: &MasterConfig, name: &str) -> String {
+    match master.instance_name {
+        Some(ref instance) => {
+            format!("lithos-{}-{}.scope", escape(instance), escape(name))
+        }
+        None => format!("lithos-{}.scope", escape(name)),
+    }
+}
+
+fn start_scope(busctl: &PathBuf, unit: &str, slice: Option<&str>, pid: pid_t)
+    -> Result<(), String>
+{
+    let mut cmd = StdCommand::new(busctl);
+    cmd.args(&["call", "--quiet",
+        "org.freedesktop.systemd1", "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager", "StartTransientUnit",
+        "ssa(sv)a(sa(sv))", unit, "fail"]);
+    let num_props = if slice.is_some() { 3 } else { 2 };
+    cmd.arg(num_props.to_string());
+    cmd.args(&["PIDs", "au", "1"]).arg(pid.to_string());
+    // otherwise failed scope is kept and the next start fails
+    cmd.args(&["CollectMode", "s", "inactive-or-failed"]);
+    if let Some(slice) = slice {
+        cmd.args(&["Slice", "s", slice]);
+    }
+    cmd.arg("0");  // no auxiliary units
+    cmd.stdin(Stdio::null());
+    let output = cmd.output()
+        .map_err(|e| format!("can't run {:?}: {}", busctl, e))?;
+    if !output.status.success() {
+        return Err(format!("can't start unit {}: {}: {}", unit,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Descriptor of the scope pipe in the knot
+///
+/// It's next to the config descriptor, which is chosen not to clash with
+/// descriptors of the container (see `config_fd::choose`).
+pub fn scope_fd(config_fd: RawFd) -> RawFd {
+    config_fd + 1
+}
+
+/// Passes read end of the scope pipe to the knot, returns the write end
+pub fn set_gate(cmd: &mut Command, config_fd: RawFd)
+    -> Result<File, String>
+{
+    let (read, write) = pipe2(OFlag::O_CLOEXEC)
+        .map_err(|e| format!("can't create pipe: {}", e))?;
+    let (read, write) = unsafe {
+        (File::from_raw_fd(read), File::from_raw_fd(write))
+    };
+    cmd.file_descriptor(scope_fd(config_fd), Fd::from_file(read));
+    Ok(write)
+}
+
+/// Puts the spawned knot into the scope unit in a background thread
+///
+/// The knot is let go through the `gate` when the unit is registered, and
+/// exits with an error otherwise.
+pub fn start_scope_in_background(cfg: &SystemdScope, unit: String,
+    pid: pid_t, gate: File)
+{
+    let busctl = cfg.busctl.clone();
+    let slice = cfg.slice.clone();
+    background::spawn("systemd-scope", move || {
+        let mut gate = gate;
+        match start_scope(&busctl, &unit, slice.as_ref().map(|x| &x[..]),
+                          pid)
+        {
+            Ok(()) => {
+                gate.write_all(b"1")
+                    .map_err(|e| error!("Can't release {}: {}", unit, e))
+                    .ok();
+            }
+            // knot exits when the pipe is closed without data
+            Err(e) => error!("Error registering systemd scope: {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape;
+
+    #[test]
+    fn escape_name() {
+        assert_eq!(escape("web/worker.0"), "web-worker.0");
+        assert_eq!(escape("my-app/cmd.1"), "my\\x2dapp-cmd.1");
+        assert_eq!(escape(".hidden/x"), "\\x2ehidden-x");
+    }
+}
@@ -0,0 +1,120 @@
+//! Unix sockets opened by `lithos_tree` for the containers (`unix-sockets`)
+//!
+//! Sockets are bound in `unix-sockets-dir` of the sandbox and passed to the
+//! process the same way as `tcp-ports`. They aren't closed on exec, so
+//! they survive reload of `lithos_tree` and are recovered by the path.
+use std::fs::{Permissions, create_dir_all, remove_file, set_permissions};
+use std::fs::symlink_metadata;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+use libc::close;
+use nix::fcntl::{fcntl, FdFlag, OFlag, F_GETFD, F_SETFD, F_GETFL, F_SETFL};
+use nix::sys::socket::{socket, bind, listen, getsockname, getsockopt};
+use nix::sys::socket::{AddressFamily, SockAddr, SockFlag, SockType, UnixAddr};
+use nix::sys::socket::sockopt::AcceptConn;
+use nix::unistd::{chown, Uid, Gid};
+
+use lithos::container_config::UnixSocket;
+
+
+/// Unix socket of the process with path and owner resolved
+#[derive(Clone)]
+pub struct Spec {
+    pub path: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+    pub cfg: UnixSocket,
+}
+
+/// Returns path of the listening unix socket `fd`, if it's one
+pub fn recover(fd: RawFd) -> Option<PathBuf> {
+    match getsockname(fd) {
+        Ok(SockAddr::Unix(ref addr)) => {
+            let path = addr.path()?;
+            match getsockopt(fd, AcceptConn) {
+                Ok(true) => Some(path.to_path_buf()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Removes a socket file left from the previous run
+fn remove_stale(path: &Path) -> Result<(), Error> {
+    match symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => {
+            remove_file(path)
+                .map_err(|e| format_err!("Can't remove stale socket {:?}: {}",
+                    path, e))
+        }
+        Ok(_) => bail!("{:?} exists and is not a socket", path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => bail!("Can't stat {:?}: {}", path, e),
+    }
+}
+
+fn setup(sock: RawFd, spec: &Spec) -> Result<(), Error> {
+    let addr = UnixAddr::new(&spec.path)
+        .map_err(|e| format_err!("Bad socket path {:?}: {}", spec.path, e))?;
+    bind(sock, &SockAddr::Unix(addr))
+        .map_err(|e| format_err!("Can't bind {:?}: {}", spec.path, e))?;
+    chown(&spec.path, Some(Uid::from_raw(spec.uid)),
+        Some(Gid::from_raw(spec.gid)))
+        .map_err(|e| format_err!("Can't chown {:?}: {}", spec.path, e))?;
+    set_permissions(&spec.path, Permissions::from_mode(spec.cfg.mode))
+        .map_err(|e| format_err!("Can't chmod {:?}: {}", spec.path, e))?;
+    listen(sock, spec.cfg.listen_backlog)
+        .map_err(|e| format_err!("Can't listen {:?}: {}", spec.path, e))?;
+    // Only reset cloexec flag when socket is fully ready
+    fcntl(sock, F_GETFD)
+        .and_then(|flags| fcntl(sock, F_SETFD(
+            FdFlag::from_bits(flags).expect("os returned valid flags")
+            & !FdFlag::FD_CLOEXEC)))
+        .map_err(|e| format_err!("Socket option error: {:?}", e))?;
+    if spec.cfg.set_non_block {
+        fcntl(sock, F_GETFL)
+            .and_then(|flags| fcntl(sock, F_SETFL(
+                OFlag::from_bits(flags).expect("os returned valid flags")
+                | OFlag::O_NONBLOCK)))
+            .map_err(|e| format_err!("Socket option error: {:?}", e))?;
+    }
+    Ok(())
+}
+
+pub fn open(spec: &Spec) -> Result<RawFd, Error> {
+    if let Some(dir) = spec.path.parent() {
+        create_dir_all(dir)
+            .map_err(|e| format_err!("Can't create dir {:?}: {}", dir, e))?;
+    }
+    remove_stale(&spec.path)?;
+    let sock = socket(AddressFamily::Unix, SockType::Stream,
+                      SockFlag::SOCK_CLOEXEC, None)
+        .map_err(|e| format_err!("Can't create socket: {:?}", e))?;
+    match setup(sock, spec) {
+        Ok(()) => {
+            info!("Socket {:?} open as {}", spec.path, sock);
+            Ok(sock)
+        }
+        Err(e) => {
+            unsafe { close(sock) };
+            remove_file(&spec.path).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Closes the socket and removes its file
+pub fn close_socket(path: &Path, fd: RawFd) {
+    info!("Closing fd {} unix socket {:?}", fd, path);
+    unsafe { close(fd) };
+    match remove_file(path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Can't remove socket {:?}: {}", path, e),
+    }
+}
@@ -0,0 +1,81 @@
+//! Systemd watchdog (`WatchdogSec=` in the unit file)
+//!
+//! Keep-alive is sent from the main loop, so if the loop is stuck systemd
+//! restarts `lithos_tree` instead of it silently not restarting processes.
+use std::env;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+use nix::sys::socket::{sendto, MsgFlags, SockAddr, UnixAddr};
+use nix::unistd::getpid;
+
+
+pub struct Watchdog {
+    socket: UnixDatagram,
+    addr: SockAddr,
+    interval: Duration,
+    next_ping: Instant,
+}
+
+fn notify_addr(path: &str) -> Result<SockAddr, String> {
+    let addr = if path.starts_with('@') {
+        UnixAddr::new_abstract(path[1..].as_bytes())
+    } else {
+        UnixAddr::new(path)
+    };
+    addr.map(SockAddr::Unix)
+        .map_err(|e| format!("invalid NOTIFY_SOCKET {:?}: {}", path, e))
+}
+
+impl Watchdog {
+    /// Returns watchdog if it's enabled for this process
+    pub fn from_env() -> Option<Watchdog> {
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse().ok() != Some(i32::from(getpid())) {
+                // watchdog is meant for another process
+                return None;
+            }
+        }
+        let result = env::var("NOTIFY_SOCKET")
+            .map_err(|_| format!("NOTIFY_SOCKET is not set"))
+            .and_then(|path| notify_addr(&path))
+            .and_then(|addr| {
+                let socket = UnixDatagram::unbound()
+                    .map_err(|e| format!("can't create socket: {}", e))?;
+                Ok((socket, addr))
+            });
+        match result {
+            Ok((socket, addr)) => {
+                // ping twice per period, as systemd recommends
+                let interval = Duration::from_micros(usec / 2);
+                info!("Systemd watchdog enabled, keep-alive every {:?}",
+                    interval);
+                Some(Watchdog {
+                    socket, addr, interval,
+                    next_ping: Instant::now(),
+                })
+            }
+            Err(e) => {
+                error!("Can't enable systemd watchdog: {}", e);
+                None
+            }
+        }
+    }
+    /// Time when the main loop must wake up to send keep-alive
+    pub fn deadline(&self) -> Instant {
+        self.next_ping
+    }
+    /// Sends keep-alive if it's time to
+    pub fn ping(&mut self, now: Instant) {
+        if now < self.next_ping {
+            return;
+        }
+        sendto(self.socket.as_raw_fd(), b"WATCHDOG=1", &self.addr,
+               MsgFlags::empty())
+            .map_err(|e| warn!("Can't send watchdog keep-alive: {}", e))
+            .ok();
+        self.next_ping = now + self.interval;
+    }
+}
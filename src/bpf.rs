@@ -0,0 +1,284 @@
+//! Minimal eBPF assembler and loader
+//!
+//! Only instructions needed for cgroup socket buffer filters are supported.
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc::{c_long, close};
+
+
+#[cfg(target_arch="x86_64")] const SYS_BPF: c_long = 321;
+#[cfg(target_arch="x86")] const SYS_BPF: c_long = 357;
+#[cfg(target_arch="aarch64")] const SYS_BPF: c_long = 280;
+#[cfg(target_arch="arm")] const SYS_BPF: c_long = 386;
+
+const BPF_PROG_LOAD: c_long = 5;
+const BPF_PROG_ATTACH: c_long = 8;
+const BPF_PROG_TYPE_CGROUP_SKB: u32 = 8;
+const BPF_CGROUP_INET_EGRESS: u32 = 1;
+
+const LOG_SIZE: usize = 65536;
+
+// instruction classes
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_ALU: u8 = 0x04;
+const BPF_JMP: u8 = 0x05;
+const BPF_ALU64: u8 = 0x07;
+// sizes
+const BPF_W: u8 = 0x00;
+const BPF_H: u8 = 0x08;
+const BPF_B: u8 = 0x10;
+const BPF_DW: u8 = 0x18;
+// modes
+const BPF_IMM: u8 = 0x00;
+const BPF_MEM: u8 = 0x60;
+// sources
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const BPF_TO_BE: u8 = 0x08;
+// operations
+const BPF_ADD: u8 = 0x00;
+const BPF_AND: u8 = 0x50;
+const BPF_LSH: u8 = 0x60;
+const BPF_MOV: u8 = 0xb0;
+const BPF_END: u8 = 0xd0;
+const BPF_JA: u8 = 0x00;
+const BPF_CALL: u8 = 0x80;
+const BPF_EXIT: u8 = 0x90;
+
+/// Conditional jump operations
+#[derive(Clone, Copy, Debug)]
+pub enum Cond {
+    Eq = 0x10,
+    Gt = 0x20,
+    Ge = 0x30,
+    Ne = 0x50,
+}
+
+/// Size of the memory access
+#[derive(Clone, Copy, Debug)]
+pub enum Size {
+    Byte,
+    Half,
+    Word,
+}
+
+/// Helper functions available to the programs
+pub const FN_SKB_LOAD_BYTES: i32 = 26;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Insn {
+    pub code: u8,
+    pub regs: u8,
+    pub off: i16,
+    pub imm: i32,
+}
+
+/// Jump target, created by `Asm::label` and placed with `Asm::bind`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Asm {
+    insns: Vec<Insn>,
+    labels: HashMap<Label, usize>,
+    fixups: Vec<(usize, Label)>,
+    next_label: usize,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    _pad: u32,
+}
+
+#[repr(C)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+/// Loaded program, closed on drop
+pub struct Program(RawFd);
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn { code, regs: (src << 4) | (dst & 0x0f), off, imm }
+}
+
+impl Asm {
+    pub fn new() -> Asm {
+        Asm::default()
+    }
+    pub fn label(&mut self) -> Label {
+        self.next_label += 1;
+        Label(self.next_label)
+    }
+    /// Places label at the next instruction
+    pub fn bind(&mut self, label: Label) {
+        let pos = self.insns.len();
+        assert!(self.labels.insert(label, pos).is_none(), "label bound twice");
+    }
+    pub fn mov_reg(&mut self, dst: u8, src: u8) {
+        self.insns.push(insn(BPF_ALU64|BPF_MOV|BPF_X, dst, src, 0, 0));
+    }
+    pub fn mov_imm(&mut self, dst: u8, imm: i32) {
+        self.insns.push(insn(BPF_ALU64|BPF_MOV|BPF_K, dst, 0, 0, imm));
+    }
+    /// Loads full 64bit constant (takes two instruction slots)
+    pub fn ld_imm64(&mut self, dst: u8, imm: u64) {
+        self.insns.push(insn(BPF_LD|BPF_DW|BPF_IMM, dst, 0, 0, imm as i32));
+        self.insns.push(insn(0, 0, 0, 0, (imm >> 32) as i32));
+    }
+    pub fn add_imm(&mut self, dst: u8, imm: i32) {
+        self.insns.push(insn(BPF_ALU64|BPF_ADD|BPF_K, dst, 0, 0, imm));
+    }
+    pub fn and_imm(&mut self, dst: u8, imm: i32) {
+        self.insns.push(insn(BPF_ALU64|BPF_AND|BPF_K, dst, 0, 0, imm));
+    }
+    pub fn lsh_imm(&mut self, dst: u8, imm: i32) {
+        self.insns.push(insn(BPF_ALU64|BPF_LSH|BPF_K, dst, 0, 0, imm));
+    }
+    /// Converts value from network byte order (`bits` is 16, 32 or 64)
+    pub fn from_be(&mut self, dst: u8, bits: i32) {
+        self.insns.push(insn(BPF_ALU|BPF_END|BPF_TO_BE, dst, 0, 0, bits));
+    }
+    pub fn load(&mut self, size: Size, dst: u8, src: u8, off: i16) {
+        let size = match size {
+            Size::Byte => BPF_B,
+            Size::Half => BPF_H,
+            Size::Word => BPF_W,
+        };
+        self.insns.push(insn(BPF_LDX|BPF_MEM|size, dst, src, off, 0));
+    }
+    pub fn jump(&mut self, target: Label) {
+        self.fixups.push((self.insns.len(), target));
+        self.insns.push(insn(BPF_JMP|BPF_JA, 0, 0, 0, 0));
+    }
+    pub fn jump_imm(&mut self, cond: Cond, dst: u8, imm: i32, target: Label) {
+        self.fixups.push((self.insns.len(), target));
+        self.insns.push(insn(BPF_JMP|cond as u8|BPF_K, dst, 0, 0, imm));
+    }
+    pub fn jump_reg(&mut self, cond: Cond, dst: u8, src: u8, target: Label) {
+        self.fixups.push((self.insns.len(), target));
+        self.insns.push(insn(BPF_JMP|cond as u8|BPF_X, dst, src, 0, 0));
+    }
+    pub fn call(&mut self, func: i32) {
+        self.insns.push(insn(BPF_JMP|BPF_CALL, 0, 0, 0, func));
+    }
+    pub fn exit(&mut self) {
+        self.insns.push(insn(BPF_JMP|BPF_EXIT, 0, 0, 0, 0));
+    }
+    /// Resolves jumps, all the labels must be bound
+    pub fn finish(mut self) -> Vec<Insn> {
+        for &(pos, label) in &self.fixups {
+            let target = *self.labels.get(&label).expect("label is bound");
+            assert!(target > pos, "only forward jumps are allowed");
+            self.insns[pos].off = (target - pos - 1) as i16;
+        }
+        self.insns
+    }
+}
+
+#[cfg(any(target_arch="x86_64", target_arch="x86",
+          target_arch="aarch64", target_arch="arm"))]
+fn bpf<T>(cmd: c_long, attr: &mut T) -> Result<RawFd, io::Error> {
+    let rc = unsafe {
+        libc::syscall(SYS_BPF, cmd, attr as *mut T, size_of::<T>() as u32)
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(rc as RawFd)
+}
+
+/// Syscall number is not known for the architecture, so the call fails
+/// the same way as on the kernel without bpf support
+#[cfg(not(any(target_arch="x86_64", target_arch="x86",
+              target_arch="aarch64", target_arch="arm")))]
+fn bpf<T>(_cmd: c_long, _attr: &mut T) -> Result<RawFd, io::Error> {
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+impl Program {
+    /// Loads a `BPF_PROG_TYPE_CGROUP_SKB` program into the kernel
+    ///
+    /// Verifier log is included into the error message on failure.
+    pub fn load_cgroup_skb(insns: &[Insn]) -> Result<Program, String> {
+        let license = b"GPL\0";
+        let mut log = vec![0u8; LOG_SIZE];
+        let mut attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_CGROUP_SKB,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 1,
+            log_size: log.len() as u32,
+            log_buf: log.as_mut_ptr() as u64,
+            kern_version: 0,
+            _pad: 0,
+        };
+        match bpf(BPF_PROG_LOAD, &mut attr) {
+            Ok(fd) => Ok(Program(fd)),
+            Err(e) => {
+                let end = log.iter().position(|&x| x == 0)
+                    .unwrap_or(log.len());
+                Err(format!("Error loading bpf program: {}. Verifier log: {}",
+                    e, String::from_utf8_lossy(&log[..end])))
+            }
+        }
+    }
+    /// Attaches program as an egress filter of the cgroup (v2) directory
+    ///
+    /// Replaces previously attached program if any.
+    pub fn attach_egress<F: AsRawFd>(&self, cgroup_dir: &F)
+        -> Result<(), io::Error>
+    {
+        let mut attr = ProgAttachAttr {
+            target_fd: cgroup_dir.as_raw_fd() as u32,
+            attach_bpf_fd: self.0 as u32,
+            attach_type: BPF_CGROUP_INET_EGRESS,
+            attach_flags: 0,
+        };
+        bpf(BPF_PROG_ATTACH, &mut attr).map(|_| ())
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe { close(self.0) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Asm, Cond};
+
+    #[test]
+    fn forward_jumps() {
+        let mut asm = Asm::new();
+        let skip = asm.label();
+        asm.jump_imm(Cond::Eq, 1, 0, skip);
+        asm.ld_imm64(2, 0x1_0000_0001);
+        asm.mov_imm(0, 1);
+        asm.bind(skip);
+        asm.exit();
+        let insns = asm.finish();
+        assert_eq!(insns.len(), 5);
+        assert_eq!(insns[0].off, 3);
+        assert_eq!(insns[1].imm, 1);
+        assert_eq!(insns[2].imm, 1);
+    }
+}
@@ -1,6 +1,6 @@
 use std::rc::Rc;
-use std::io::{Write, BufRead, BufReader};
-use std::fs::{File, create_dir, remove_dir, metadata};
+use std::io::{Read, Write, BufRead, BufReader};
+use std::fs::{File, create_dir, remove_dir, metadata, read_dir};
 use std::io::ErrorKind::NotFound;
 use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
@@ -20,6 +20,10 @@ pub struct CGroupPath(pub String, pub PathBuf);
 pub enum Controller {
     Cpu,
     Memory,
+    Devices,
+    Pids,
+    Blkio,
+    Cpuset,
 }
 
 
@@ -71,16 +75,43 @@ pub fn parse_cgroups(pid: Option<pid_t>) -> Result<ParsedCGroups, String> {
     return Ok(res);
 }
 
-pub fn ensure_in_group(name: &String, controllers: &Vec<String>)
-    -> Result<CGroups, String>
-{
-    let default_controllers = vec!(
+/// Controllers used when `cgroup-controllers` is empty
+///
+/// `pids` is only used if it's mounted, as older kernels don't have it.
+fn default_controllers(parent: &ParsedCGroups) -> Vec<String> {
+    let mut result = vec!(
         "name".to_string(),
         "cpu".to_string(),
         "cpuacct".to_string(),
         "memory".to_string(),
         "blkio".to_string(),
         );
+    if parent.by_name.contains_key("pids") {
+        result.push("pids".to_string());
+    }
+    return result;
+}
+
+/// Copies `cpuset.cpus` and `cpuset.mems` of the parent (cgroup v1)
+fn inherit_cpuset(path: &Path) -> Result<(), String> {
+    let parent = path.parent().expect("cgroup has a parent");
+    for key in &["cpuset.cpus", "cpuset.mems"] {
+        let mut buf = String::with_capacity(64);
+        try!(File::open(parent.join(key))
+            .and_then(|mut f| f.read_to_string(&mut buf))
+            .and_then(|_| File::create(path.join(key)))
+            .and_then(|mut f| f.write_all(buf.trim().as_bytes()))
+            .map_err(|e| format!("Error copying {} of {:?}: {}",
+                key, parent, e)));
+    }
+    Ok(())
+}
+
+pub fn ensure_in_group(name: &String, controllers: &Vec<String>)
+    -> Result<CGroups, String>
+{
+    let parent_grp = try!(parse_cgroups(Some(1)));
+    let default_controllers = default_controllers(&parent_grp);
     let controllers = if controllers.len() > 0
         { controllers } else { &default_controllers };
     debug!("Setting up cgroup {} with controllers {:?}", name, controllers);
@@ -89,7 +120,6 @@ pub fn ensure_in_group(name: &String, controllers: &Vec<String>)
 
     let root_path = Path::new("/");
 
-    let parent_grp = try!(parse_cgroups(Some(1)));
     let old_grp = try!(parse_cgroups(None));
     let mypid = unsafe { getpid() };
     let mut res = CGroups { full_paths: BTreeMap::new() };
@@ -122,6 +152,10 @@ pub fn ensure_in_group(name: &String, controllers: &Vec<String>)
             try!(create_dir(&fullpath)
                  .map_err(|e| format!("Error creating cgroup dir {:?}: {}",
                                       fullpath, e)));
+            if ctr == "cpuset" {
+                // new cpuset is empty, tasks can't be added until it's set
+                try!(inherit_cpuset(&fullpath));
+            }
         } else {
             debug!("CGroup {} already exists", fullpath.display());
         }
@@ -138,47 +172,103 @@ pub fn ensure_in_group(name: &String, controllers: &Vec<String>)
             "memory" => {
                 res.full_paths.insert(Controller::Memory, fullpath);
             }
+            "devices" => {
+                res.full_paths.insert(Controller::Devices, fullpath);
+            }
+            "pids" => {
+                res.full_paths.insert(Controller::Pids, fullpath);
+            }
+            "blkio" => {
+                res.full_paths.insert(Controller::Blkio, fullpath);
+            }
+            "cpuset" => {
+                res.full_paths.insert(Controller::Cpuset, fullpath);
+            }
             _ => {}
         };
     }
     return Ok(res);
 }
 
+/// Name of the cgroup of the process `sandbox/child.N`
+///
+/// Generation of the child config is appended if known, so that it's
+/// visible which processes run an old config: `sandbox:child.0.g5.scope`.
+pub fn scope_name(name: &str, generation: Option<u64>) -> String {
+    match generation {
+        Some(gen) => format!("{}.g{}.scope", name.replace("/", ":"), gen),
+        None => format!("{}.scope", name.replace("/", ":")),
+    }
+}
+
+/// Returns true if `filename` is a cgroup of the process of any generation
+pub fn is_scope_of(filename: &str, name: &str) -> bool {
+    let prefix = name.replace("/", ":");
+    if !filename.starts_with(&prefix[..]) {
+        return false;
+    }
+    let rest = &filename[prefix.len()..];
+    if rest == ".scope" {
+        return true;
+    }
+    rest.starts_with(".g") && rest.ends_with(".scope") &&
+        rest[2..rest.len()-6].parse::<u64>().is_ok()
+}
+
+/// Removes cgroups of the process (of all generations) in the `dir`
+fn remove_scopes(dir: &Path, child: &str) {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == NotFound => return,
+        Err(e) => {
+            error!("Error reading cgroup dir {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let matches = entry.file_name().to_str()
+            .map(|x| is_scope_of(x, child)).unwrap_or(false);
+        if matches {
+            let fullpath = entry.path();
+            remove_dir(&fullpath)
+                .map_err(|e| if e.kind() != NotFound {
+                    error!("Error removing cgroup {}: {}",
+                        fullpath.display(), e)})
+                .ok();
+        }
+    }
+}
+
+/// Removes cgroups of the process `sandbox/child.N`
 pub fn remove_child_cgroup(child: &str, master: &String,
     controllers: &Vec<String>)
     -> Result<(), String>
 {
     // TODO(tailhook) do we need to customize cgroup mount points?
     let cgroup_base = PathBuf::from("/sys/fs/cgroup");
-    let default_controllers = vec!(
-        "name".to_string(),
-        "cpu".to_string(),
-        "cpuacct".to_string(),
-        "memory".to_string(),
-        "blkio".to_string(),
-        );
+    let parent_grp = try!(parse_cgroups(Some(1)));
+    let default_controllers = default_controllers(&parent_grp);
     let controllers = if controllers.len() > 0
         { controllers } else { &default_controllers };
     debug!("Removing cgroup {}", child);
 
     let root_path = PathBuf::from("/");
-    let parent_grp = try!(parse_cgroups(Some(1)));
 
     for ctr in controllers.iter() {
         let CGroupPath(ref folder, ref path) = **parent_grp.by_name.get(ctr)
             .expect("CGroups already checked");
-        let fullpath = cgroup_base.join(&folder)
+        let dir = cgroup_base.join(&folder)
             .join(relative(path, &root_path))
-            .join(&master).join(child);
-        remove_dir(&fullpath)
-            .map_err(|e| if e.kind() != NotFound {
-                error!("Error removing cgroup {}: {}", fullpath.display(), e)})
-            .ok();
+            .join(&master);
+        remove_scopes(&dir, child);
     }
     return Ok(());
 }
 
 impl CGroups {
+    pub fn has_controller(&self, ctr: Controller) -> bool {
+        self.full_paths.contains_key(&ctr)
+    }
     pub fn set_value(&self, ctr: Controller, key: &str, value: &str)
         -> Result<(), String>
     {
@@ -189,6 +279,18 @@ impl CGroups {
             .map_err(|e| format!("Can't write to cgroup path {:?}/{}: {}",
                 path, key, e))
     }
+    pub fn get_value(&self, ctr: Controller, key: &str)
+        -> Result<String, String>
+    {
+        let path = try!(self.full_paths.get(&ctr)
+            .ok_or(format!("Controller {:?} is not initialized", ctr)));
+        let mut buf = String::with_capacity(64);
+        File::open(&path.join(key))
+            .and_then(|mut f| f.read_to_string(&mut buf))
+            .map_err(|e| format!("Can't read cgroup path {:?}/{}: {}",
+                path, key, e))?;
+        Ok(buf)
+    }
     pub fn set_value_if_exists(&self, ctr: Controller, key: &str, value: &str)
         -> Result<(), String>
     {
@@ -206,3 +308,102 @@ impl CGroups {
         }
     }
 }
+
+/// Returns mount point of the unified (v2) cgroup hierarchy if any
+pub fn unified_base() -> Option<PathBuf> {
+    // TODO(tailhook) do we need to customize cgroup mount points?
+    let hybrid = Path::new("/sys/fs/cgroup/unified");
+    if metadata(hybrid.join("cgroup.procs")).is_ok() {
+        return Some(hybrid.to_path_buf());
+    }
+    let pure = Path::new("/sys/fs/cgroup");
+    if metadata(pure.join("cgroup.controllers")).is_ok() {
+        return Some(pure.to_path_buf());
+    }
+    return None;
+}
+
+/// Moves current process into the unified cgroup, creating it if needed
+///
+/// Returns opened cgroup directory (useful to attach bpf programs).
+pub fn ensure_in_unified_group(name: &str) -> Result<File, String> {
+    let base = try!(unified_base()
+        .ok_or("Unified cgroup hierarchy (cgroup2) is not mounted"
+               .to_string()));
+    let mut path = base.clone();
+    for part in Path::new(name).components() {
+        path.push(part.as_os_str());
+        if metadata(&path).is_err() {
+            debug!("Creating cgroup {:?}", path);
+            try!(create_dir(&path)
+                 .map_err(|e| format!("Error creating cgroup dir {:?}: {}",
+                                      path, e)));
+        }
+    }
+    let mypid = unsafe { getpid() };
+    try!(OpenOptions::new().write(true).open(path.join("cgroup.procs"))
+         .and_then(|mut f| write!(&mut f, "{}", mypid))
+         .map_err(|e| format!(
+            "Error adding myself (pid: {}) to the group {:?}: {}",
+            mypid, path, e)));
+    File::open(&path)
+        .map_err(|e| format!("Error opening cgroup dir {:?}: {}", path, e))
+}
+
+/// Enables controller `ctr` for the unified cgroup `name`
+///
+/// Controller is added to `cgroup.subtree_control` of every parent of the
+/// group, starting from the root, so it's called after
+/// `ensure_in_unified_group`.
+pub fn enable_unified_controller(name: &str, ctr: &str)
+    -> Result<(), String>
+{
+    let mut path = try!(unified_base()
+        .ok_or("Unified cgroup hierarchy (cgroup2) is not mounted"
+               .to_string()));
+    for part in Path::new(name).components() {
+        let control = path.join("cgroup.subtree_control");
+        try!(OpenOptions::new().write(true).open(&control)
+            .and_then(|mut f| write!(&mut f, "+{}", ctr))
+            .map_err(|e| format!("Error enabling {} controller in {:?}: {}",
+                ctr, control, e)));
+        path.push(part.as_os_str());
+    }
+    Ok(())
+}
+
+/// Writes `key` of the unified cgroup `name`
+pub fn set_unified_value(name: &str, key: &str, value: &str)
+    -> Result<(), String>
+{
+    let base = try!(unified_base()
+        .ok_or("Unified cgroup hierarchy (cgroup2) is not mounted"
+               .to_string()));
+    let path = base.join(name).join(key);
+    File::create(&path)
+        .and_then(|mut f| f.write_all(value.as_bytes()))
+        .map_err(|e| format!("Can't write to cgroup path {:?}: {}", path, e))
+}
+
+/// Removes unified cgroups of the process `sandbox/child.N`
+pub fn remove_unified_child(child: &str, master: &str) {
+    if let Some(base) = unified_base() {
+        remove_scopes(&base.join(master), child);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{scope_name, is_scope_of};
+
+    #[test]
+    fn scopes() {
+        assert_eq!(scope_name("web/worker.0", None), "web:worker.0.scope");
+        assert_eq!(scope_name("web/worker.0", Some(5)),
+                   "web:worker.0.g5.scope");
+        assert!(is_scope_of("web:worker.0.scope", "web/worker.0"));
+        assert!(is_scope_of("web:worker.0.g12.scope", "web/worker.0"));
+        assert!(!is_scope_of("web:worker.01.scope", "web/worker.0"));
+        assert!(!is_scope_of("web:worker.0.gx.scope", "web/worker.0"));
+    }
+}
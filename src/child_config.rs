@@ -2,9 +2,19 @@ use failure::Error;
 use std::str::FromStr;
 use std::net::IpAddr;
 use std::collections::BTreeMap;
+use std::path::PathBuf;
 
+use blake2::{Blake2b, digest::{VariableOutput, Input}};
 use quire::validate::{Structure, Scalar, Numeric, Mapping, Sequence};
 use quire::{Options, parse_string};
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use serde::ser::{Serialize, Serializer};
+use serde_json::{from_str, to_value, to_string};
+
+use container_config::ContainerConfig;
+use master_config::ContainerDefaults;
+
+use version::CONFIG_SCHEMA;
 
 #[derive(Serialize, Deserialize)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -13,6 +23,49 @@ pub enum ChildKind {
     Command,
 }
 
+/// File in the state dir where knot saves its config
+///
+/// Config is not on the command-line of the knot, so `lithos_tree` reads it
+/// from here when recovering processes after restart.
+pub const INSTANCE_CONFIG_FILE: &str = "child_config.json";
+
+/// Container config embedded into the process config
+///
+/// Stored serialized to JSON: it's passed to `lithos_knot` as a string, as
+/// quire can't parse tagged enums (i.e. volumes) back from JSON.
+#[derive(PartialEq, Debug, Clone)]
+pub struct InlineContainer(String);
+
+impl InlineContainer {
+    pub fn new(cfg: &ContainerConfig) -> Result<InlineContainer, String> {
+        to_string(cfg).map(InlineContainer)
+            .map_err(|e| format!("Can't serialize container config: {}", e))
+    }
+    pub fn as_json(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> Deserialize<'a> for InlineContainer {
+    fn deserialize<D: Deserializer<'a>>(d: D)
+        -> Result<InlineContainer, D::Error>
+    {
+        let cfg = ContainerConfig::deserialize(d)?;
+        to_string(&cfg).map(InlineContainer).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for InlineContainer {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(s)
+    }
+}
+
+/// Parses container config passed as `ChildInstance::container`
+pub fn parse_inline_container(json: &str) -> Result<ContainerConfig, String> {
+    from_str(json).map_err(|e| format!("Bad inline container config: {}", e))
+}
+
 // Note everything here should be stable-serializable
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct ChildInstance {
@@ -25,11 +78,35 @@ pub struct ChildInstance {
     pub extra_secrets_namespaces: Vec<String>,
     #[serde(skip_serializing_if="Option::is_none", default)]
     pub ip_address: Option<IpAddr>,
+    /// JSON-serialized container config, used instead of `config` file
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub container: Option<String>,
+    /// Seconds after which the command is terminated
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub max_runtime: Option<f32>,
     pub kind: ChildKind,
+    // omitted for the first schema, so the command-line of the containers
+    // (and so the config comparison on upgrade) is unchanged
+    #[serde(skip_serializing_if="is_base_schema", default="base_schema")]
+    pub schema: u32,
 }
 
 fn one() -> usize { 1 }
 
+fn canonical_hash<T: Serialize>(value: &T) -> String {
+    // json objects are sorted maps, so this is canonical
+    let value = to_value(value).expect("config is serializable");
+    let mut buf = [0u8; 16];
+    let mut hash: Blake2b = VariableOutput::new(buf.len())
+        .expect("blake2b");
+    hash.process(to_string(&value).expect("config is serializable")
+        .as_bytes());
+    hash.variable_result(&mut buf[..]).expect("blake2b");
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+fn base_schema() -> u32 { 1 }
+fn is_base_schema(x: &u32) -> bool { *x == 1 }
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct ChildConfig {
     #[serde(default="one")]
@@ -42,10 +119,39 @@ pub struct ChildConfig {
     pub extra_secrets_namespaces: Vec<String>,
     #[serde(skip_serializing_if="Vec::is_empty", default)]
     pub ip_addresses: Vec<IpAddr>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub container: Option<InlineContainer>,
+    /// Template of the container config rendered into `container`
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub config_template: Option<PathBuf>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub max_runtime: Option<f32>,
+    /// Number of times failed command is rerun by `lithos_cmd`
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub retries: Option<u32>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub retry_delay: Option<f32>,
+    /// Daemon which listening sockets are passed to the command
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub inherit_sockets: Option<String>,
+    /// Number of instances restarted at once when config is changed
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub max_parallel_restarts: Option<usize>,
+    /// Seconds to wait between batches of `max_parallel_restarts`
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub restart_batch_delay: Option<f32>,
     pub kind: ChildKind,
 }
 
 impl ChildConfig {
+    /// Hash of the config, which doesn't depend on number of instances
+    ///
+    /// Used to bump generation of the child when config changes.
+    pub fn config_hash(&self) -> String {
+        let mut cfg = self.clone();
+        cfg.instances = 1;
+        canonical_hash(&cfg)
+    }
     pub fn instantiate(&self, instance: usize) -> Result<ChildInstance, Error>
     {
         let cfg = ChildInstance {
@@ -64,16 +170,29 @@ impl ChildConfig {
                 None
             },
             extra_secrets_namespaces: self.extra_secrets_namespaces.clone(),
+            container: self.container.as_ref()
+                .map(|c| c.as_json().to_string()),
+            max_runtime: self.max_runtime,
             kind: self.kind,
+            schema: CONFIG_SCHEMA,
         };
         return Ok(cfg);
     }
     pub fn mapping_validator<'x>() -> Mapping<'x> {
+        ChildConfig::mapping_validator_with(&ContainerDefaults::default())
+    }
+    /// Validator which uses `container-defaults` for inline containers
+    pub fn mapping_validator_with<'x>(defaults: &ContainerDefaults)
+        -> Mapping<'x>
+    {
         return Mapping::new(
             Scalar::new(),
-            ChildConfig::validator());
+            ChildConfig::validator_with(defaults));
     }
     pub fn validator<'x>() -> Structure<'x> {
+        ChildConfig::validator_with(&ContainerDefaults::default())
+    }
+    pub fn validator_with<'x>(defaults: &ContainerDefaults) -> Structure<'x> {
         Structure::new()
         .member("instances", Numeric::new().default(1))
         .member("image", Scalar::new())
@@ -82,6 +201,15 @@ impl ChildConfig {
         .member("extra_secrets_namespaces", Sequence::new(Scalar::new()))
         .member("kind", Scalar::new().default("Daemon"))
         .member("ip_addresses", Sequence::new(Scalar::new()))
+        .member("container",
+            ContainerConfig::validator_with(defaults).optional())
+        .member("config_template", Scalar::new().optional())
+        .member("max_runtime", Numeric::new().min(0).optional())
+        .member("retries", Numeric::new().min(0).optional())
+        .member("retry_delay", Numeric::new().min(0).optional())
+        .member("inherit_sockets", Scalar::new().optional())
+        .member("max_parallel_restarts", Numeric::new().min(1).optional())
+        .member("restart_batch_delay", Numeric::new().min(0).optional())
     }
 }
 impl ChildInstance {
@@ -94,6 +222,23 @@ impl ChildInstance {
         .member("extra_secrets_namespaces", Sequence::new(Scalar::new()))
         .member("kind", Scalar::new().default("Daemon"))
         .member("ip_address", Scalar::new().optional())
+        .member("container", Scalar::new().optional())
+        .member("max_runtime", Numeric::new().min(0).optional())
+        .member("schema", Numeric::new().min(1).default(1))
+    }
+    /// Hash of the serialized config which doesn't depend on field order,
+    /// formatting and omitted defaults
+    ///
+    /// Used to compare config of the running process with the current one,
+    /// so that serialization differences between lithos versions don't
+    /// restart processes. Returns `None` if config can't be parsed.
+    pub fn config_hash(config: &str) -> Option<String> {
+        let inst = ChildInstance::from_str(config).ok()?;
+        Some(canonical_hash(&inst))
+    }
+    /// The same as `config_hash` of the serialized config
+    pub fn hash(&self) -> String {
+        canonical_hash(self)
     }
 }
 
@@ -129,7 +274,10 @@ mod test {
             variables: BTreeMap::new(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            container: None,
+            max_runtime: None,
             kind: Daemon,
+            schema: 1,
         });
 
         let cc: ChildInstance = from_str(&data).unwrap();
@@ -140,7 +288,10 @@ mod test {
             variables: BTreeMap::new(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            container: None,
+            max_runtime: None,
             kind: Daemon,
+            schema: 1,
         });
     }
 
@@ -162,10 +313,72 @@ mod test {
             ].into_iter().collect(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            container: None,
+            max_runtime: None,
             kind: Daemon,
+            schema: 1,
         })
     }
 
+    #[test]
+    fn inline_container() {
+        use quire::{parse_string, Options};
+        use container_config::Volume;
+        use super::{ChildConfig, parse_inline_container};
+
+        let cfg: ChildConfig = parse_string("<test>", r#"
+            image: app.1
+            config: /config/app.yaml
+            container:
+              executable: /bin/app
+              volumes:
+                /tmp: !Tmpfs { size: 1Mi }
+            "#, &ChildConfig::validator(), &Options::default()).unwrap();
+        let inst = cfg.instantiate(0).unwrap();
+        let inst = ChildInstance::from_str(&to_string(&inst).unwrap())
+            .unwrap();
+        let container = parse_inline_container(
+            inst.container.as_ref().unwrap()).unwrap();
+        assert_eq!(container.executable, "/bin/app");
+        match container.volumes.get("/tmp") {
+            Some(&Volume::Tmpfs(ref tmp)) => assert_eq!(tmp.size, 1048576),
+            _ => panic!("tmpfs volume expected"),
+        }
+    }
+
+    #[test]
+    fn ip_address_per_instance() {
+        use quire::{parse_string, Options};
+        use super::ChildConfig;
+
+        let cfg: ChildConfig = parse_string("<test>", r#"
+            instances: 2
+            image: app.1
+            config: /config/app.yaml
+            ip_addresses: [10.0.0.5, 10.0.0.7]
+            "#, &ChildConfig::validator(), &Options::default()).unwrap();
+        assert_eq!(cfg.instantiate(0).unwrap().ip_address,
+                   Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(cfg.instantiate(1).unwrap().ip_address,
+                   Some("10.0.0.7".parse().unwrap()));
+        assert!(cfg.instantiate(2).is_err());
+    }
+
+    #[test]
+    fn config_hash() {
+        let a = r#"{"instances":1,"image":"img","config":"/c.yaml",
+            "kind":"Daemon"}"#;
+        let b = r#"{"kind":"Daemon","config":"/c.yaml","image":"img",
+            "variables":{},"schema":1}"#;
+        let c = r#"{"instances":1,"image":"img2","config":"/c.yaml",
+            "kind":"Daemon"}"#;
+        assert!(ChildInstance::config_hash(a).is_some());
+        assert_eq!(ChildInstance::config_hash(a),
+                   ChildInstance::config_hash(b));
+        assert!(ChildInstance::config_hash(a) !=
+                ChildInstance::config_hash(c));
+    }
+
     #[test]
     fn serialize_compat() {
         let data = to_string(&ChildInstance {
@@ -175,7 +388,10 @@ mod test {
             variables: BTreeMap::new(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            container: None,
+            max_runtime: None,
             kind: Daemon,
+            schema: 1,
         }).unwrap();
         assert_eq!(data, "{\
             \"instances\":1,\
@@ -196,7 +412,10 @@ mod test {
             ].into_iter().collect(),
             extra_secrets_namespaces: Vec::new(),
             ip_address: None,
+            container: None,
+            max_runtime: None,
             kind: Daemon,
+            schema: 1,
         }).unwrap();
         assert_eq!(data, "{\
             \"instances\":1,\
@@ -205,4 +424,26 @@ mod test {
             \"variables\":{\"a\":\"b\",\"c\":\"d\"},\
             \"kind\":\"Daemon\"}");
     }
+
+    #[test]
+    fn serialize_schema() {
+        let data = to_string(&ChildInstance {
+            instances: 1,
+            image: String::from("myproj.4a20772b"),
+            config: String::from("/config/staging/myproj.yaml"),
+            variables: BTreeMap::new(),
+            extra_secrets_namespaces: Vec::new(),
+            ip_address: None,
+            container: None,
+            max_runtime: None,
+            kind: Daemon,
+            schema: 2,
+        }).unwrap();
+        assert_eq!(data, "{\
+            \"instances\":1,\
+            \"image\":\"myproj.4a20772b\",\
+            \"config\":\"/config/staging/myproj.yaml\",\
+            \"kind\":\"Daemon\",\
+            \"schema\":2}");
+    }
 }
@@ -0,0 +1,98 @@
+//! Generations of the child configs
+//!
+//! Generation is a number which is incremented each time config of the
+//! child changes. It's passed to `lithos_knot` (so it's visible in `ps`),
+//! put into the cgroup name and metrics, so it's easy to see which
+//! processes still run an old config during a rollout. Generations are
+//! stored in `<runtime-dir>/generations` as `<sandbox>.json`.
+use std::collections::BTreeMap;
+use std::fs::{File, create_dir_all, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::{from_reader, to_writer_pretty};
+
+use master_config::MasterConfig;
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    hash: String,
+    generation: u64,
+}
+
+pub struct Generations {
+    path: PathBuf,
+    children: BTreeMap<String, Entry>,
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, Entry>, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(BTreeMap::new());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    from_reader(file).map_err(|e| e.to_string())
+}
+
+impl Generations {
+    /// Loads generations of the children of the sandbox
+    pub fn load(master: &MasterConfig, sandbox: &str) -> Generations {
+        let path = master.child_generations_path()
+            .join(format!("{}.json", sandbox));
+        let children = read(&path)
+            .map_err(|e| error!("Can't read generations {:?}: {}", path, e))
+            .unwrap_or_else(|_| BTreeMap::new());
+        Generations { path, children }
+    }
+    /// Forgets children which are not in the config any more
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.children.retain(|name, _| keep(name));
+    }
+    /// Returns generation of the child, bumping it if `hash` has changed
+    ///
+    /// First generation is `1`.
+    pub fn get(&mut self, child: &str, hash: &str) -> u64 {
+        let entry = self.children.entry(child.to_string())
+            .or_insert_with(|| Entry { hash: hash.to_string(), generation: 1 });
+        if entry.hash != hash {
+            entry.hash = hash.to_string();
+            entry.generation += 1;
+            info!("Config of {:?} changed, generation {}",
+                child, entry.generation);
+        }
+        entry.generation
+    }
+    /// Writes generations to the runtime dir
+    pub fn save(&self) -> Result<(), io::Error> {
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        to_writer_pretty(File::create(&tmp)?, &self.children)?;
+        rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use super::Generations;
+
+    #[test]
+    fn bump() {
+        let mut gens = Generations {
+            path: PathBuf::from("/nonexistent"),
+            children: BTreeMap::new(),
+        };
+        assert_eq!(gens.get("x", "aaa"), 1);
+        assert_eq!(gens.get("x", "aaa"), 1);
+        assert_eq!(gens.get("x", "bbb"), 2);
+        assert_eq!(gens.get("y", "bbb"), 1);
+        gens.retain(|name| name != "x");
+        assert_eq!(gens.get("x", "bbb"), 1);
+    }
+}
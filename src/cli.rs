@@ -0,0 +1,555 @@
+//! Machine-readable help and shell completions of the command-line tools
+//!
+//! `argparse` has no way to enumerate options of the parser, so tools
+//! build it via `Parser`, which records every option and argument as it's
+//! added. The description of the command-line is made of these records.
+//!
+//! Tools call `parse` instead of `ArgumentParser::parse`, which handles
+//! `--help-json` and `--completions SHELL` given as the first argument.
+use std::env;
+use std::io::{Write, stdout, stderr};
+use std::path::Path;
+use std::process::exit;
+use std::str::FromStr;
+
+use argparse::{self, ArgumentParser};
+use argparse::{StoreTrue, StoreFalse, StoreConst, PushConst, IncrBy, DecrBy};
+use argparse::{Print, Store, Parse, StoreOption, ParseOption};
+use argparse::{List, ParseList, Collect, ParseCollect};
+use argparse::action::{TypedAction, IFlagAction};
+use serde_json;
+
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Argument {
+    pub name: String,
+    pub help: String,
+    pub required: bool,
+    /// Argument takes all the remaining values
+    pub multiple: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Opt {
+    pub names: Vec<String>,
+    /// Name of the value, `None` for flags
+    pub metavar: Option<String>,
+    pub help: String,
+}
+
+/// Description of the command-line, printed by `--help-json`
+#[derive(Debug, Serialize)]
+pub struct Help {
+    pub program: String,
+    pub usage: String,
+    pub description: String,
+    pub arguments: Vec<Argument>,
+    pub options: Vec<Opt>,
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    pub subcommands: Vec<Help>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// How many values the action of `argparse` takes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Flag,
+    Single,
+    Multiple,
+}
+
+/// Actions of `argparse` which options can be described
+pub trait Action {
+    fn kind() -> Kind;
+}
+
+macro_rules! action_kind {
+    ($kind:ident: $($action:ty),*) => {
+        $(
+            impl Action for $action {
+                fn kind() -> Kind { Kind::$kind }
+            }
+        )*
+    }
+}
+
+action_kind!(Flag: StoreTrue, StoreFalse, Print);
+action_kind!(Single: Store, Parse, StoreOption, ParseOption);
+action_kind!(Multiple: List, ParseList, Collect, ParseCollect);
+impl<T> Action for StoreConst<T> { fn kind() -> Kind { Kind::Flag } }
+impl<T> Action for PushConst<T> { fn kind() -> Kind { Kind::Flag } }
+impl<T> Action for IncrBy<T> { fn kind() -> Kind { Kind::Flag } }
+impl<T> Action for DecrBy<T> { fn kind() -> Kind { Kind::Flag } }
+
+/// Wrapper of `ArgumentParser` which keeps description of the options
+pub struct Parser<'parser> {
+    inner: ArgumentParser<'parser>,
+    description: &'parser str,
+    arguments: Vec<Argument>,
+    options: Vec<Opt>,
+}
+
+/// Wrapper of `argparse::Ref`, see `Parser::refer`
+pub struct Ref<'parser: 'refer, 'refer, T: 'parser> {
+    inner: Box<argparse::Ref<'parser, 'refer, T>>,
+    arguments: &'refer mut Vec<Argument>,
+    options: &'refer mut Vec<Opt>,
+    /// Indexes of the options of this variable which take a value
+    valued: Vec<usize>,
+    /// Indexes of the arguments of this variable
+    positional: Vec<usize>,
+    metavar: String,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Shell, String> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("Unknown shell {:?}, \
+                expected bash, zsh or fish", s)),
+        }
+    }
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|x| x.to_string()).collect()
+}
+
+impl<'parser> Parser<'parser> {
+    pub fn new() -> Parser<'parser> {
+        Parser {
+            inner: ArgumentParser::new(),
+            description: "",
+            arguments: Vec::new(),
+            // added by `ArgumentParser::new`
+            options: vec![Opt {
+                names: names(&["-h", "--help"]),
+                metavar: None,
+                help: "show this help message and exit".to_string(),
+            }],
+        }
+    }
+    /// Borrows a variable for options, like `ArgumentParser::refer`
+    pub fn refer<'x, T>(&'x mut self, val: &'parser mut T)
+        -> Ref<'parser, 'x, T>
+    {
+        Ref {
+            inner: self.inner.refer(val),
+            arguments: &mut self.arguments,
+            options: &mut self.options,
+            valued: Vec::new(),
+            positional: Vec::new(),
+            metavar: String::new(),
+        }
+    }
+    /// Adds an option which doesn't store a value, e.g. `Print(...)`
+    pub fn add_option<F: IFlagAction + 'parser>(&mut self,
+        names: &[&'parser str], action: F, help: &'parser str)
+    {
+        self.inner.add_option(names, action, help);
+        self.options.push(Opt {
+            names: self::names(names),
+            metavar: None,
+            help: help.to_string(),
+        });
+    }
+    pub fn set_description(&mut self, descr: &'parser str) {
+        self.inner.set_description(descr);
+        self.description = descr;
+    }
+    pub fn stop_on_first_argument(&mut self, want_stop: bool) {
+        self.inner.stop_on_first_argument(want_stop);
+    }
+    pub fn silence_double_dash(&mut self, silence: bool) {
+        self.inner.silence_double_dash(silence);
+    }
+}
+
+impl<'parser, 'refer, T> Ref<'parser, 'refer, T> {
+    fn update_metavar(&mut self) {
+        for &idx in &self.valued {
+            self.options[idx].metavar = Some(self.metavar.clone());
+        }
+    }
+    pub fn add_option<'x, A: TypedAction<T> + Action>(&'x mut self,
+        names: &[&'parser str], action: A, help: &'parser str)
+        -> &'x mut Ref<'parser, 'refer, T>
+    {
+        if self.metavar.is_empty() {
+            // the same default as `argparse` uses
+            let mut longest = names[0];
+            for &name in names {
+                if name.len() > longest.len() {
+                    longest = name;
+                }
+            }
+            if longest.len() > 2 {
+                self.metavar = longest[2..].to_ascii_uppercase()
+                    .replace("-", "_");
+            }
+        }
+        if A::kind() != Kind::Flag {
+            self.valued.push(self.options.len());
+        }
+        self.inner.add_option(names, action, help);
+        self.options.push(Opt {
+            names: self::names(names),
+            metavar: None,
+            help: help.to_string(),
+        });
+        self.update_metavar();
+        self
+    }
+    pub fn add_argument<'x, A: TypedAction<T> + Action>(&'x mut self,
+        name: &'parser str, action: A, help: &'parser str)
+        -> &'x mut Ref<'parser, 'refer, T>
+    {
+        self.inner.add_argument(name, action, help);
+        self.positional.push(self.arguments.len());
+        self.arguments.push(Argument {
+            name: name.to_string(),
+            help: help.to_string(),
+            required: false,
+            multiple: A::kind() == Kind::Multiple,
+        });
+        if self.metavar.is_empty() {
+            self.metavar = name.to_string();
+            self.update_metavar();
+        }
+        self
+    }
+    pub fn metavar<'x>(&'x mut self, name: &str)
+        -> &'x mut Ref<'parser, 'refer, T>
+    {
+        self.inner.metavar(name);
+        self.metavar = name.to_string();
+        self.update_metavar();
+        self
+    }
+    pub fn required<'x>(&'x mut self)
+        -> &'x mut Ref<'parser, 'refer, T>
+    {
+        self.inner.required();
+        for &idx in &self.positional {
+            self.arguments[idx].required = true;
+        }
+        self
+    }
+}
+
+/// Usage line in the same format as `argparse` prints it
+pub fn usage(program: &str, arguments: &[Argument]) -> String {
+    let mut result = format!("{} [OPTIONS]", program);
+    for arg in arguments {
+        let name = arg.name.to_ascii_uppercase();
+        result.push_str(&match (arg.required, arg.multiple) {
+            (true, false) => format!(" {}", name),
+            (false, false) => format!(" [{}]", name),
+            (true, true) => format!(" {} [...]", name),
+            (false, true) => format!(" [{} ...]", name),
+        });
+    }
+    result
+}
+
+/// Options handled by `parse`
+pub fn builtin_options() -> Vec<Opt> {
+    vec![
+        Opt {
+            names: vec!["--help-json".to_string()],
+            metavar: None,
+            help: "Print description of the options as JSON and exit \
+                   (must be the first argument)".to_string(),
+        },
+        Opt {
+            names: vec!["--completions".to_string()],
+            metavar: Some("SHELL".to_string()),
+            help: "Print completion script for bash, zsh or fish and exit \
+                   (must be the first argument)".to_string(),
+        },
+    ]
+}
+
+/// Describes options of the parser, including ones handled by `parse`
+pub fn describe(ap: &Parser, program: &str) -> Help {
+    let mut options = ap.options.iter().map(|o| Opt {
+        names: o.names.clone(),
+        metavar: o.metavar.clone(),
+        help: o.help.clone(),
+    }).collect::<Vec<_>>();
+    options.extend(builtin_options());
+    let arguments = ap.arguments.iter().map(|a| Argument {
+        name: a.name.clone(),
+        help: a.help.clone(),
+        required: a.required,
+        multiple: a.multiple,
+    }).collect::<Vec<_>>();
+    Help {
+        program: program.to_string(),
+        usage: usage(program, &arguments),
+        // descriptions are indented as string literals in the code
+        description: ap.description.lines().map(|x| x.trim())
+            .collect::<Vec<_>>().join("\n"),
+        arguments: arguments,
+        options: options,
+        subcommands: Vec::new(),
+    }
+}
+
+/// Serializes the description for `--help-json`
+pub fn help_json(help: &Help) -> String {
+    serde_json::to_string_pretty(help).expect("help is serializable")
+}
+
+/// First sentence of the help, as completion menus are narrow
+fn summary(help: &str) -> &str {
+    help.find(". ").map(|end| &help[..end]).unwrap_or(help)
+        .trim_end_matches('.')
+}
+
+fn bash(help: &Help) -> String {
+    let func = format!("_{}", help.program.replace(|c: char| {
+        !c.is_ascii_alphanumeric()
+    }, "_"));
+    let words = help.options.iter()
+        .flat_map(|o| o.names.iter().map(|x| &x[..]))
+        .collect::<Vec<_>>().join(" ");
+    let values = help.options.iter()
+        .filter(|o| o.metavar.is_some())
+        .flat_map(|o| o.names.iter().map(|x| &x[..]))
+        .collect::<Vec<_>>().join("|");
+    let mut result = format!("{}() {{\n", func);
+    result.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    result.push_str("    local prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    if !values.is_empty() {
+        result.push_str(&format!("    case \"$prev\" in\n        {})\n\
+            \x20           COMPREPLY=($(compgen -f -- \"$cur\"))\n\
+            \x20           return;;\n\
+            \x20   esac\n", values));
+    }
+    result.push_str(&format!("    if [[ \"$cur\" == -* ]]; then\n\
+        \x20       COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n\
+        \x20   else\n\
+        \x20       COMPREPLY=($(compgen -f -- \"$cur\"))\n\
+        \x20   fi\n\
+        }}\n\
+        complete -F {} {}\n", words, func, help.program));
+    result
+}
+
+fn zsh_escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\'' => result.push_str("'\\''"),
+            '[' | ']' | ':' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn zsh(help: &Help) -> String {
+    let mut result = format!("#compdef {}\n\n_arguments -s", help.program);
+    for opt in &help.options {
+        for name in &opt.names {
+            result.push_str(&format!(" \\\n    '{}[{}]", name,
+                zsh_escape(summary(&opt.help))));
+            if let Some(ref metavar) = opt.metavar {
+                result.push_str(&format!(":{}:_files", zsh_escape(metavar)));
+            }
+            result.push('\'');
+        }
+    }
+    if !help.arguments.is_empty() {
+        result.push_str(" \\\n    '*::argument:_files'");
+    }
+    result.push('\n');
+    result
+}
+
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish(help: &Help) -> String {
+    let mut result = String::new();
+    for opt in &help.options {
+        result.push_str(&format!("complete -c {}", help.program));
+        for name in &opt.names {
+            if name.starts_with("--") {
+                result.push_str(&format!(" -l {}", &name[2..]));
+            } else {
+                result.push_str(&format!(" -s {}", &name[1..]));
+            }
+        }
+        if opt.metavar.is_some() {
+            result.push_str(" -r");
+        }
+        result.push_str(&format!(" -d '{}'\n",
+            fish_escape(summary(&opt.help))));
+    }
+    result
+}
+
+/// Returns completion script for the shell
+pub fn completion(help: &Help, shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash(help),
+        Shell::Zsh => zsh(help),
+        Shell::Fish => fish(help),
+    }
+}
+
+/// Parses arguments like `ArgumentParser::parse` does, but first handles
+/// `--help-json` and `--completions SHELL`
+pub fn parse(ap: &Parser, args: Vec<String>,
+    stdout: &mut Write, stderr: &mut Write)
+    -> Result<(), i32>
+{
+    let program = args.get(0)
+        .and_then(|x| Path::new(x).file_name())
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let shell = match args.get(1).map(|x| &x[..]) {
+        Some("--help-json") => {
+            let help = describe(ap, &program);
+            writeln!(stdout, "{}", help_json(&help)).ok();
+            return Err(0);
+        }
+        Some("--completions") => args.get(2).map(|x| &x[..]),
+        Some(x) if x.starts_with("--completions=") => {
+            Some(&x["--completions=".len()..])
+        }
+        _ => return ap.inner.parse(args, stdout, stderr),
+    };
+    match shell.map(|x| x.parse::<Shell>()) {
+        Some(Ok(shell)) => {
+            let help = describe(ap, &program);
+            write!(stdout, "{}", completion(&help, shell)).ok();
+            Err(0)
+        }
+        Some(Err(e)) => {
+            ap.inner.error(&program, &e, stderr);
+            Err(2)
+        }
+        None => {
+            ap.inner.error(&program, "Option --completions requires SHELL",
+                stderr);
+            Err(2)
+        }
+    }
+}
+
+/// Convenience wrapper of `parse`, like `ArgumentParser::parse_args`
+pub fn parse_args(ap: &Parser) -> Result<(), i32> {
+    parse(ap, env::args().collect(), &mut stdout(), &mut stderr())
+}
+
+/// Convenience wrapper of `parse`, like
+/// `ArgumentParser::parse_args_or_exit`
+pub fn parse_args_or_exit(ap: &Parser) {
+    parse_args(ap).map_err(|c| exit(c)).ok();
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::sink;
+    use std::str::from_utf8;
+    use argparse::{Store, StoreTrue, List, Collect};
+    use super::{Parser, describe, parse, completion, Opt, Shell};
+
+    #[test]
+    fn options() {
+        let mut name = String::new();
+        let mut verbose = false;
+        let mut args = Vec::<String>::new();
+        let mut ap = Parser::new();
+        ap.set_description("Test program");
+        ap.refer(&mut name)
+          .add_option(&["-N", "--name"], Store, "The name. Long description \
+            of the option, which is wrapped into multiple lines by the \
+            argparse")
+          .metavar("NAME");
+        ap.refer(&mut verbose)
+          .add_option(&["--a-very-long-verbose-flag"], StoreTrue,
+            "Verbose output");
+        ap.refer(&mut args)
+          .add_argument("argument", List, "Arguments");
+        let help = describe(&ap, "test");
+        assert_eq!(help.usage, "test [OPTIONS] [ARGUMENT ...]");
+        assert_eq!(help.description, "Test program");
+        assert_eq!(help.arguments.len(), 1);
+        assert_eq!(help.arguments[0].name, "argument");
+        assert_eq!(help.arguments[0].help, "Arguments");
+        assert!(!help.arguments[0].required);
+        assert!(help.arguments[0].multiple);
+        assert_eq!(help.options[0].names, vec!["-h", "--help"]);
+        assert_eq!(help.options[1], Opt {
+            names: vec!["-N".to_string(), "--name".to_string()],
+            metavar: Some("NAME".to_string()),
+            help: "The name. Long description of the option, which is \
+                   wrapped into multiple lines by the argparse".to_string(),
+        });
+        assert_eq!(help.options[2], Opt {
+            names: vec!["--a-very-long-verbose-flag".to_string()],
+            metavar: None,
+            help: "Verbose output".to_string(),
+        });
+        assert_eq!(help.options[3].names, vec!["--help-json"]);
+        let fish = completion(&help, Shell::Fish);
+        assert!(fish.contains("complete -c test -s N -l name -r \
+                               -d 'The name'\n"));
+    }
+
+    #[test]
+    fn default_metavar() {
+        let mut dirs = Vec::<String>::new();
+        let mut command = String::new();
+        let mut ap = Parser::new();
+        ap.refer(&mut dirs)
+          .add_option(&["-d", "--image-dir"], Collect, "Image directory");
+        ap.refer(&mut command)
+          .add_argument("command", Store, "Command to run")
+          .required();
+        let help = describe(&ap, "test");
+        assert_eq!(help.usage, "test [OPTIONS] COMMAND");
+        assert_eq!(help.options[1].metavar, Some("IMAGE_DIR".to_string()));
+        assert!(help.arguments[0].required);
+        assert!(!help.arguments[0].multiple);
+    }
+
+    #[test]
+    fn completions_arg() {
+        let mut verbose = false;
+        let mut out = Vec::new();
+        {
+            let mut ap = Parser::new();
+            ap.refer(&mut verbose)
+              .add_option(&["-v"], StoreTrue, "Verbose output");
+            let args = vec!["/usr/bin/test".to_string(),
+                            "--completions=bash".to_string()];
+            assert_eq!(parse(&ap, args, &mut out, &mut sink()), Err(0));
+            let args = vec!["test".to_string(), "--completions".to_string(),
+                            "tcsh".to_string()];
+            assert_eq!(parse(&ap, args, &mut sink(), &mut sink()), Err(2));
+            let args = vec!["test".to_string(), "-v".to_string()];
+            assert_eq!(parse(&ap, args, &mut sink(), &mut sink()), Ok(()));
+        }
+        assert!(verbose);
+        let out = from_utf8(&out).unwrap();
+        assert!(out.contains("complete -F _test test\n"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Limit of concurrently running commands (`max-concurrent-commands`)
+//!
+//! Commands are run by independent `lithos_cmd` processes, so the limit is
+//! implemented with `flock`'ed files in `<runtime-dir>/command-slots`.
+//! Each running command holds a lock on one of `<N>.lock` files, lock is
+//! released by the kernel when `lithos_cmd` exits, even if it's killed.
+//!
+//! While waiting for a free slot `lithos_cmd` holds a lock on
+//! `queue/<pid>` file, so `lithos_tree` can report the length of the queue.
+use std::fs::{File, OpenOptions, create_dir_all, read_dir, remove_file};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread::sleep;
+use std::time::Duration;
+
+use nix::fcntl::{flock, FlockArg};
+
+use master_config::MasterConfig;
+
+
+/// How often waiting command checks for a free slot
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Slot of the running command, freed when dropped
+pub struct Slot {
+    _lock: File,
+}
+
+/// Marks the command as waiting in the queue until dropped
+struct Queued {
+    path: PathBuf,
+    _lock: File,
+}
+
+fn try_lock(path: &Path) -> Result<Option<File>, io::Error> {
+    let file = OpenOptions::new()
+        .read(true).write(true).create(true).truncate(false)
+        .open(path)?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(Some(file)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn try_acquire(dir: &Path, limit: usize) -> Result<Option<Slot>, io::Error> {
+    for idx in 0..limit {
+        if let Some(file) = try_lock(&dir.join(format!("{}.lock", idx)))? {
+            return Ok(Some(Slot { _lock: file }));
+        }
+    }
+    Ok(None)
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        remove_file(&self.path)
+            .map_err(|e| debug!("Can't remove {:?}: {}", self.path, e))
+            .ok();
+    }
+}
+
+/// Waits until number of running commands is less than the `limit` and
+/// takes a slot
+pub fn acquire(master: &MasterConfig, limit: usize)
+    -> Result<Slot, String>
+{
+    let dir = master.command_slots_path();
+    let queue_dir = dir.join("queue");
+    create_dir_all(&queue_dir)
+        .map_err(|e| format!("Can't create dir {:?}: {}", queue_dir, e))?;
+    let err = |e: io::Error| {
+        format!("Can't lock command slot in {:?}: {}", dir, e)
+    };
+    if let Some(slot) = try_acquire(&dir, limit).map_err(&err)? {
+        return Ok(slot);
+    }
+    let path = queue_dir.join(process::id().to_string());
+    // file may be locked by `queue_length` for a moment, it only affects
+    // metrics, so we don't retry
+    let _queued = try_lock(&path).map_err(&err)?
+        .map(|lock| Queued { path, _lock: lock });
+    info!("Maximum number of concurrent commands ({}) reached, waiting",
+        limit);
+    loop {
+        sleep(POLL_INTERVAL);
+        if let Some(slot) = try_acquire(&dir, limit).map_err(&err)? {
+            return Ok(slot);
+        }
+    }
+}
+
+/// Returns number of commands waiting for a slot
+///
+/// Files left by killed processes aren't locked, they are removed.
+pub fn queue_length(master: &MasterConfig) -> usize {
+    let queue_dir = master.command_slots_path().join("queue");
+    let entries = match read_dir(&queue_dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return 0,
+        Err(e) => {
+            debug!("Can't read dir {:?}: {}", queue_dir, e);
+            return 0;
+        }
+    };
+    let mut waiting = 0;
+    for entry in entries.filter_map(|e| e.ok()) {
+        match try_lock(&entry.path()) {
+            Ok(Some(_)) => {
+                remove_file(entry.path()).ok();
+            }
+            Ok(None) => waiting += 1,
+            Err(e) => debug!("Can't lock {:?}: {}", entry.path(), e),
+        }
+    }
+    return waiting;
+}
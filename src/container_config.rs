@@ -1,21 +1,32 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::fmt;
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 #[cfg(not(target_arch="wasm32"))] use std::os::unix::io::RawFd;
 
 use serde::de::{Deserializer, Deserialize, Error as DeError};
+use serde::de::{Visitor, SeqAccess};
 use serde::ser::{Serializer, Serialize};
 use serde_json::Value as Json;
 use quire::validate::{Structure, Sequence, Scalar, Numeric, Enum};
 use quire::validate::{Mapping, Nothing, Anything};
 use id_map::{IdMap, IdMapExt, mapping_validator};
+use ipnetwork::IpNetwork;
 
 use sandbox_config::SandboxConfig;
-use range::{in_range};
+use range::{Range, in_range};
 use child_config::ChildKind;
+use host_facts::HostFacts;
+use master_config::ContainerDefaults;
 
 
 pub const DEFAULT_KILL_TIMEOUT: f32 = 5.;
+/// Limit of the length of a single argument or environment variable
+/// (`MAX_ARG_STRLEN` of the kernel, 32 pages)
+pub const MAX_ARG_STRLEN: usize = 32 * 4096;
+/// `ARG_MAX` with the default 8 MiB stack, used if it can't be queried
+const DEFAULT_ARG_MAX: usize = 2 * 1024 * 1024;
 
 #[cfg(target_arch="wasm32")] type RawFd = i32;
 
@@ -32,6 +43,7 @@ pub struct PersistentInfo {
     pub mode: u32,
     pub user: u32,
     pub group: u32,
+    pub idmap: bool,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -86,18 +98,350 @@ pub struct HostsFile {
     pub public_hostname: Option<bool>,
 }
 
+/// One or more addresses to listen on
+///
+/// Serialized as a string for a single address (the only form supported
+/// before), and as a list otherwise.
 #[derive(Clone, Debug)]
-pub struct Host(pub IpAddr);
+pub struct Hosts(pub Vec<IpAddr>);
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct TcpPort {
-    pub host: Host,
+    pub host: Hosts,
     pub fd: RawFd,
     pub reuse_addr: bool,
     pub reuse_port: bool,
     pub set_non_block: bool,
     pub listen_backlog: usize,
     pub external: bool,
+    pub accept_before_exec: Option<RawFd>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub wait_for_address: Option<f32>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub syn_count: Option<u8>,
+    /// Sets `IPV6_V6ONLY` for IPv6 addresses
+    #[serde(skip_serializing_if="is_true", default="default_true")]
+    pub v6only: bool,
+}
+
+fn is_true(x: &bool) -> bool { *x }
+fn default_true() -> bool { true }
+
+/// Unix socket created by `lithos_tree` in `unix-sockets-dir` of the sandbox
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UnixSocket {
+    pub fd: RawFd,
+    pub mode: u32,
+    /// Owner of the socket file as seen in the container, by default it's
+    /// the user of the container
+    pub user: Option<u32>,
+    pub group: Option<u32>,
+    pub set_non_block: bool,
+    pub listen_backlog: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct EgressRule {
+    #[serde(with="::serde_str")]
+    pub network: IpNetwork,
+    pub ports: Vec<Range>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct EgressPolicy {
+    pub allow: Vec<EgressRule>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum GpuVendor {
+    Nvidia,
+    Amd,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum CgroupVersion {
+    V1,
+    V2,
+}
+
+impl CgroupVersion {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CgroupVersion::V1 => "v1",
+            CgroupVersion::V2 => "v2",
+        }
+    }
+}
+
+/// Command run in the container before `SIGTERM` on planned stops
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PreStop {
+    pub executable: String,
+    pub arguments: Vec<String>,
+    /// Seconds the command may run, the process is terminated after that
+    pub timeout: f32,
+}
+
+/// Throttling of a block device
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct IoLimit {
+    /// Whole block device, e.g. `/dev/sda`
+    pub device: PathBuf,
+    pub read_bps: Option<u64>,
+    pub write_bps: Option<u64>,
+    pub read_iops: Option<u64>,
+    pub write_iops: Option<u64>,
+}
+
+/// File in a volume which is flipped to `draining` on planned stops
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DrainFile {
+    pub path: PathBuf,
+    /// Seconds between writing the file and the rest of the stop
+    pub delay: f32,
+}
+
+/// Check run periodically while the process is alive
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HealthCheck {
+    pub check: HealthProbe,
+    /// Seconds between the checks
+    pub interval: f32,
+    /// Seconds a single check may take, it's failed after that
+    pub timeout: f32,
+    /// Number of consecutive failures after which process is restarted
+    pub failure_threshold: u32,
+}
+
+/// Check which tells that the process is ready after it's started
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ReadinessCheck {
+    pub check: HealthProbe,
+    /// Seconds between the attempts
+    pub interval: f32,
+    /// Seconds a single attempt may take
+    pub timeout: f32,
+    /// Seconds after the start when attempts are given up
+    pub max_wait: f32,
+}
+
+/// Socket for systemd notification protocol (`sd_notify`)
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SdNotify {
+    /// Path of the socket inside the container
+    pub path: PathBuf,
+    /// Seconds after the start when waiting for `READY=1` is given up
+    pub max_wait: f32,
+    /// Seconds between `WATCHDOG=1` messages after which process is
+    /// restarted
+    pub watchdog: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum HealthProbe {
+    /// Passes if connection can be established
+    Tcp(TcpCheck),
+    /// Passes if response status is `2xx` or `3xx`
+    Http(HttpCheck),
+    /// Passes if command exits with code zero
+    Exec(ExecCheck),
+}
+
+impl Volume {
+    /// Substitutes variables in the paths of the volume
+    fn substitute<F, S>(&self, mut replacer: F) -> Volume
+        where F: FnMut(&str) -> S,
+              S: AsRef<str>,
+    {
+        let mut path = |p: &PathBuf| {
+            PathBuf::from(replace_vars(&p.to_string_lossy(), &mut replacer))
+        };
+        match *self {
+            Volume::Readonly(ref p) => Volume::Readonly(path(p)),
+            Volume::Persistent(ref info) => Volume::Persistent(PersistentInfo {
+                path: path(&info.path),
+                .. info.clone()
+            }),
+            Volume::Statedir(ref info) => Volume::Statedir(StatedirInfo {
+                path: path(&info.path),
+                .. info.clone()
+            }),
+            ref vol => vol.clone(),
+        }
+    }
+}
+
+impl HealthProbe {
+    /// Substitutes variables in the arguments of `Exec` check
+    fn substitute<F, S>(&self, mut replacer: F) -> HealthProbe
+        where F: FnMut(&str) -> S,
+              S: AsRef<str>,
+    {
+        match *self {
+            HealthProbe::Exec(ref cmd) => HealthProbe::Exec(ExecCheck {
+                executable: cmd.executable.clone(),
+                arguments: cmd.arguments.iter()
+                    .map(|x| replace_vars(&x, &mut replacer))
+                    .collect(),
+            }),
+            ref probe => probe.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TcpCheck {
+    pub host: IpAddr,
+    pub port: u16,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HttpCheck {
+    pub host: IpAddr,
+    pub port: u16,
+    pub path: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ExecCheck {
+    pub executable: String,
+    pub arguments: Vec<String>,
+}
+
+/// Seccomp filter of the process
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum SeccompProfile {
+    /// Path to the JSON profile in the image (in docker/OCI format)
+    File(PathBuf),
+    Inline(SeccompRules),
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SeccompAction {
+    #[serde(rename="SCMP_ACT_KILL")]
+    Kill,
+    #[serde(rename="SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+    #[serde(rename="SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename="SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename="SCMP_ACT_LOG")]
+    Log,
+    #[serde(rename="SCMP_ACT_ALLOW")]
+    Allow,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SeccompOp {
+    #[serde(rename="SCMP_CMP_NE")]
+    NotEqual,
+    #[serde(rename="SCMP_CMP_LT")]
+    Less,
+    #[serde(rename="SCMP_CMP_LE")]
+    LessOrEqual,
+    #[serde(rename="SCMP_CMP_EQ")]
+    Equal,
+    #[serde(rename="SCMP_CMP_GE")]
+    GreaterOrEqual,
+    #[serde(rename="SCMP_CMP_GT")]
+    Greater,
+    #[serde(rename="SCMP_CMP_MASKED_EQ")]
+    MaskedEqual,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SeccompRules {
+    pub default_action: SeccompAction,
+    /// Error code for `SCMP_ACT_ERRNO` default action
+    pub default_errno: u32,
+    pub syscalls: Vec<SeccompRule>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SeccompRule {
+    pub names: Vec<String>,
+    pub action: SeccompAction,
+    /// Error code for `SCMP_ACT_ERRNO` action
+    pub errno: u32,
+    pub args: Vec<SeccompArg>,
+}
+
+/// Rule matches only if argument `index` compares to `value` with `op`
+///
+/// `value_two` is only used by `SCMP_CMP_MASKED_EQ`, as the value compared
+/// to the argument masked with `value`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SeccompArg {
+    pub index: u32,
+    pub value: u64,
+    pub value_two: u64,
+    pub op: SeccompOp,
+}
+
+/// How instances of the child are placed on the host CPUs
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum Spread {
+    None,
+    /// Each instance is pinned to the CPUs of a NUMA node, round-robin
+    Numa,
+}
+
+impl Default for Spread {
+    fn default() -> Spread { Spread::None }
+}
+
+impl Spread {
+    pub fn is_none(&self) -> bool {
+        *self == Spread::None
+    }
+}
+
+/// Whether daemon is restarted when it exits by itself
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    /// Restarted only if exit code is not in `normal-exit-codes`
+    OnFailure,
+    Never,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> RestartPolicy { RestartPolicy::Always }
+}
+
+impl RestartPolicy {
+    pub fn is_always(&self) -> bool {
+        *self == RestartPolicy::Always
+    }
+}
+
+fn default_restart_window() -> f32 { 600. }
+
+/// Files of the state dir written by `lithos_knot` itself
+pub const RESERVED_STATE_FILES: &[&str] = &[
+    "resolv.conf", "hosts", "lithos-info",
+];
+
+/// Properties of the host the container can run on, checked by knot
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Requirements {
+    /// Machine as reported by `uname -m`, e.g. `x86_64` or `aarch64`
+    pub arch: Option<String>,
+    /// Minimum kernel version, like `4.18`
+    pub min_kernel: Option<String>,
+    pub cgroup: Option<CgroupVersion>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct GpuConfig {
+    pub vendor: GpuVendor,
+    pub devices: Vec<u32>,
+    pub driver_libraries: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
@@ -121,17 +465,24 @@ pub struct ContainerConfig {
     pub volumes: BTreeMap<String, Volume>,
     pub user_id: Option<u32>,
     pub group_id: Option<u32>,
+    #[serde(deserialize_with="timeout")]
     pub restart_timeout: f32,
+    #[serde(deserialize_with="timeout")]
     pub kill_timeout: f32,
     pub memory_limit: u64,
     pub fileno_limit: u64,
     pub cpu_shares: usize,
+    #[serde(default)]
+    pub process_limit: Option<u32>,
+    #[serde(default)]
+    pub io_limits: Vec<IoLimit>,
     pub executable: String,
     pub arguments: Vec<String>,
     pub environ: BTreeMap<String, String>,
     pub secret_environ: BTreeMap<String, Vec<String>>,
     pub secret_environ_file: Option<PathBuf>,
     pub workdir: PathBuf,
+    pub create_workdir: bool,
     pub resolv_conf: ResolvConf,
     pub hosts_file: HostsFile,
     pub uid_map: Vec<IdMap>,
@@ -141,6 +492,34 @@ pub struct ContainerConfig {
     pub restart_process_only: bool,
     pub normal_exit_codes: BTreeSet<i32>,
     pub tcp_ports: HashMap<String, TcpPort>,
+    pub egress_policy: Option<EgressPolicy>,
+    pub restart_on_fd_usage: Option<f32>,
+    pub gpus: Option<GpuConfig>,
+    pub requires: Option<Requirements>,
+    #[serde(default)]
+    pub unix_sockets: BTreeMap<String, UnixSocket>,
+    #[serde(default)]
+    pub spread: Spread,
+    #[serde(default)]
+    pub pre_stop: Option<PreStop>,
+    #[serde(default)]
+    pub seccomp_profile: Option<SeccompProfile>,
+    #[serde(default)]
+    pub drain_file: Option<DrainFile>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub readiness: Option<ReadinessCheck>,
+    #[serde(default)]
+    pub sd_notify: Option<SdNotify>,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub max_restarts: Option<u32>,
+    #[serde(default="default_restart_window")]
+    pub restart_window: f32,
+    #[serde(default)]
+    pub state_files: BTreeMap<String, String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -154,10 +533,15 @@ pub struct InstantiatedConfig {
     pub memory_limit: u64,
     pub fileno_limit: u64,
     pub cpu_shares: usize,
+    #[serde(default)]
+    pub process_limit: Option<u32>,
+    #[serde(default)]
+    pub io_limits: Vec<IoLimit>,
     pub executable: String,
     pub arguments: Vec<String>,
     pub environ: BTreeMap<String, String>,
     pub workdir: PathBuf,
+    pub create_workdir: bool,
     pub resolv_conf: ResolvConf,
     pub hosts_file: HostsFile,
     pub uid_map: Vec<IdMap>,
@@ -168,6 +552,45 @@ pub struct InstantiatedConfig {
     pub normal_exit_codes: BTreeSet<i32>,
     pub tcp_ports: HashMap<u16, TcpPort>,
     pub pid_env_vars: HashSet<String>,
+    pub egress_policy: Option<EgressPolicy>,
+    pub restart_on_fd_usage: Option<f32>,
+    pub gpus: Option<GpuConfig>,
+    /// Unix sockets by file name in `unix-sockets-dir` of the sandbox
+    #[serde(skip_serializing_if="BTreeMap::is_empty", default)]
+    pub unix_sockets: BTreeMap<String, UnixSocket>,
+    #[serde(skip_serializing_if="Spread::is_none", default)]
+    pub spread: Spread,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub pre_stop: Option<PreStop>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub seccomp_profile: Option<SeccompProfile>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub drain_file: Option<DrainFile>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub readiness: Option<ReadinessCheck>,
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub sd_notify: Option<SdNotify>,
+    #[serde(skip_serializing_if="RestartPolicy::is_always", default)]
+    pub restart_policy: RestartPolicy,
+    /// Restarts allowed within `restart_window` seconds
+    #[serde(skip_serializing_if="Option::is_none", default)]
+    pub max_restarts: Option<u32>,
+    #[serde(default="default_restart_window")]
+    pub restart_window: f32,
+    /// Contents of the files written into the state dir, by file name
+    #[serde(skip_serializing_if="BTreeMap::is_empty", default)]
+    pub state_files: BTreeMap<String, String>,
+}
+
+impl InstantiatedConfig {
+    /// Seconds the process may take to become ready after it's started,
+    /// `None` if it doesn't report readiness
+    pub fn ready_wait(&self) -> Option<f32> {
+        self.readiness.as_ref().map(|rc| rc.max_wait)
+            .or(self.sd_notify.as_ref().map(|n| n.max_wait))
+    }
 }
 
 
@@ -175,9 +598,89 @@ pub struct Variables<'a> {
     pub user_vars: &'a BTreeMap<String, String>,
     pub lithos_name: &'a str,
     pub lithos_config_filename: &'a str,
+    pub host_facts: Option<&'a HostFacts>,
+    /// Decrypts values of `@{secret:...}`, they are substituted with a
+    /// placeholder when it's `None` (i.e. outside of `lithos_knot`)
+    pub secrets: Option<&'a Fn(&str) -> Result<String, String>>,
+}
+
+#[cfg(not(target_arch="wasm32"))]
+fn arg_max() -> usize {
+    match unsafe { ::libc::sysconf(::libc::_SC_ARG_MAX) } {
+        val if val > 0 => val as usize,
+        _ => DEFAULT_ARG_MAX,
+    }
+}
+
+#[cfg(target_arch="wasm32")]
+fn arg_max() -> usize {
+    DEFAULT_ARG_MAX
+}
+
+/// Space the string takes in the new process, as counted by the kernel:
+/// the string itself, terminating zero, and a pointer to it
+fn exec_size(len: usize) -> usize {
+    len + 1 + size_of::<usize>()
 }
 
 impl InstantiatedConfig {
+    /// Checks that arguments and environment fit into the limits of
+    /// `execve`, and environment into `max-environ-size` of the sandbox
+    ///
+    /// Otherwise the process fails to start with uninformative `E2BIG`.
+    /// Errors list the largest variables, so it's clear what to trim.
+    pub fn check_exec_size(&self, max_environ_size: Option<usize>)
+        -> Result<(), Vec<String>>
+    {
+        let mut errors = Vec::new();
+        // each variable is passed as `KEY=VALUE`
+        let mut sizes = self.environ.iter()
+            .map(|(key, val)| (key, key.len() + 1 + val.len()))
+            .collect::<Vec<_>>();
+        sizes.sort_by(|a, b| b.1.cmp(&a.1));
+        for &(key, size) in &sizes {
+            if size > MAX_ARG_STRLEN {
+                errors.push(format!("environment variable {:?} is {} bytes, \
+                    more than the limit of a single variable ({})",
+                    key, size, MAX_ARG_STRLEN));
+            }
+        }
+        // arguments may contain secrets, so only the number is reported
+        for (idx, arg) in self.arguments.iter().enumerate() {
+            if arg.len() > MAX_ARG_STRLEN {
+                errors.push(format!("argument {} is {} bytes, \
+                    more than the limit of a single argument ({})",
+                    idx + 1, arg.len(), MAX_ARG_STRLEN));
+            }
+        }
+        let largest = || {
+            sizes.iter().take(3)
+                .map(|&(key, size)| format!("{} ({} bytes)", key, size))
+                .collect::<Vec<_>>().join(", ")
+        };
+        let environ = sizes.iter()
+            .map(|&(_, size)| exec_size(size)).sum::<usize>();
+        if let Some(limit) = max_environ_size {
+            if environ > limit {
+                errors.push(format!("environment is {} bytes, more than \
+                    max-environ-size of the sandbox ({}), largest \
+                    variables: {}", environ, limit, largest()));
+            }
+        }
+        let total = environ + exec_size(self.executable.len()) +
+            self.arguments.iter().map(|x| exec_size(x.len())).sum::<usize>();
+        let limit = arg_max();
+        if total > limit {
+            errors.push(format!("arguments and environment are {} bytes, \
+                more than ARG_MAX ({}), largest variables: {}",
+                total, limit, largest()));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
     pub fn map_uid(&self, internal_uid: u32) -> Option<u32> {
         self.uid_map.map_id(internal_uid)
     }
@@ -186,7 +689,7 @@ impl InstantiatedConfig {
     }
 }
 
-fn wrap_into_list(ast: ::quire::ast::Ast) -> Vec<::quire::ast::Ast> {
+pub fn wrap_into_list(ast: ::quire::ast::Ast) -> Vec<::quire::ast::Ast> {
     use quire::ast::Ast::Scalar;
     use quire::ast::Tag::NonSpecific;
     use quire::ast::ScalarKind::Plain;
@@ -214,6 +717,14 @@ pub fn environ_validator<'x>() -> Mapping<'x> {
 
 impl ContainerConfig {
     pub fn validator<'x>() -> Structure<'x> {
+        ContainerConfig::validator_with(&ContainerDefaults::default())
+    }
+    /// Validator which uses `container-defaults` of the master config
+    pub fn validator_with<'x>(defaults: &ContainerDefaults) -> Structure<'x> {
+        let stdout_stderr_file = match defaults.stdout_stderr_file {
+            Some(ref path) => Scalar::new().default(path.display()),
+            None => Scalar::new().optional(),
+        };
         Structure::new()
         .member("kind", Scalar::new().default("Daemon"))
         .member("variables", Mapping::new(
@@ -235,12 +746,21 @@ impl ContainerConfig {
         .member("user_id", Numeric::new().optional())
         .member("group_id", Numeric::new().optional())
         .member("memory_limit", Numeric::new().default(0x7fffffffffffffffi64))
-        .member("fileno_limit", Numeric::new().default(1024))
+        .member("fileno_limit", Numeric::new()
+            .default(defaults.fileno_limit.unwrap_or(1024) as i64))
         .member("cpu_shares", Numeric::new().default(1024))
-        .member("restart_timeout", Numeric::new().min(0).max(86400).default(1))
-        .member("kill_timeout",
-            Numeric::new().min(0).max(86400)
-                .default(DEFAULT_KILL_TIMEOUT as i64))
+        .member("process_limit", Numeric::new().min(1).optional())
+        .member("io_limits", Sequence::new(Structure::new()
+            .member("device", Scalar::new())
+            .member("read_bps", Numeric::new().min(1).optional())
+            .member("write_bps", Numeric::new().min(1).optional())
+            .member("read_iops", Numeric::new().min(1).optional())
+            .member("write_iops", Numeric::new().min(1).optional())))
+        // fractional seconds are allowed, range is checked by `timeout`
+        .member("restart_timeout", Scalar::new()
+            .default(defaults.restart_timeout.unwrap_or(1.)))
+        .member("kill_timeout", Scalar::new()
+            .default(defaults.kill_timeout.unwrap_or(DEFAULT_KILL_TIMEOUT)))
         .member("executable", Scalar::new())
         .member("arguments", Sequence::new(Scalar::new()))
         .member("environ", Mapping::new(
@@ -249,6 +769,7 @@ impl ContainerConfig {
         .member("secret_environ", environ_validator())
         .member("secret_environ_file", Scalar::new().optional())
         .member("workdir", Scalar::new().default("/"))
+        .member("create_workdir", Scalar::new().default(false))
         .member("resolv_conf", Structure::new()
             .member("mount", Scalar::new().optional())
             .member("copy_from_host", Scalar::new().default(true)))
@@ -259,21 +780,105 @@ impl ContainerConfig {
             .member("public_hostname", Scalar::new().optional()))
         .member("uid_map", mapping_validator())
         .member("gid_map", mapping_validator())
-        .member("stdout_stderr_file", Scalar::new().optional())
+        .member("stdout_stderr_file", stdout_stderr_file)
         .member("interactive", Scalar::new().default(false))
         .member("restart_process_only", Scalar::new().default(false))
         .member("normal_exit_codes", Sequence::new(Numeric::new()))
         .member("tcp_ports", Mapping::new(
             Scalar::new(),
             Structure::new()
-                .member("host", Scalar::new().default("0.0.0.0"))
+                .member("host", Anything)
                 .member("fd", Numeric::new().min(0).optional())
                 .member("reuse_addr", Scalar::new().default(true))
                 .member("reuse_port", Scalar::new().default(false))
                 .member("set_non_block", Scalar::new().default(false))
                 .member("listen_backlog", Scalar::new().default(128))
                 .member("external", Scalar::new().default(false))
+                .member("accept_before_exec",
+                    Numeric::new().min(3).optional())
+                .member("wait_for_address",
+                    Numeric::new().min(0).optional())
+                .member("syn_count",
+                    Numeric::new().min(1).max(127).optional())
+                .member("v6only", Scalar::new().default(true))
+            ))
+        .member("unix_sockets", Mapping::new(
+            Scalar::new(),
+            Structure::new()
+                .member("fd", Numeric::new().min(3))
+                .member("mode", Numeric::new().min(0).max(0o777)
+                    .default(0o660))
+                .member("user", Numeric::new().optional())
+                .member("group", Numeric::new().optional())
+                .member("set_non_block", Scalar::new().default(false))
+                .member("listen_backlog", Numeric::new().min(1).default(128))
             ))
+        .member("restart_on_fd_usage", Scalar::new().optional())
+        .member("egress_policy", Structure::new()
+            .member("allow", Sequence::new(Structure::new()
+                .member("network", Scalar::new())
+                .member("ports", Sequence::new(Scalar::new()))))
+            .optional())
+        .member("gpus", Structure::new()
+            .member("vendor", Scalar::new())
+            .member("devices", Sequence::new(Numeric::new().min(0)))
+            .member("driver_libraries", Scalar::new().optional())
+            .optional())
+        .member("requires", Structure::new()
+            .member("arch", Scalar::new().optional())
+            .member("min_kernel", Scalar::new().optional())
+            .member("cgroup", Scalar::new().optional())
+            .optional())
+        .member("spread", Scalar::new().default("none"))
+        .member("restart_policy", Scalar::new().default("always"))
+        .member("max_restarts", Numeric::new().min(0).optional())
+        .member("restart_window", Numeric::new().min(1).max(86400)
+            .default(600))
+        .member("state_files", Mapping::new(Scalar::new(), Scalar::new()))
+        .member("pre_stop", Structure::new()
+            .member("executable", Scalar::new())
+            .member("arguments", Sequence::new(Scalar::new()))
+            .member("timeout", Numeric::new().min(0).max(86400).default(10))
+            .optional())
+        .member("seccomp_profile", Enum::new()
+            .option("File", Scalar::new())
+            .option("Inline", Structure::new()
+                .member("default_action", Scalar::new())
+                .member("default_errno", Numeric::new().min(0).max(4095)
+                    .default(1))
+                .member("syscalls", Sequence::new(Structure::new()
+                    .member("names", Sequence::new(Scalar::new()))
+                    .member("action", Scalar::new())
+                    .member("errno", Numeric::new().min(0).max(4095)
+                        .default(1))
+                    .member("args", Sequence::new(Structure::new()
+                        .member("index", Numeric::new().min(0).max(5))
+                        .member("value", Numeric::new().min(0))
+                        .member("value_two", Numeric::new().min(0)
+                            .default(0))
+                        .member("op", Scalar::new()))))))
+            .optional())
+        .member("drain_file", Structure::new()
+            .member("path", Scalar::new())
+            .member("delay", Numeric::new().min(0).max(3600).default(5))
+            .optional())
+        .member("health_check", Structure::new()
+            .member("check", probe_validator())
+            .member("interval", Numeric::new().min(0).max(86400).default(10))
+            .member("timeout", Numeric::new().min(0).max(86400).default(1))
+            .member("failure_threshold", Numeric::new().min(1).default(3))
+            .optional())
+        .member("readiness", Structure::new()
+            .member("check", probe_validator())
+            .member("interval", Numeric::new().min(0).max(86400).default(1))
+            .member("timeout", Numeric::new().min(0).max(86400).default(1))
+            .member("max_wait", Numeric::new().min(0).default(300))
+            .optional())
+        .member("sd_notify", Structure::new()
+            .member("path", Scalar::new())
+            .member("max_wait", Numeric::new().min(0).default(300))
+            .member("watchdog", Numeric::new().min(0).max(86400).optional())
+            .optional())
     }
     pub fn instantiate(&self, variables: &Variables)
         -> Result<InstantiatedConfig, Vec<String>>
@@ -283,12 +888,25 @@ impl ContainerConfig {
         let mut errors3 = Vec::new();
         let result = {
             let mut replacer = |varname: &str| {
+                if varname.starts_with("secret:") {
+                    let value = &varname["secret:".len()..];
+                    return match variables.secrets {
+                        Some(decrypt) => decrypt(value).unwrap_or_else(|e| {
+                            errors1.insert(e);
+                            String::new()
+                        }),
+                        None => "<<secret>>".to_string(),
+                    };
+                }
                 let val = variables.user_vars.get(varname).map(|x| x.clone())
                     .or_else(|| match varname {
                         "lithos:name"
                         => Some(variables.lithos_name.to_string()),
                         "lithos:config_filename"
                         => Some(variables.lithos_config_filename.to_string()),
+                        _ if varname.starts_with("fact:")
+                        => variables.host_facts
+                            .and_then(|f| f.get(&varname["fact:".len()..])),
                         _ => None,
                     });
                 match val {
@@ -298,6 +916,9 @@ impl ContainerConfig {
                             errors1.insert("lithos:pid variable \
                                 can only be used in environment as a sole \
                                 value".into());
+                        } else if varname.starts_with("fact:") {
+                            errors1.insert(format!("unknown host fact {:?}",
+                                varname));
                         } else {
                             errors1.insert(format!("unknown variable {:?}",
                                 varname));
@@ -321,6 +942,36 @@ impl ContainerConfig {
                 })
                 .collect::<HashMap<_, _>>();
 
+            let unix_sockets = self.unix_sockets.iter()
+                .map(|(key, val)| {
+                    let name = replace_vars(&key, &mut replacer);
+                    if name.is_empty() || name == "." || name == ".." ||
+                        name.contains('/')
+                    {
+                        errors2.insert(format!("Bad unix socket name {:?}",
+                            key));
+                    }
+                    (name, val.clone())
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            let state_files = self.state_files.iter()
+                .map(|(name, content)| {
+                    if name.is_empty() || name == "." || name == ".." ||
+                        name.contains('/') ||
+                        RESERVED_STATE_FILES.contains(&&name[..])
+                    {
+                        errors2.insert(format!("Bad state file name {:?}",
+                            name));
+                    }
+                    (name.clone(), replace_vars(&content, &mut replacer))
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            let volumes = self.volumes.iter()
+                .map(|(mp, vol)| (mp.clone(), vol.substitute(&mut replacer)))
+                .collect::<BTreeMap<_, _>>();
+
             let mut pid_env_vars = HashSet::new();
             let mut environ = self.environ.iter()
                 .map(|(key, val)| {
@@ -358,13 +1009,17 @@ impl ContainerConfig {
                             Ok(port) => port,
                         };
                         tcp_ports.insert(port, TcpPort {
-                            host: Host(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))),
+                            host: Hosts::default(),
                             fd,
                             reuse_addr: true,
                             reuse_port: false,
                             set_non_block: false,
                             listen_backlog: 128,
                             external: false,
+                            accept_before_exec: None,
+                            wait_for_address: None,
+                            syn_count: None,
+                            v6only: true,
                         });
                     }
                     _ => {}
@@ -378,7 +1033,7 @@ impl ContainerConfig {
 
             InstantiatedConfig {
                 kind: self.kind.clone(),
-                volumes: self.volumes.clone(),
+                volumes,
                 user_id: self.user_id.clone(),
                 group_id: self.group_id.clone(),
                 restart_timeout: self.restart_timeout.clone(),
@@ -386,13 +1041,16 @@ impl ContainerConfig {
                 memory_limit: self.memory_limit.clone(),
                 fileno_limit: self.fileno_limit.clone(),
                 cpu_shares: self.cpu_shares.clone(),
-                executable: self.executable.clone(),
+                process_limit: self.process_limit,
+                io_limits: self.io_limits.clone(),
+                executable: replace_vars(&self.executable, &mut replacer),
                 arguments: self.arguments.iter()
                     .map(|x| replace_vars(&x, &mut replacer).into())
                     .collect(),
                 environ,
                 // ignore secret environ, it will be pushed into environ later
                 workdir: self.workdir.clone(),
+                create_workdir: self.create_workdir,
                 resolv_conf: self.resolv_conf.clone(),
                 hosts_file: self.hosts_file.clone(),
                 uid_map: self.uid_map.clone(),
@@ -403,8 +1061,46 @@ impl ContainerConfig {
                 normal_exit_codes: self.normal_exit_codes.clone(),
                 tcp_ports,
                 pid_env_vars,
+                egress_policy: self.egress_policy.clone(),
+                restart_on_fd_usage: self.restart_on_fd_usage,
+                gpus: self.gpus.clone(),
+                unix_sockets,
+                spread: self.spread,
+                pre_stop: self.pre_stop.as_ref().map(|hook| PreStop {
+                    executable: hook.executable.clone(),
+                    arguments: hook.arguments.iter()
+                        .map(|x| replace_vars(&x, &mut replacer).into())
+                        .collect(),
+                    timeout: hook.timeout,
+                }),
+                seccomp_profile: self.seccomp_profile.clone(),
+                drain_file: self.drain_file.clone(),
+                health_check: self.health_check.as_ref().map(|hc| {
+                    HealthCheck {
+                        check: hc.check.substitute(&mut replacer),
+                        interval: hc.interval,
+                        timeout: hc.timeout,
+                        failure_threshold: hc.failure_threshold,
+                    }
+                }),
+                readiness: self.readiness.as_ref().map(|rc| {
+                    ReadinessCheck {
+                        check: rc.check.substitute(&mut replacer),
+                        interval: rc.interval,
+                        timeout: rc.timeout,
+                        max_wait: rc.max_wait,
+                    }
+                }),
+                sd_notify: self.sd_notify.clone(),
+                restart_policy: self.restart_policy,
+                max_restarts: self.max_restarts,
+                restart_window: self.restart_window,
+                state_files,
             }
         };
+        if let Err(errors) = result.check_exec_size(None) {
+            errors2.extend(errors);
+        }
         if errors1.len() > 0 || errors2.len() > 0 || errors3.len() > 0 {
             return Err(errors1.into_iter()
                 .chain(errors2.into_iter())
@@ -416,6 +1112,20 @@ impl ContainerConfig {
     }
 }
 
+fn probe_validator<'x>() -> Enum<'x> {
+    Enum::new()
+    .option("Tcp", Structure::new()
+        .member("host", Scalar::new().default("127.0.0.1"))
+        .member("port", Numeric::new().min(1).max(65535)))
+    .option("Http", Structure::new()
+        .member("host", Scalar::new().default("127.0.0.1"))
+        .member("port", Numeric::new().min(1).max(65535))
+        .member("path", Scalar::new().default("/")))
+    .option("Exec", Structure::new()
+        .member("executable", Scalar::new())
+        .member("arguments", Sequence::new(Scalar::new())))
+}
+
 pub fn volume_validator<'x>() -> Enum<'x> {
     Enum::new()
     .option("Persistent",  Structure::new()
@@ -423,7 +1133,8 @@ pub fn volume_validator<'x>() -> Enum<'x> {
         .member("mkdir",  Scalar::new().default(false))
         .member("mode",  Numeric::new().min(0).max(0o1777).default(0o777))
         .member("user",  Numeric::new().default(0))
-        .member("group",  Numeric::new().default(0)))
+        .member("group",  Numeric::new().default(0))
+        .member("idmap",  Scalar::new().default(false)))
     .option("Readonly", Scalar::new())
     .option("Tmpfs", Structure::new()
         .member("size", Numeric::new().min(0).default(100*1024*1024))
@@ -435,16 +1146,90 @@ pub fn volume_validator<'x>() -> Enum<'x> {
         .member("group", Numeric::new().default(0)))
 }
 
-impl<'a> Deserialize<'a> for Host {
-    fn deserialize<D: Deserializer<'a>>(d: D) -> Result<Host, D::Error> {
-        String::deserialize(d)?.parse().map(Host)
-            .map_err(|x| D::Error::custom(format!("{}", x)))
+impl Default for Hosts {
+    fn default() -> Hosts {
+        Hosts(vec![IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))])
+    }
+}
+
+impl TcpPort {
+    /// Addresses to listen on with file descriptors they are passed as
+    ///
+    /// Each next address is passed as the next file descriptor after `fd`.
+    pub fn sockets(&self, port: u16) -> Vec<(SocketAddr, RawFd)> {
+        self.host.0.iter().enumerate()
+            .map(|(idx, ip)| {
+                (SocketAddr::new(*ip, port), self.fd + idx as RawFd)
+            })
+            .collect()
+    }
+}
+
+/// Deserializes number of seconds in range `0..=86400`
+fn timeout<'a, D: Deserializer<'a>>(d: D) -> Result<f32, D::Error> {
+    let value = f32::deserialize(d)?;
+    if !(value >= 0. && value <= 86400.) {
+        return Err(D::Error::custom(format!(
+            "timeout must be from 0 to 86400 seconds, got {}", value)));
     }
+    Ok(value)
 }
 
-impl Serialize for Host {
+struct HostsVisitor;
+
+fn parse_host<E: DeError>(value: &str) -> Result<IpAddr, E> {
+    // IPv6 address may be written in brackets, like in URLs: `[::1]`
+    let addr = if value.starts_with('[') && value.ends_with(']') {
+        &value[1..value.len()-1]
+    } else {
+        value
+    };
+    addr.parse()
+        .map_err(|e| E::custom(format!("invalid host {:?}: {}", value, e)))
+}
+
+impl<'a> Visitor<'a> for HostsVisitor {
+    type Value = Hosts;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ip address or list of ip addresses")
+    }
+    fn visit_str<E: DeError>(self, value: &str) -> Result<Hosts, E> {
+        Ok(Hosts(vec![parse_host(value)?]))
+    }
+    fn visit_unit<E: DeError>(self) -> Result<Hosts, E> {
+        Ok(Hosts::default())
+    }
+    fn visit_none<E: DeError>(self) -> Result<Hosts, E> {
+        Ok(Hosts::default())
+    }
+    fn visit_seq<A: SeqAccess<'a>>(self, mut seq: A)
+        -> Result<Hosts, A::Error>
+    {
+        let mut hosts = Vec::new();
+        while let Some(value) = seq.next_element::<String>()? {
+            hosts.push(parse_host(&value)?);
+        }
+        if hosts.is_empty() {
+            return Err(A::Error::custom("list of hosts is empty"));
+        }
+        Ok(Hosts(hosts))
+    }
+}
+
+impl<'a> Deserialize<'a> for Hosts {
+    fn deserialize<D: Deserializer<'a>>(d: D) -> Result<Hosts, D::Error> {
+        d.deserialize_any(HostsVisitor)
+    }
+}
+
+impl Serialize for Hosts {
     fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
-        format!("{}", self.0).serialize(s)
+        if self.0.len() == 1 {
+            format!("{}", self.0[0]).serialize(s)
+        } else {
+            self.0.iter().map(|x| format!("{}", x)).collect::<Vec<_>>()
+                .serialize(s)
+        }
     }
 }
 
@@ -531,7 +1316,103 @@ pub fn replace_vars<F, S>(mut s: &str, mut f: F)
 
 #[cfg(test)]
 mod test {
-    use super::replace_vars;
+    use std::collections::BTreeMap;
+    use quire::{parse_string, Options};
+    use master_config::ContainerDefaults;
+    use super::{replace_vars, ContainerConfig, InstantiatedConfig, Variables};
+    use super::{MAX_ARG_STRLEN, DEFAULT_KILL_TIMEOUT};
+
+    fn parse(data: &str, defaults: &ContainerDefaults)
+        -> Result<ContainerConfig, String>
+    {
+        parse_string("<test>", &format!("executable: /bin/true\n{}", data),
+            &ContainerConfig::validator_with(defaults), &Options::default())
+        .map_err(|e| e.to_string())
+    }
+
+    fn instantiated() -> InstantiatedConfig {
+        let cfg: ContainerConfig = parse_string("<test>",
+            "executable: /bin/true\narguments: [--port, '8080']\n",
+            &ContainerConfig::validator(), &Options::default()).unwrap();
+        cfg.instantiate(&Variables {
+            user_vars: &BTreeMap::new(),
+            lithos_name: "test/main.0",
+            lithos_config_filename: "/config/main.yaml",
+            host_facts: None,
+            secrets: None,
+        }).unwrap()
+    }
+
+    #[test]
+    fn default_timeouts() {
+        let cfg = parse("", &ContainerDefaults::default()).unwrap();
+        assert_eq!(cfg.kill_timeout, DEFAULT_KILL_TIMEOUT);
+        assert_eq!(cfg.restart_timeout, 1.);
+        let defaults = ContainerDefaults {
+            kill_timeout: Some(0.5),
+            restart_timeout: Some(2.5),
+            .. ContainerDefaults::default()
+        };
+        let cfg = parse("", &defaults).unwrap();
+        assert_eq!(cfg.kill_timeout, 0.5);
+        assert_eq!(cfg.restart_timeout, 2.5);
+        let cfg = parse("kill-timeout: 30\n", &defaults).unwrap();
+        assert_eq!(cfg.kill_timeout, 30.);
+    }
+
+    #[test]
+    fn invalid_timeout() {
+        let defaults = ContainerDefaults::default();
+        assert!(parse("kill-timeout: -1\n", &defaults).is_err());
+        assert!(parse("restart-timeout: 100000\n", &defaults).is_err());
+        assert!(parse("kill-timeout: soon\n", &defaults).is_err());
+    }
+
+    #[test]
+    fn exec_size_ok() {
+        let mut cfg = instantiated();
+        cfg.environ.insert("HOME".into(), "/".into());
+        assert_eq!(cfg.check_exec_size(None), Ok(()));
+        assert_eq!(cfg.check_exec_size(Some(1024)), Ok(()));
+    }
+
+    #[test]
+    fn exec_size_big_variable() {
+        let mut cfg = instantiated();
+        cfg.environ.insert("SMALL".into(), "1".into());
+        cfg.environ.insert("BIG".into(), "x".repeat(MAX_ARG_STRLEN));
+        let errors = cfg.check_exec_size(None).unwrap_err();
+        assert_eq!(errors, vec![format!("environment variable \"BIG\" \
+            is {} bytes, more than the limit of a single variable ({})",
+            MAX_ARG_STRLEN + 4, MAX_ARG_STRLEN)]);
+    }
+
+    #[test]
+    fn exec_size_big_argument() {
+        let mut cfg = instantiated();
+        cfg.arguments.push("x".repeat(MAX_ARG_STRLEN + 1));
+        let errors = cfg.check_exec_size(None).unwrap_err();
+        assert_eq!(errors, vec![format!("argument 3 is {} bytes, \
+            more than the limit of a single argument ({})",
+            MAX_ARG_STRLEN + 1, MAX_ARG_STRLEN)]);
+        // the argument itself is not in the message, it may be a secret
+        assert!(!errors[0].contains("xxx"));
+    }
+
+    #[test]
+    fn exec_size_max_environ_size() {
+        let mut cfg = instantiated();
+        cfg.environ.insert("A".into(), "1".repeat(100));
+        cfg.environ.insert("B".into(), "2".repeat(10));
+        assert_eq!(cfg.check_exec_size(Some(1000)), Ok(()));
+        let errors = cfg.check_exec_size(Some(100)).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("environment is "));
+        assert!(errors[0].contains("max-environ-size of the sandbox (100)"));
+        // largest variable first
+        assert!(errors[0].ends_with("largest variables: \
+            A (102 bytes), B (12 bytes)"));
+    }
 
     #[test]
     fn just_var() {
@@ -0,0 +1,62 @@
+//! Machine-readable log of the notable events of the supervisor
+//!
+//! Each event is a JSON object on its own line of the `event-log` with
+//! `time` and `event` fields and some event-specific ones, so deployment
+//! tooling may follow it without parsing the human-readable log. Events are
+//! best-effort: write errors are logged and otherwise ignored.
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use humantime::format_rfc3339_seconds;
+use serde_json::{Map, Value};
+
+use master_config::MasterConfig;
+
+
+/// Appends `event` with `fields` (a JSON object) to the event log
+pub fn emit(master: &MasterConfig, event: &str, fields: Value) {
+    if let Some(path) = master.event_log_path() {
+        write(&path, event, fields);
+    }
+}
+
+/// Same as `emit`, for threads which have no master config
+pub fn write(path: &Path, event: &str, fields: Value) {
+    let event = format_event(event, fields, SystemTime::now());
+    let line = format!("{}\n", event);
+    // single write of the whole line, so lines of the several writers
+    // (i.e. `lithos_tree` and `lithos_cmd`) are not interleaved
+    OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .map_err(|e| error!("Can't write event log {:?}: {}", path, e))
+        .ok();
+}
+
+fn format_event(event: &str, fields: Value, time: SystemTime) -> Value {
+    let mut obj = match fields {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+    obj.insert("time".into(), format_rfc3339_seconds(time).to_string().into());
+    obj.insert("event".into(), event.into());
+    Value::Object(obj)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::format_event;
+
+    #[test]
+    fn format() {
+        let time = UNIX_EPOCH + Duration::from_secs(86400);
+        assert_eq!(format_event("drain", json!({"process": "a/b.0"}), time)
+            .to_string(),
+            "{\"event\":\"drain\",\"process\":\"a/b.0\",\
+              \"time\":\"1970-01-02T00:00:00Z\"}");
+        assert_eq!(format_event("x", json!(null), time).to_string(),
+            r#"{"event":"x","time":"1970-01-02T00:00:00Z"}"#);
+    }
+}
@@ -0,0 +1,125 @@
+//! Structured report of why `lithos_knot` exited
+//!
+//! Exit code of the knot only distinguishes success from failure, so the
+//! knot also writes this report into the state dir of the container, and
+//! `lithos_tree` reads it before cleaning the state dir up. Exit codes are
+//! kept as a fallback for knots not writing a report.
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde_json::{from_reader, to_writer};
+
+use utils::create_at;
+
+
+/// File in the state dir of the container where knot puts the report
+pub const EXIT_REPORT_FILE: &str = "exit_report.json";
+/// Exit code of the knot when command is terminated by `max-runtime`
+pub const EXIT_MAX_RUNTIME: i32 = 5;
+/// Exit code of the knot when host doesn't meet `requires` of the container
+pub const EXIT_REQUIREMENTS: i32 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag="kind", rename_all="kebab-case")]
+pub enum ExitReport {
+    /// Process exited with one of the normal exit codes
+    CleanExit,
+    /// Knot received `SIGTERM` (i.e. stop requested by operator or tree)
+    Stopped,
+    /// Process exited with a failure code
+    Crash { code: i32 },
+    /// Process was killed by a signal
+    Signal { signal: i32 },
+    /// Process did not respond to `SIGTERM` in `kill-timeout`
+    KilledByTimeout,
+    /// Command was terminated because it exceeded `max-runtime`
+    MaxRuntime,
+    /// Process was stopped after `failures` consecutive failed health checks
+    Unhealthy { failures: u32 },
+    /// Process didn't send `WATCHDOG=1` in time (see `sd-notify`)
+    WatchdogTimeout,
+    /// Container could not be set up, `stage` is one of the startup stages
+    SetupFailure { stage: String },
+    /// Host doesn't meet `requires` of the container config
+    RequirementsNotMet { message: String },
+}
+
+impl ExitReport {
+    /// Returns true if exit is a failure of the container
+    pub fn is_failure(&self) -> bool {
+        use self::ExitReport::*;
+        match *self {
+            CleanExit | Stopped => false,
+            Crash {..} | Signal {..} | KilledByTimeout | MaxRuntime
+            | Unhealthy {..} | WatchdogTimeout | SetupFailure {..}
+            | RequirementsNotMet {..}
+            => true,
+        }
+    }
+}
+
+impl fmt::Display for ExitReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ExitReport::*;
+        match *self {
+            CleanExit => write!(f, "clean exit"),
+            Stopped => write!(f, "stopped"),
+            Crash { code } => write!(f, "crash (exit code {})", code),
+            Signal { signal } => write!(f, "killed by signal {}", signal),
+            KilledByTimeout => write!(f, "killed by timeout"),
+            MaxRuntime => write!(f, "max runtime exceeded"),
+            Unhealthy { failures } => {
+                write!(f, "failed {} health checks in a row", failures)
+            }
+            WatchdogTimeout => write!(f, "watchdog timeout"),
+            SetupFailure { ref stage } => {
+                write!(f, "setup failure at stage {:?}", stage)
+            }
+            RequirementsNotMet { ref message } => {
+                write!(f, "requirements not met: {}", message)
+            }
+        }
+    }
+}
+
+/// Writes report into the state dir opened as `state_dir`
+pub fn write(state_dir: &File, report: &ExitReport) -> Result<(), io::Error> {
+    let f = create_at(state_dir, EXIT_REPORT_FILE)?;
+    to_writer(f, report)?;
+    Ok(())
+}
+
+/// Reads report from the state dir, `None` if knot didn't write any
+pub fn read(state_dir: &Path) -> Option<ExitReport> {
+    let path = state_dir.join(EXIT_REPORT_FILE);
+    let f = match File::open(&path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Can't open {:?}: {}", path, e);
+            return None;
+        }
+    };
+    from_reader(f)
+        .map_err(|e| warn!("Can't parse {:?}: {}", path, e))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{from_str, to_string};
+    use super::ExitReport;
+
+    #[test]
+    fn serialize() {
+        assert_eq!(to_string(&ExitReport::Crash { code: 1 }).unwrap(),
+            r#"{"kind":"crash","code":1}"#);
+        assert_eq!(to_string(&ExitReport::KilledByTimeout).unwrap(),
+            r#"{"kind":"killed-by-timeout"}"#);
+        assert_eq!(from_str::<ExitReport>(
+            r#"{"kind":"setup-failure","stage":"mounts"}"#).unwrap(),
+            ExitReport::SetupFailure { stage: "mounts".to_string() });
+    }
+}
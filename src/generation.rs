@@ -0,0 +1,133 @@
+//! Versioned config directories
+//!
+//! `sandboxes-dir` and `processes-dir` may be symlinks to generation
+//! directories, so deploy tools can push multiple files atomically: write
+//! a new generation, put a marker file into it, and switch the symlink.
+//! Symlinks are resolved once when configs are read, and generations without
+//! the marker are rejected in favor of the last complete one.
+use std::fs::{File, canonicalize, symlink_metadata};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use master_config::MasterConfig;
+
+
+/// File which must exist in a generation directory when it's fully written
+pub const COMPLETE_MARKER: &str = ".complete";
+
+/// Config directory with symlinks resolved
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub path: PathBuf,
+    /// Name of the generation directory, `None` if not a symlink
+    pub generation: Option<String>,
+}
+
+/// Sandboxes and processes directories resolved at once
+#[derive(Debug, Clone)]
+pub struct ConfigDirs {
+    pub sandboxes: Resolved,
+    pub processes: Resolved,
+}
+
+impl ConfigDirs {
+    /// Generation of the configs for logs and metrics
+    pub fn generation(&self) -> Option<&str> {
+        self.processes.generation.as_ref()
+            .or(self.sandboxes.generation.as_ref())
+            .map(|x| &x[..])
+    }
+}
+
+/// Numeric suffix of the generation name, i.e. `42` for `gen-42`
+pub fn generation_number(name: &str) -> Option<u64> {
+    let start = name.rfind(|c: char| !c.is_ascii_digit())
+        .map(|i| i+1).unwrap_or(0);
+    name[start..].parse().ok()
+}
+
+fn is_complete(path: &Path) -> bool {
+    path.join(COMPLETE_MARKER).exists()
+}
+
+/// Resolves `dir`, returns error if it's a symlink to incomplete generation
+pub fn resolve(dir: &Path) -> Result<Resolved, String> {
+    match symlink_metadata(dir) {
+        Ok(ref meta) if meta.file_type().is_symlink() => {}
+        _ => {
+            return Ok(Resolved { path: dir.to_path_buf(), generation: None });
+        }
+    }
+    let path = canonicalize(dir)
+        .map_err(|e| format!("Can't resolve {:?}: {}", dir, e))?;
+    if !is_complete(&path) {
+        return Err(format!("Generation {:?} is incomplete \
+            (no {} marker)", path, COMPLETE_MARKER));
+    }
+    let generation = path.file_name()
+        .map(|x| x.to_string_lossy().into_owned());
+    Ok(Resolved { path, generation })
+}
+
+fn resolve_or_last(master: &MasterConfig, dir: &Path, kind: &str)
+    -> Result<Resolved, String>
+{
+    let last_file = master.generation_file(kind);
+    match resolve(dir) {
+        Ok(res) => {
+            if res.generation.is_some() {
+                File::create(&last_file)
+                    .and_then(|mut f| {
+                        f.write_all(res.path.to_string_lossy().as_bytes())
+                    })
+                    .map_err(|e| warn!("Can't write {:?}: {}", last_file, e))
+                    .ok();
+            }
+            Ok(res)
+        }
+        Err(e) => {
+            let mut buf = String::new();
+            File::open(&last_file)
+                .and_then(|mut f| f.read_to_string(&mut buf))
+                .map_err(|_| format!("{}, and there is no previous \
+                    generation to fall back to", e))?;
+            let path = PathBuf::from(buf.trim());
+            if !is_complete(&path) {
+                return Err(format!("{}, and previous generation {:?} \
+                    is not available", e, path));
+            }
+            error!("{}. Using previous generation {:?}", e, path);
+            let generation = path.file_name()
+                .map(|x| x.to_string_lossy().into_owned());
+            Ok(Resolved { path, generation })
+        }
+    }
+}
+
+/// Resolves config directories of the master config
+///
+/// Incomplete generation is replaced by the last complete one used by this
+/// `lithos_tree` instance.
+pub fn resolve_dirs(master: &MasterConfig, master_file: &Path)
+    -> Result<ConfigDirs, String>
+{
+    let base = master_file.parent().unwrap();
+    Ok(ConfigDirs {
+        sandboxes: resolve_or_last(master,
+            &base.join(&master.sandboxes_dir), "sandboxes")?,
+        processes: resolve_or_last(master,
+            &base.join(&master.processes_dir), "processes")?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::generation_number;
+
+    #[test]
+    fn number() {
+        assert_eq!(generation_number("gen-42"), Some(42));
+        assert_eq!(generation_number("20181012"), Some(20181012));
+        assert_eq!(generation_number("current"), None);
+    }
+}
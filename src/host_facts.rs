@@ -0,0 +1,193 @@
+//! Facts about the host: hostname, addresses, cpus, memory, kernel features
+//!
+//! Facts are gathered by `lithos_tree` at startup and written to
+//! `<runtime-dir>/host-facts.json`, so `lithos_knot` uses the same values.
+//! They are available as `@{fact:<name>}` in variable substitution and
+//! network hooks, and the file is mounted into containers which have
+//! `host-facts-mount-point` in the image.
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, rename};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use libc::{sysconf, _SC_NPROCESSORS_ONLN};
+use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::SockAddr;
+use serde_json::{from_str, to_string_pretty};
+
+use kernel_features::KernelFeatures;
+use network::get_host_name;
+use version::VERSION;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostFacts {
+    pub hostname: String,
+    /// Addresses by interface name, loopback interface included
+    pub addresses: BTreeMap<String, Vec<IpAddr>>,
+    /// Number of online cpus
+    pub cpu_count: usize,
+    /// Total memory in bytes
+    pub memory: u64,
+    pub lithos_version: String,
+    #[serde(default)]
+    pub kernel_features: KernelFeatures,
+}
+
+fn total_memory() -> Result<u64, String> {
+    let f = File::open("/proc/meminfo")
+        .map_err(|e| format!("Can't read /proc/meminfo: {}", e))?;
+    for line in BufReader::new(f).lines() {
+        let line = line
+            .map_err(|e| format!("Can't read /proc/meminfo: {}", e))?;
+        if line.starts_with("MemTotal:") {
+            // value is always in kilobytes
+            return line.split_whitespace().nth(1)
+                .and_then(|kb| kb.parse::<u64>().ok())
+                .map(|kb| kb * 1024)
+                .ok_or_else(|| format!("Bad MemTotal line {:?}", line));
+        }
+    }
+    Err("No MemTotal in /proc/meminfo".into())
+}
+
+impl HostFacts {
+    pub fn gather() -> Result<HostFacts, String> {
+        let hostname = get_host_name()
+            .map_err(|e| format!("Can't get hostname: {}", e))?;
+        let mut addresses = BTreeMap::new();
+        let ifaddrs = getifaddrs()
+            .map_err(|e| format!("Can't get interface addresses: {}", e))?;
+        for item in ifaddrs {
+            if let Some(SockAddr::Inet(addr)) = item.address {
+                addresses.entry(item.interface_name)
+                    .or_insert_with(Vec::new)
+                    .push(addr.to_std().ip());
+            }
+        }
+        let cpu_count = match unsafe { sysconf(_SC_NPROCESSORS_ONLN) } {
+            n if n > 0 => n as usize,
+            _ => return Err("Can't get number of cpus".into()),
+        };
+        Ok(HostFacts {
+            hostname,
+            addresses,
+            cpu_count,
+            memory: total_memory()?,
+            lithos_version: VERSION.to_string(),
+            kernel_features: KernelFeatures::probe(),
+        })
+    }
+    pub fn read(path: &Path) -> Result<HostFacts, String> {
+        let mut buf = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut buf))
+            .map_err(|e| format!("Can't read {:?}: {}", path, e))?;
+        from_str(&buf).map_err(|e| format!("Can't parse {:?}: {}", path, e))
+    }
+    /// Writes facts atomically, so knot never reads partial file
+    ///
+    /// Also updates the copy mounted into containers (see `mounted_path`).
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let data = to_string_pretty(self).expect("facts are serializable");
+        let tmp = path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut f| f.write_all(data.as_bytes()))
+            .and_then(|()| rename(&tmp, path))
+            .map_err(|e| format!("Can't write {:?}: {}", path, e))?;
+        let mounted = HostFacts::mounted_path(path);
+        OpenOptions::new().write(true).create(true).open(&mounted)
+            .and_then(|mut f| {
+                f.write_all(data.as_bytes())?;
+                f.set_len(data.len() as u64)
+            })
+            .map_err(|e| format!("Can't write {:?}: {}", mounted, e))
+    }
+    /// Copy of the facts file which is bind-mounted into containers
+    ///
+    /// Bind mount pins the inode, so unlike the main file, the copy is
+    /// rewritten in place instead of being replaced, otherwise running
+    /// containers would keep seeing the old facts.
+    pub fn mounted_path(path: &Path) -> PathBuf {
+        path.with_extension("mounted.json")
+    }
+    /// Value of the `@{fact:<name>}` variable
+    ///
+    /// Addresses are available as `addresses.<interface>`, comma-separated.
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "hostname" => Some(self.hostname.clone()),
+            "cpu_count" => Some(self.cpu_count.to_string()),
+            "memory" => Some(self.memory.to_string()),
+            "lithos_version" => Some(self.lithos_version.clone()),
+            _ if name.starts_with("addresses.") => {
+                self.addresses.get(&name["addresses.".len()..])
+                .map(|addrs| {
+                    addrs.iter().map(|a| a.to_string())
+                        .collect::<Vec<_>>().join(",")
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::env::temp_dir;
+    use std::fs::{metadata, remove_file};
+    use std::os::unix::fs::MetadataExt;
+    use super::HostFacts;
+
+    fn facts() -> HostFacts {
+        HostFacts {
+            hostname: "node1".into(),
+            addresses: BTreeMap::new(),
+            cpu_count: 8,
+            memory: 1024,
+            lithos_version: "0.19.0".into(),
+            kernel_features: Default::default(),
+        }
+    }
+
+    #[test]
+    fn mounted_copy_in_place() {
+        let path = temp_dir().join("lithos-test-host-facts.json");
+        let mounted = HostFacts::mounted_path(&path);
+        let mut facts = facts();
+        facts.hostname = "a-rather-long-hostname".into();
+        facts.write(&path).unwrap();
+        let inode = metadata(&mounted).unwrap().ino();
+        facts.hostname = "short".into();
+        facts.write(&path).unwrap();
+        assert_eq!(metadata(&mounted).unwrap().ino(), inode);
+        assert_eq!(HostFacts::read(&mounted).unwrap().hostname, "short");
+        assert_eq!(HostFacts::read(&path).unwrap().hostname, "short");
+        remove_file(&path).ok();
+        remove_file(&mounted).ok();
+    }
+
+    #[test]
+    fn get() {
+        let mut addresses = BTreeMap::new();
+        addresses.insert("eth0".to_string(), vec![
+            "10.0.0.1".parse().unwrap(),
+            "fe80::1".parse().unwrap(),
+        ]);
+        let facts = HostFacts {
+            hostname: "node1".into(),
+            addresses,
+            cpu_count: 8,
+            memory: 1024,
+            lithos_version: "0.19.0".into(),
+            kernel_features: Default::default(),
+        };
+        assert_eq!(facts.get("hostname").unwrap(), "node1");
+        assert_eq!(facts.get("cpu_count").unwrap(), "8");
+        assert_eq!(facts.get("addresses.eth0").unwrap(), "10.0.0.1,fe80::1");
+        assert_eq!(facts.get("addresses.eth1"), None);
+        assert_eq!(facts.get("unknown"), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! NUMA topology of the host, used to spread instances across nodes
+use std::fs::{File, read_dir};
+use std::io::Read;
+use std::path::Path;
+
+
+const NODES_DIR: &str = "/sys/devices/system/node";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: u32,
+    pub cpus: Vec<usize>,
+}
+
+/// Parses kernel cpu list format, e.g. `0-3,8-11`
+pub fn parse_cpulist(value: &str) -> Result<Vec<usize>, String> {
+    let mut result = Vec::new();
+    for item in value.trim().split(',').filter(|x| !x.is_empty()) {
+        let mut pair = item.splitn(2, '-');
+        let start = pair.next().unwrap().parse::<usize>()
+            .map_err(|e| format!("bad cpu list {:?}: {}", value, e))?;
+        let end = match pair.next() {
+            Some(x) => x.parse::<usize>()
+                .map_err(|e| format!("bad cpu list {:?}: {}", value, e))?,
+            None => start,
+        };
+        if end < start {
+            return Err(format!("bad cpu list {:?}: reversed range", value));
+        }
+        result.extend(start..end+1);
+    }
+    return Ok(result);
+}
+
+/// Discovers NUMA nodes which have CPUs, sorted by node id
+///
+/// Returns an empty list if the kernel doesn't expose NUMA topology.
+pub fn discover() -> Result<Vec<Node>, String> {
+    let dir = Path::new(NODES_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut nodes = Vec::new();
+    let entries = read_dir(dir)
+        .map_err(|e| format!("can't read {:?}: {}", dir, e))?;
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| format!("can't read {:?}: {}", dir, e))?;
+        let id = match entry.file_name().to_str()
+            .and_then(|n| if n.starts_with("node") { Some(&n[4..]) }
+                          else { None })
+            .and_then(|n| n.parse().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let path = entry.path().join("cpulist");
+        let mut buf = String::new();
+        File::open(&path).and_then(|mut f| f.read_to_string(&mut buf))
+            .map_err(|e| format!("can't read {:?}: {}", path, e))?;
+        let cpus = parse_cpulist(&buf)?;
+        // memory-only nodes can't run anything
+        if !cpus.is_empty() {
+            nodes.push(Node { id, cpus });
+        }
+    }
+    nodes.sort_by_key(|n| n.id);
+    return Ok(nodes);
+}
+
+/// Node for the instance number, instances are assigned round-robin
+pub fn node_for_instance(nodes: &[Node], instance: usize) -> Option<&Node> {
+    if nodes.is_empty() {
+        return None;
+    }
+    return Some(&nodes[instance % nodes.len()]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_cpulist, node_for_instance, Node};
+
+    #[test]
+    fn cpulist() {
+        assert_eq!(parse_cpulist("0\n").unwrap(), vec![0]);
+        assert_eq!(parse_cpulist("0-3,8-9\n").unwrap(),
+                   vec![0, 1, 2, 3, 8, 9]);
+        assert_eq!(parse_cpulist("\n").unwrap(), Vec::<usize>::new());
+        assert!(parse_cpulist("3-1").is_err());
+        assert!(parse_cpulist("x").is_err());
+    }
+
+    #[test]
+    fn round_robin() {
+        let nodes = vec![
+            Node { id: 0, cpus: vec![0, 1] },
+            Node { id: 1, cpus: vec![2, 3] },
+        ];
+        assert_eq!(node_for_instance(&nodes, 0).unwrap().id, 0);
+        assert_eq!(node_for_instance(&nodes, 1).unwrap().id, 1);
+        assert_eq!(node_for_instance(&nodes, 2).unwrap().id, 0);
+        assert!(node_for_instance(&[], 0).is_none());
+    }
+}
@@ -18,7 +18,7 @@ impl IdMapExt for Vec<IdMap> {
         }
         for rng in self.iter() {
             if internal_id >= rng.inside &&
-                internal_id <= rng.inside + rng.count
+                internal_id < rng.inside + rng.count
             {
                 return Some(rng.outside + (internal_id - rng.inside));
             }
@@ -34,3 +34,23 @@ pub fn mapping_validator<'x>() -> Sequence<'x> {
         .member("outside", Numeric::new())
         .member("count", Numeric::new()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::{IdMap, IdMapExt};
+
+    #[test]
+    fn empty_map() {
+        assert_eq!(Vec::<IdMap>::new().map_id(1000), Some(1000));
+    }
+
+    #[test]
+    fn range_bounds() {
+        let map = vec![
+            IdMap { inside: 0, outside: 100000, count: 1000 },
+        ];
+        assert_eq!(map.map_id(0), Some(100000));
+        assert_eq!(map.map_id(999), Some(100999));
+        assert_eq!(map.map_id(1000), None);
+    }
+}
@@ -0,0 +1,313 @@
+//! Manifest of the image, which is signed for `image-signature-key`
+//!
+//! Each line of the manifest describes a single entry of the image:
+//!
+//! ```text
+//! d 0755 - .
+//! f 4755 <sha256 of the contents> ./bin/ping
+//! l - <sha256 of the target> ./bin/sh
+//! ```
+//!
+//! Modes include setuid, setgid and sticky bits. Paths are relative to the
+//! image root and can't contain newlines. Other file types (devices,
+//! sockets) are not allowed in the image. Manifest is made by
+//! `lithos_crypt image-manifest`.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::{File, Metadata, read_dir, read_link, symlink_metadata};
+use std::io::{Read, BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+use sha2::{Sha256, Digest};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Dir { mode: u32 },
+    File { mode: u32, hash: String },
+    /// Hash of the target path
+    Symlink { hash: String },
+}
+
+/// Entries of the image by path relative to the root, root itself is `.`
+pub type Manifest = BTreeMap<PathBuf, Entry>;
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Sha256 of the contents of the file
+pub fn file_hash(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)
+        .context(format!("can't open {:?}", path))?;
+    let mut hash = Sha256::default();
+    let mut buf = [0u8; 65536];
+    loop {
+        let n = file.read(&mut buf)
+            .context(format!("can't read {:?}", path))?;
+        if n == 0 {
+            break;
+        }
+        hash.input(&buf[..n]);
+    }
+    Ok(hex(&hash.result()))
+}
+
+fn mode(meta: &Metadata) -> u32 {
+    meta.permissions().mode() & 0o7777
+}
+
+/// Lists the image at `root`
+///
+/// Symlinks are not followed. Contents of the regular files are hashed by
+/// `hash_file`, which gets the full path and the metadata of the file (so
+/// it can cache hashes).
+pub fn scan<F>(root: &Path, mut hash_file: F) -> Result<Manifest, Error>
+    where F: FnMut(&Path, &Metadata) -> Result<String, Error>
+{
+    let mut result = Manifest::new();
+    let meta = symlink_metadata(root)
+        .context(format!("can't stat {:?}", root))?;
+    if !meta.file_type().is_dir() {
+        bail!("{:?} is not a directory", root);
+    }
+    result.insert(PathBuf::from("."), Entry::Dir { mode: mode(&meta) });
+    scan_dir(root, Path::new("."), &mut hash_file, &mut result)?;
+    Ok(result)
+}
+
+fn scan_dir<F>(root: &Path, dir: &Path, hash_file: &mut F,
+    result: &mut Manifest)
+    -> Result<(), Error>
+    where F: FnMut(&Path, &Metadata) -> Result<String, Error>
+{
+    let list = read_dir(root.join(dir))
+        .context(format!("can't list {:?}", dir))?;
+    for entry in list {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        if path.as_os_str().as_bytes().contains(&b'\n') {
+            bail!("newline in the file name {:?}", path);
+        }
+        let full_path = entry.path();
+        let meta = symlink_metadata(&full_path)
+            .context(format!("can't stat {:?}", path))?;
+        let kind = meta.file_type();
+        let item = if kind.is_dir() {
+            scan_dir(root, &path, hash_file, result)?;
+            Entry::Dir { mode: mode(&meta) }
+        } else if kind.is_file() {
+            Entry::File {
+                mode: mode(&meta),
+                hash: hash_file(&full_path, &meta)?,
+            }
+        } else if kind.is_symlink() {
+            let target = read_link(&full_path)
+                .context(format!("can't read link {:?}", path))?;
+            let mut hash = Sha256::default();
+            hash.input(target.as_os_str().as_bytes());
+            Entry::Symlink { hash: hex(&hash.result()) }
+        } else {
+            bail!("{:?} is neither a file, directory nor symlink", path);
+        };
+        result.insert(path, item);
+    }
+    Ok(())
+}
+
+/// Formats the manifest, so that `parse` returns the same one
+pub fn format(manifest: &Manifest) -> String {
+    let mut buf = String::new();
+    for (path, entry) in manifest {
+        let path = path.display();
+        match *entry {
+            Entry::Dir { mode } => writeln!(buf, "d {:04o} - {}", mode, path),
+            Entry::File { mode, ref hash } => {
+                writeln!(buf, "f {:04o} {} {}", mode, hash, path)
+            }
+            Entry::Symlink { ref hash } => {
+                writeln!(buf, "l - {} {}", hash, path)
+            }
+        }.expect("writing to string");
+    }
+    return buf;
+}
+
+fn parse_hash(hash: &str) -> Result<String, Error> {
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid hash {:?}", hash);
+    }
+    Ok(hash.to_lowercase())
+}
+
+fn parse_mode(mode: &str) -> Result<u32, Error> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => bail!("invalid mode {:?}", mode),
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<Manifest, Error> {
+    let mut result = Manifest::new();
+    for line in BufReader::new(data).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(4, ' ');
+        let (kind, mode, hash, path) = match (parts.next(), parts.next(),
+            parts.next(), parts.next())
+        {
+            (Some(k), Some(m), Some(h), Some(p)) => (k, m, h, p),
+            _ => bail!("invalid manifest line {:?}", line),
+        };
+        let entry = match (kind, mode, hash) {
+            ("d", mode, "-") => Entry::Dir { mode: parse_mode(mode)? },
+            ("f", mode, hash) => Entry::File {
+                mode: parse_mode(mode)?,
+                hash: parse_hash(hash)?,
+            },
+            ("l", "-", hash) => Entry::Symlink { hash: parse_hash(hash)? },
+            _ => bail!("invalid manifest line {:?}", line),
+        };
+        let path = Path::new(path);
+        if path.is_absolute() ||
+            path.components().any(|c| c.as_os_str() == "..")
+        {
+            bail!("invalid path {:?} in manifest", path);
+        }
+        // `./x` and `x` are the same path
+        let path = if path == Path::new(".") {
+            path.to_path_buf()
+        } else {
+            Path::new(".").join(path.strip_prefix(".").unwrap_or(path))
+        };
+        if result.insert(path.clone(), entry).is_some() {
+            bail!("duplicate path {:?} in manifest", path);
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the first difference between the manifests, if any
+pub fn compare(expected: &Manifest, actual: &Manifest) -> Result<(), Error> {
+    for (path, entry) in actual {
+        match expected.get(path) {
+            None => bail!("{:?} is not in the manifest", path),
+            Some(exp) if exp == entry => {}
+            Some(exp) => match (exp, entry) {
+                (&Entry::File { mode: a, .. }, &Entry::File { mode: b, .. })
+                if a == b => {
+                    bail!("checksum mismatch for {:?}", path);
+                }
+                _ => {
+                    bail!("{:?} doesn't match the manifest: expected {:?}, \
+                        found {:?}", path, exp, entry);
+                }
+            },
+        }
+    }
+    if let Some(path) = expected.keys().find(|p| !actual.contains_key(*p)) {
+        bail!("{:?} from the manifest is missing", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, set_permissions, write};
+    use std::fs::Permissions;
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    use std::path::PathBuf;
+    use std::process::id;
+
+    use super::{scan, format, parse, compare, file_hash, Manifest};
+
+    fn image(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("lithos-image-{}-{}", name, id()));
+        remove_dir_all(&dir).ok();
+        create_dir_all(dir.join("bin")).unwrap();
+        write(dir.join("bin/app"), "#!/bin/sh\necho hello\n").unwrap();
+        set_permissions(dir.join("bin/app"), Permissions::from_mode(0o755))
+            .unwrap();
+        write(dir.join("config.yaml"), "port: 80\n").unwrap();
+        symlink("bin/app", dir.join("app")).unwrap();
+        return dir;
+    }
+
+    fn manifest(dir: &PathBuf) -> Manifest {
+        scan(dir, |path, _| file_hash(path)).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = image("roundtrip");
+        let m = manifest(&dir);
+        assert_eq!(m.len(), 5);
+        assert_eq!(parse(format(&m).as_bytes()).unwrap(), m);
+        compare(&m, &manifest(&dir)).unwrap();
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tampered_file() {
+        let dir = image("tampered");
+        let m = manifest(&dir);
+        write(dir.join("config.yaml"), "port: 81\n").unwrap();
+        let err = compare(&m, &manifest(&dir)).unwrap_err();
+        assert_eq!(err.to_string(),
+            "checksum mismatch for \"./config.yaml\"");
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_symlink() {
+        let dir = image("symlink");
+        let m = manifest(&dir);
+        ::std::fs::remove_file(dir.join("app")).unwrap();
+        symlink("/bin/sh", dir.join("app")).unwrap();
+        let err = compare(&m, &manifest(&dir)).unwrap_err();
+        assert!(err.to_string().starts_with(
+            "\"./app\" doesn't match the manifest"));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_mode() {
+        let dir = image("mode");
+        let m = manifest(&dir);
+        set_permissions(dir.join("bin/app"), Permissions::from_mode(0o4755))
+            .unwrap();
+        let err = compare(&m, &manifest(&dir)).unwrap_err();
+        assert!(err.to_string().starts_with(
+            "\"./bin/app\" doesn't match the manifest"));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn added_and_missing() {
+        let dir = image("added");
+        let m = manifest(&dir);
+        write(dir.join("bin/extra"), "").unwrap();
+        let err = compare(&m, &manifest(&dir)).unwrap_err();
+        assert_eq!(err.to_string(), "\"./bin/extra\" is not in the manifest");
+        ::std::fs::remove_file(dir.join("bin/extra")).unwrap();
+        ::std::fs::remove_file(dir.join("config.yaml")).unwrap();
+        let err = compare(&m, &manifest(&dir)).unwrap_err();
+        assert_eq!(err.to_string(),
+            "\"./config.yaml\" from the manifest is missing");
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_lines() {
+        assert!(parse(b"f 0644 abc ./x\n").is_err());
+        assert!(parse(b"d 0755 - ../x\n").is_err());
+        assert!(parse(b"x 0755 - ./x\n").is_err());
+        assert!(parse(b"d 17777 - ./x\n").is_err());
+    }
+}
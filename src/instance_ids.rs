@@ -0,0 +1,100 @@
+//! Stable instance numbers of the children
+//!
+//! Process `sandbox/child.N` owns its state dir, sockets and address, so
+//! number `N` must denote the same logical instance across reloads. Numbers
+//! allocated to each child are stored in `<runtime-dir>/instances` as
+//! `<sandbox>.json`. When `instances` is decreased, the highest numbers are
+//! removed; when it's increased, the lowest free numbers are added.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, create_dir_all, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::{from_reader, to_writer_pretty};
+
+use master_config::MasterConfig;
+
+
+pub struct InstanceIds {
+    path: PathBuf,
+    allocated: BTreeMap<String, BTreeSet<usize>>,
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, BTreeSet<usize>>, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(BTreeMap::new());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    from_reader(file).map_err(|e| e.to_string())
+}
+
+impl InstanceIds {
+    /// Loads instance numbers of the children of the sandbox
+    pub fn load(master: &MasterConfig, sandbox: &str) -> InstanceIds {
+        let path = master.instances_path()
+            .join(format!("{}.json", sandbox));
+        let allocated = read(&path)
+            .map_err(|e| error!("Can't read instances {:?}: {}", path, e))
+            .unwrap_or_else(|_| BTreeMap::new());
+        InstanceIds { path, allocated }
+    }
+    /// Forgets numbers of the children which are not in the config any more
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.allocated.retain(|name, _| keep(name));
+    }
+    /// Returns `count` instance numbers of the child
+    ///
+    /// Numbers already allocated are kept, so processes of the other
+    /// instances are not affected by scaling.
+    pub fn allocate(&mut self, child: &str, count: usize) -> Vec<usize> {
+        let ids = self.allocated.entry(child.to_string())
+            .or_default();
+        while ids.len() > count {
+            let last = *ids.iter().next_back().unwrap();
+            ids.remove(&last);
+        }
+        let mut next = 0;
+        while ids.len() < count {
+            if !ids.contains(&next) {
+                ids.insert(next);
+            }
+            next += 1;
+        }
+        ids.iter().cloned().collect()
+    }
+    /// Writes instance numbers to the runtime dir
+    pub fn save(&self) -> Result<(), io::Error> {
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        to_writer_pretty(File::create(&tmp)?, &self.allocated)?;
+        rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use super::InstanceIds;
+
+    #[test]
+    fn allocate() {
+        let mut ids = InstanceIds {
+            path: PathBuf::from("/nonexistent"),
+            allocated: BTreeMap::new(),
+        };
+        assert_eq!(ids.allocate("x", 3), vec![0, 1, 2]);
+        ids.allocated.get_mut("x").unwrap().remove(&0);
+        assert_eq!(ids.allocate("x", 2), vec![1, 2]);
+        assert_eq!(ids.allocate("x", 3), vec![0, 1, 2]);
+        ids.allocated.get_mut("x").unwrap().insert(5);
+        assert_eq!(ids.allocate("x", 3), vec![0, 1, 2]);
+        assert_eq!(ids.allocate("x", 4), vec![0, 1, 2, 3]);
+        assert_eq!(ids.allocate("x", 2), vec![0, 1]);
+    }
+}
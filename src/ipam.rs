@@ -0,0 +1,248 @@
+//! Address allocation for containers in bridged networks
+//!
+//! When sandbox has `ip-pool`, instances which have no static address get
+//! one from the pool. Allocations are stored in `<runtime-dir>/ipam` as
+//! `<sandbox>.json`, so addresses are stable across restarts and reloads
+//! of `lithos_tree`. Address is returned to the pool when the process is
+//! retired (see `setup::clean_child`).
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, create_dir_all, rename};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use ipnetwork::IpNetwork;
+use serde_json::{from_reader, to_writer_pretty};
+
+use master_config::MasterConfig;
+
+
+pub struct Ipam {
+    path: PathBuf,
+    pool: IpNetwork,
+    reserved: HashSet<IpAddr>,
+    allocated: BTreeMap<String, IpAddr>,
+}
+
+fn to_int(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn from_int(pool: &IpNetwork, val: u128) -> IpAddr {
+    match *pool {
+        IpNetwork::V4(_) => IpAddr::V4(Ipv4Addr::from(val as u32)),
+        IpNetwork::V6(_) => IpAddr::V6(Ipv6Addr::from(val)),
+    }
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, IpAddr>, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(BTreeMap::new());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    from_reader(file).map_err(|e| e.to_string())
+}
+
+fn write(path: &Path, allocated: &BTreeMap<String, IpAddr>)
+    -> Result<(), io::Error>
+{
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    to_writer_pretty(File::create(&tmp)?, allocated)?;
+    rename(&tmp, path)
+}
+
+/// Writes hosts file of all the allocations of the sandbox
+fn write_hosts(path: &Path, allocated: &BTreeMap<String, IpAddr>)
+    -> Result<(), io::Error>
+{
+    let tmp = path.with_extension("hosts.tmp");
+    {
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        for (name, ip) in allocated {
+            writeln!(file, "{} {}", ip, host_name(name))?;
+        }
+    }
+    rename(&tmp, path)
+}
+
+/// Host name of the process in generated hosts file
+///
+/// Process `sandbox/child.0` is named `child-0.sandbox`.
+fn host_name(name: &str) -> String {
+    let mut pair = name.splitn(2, '/');
+    let sandbox = pair.next().unwrap_or("");
+    let child = pair.next().unwrap_or("");
+    match child.rfind('.') {
+        Some(idx) => format!("{}-{}.{}",
+            &child[..idx], &child[idx+1..], sandbox),
+        None => format!("{}.{}", child, sandbox),
+    }
+}
+
+/// Path to the file with allocations for the sandbox
+pub fn allocations_path(master: &MasterConfig, sandbox: &str) -> PathBuf {
+    master.ipam_path().join(format!("{}.json", sandbox))
+}
+
+/// Path to the hosts file generated from allocations of the sandbox
+pub fn hosts_path(master: &MasterConfig, sandbox: &str) -> PathBuf {
+    master.ipam_path().join(format!("{}.hosts", sandbox))
+}
+
+impl Ipam {
+    /// Loads allocations for the sandbox
+    ///
+    /// `reserved` are addresses which are never allocated: the gateway and
+    /// static addresses of the processes. Allocations outside of the pool
+    /// (i.e. if pool was changed) are dropped.
+    pub fn load(master: &MasterConfig, sandbox: &str, pool: IpNetwork,
+        reserved: Vec<IpAddr>)
+        -> Ipam
+    {
+        let path = allocations_path(master, sandbox);
+        let mut allocated = read(&path)
+            .map_err(|e| error!("Can't read allocations {:?}: {}", path, e))
+            .unwrap_or_else(|_| BTreeMap::new());
+        let reserved = reserved.into_iter().collect::<HashSet<_>>();
+        allocated.retain(|name, ip| {
+            let valid = pool.contains(*ip) && !reserved.contains(ip);
+            if !valid {
+                warn!("Address {} of {:?} is not in pool {} any more",
+                    ip, name, pool);
+            }
+            valid
+        });
+        Ipam { path, pool, reserved, allocated }
+    }
+    /// Returns address of the process, allocating a new one if needed
+    pub fn allocate(&mut self, name: &str) -> Option<IpAddr> {
+        if let Some(ip) = self.allocated.get(name) {
+            return Some(*ip);
+        }
+        let used = self.allocated.values().cloned().collect::<HashSet<_>>();
+        let start = to_int(self.pool.ip()) & to_int(self.pool.mask());
+        let bits = match self.pool {
+            IpNetwork::V4(_) => 32,
+            IpNetwork::V6(_) => 128,
+        };
+        let host_bits = bits - self.pool.prefix() as u32;
+        let size = if host_bits >= 128 { u128::MAX }
+                   else { 1u128 << host_bits };
+        // skip network address, and broadcast address for IPv4
+        let last = if self.pool.is_ipv4() && size > 2 { size - 1 }
+                   else { size };
+        let first = if size > 2 { 1 } else { 0 };
+        for off in first..last {
+            let ip = from_int(&self.pool, start + off);
+            if !used.contains(&ip) && !self.reserved.contains(&ip) {
+                info!("Allocated {} for {:?}", ip, name);
+                self.allocated.insert(name.to_string(), ip);
+                return Some(ip);
+            }
+        }
+        None
+    }
+    /// Writes allocations and hosts file
+    pub fn save(&self, master: &MasterConfig, sandbox: &str)
+        -> Result<(), io::Error>
+    {
+        write(&self.path, &self.allocated)?;
+        write_hosts(&hosts_path(master, sandbox), &self.allocated)
+    }
+}
+
+/// Returns address of the process `name` back to the pool
+///
+/// Hosts file is regenerated, so other processes don't resolve the name
+/// to the address which may be given to another process.
+pub fn release(master: &MasterConfig, name: &str) {
+    let sandbox = name.split('/').next().unwrap_or(name);
+    release_at(&allocations_path(master, sandbox),
+        &hosts_path(master, sandbox), name)
+}
+
+fn release_at(path: &Path, hosts: &Path, name: &str) {
+    let mut allocated = match read(path) {
+        Ok(ref x) if x.is_empty() => return,
+        Ok(x) => x,
+        Err(e) => {
+            error!("Can't read allocations {:?}: {}", path, e);
+            return;
+        }
+    };
+    if let Some(ip) = allocated.remove(name) {
+        info!("Released {} of {:?}", ip, name);
+        write(path, &allocated)
+            .map_err(|e| error!("Can't write allocations {:?}: {}", path, e))
+            .ok();
+        write_hosts(hosts, &allocated)
+            .map_err(|e| error!("Can't write hosts {:?}: {}", hosts, e))
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashSet};
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all};
+    use std::path::PathBuf;
+    use super::{Ipam, host_name, write, write_hosts, release_at};
+
+    fn ipam(pool: &str, reserved: &[&str]) -> Ipam {
+        Ipam {
+            path: PathBuf::from("/nonexistent"),
+            pool: pool.parse().unwrap(),
+            reserved: reserved.iter().map(|x| x.parse().unwrap())
+                .collect::<HashSet<_>>(),
+            allocated: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn allocate() {
+        let mut ipam = ipam("10.0.0.0/30", &[]);
+        assert_eq!(ipam.allocate("a/x.0"), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(ipam.allocate("a/x.1"), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(ipam.allocate("a/x.0"), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(ipam.allocate("a/x.2"), None);
+    }
+
+    #[test]
+    fn reserved() {
+        let mut ipam = ipam("10.0.0.16/28", &["10.0.0.17"]);
+        assert_eq!(ipam.allocate("a/x.0"),
+                   Some("10.0.0.18".parse().unwrap()));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(host_name("sandbox/child.0"), "child-0.sandbox");
+        assert_eq!(host_name("sandbox/cmd"), "cmd.sandbox");
+    }
+
+    #[test]
+    fn release_updates_hosts() {
+        let dir = temp_dir().join("lithos-test-ipam-release");
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("a.json");
+        let hosts = dir.join("a.hosts");
+        let mut allocated = BTreeMap::new();
+        allocated.insert("a/x.0".to_string(), "10.0.0.1".parse().unwrap());
+        allocated.insert("a/x.1".to_string(), "10.0.0.2".parse().unwrap());
+        write(&path, &allocated).unwrap();
+        write_hosts(&hosts, &allocated).unwrap();
+        release_at(&path, &hosts, "a/x.1");
+        assert_eq!(read_to_string(&hosts).unwrap(), "10.0.0.1 x-0.a\n");
+        remove_dir_all(&dir).unwrap();
+    }
+}
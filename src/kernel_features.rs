@@ -0,0 +1,185 @@
+//! Kernel features probed at startup
+//!
+//! Features are probed by `lithos_tree` as part of the host facts, so
+//! configs which need a missing feature are refused when they are read,
+//! with a clear error, instead of failing in `lithos_knot` on every start.
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::ptr;
+
+use libc::{self, c_long, getpid, ENOSYS};
+
+use cgroup;
+use container_config::{ContainerConfig, Volume};
+use sandbox_config::SandboxConfig;
+
+
+// These are the same on all architectures (unified syscall numbering)
+const SYS_PIDFD_OPEN: c_long = 434;
+const SYS_MOUNT_SETATTR: c_long = 442;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KernelFeatures {
+    pub user_namespaces: bool,
+    /// Unified (v2) cgroup hierarchy is mounted
+    pub cgroup_v2: bool,
+    /// Controllers enabled in the unified hierarchy
+    pub cgroup_v2_controllers: BTreeSet<String>,
+    pub pidfd: bool,
+    pub idmapped_mounts: bool,
+}
+
+/// Numeric components of the kernel release, e.g. `4.18.0-80.el8` is
+/// `[4, 18, 0]`
+pub fn kernel_version(release: &str) -> Vec<u32> {
+    let mut result = Vec::new();
+    for part in release.split(|c: char| c == '.' || c == '-') {
+        match part.parse() {
+            Ok(x) => result.push(x),
+            Err(_) => break,
+        }
+    }
+    return result;
+}
+
+fn read_file(path: &Path) -> Option<String> {
+    let mut buf = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut buf)).ok()?;
+    Some(buf)
+}
+
+fn user_namespaces() -> bool {
+    if !Path::new("/proc/self/ns/user").exists() {
+        return false;
+    }
+    // file doesn't exist on kernels older than 4.9
+    match read_file(Path::new("/proc/sys/user/max_user_namespaces")) {
+        Some(value) => value.trim() != "0",
+        None => true,
+    }
+}
+
+/// Returns true if syscall exists, i.e. it fails with anything but ENOSYS
+fn syscall_supported(rc: c_long) -> bool {
+    rc >= 0 || io::Error::last_os_error().raw_os_error() != Some(ENOSYS)
+}
+
+fn pidfd() -> bool {
+    let rc = unsafe { libc::syscall(SYS_PIDFD_OPEN, getpid(), 0) };
+    if rc >= 0 {
+        unsafe { libc::close(rc as libc::c_int) };
+    }
+    syscall_supported(rc)
+}
+
+fn idmapped_mounts() -> bool {
+    // invalid file descriptor, so it fails with EBADF if supported
+    let rc = unsafe {
+        libc::syscall(SYS_MOUNT_SETATTR, -1, b"\0".as_ptr(),
+            libc::AT_EMPTY_PATH, ptr::null::<u8>(), 0)
+    };
+    syscall_supported(rc)
+}
+
+impl KernelFeatures {
+    pub fn probe() -> KernelFeatures {
+        let unified = cgroup::unified_base();
+        let cgroup_v2_controllers = unified.as_ref()
+            .and_then(|base| read_file(&base.join("cgroup.controllers")))
+            .map(|data| data.split_whitespace().map(String::from).collect())
+            .unwrap_or_else(BTreeSet::new);
+        KernelFeatures {
+            user_namespaces: user_namespaces(),
+            cgroup_v2: unified.is_some(),
+            cgroup_v2_controllers,
+            pidfd: pidfd(),
+            idmapped_mounts: idmapped_mounts(),
+        }
+    }
+    /// Names of the features which are not available, for logging
+    pub fn missing(&self) -> Vec<&'static str> {
+        let mut result = Vec::new();
+        if !self.user_namespaces { result.push("user-namespaces"); }
+        if !self.cgroup_v2 { result.push("cgroup-v2"); }
+        if !self.pidfd { result.push("pidfd"); }
+        if !self.idmapped_mounts { result.push("idmapped-mounts"); }
+        return result;
+    }
+    /// Checks that the container can be run with these features
+    ///
+    /// `cgroup_controllers` is the setting of the master config.
+    pub fn check(&self, cgroup_controllers: &[String],
+        sandbox: &SandboxConfig, container: &ContainerConfig)
+        -> Result<(), String>
+    {
+        let uses_userns = !sandbox.uid_map.is_empty() ||
+            !sandbox.gid_map.is_empty() ||
+            !container.uid_map.is_empty() ||
+            !container.gid_map.is_empty();
+        if uses_userns && !self.user_namespaces {
+            return Err(format!("uid-map and gid-map require user \
+                namespaces, which are disabled in the kernel"));
+        }
+        for (path, volume) in &container.volumes {
+            match *volume {
+                Volume::Persistent(ref info) if info.idmap => {
+                    if !self.idmapped_mounts {
+                        return Err(format!("volume {:?} is idmapped, but \
+                            kernel doesn't support idmapped mounts \
+                            (linux 5.12 or newer is required)", path));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if container.egress_policy.is_some() && !self.cgroup_v2 {
+            return Err(format!("egress-policy requires unified cgroup \
+                hierarchy (cgroup2) to be mounted"));
+        }
+        // `io.max` of the unified hierarchy is used if there is no blkio
+        let blkio = cgroup_controllers.iter().any(|x| x == "blkio");
+        if !container.io_limits.is_empty() && !blkio &&
+            !self.cgroup_v2_controllers.contains("io")
+        {
+            return Err(format!("io-limits require either blkio in \
+                cgroup-controllers or io controller of the unified cgroup \
+                hierarchy (cgroup2)"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::kernel_version;
+
+    #[test]
+    fn plain_version() {
+        assert_eq!(kernel_version("5.11"), vec![5, 11]);
+        assert_eq!(kernel_version("4.19.0"), vec![4, 19, 0]);
+    }
+
+    #[test]
+    fn distro_suffix() {
+        assert_eq!(kernel_version("4.18.0-80.el8.x86_64"), vec![4, 18, 0, 80]);
+        assert_eq!(kernel_version("5.10.0-21-amd64"), vec![5, 10, 0, 21]);
+        assert_eq!(kernel_version("6.1.0+"), vec![6, 1]);
+        assert_eq!(kernel_version("5.15.0rc1"), vec![5, 15]);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(kernel_version(""), Vec::<u32>::new());
+        assert_eq!(kernel_version("linux"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn compare() {
+        assert!(kernel_version("4.18.0-80.el8") >= kernel_version("4.18"));
+        assert!(kernel_version("5.4.0") < kernel_version("5.11"));
+        assert!(kernel_version("5.11") >= vec![5, 11]);
+        assert!(kernel_version("4.9") < vec![5, 11]);
+    }
+}
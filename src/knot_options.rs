@@ -1,15 +1,21 @@
 use std::collections::BTreeMap;
 use std::env;
+use std::fs::File;
 use std::io::{stdout, stderr};
-use std::io::{Write};
+use std::io::{Read, Write};
+use std::os::unix::io::{RawFd, FromRawFd};
 use std::path::{PathBuf};
+use std::str::FromStr;
 
 use log;
-use argparse::{ArgumentParser, StoreOption, Store, Parse, List, StoreTrue};
+use argparse::{StoreOption, Store, Parse, List, StoreTrue};
 use argparse::{Print};
 
 use child_config::ChildInstance;
 use child_config::ChildKind::Daemon;
+use version::{CONFIG_SCHEMA, EXIT_SCHEMA_MISMATCH, check_config_schema};
+use cli;
+use version;
 
 
 pub struct Options {
@@ -19,6 +25,14 @@ pub struct Options {
     pub args: Vec<String>,
     pub log_stderr: bool,
     pub log_level: Option<log::LogLevel>,
+    /// Run `sandbox/child[.instance]` in foreground instead of `config`
+    pub debug: Option<String>,
+    /// Generation of the child config, see `child_generation`
+    pub generation: Option<u64>,
+    /// Local development mode, same as `dev-mode` in master config
+    pub dev: bool,
+    /// Pipe to wait on until the knot is put into systemd scope
+    pub scope_fd: Option<RawFd>,
 }
 
 impl Options {
@@ -39,15 +53,24 @@ impl Options {
                 variables: BTreeMap::new(),
                 extra_secrets_namespaces: Vec::new(),
                 ip_address: None,
+                container: None,
+                max_runtime: None,
                 kind: Daemon,
+                schema: CONFIG_SCHEMA,
             },
             name: "".to_string(),
             args: vec!(),
             log_stderr: false,
             log_level: None,
+            debug: None,
+            generation: None,
+            dev: false,
+            scope_fd: None,
         };
+        let mut config = String::new();
+        let mut config_fd = None::<RawFd>;
         let parse_result = {
-            let mut ap = ArgumentParser::new();
+            let mut ap = cli::Parser::new();
             ap.set_description("Runs tree of processes");
             ap.refer(&mut options.name)
               .add_option(&["--name"], Store,
@@ -57,11 +80,34 @@ impl Options {
                 "Name of the master configuration file \
                  (default /etc/lithos/master.yaml)")
               .metavar("FILE");
-            ap.refer(&mut options.config)
+            ap.refer(&mut config)
               .add_option(&["--config"], Store,
                 "JSON-serialized container configuration")
-              .required()
               .metavar("JSON");
+            ap.refer(&mut config_fd)
+              .add_option(&["--config-fd"], StoreOption,
+                "Read JSON-serialized container configuration from \
+                 this file descriptor (so it's not visible in ps)")
+              .metavar("FD");
+            ap.refer(&mut options.generation)
+              .add_option(&["--generation"], StoreOption,
+                "Generation of the child config (used in cgroup name)")
+              .metavar("NUM");
+            ap.refer(&mut options.scope_fd)
+              .add_option(&["--scope-fd"], StoreOption,
+                "Wait until lithos_tree writes to this file descriptor, \
+                 i.e. until the process is put into systemd scope")
+              .metavar("FD");
+            ap.refer(&mut options.debug)
+              .add_option(&["--debug"], StoreOption,
+                "Read configs of the child `sandbox/child[.instance]`, like \
+                 lithos_tree does, and run it in foreground with logs to \
+                 stderr (--name and --config are not needed)")
+              .metavar("NAME");
+            ap.refer(&mut options.dev)
+              .add_option(&["--dev"], StoreTrue,
+                "Local development mode: run as a plain user, skipping \
+                 privileged setup which can't be done, with verbose logs");
             ap.refer(&mut options.args)
               .add_argument("argument", List,
                 "Additional arguments for the command");
@@ -72,14 +118,50 @@ impl Options {
               .add_option(&["--log-level"], StoreOption,
                 "Set log level (default info for now)");
             ap.add_option(&["--version"],
-                Print(env!("CARGO_PKG_VERSION").to_string()),
+                Print(version::build_info()),
                 "Show version");
             ap.stop_on_first_argument(true);
-            ap.parse(args, stdout, stderr)
+            cli::parse(&ap, args, stdout, stderr)
         };
-        match parse_result {
-            Ok(()) => Ok(options),
-            Err(x) => Err(x),
+        parse_result?;
+        if !config.is_empty() && config_fd.is_some() {
+            writeln!(stderr, "--config and --config-fd are mutually \
+                exclusive").ok();
+            return Err(2);
         }
+        if options.debug.is_some() {
+            if !config.is_empty() || config_fd.is_some() {
+                writeln!(stderr, "--config can't be used with --debug").ok();
+                return Err(2);
+            }
+            options.log_stderr = true;
+            return Ok(options);
+        }
+        if let Some(fd) = config_fd {
+            // file is closed here, so the container doesn't inherit it
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            if let Err(e) = file.read_to_string(&mut config) {
+                writeln!(stderr, "Can't read config from fd {}: {}",
+                    fd, e).ok();
+                return Err(2);
+            }
+        }
+        if config.is_empty() {
+            writeln!(stderr, "Option --config is required").ok();
+            return Err(2);
+        }
+        // schema is checked first to give meaningful error on upgrade
+        if let Err(e) = check_config_schema(&config) {
+            writeln!(stderr, "{}", e).ok();
+            return Err(EXIT_SCHEMA_MISMATCH);
+        }
+        options.config = match ChildInstance::from_str(&config) {
+            Ok(cfg) => cfg,
+            Err(()) => {
+                writeln!(stderr, "Bad value for --config").ok();
+                return Err(2);
+            }
+        };
+        Ok(options)
     }
 }
@@ -15,8 +15,9 @@ extern crate libmount;
 extern crate nix;
 extern crate quire;
 extern crate rand;
+extern crate regex;
 extern crate serde;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 extern crate serde_str;
 extern crate signal;
 extern crate sha2;
@@ -44,5 +45,30 @@ pub mod range;
 pub mod knot_options;
 pub mod tree_options;
 pub mod nacl;
+pub mod socket_stats;
+pub mod statsd;
+pub mod bpf;
+pub mod version;
+pub mod reason;
+pub mod shared_metrics;
+pub mod ipam;
+pub mod proc_stats;
+pub mod image_manifest;
+pub mod exit_report;
+pub mod generation;
+pub mod instance_ids;
+pub mod child_generation;
+pub mod templates;
+pub mod command_slots;
+pub mod socket_loans;
+pub mod sandbox_dirs;
+pub mod host_facts;
+pub mod kernel_features;
+pub mod host_topology;
+pub mod cli;
+pub mod schema;
+pub mod events;
 
 pub const MAX_CONFIG_LOGS: u32 = 100;
+/// Suffix of rotated config logs compressed and encrypted by `lithos_tree`
+pub const ENCRYPTED_LOG_SUFFIX: &str = ".gz.age";
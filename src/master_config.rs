@@ -1,32 +1,170 @@
-use std::path::PathBuf;
+use std::env;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-use quire::validate::{Structure, Sequence};
-use quire::validate::{Scalar};
+use blake2::{Blake2b, digest::{VariableOutput, Input}};
+
+use quire::validate::{Structure, Sequence, Mapping};
+use quire::validate::{Scalar, Numeric, Enum, Nothing};
 use super::utils::ensure_dir;
 
+#[derive(Deserialize, Clone)]
+pub struct StatsdConfig {
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+    pub interval: f32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TrustBundle {
+    pub path: PathBuf,
+    pub mount_point: PathBuf,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SetupFailurePolicy {
+    pub restart_timeout: f32,
+    pub max_restart_timeout: f32,
+    pub give_up_after: Option<u32>,
+}
+
+/// Defaults for the container configs of all sandboxes
+///
+/// Used instead of the built-in defaults when the setting is not specified
+/// in the container config itself.
+#[derive(Deserialize, Clone, Default)]
+pub struct ContainerDefaults {
+    pub kill_timeout: Option<f32>,
+    pub restart_timeout: Option<f32>,
+    pub fileno_limit: Option<u64>,
+    pub stdout_stderr_file: Option<PathBuf>,
+}
+
+/// Settings of the experimental backend running processes in systemd scopes
+#[derive(Deserialize, Clone)]
+pub struct SystemdScope {
+    pub slice: Option<String>,
+    pub busctl: PathBuf,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ConfigLogEncryption {
+    pub recipients: Vec<String>,
+    pub age: PathBuf,
+    pub gzip: PathBuf,
+}
+
+/// What to do with logs of the sandbox when its config is removed
+#[derive(Deserialize, Clone)]
+pub enum SandboxRetention {
+    Remove,
+    /// Logs are put into the archive in `dir` before removing
+    Archive(RetentionArchive),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RetentionArchive {
+    pub dir: PathBuf,
+    pub tar: PathBuf,
+}
+
+/// Set of children (possibly from different sandboxes) which are started
+/// and stopped together via the control socket
+#[derive(Deserialize, Clone)]
+pub struct RunGroup {
+    /// Sandboxes or `sandbox/child` pairs in the order of the start
+    pub children: Vec<String>,
+}
+
+/// Host firewall where `lithos_tree` opens ports of the running processes
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum ManageFirewall {
+    Firewalld,
+    /// Ports are put into the set of nftables (`nft-port-set`)
+    Nft,
+}
+
 #[derive(Deserialize)]
 pub struct MasterConfig {
     pub runtime_dir: PathBuf,
     pub sandboxes_dir: PathBuf,
+    pub sandboxes_include: Vec<PathBuf>,
     pub processes_dir: PathBuf,
     pub state_dir: PathBuf,
     pub mount_dir: PathBuf,
     pub devfs_dir: PathBuf,
     pub default_log_dir: PathBuf,
     pub config_log_dir: Option<PathBuf>,
+    pub config_log_encryption: Option<ConfigLogEncryption>,
+    pub config_log_max_size: Option<u64>,
+    pub sandbox_retention: Option<SandboxRetention>,
     pub stdio_log_dir: PathBuf,
     pub log_file: PathBuf,
+    pub event_log: Option<PathBuf>,
     pub syslog_facility: Option<String>,
     pub syslog_app_name: String,
     pub log_level: String,
     pub cgroup_name: Option<String>,
     pub cgroup_controllers: Vec<String>,
+    pub statsd: Option<StatsdConfig>,
+    pub metrics: bool,
+    pub metrics_path: Option<PathBuf>,
+    pub instance_name: Option<String>,
+    pub knot_binary: Option<PathBuf>,
+    pub max_concurrent_starts: Option<usize>,
+    pub max_concurrent_commands: Option<usize>,
+    pub crash_reports_dir: PathBuf,
+    pub keep_crash_reports: usize,
+    pub trust_bundle: Option<TrustBundle>,
+    pub host_facts_mount_point: PathBuf,
+    pub proxy_environ: BTreeMap<String, String>,
+    pub nested: bool,
+    /// Local development mode of `lithos_knot` (also enabled by `--dev`)
+    pub dev_mode: bool,
+    pub setup_failure_policy: SetupFailurePolicy,
+    pub container_defaults: ContainerDefaults,
+    pub max_total_instances: Option<usize>,
+    pub control_socket: Option<PathBuf>,
+    pub run_groups: BTreeMap<String, RunGroup>,
+    pub systemd_scope: Option<SystemdScope>,
+    pub manage_firewall: Option<ManageFirewall>,
+    pub nft_port_set: String,
+    pub firewalld_zone: Option<String>,
+}
+
+impl SetupFailurePolicy {
+    /// Delay in seconds before restart after `failures` consecutive setup
+    /// failures of the container
+    ///
+    /// Delay is doubled on each failure up to `max-restart-timeout`.
+    pub fn restart_delay(&self, failures: u32) -> f32 {
+        let factor = 2f32.powi(failures.saturating_sub(1).min(30) as i32);
+        (self.restart_timeout * factor).min(self.max_restart_timeout)
+    }
+}
+
+impl RunGroup {
+    /// Position of the child in the group, i.e. the index of the first
+    /// item of `children` matching the child
+    pub fn position(&self, sandbox: &str, child: &str) -> Option<usize> {
+        self.children.iter().position(|item| {
+            let mut pair = item.splitn(2, '/');
+            let sitem = pair.next().unwrap_or("");
+            match pair.next() {
+                None => sitem == sandbox,
+                Some(citem) => sitem == sandbox && citem == child,
+            }
+        })
+    }
 }
 
 impl MasterConfig {
     pub fn validator<'x>() -> Structure<'x> {
         Structure::new()
         .member("sandboxes_dir", Scalar::new().default("./sandboxes"))
+        .member("sandboxes_include", Sequence::new(Scalar::new()))
         .member("processes_dir", Scalar::new().default("./processes"))
         .member("runtime_dir", Scalar::new().default("/run/lithos"))
         .member("state_dir", Scalar::new().default("state"))
@@ -37,21 +175,310 @@ impl MasterConfig {
         .member("syslog_facility", Scalar::new().optional())
         .member("syslog_app_name", Scalar::new().default("lithos"))
         .member("log_file", Scalar::new().default("master.log"))
+        .member("event_log", Scalar::new().optional().default("events.log"))
         .member("log_level", Scalar::new().default("warn"))
         .member("config_log_dir", Scalar::new().optional()
             .default("/var/log/lithos/config"))
+        .member("config_log_max_size", Numeric::new().min(0).optional())
+        .member("sandbox_retention", Enum::new()
+            .option("Remove", Nothing)
+            .option("Archive", Structure::new()
+                .member("dir", Scalar::new())
+                .member("tar", Scalar::new().default("/bin/tar")))
+            .optional())
+        .member("config_log_encryption", Structure::new()
+            .member("recipients", Sequence::new(Scalar::new()).min_length(1))
+            .member("age", Scalar::new().default("/usr/bin/age"))
+            .member("gzip", Scalar::new().default("/bin/gzip"))
+            .optional())
         .member("stdio_log_dir", Scalar::new()
             .default("/var/log/lithos/stderr"))
         .member("cgroup_name",
             Scalar::new().optional().default("lithos.slice"))
         .member("cgroup_controllers", Sequence::new(Scalar::new()))
+        .member("statsd", Structure::new()
+            .member("host", Scalar::new().default("127.0.0.1"))
+            .member("port", Numeric::new().min(1).max(65535).default(8125))
+            .member("prefix", Scalar::new().default("lithos"))
+            .member("interval", Numeric::new().min(1).max(3600).default(10))
+            .optional())
+        .member("metrics", Scalar::new().default(true))
+        .member("metrics_path", Scalar::new().optional())
+        .member("instance_name", Scalar::new().optional())
+        .member("knot_binary", Scalar::new().optional())
+        .member("max_concurrent_starts", Numeric::new().min(1).optional())
+        .member("max_concurrent_commands", Numeric::new().min(1).optional())
+        .member("crash_reports_dir", Scalar::new().default("crashes"))
+        .member("keep_crash_reports", Numeric::new().min(0).default(5))
+        .member("trust_bundle", Structure::new()
+            .member("path", Scalar::new())
+            .member("mount_point", Scalar::new()
+                .default("/etc/ssl/certs/ca-certificates.crt"))
+            .optional())
+        .member("host_facts_mount_point", Scalar::new()
+            .default("/etc/lithos/host-facts.json"))
+        .member("proxy_environ", Mapping::new(Scalar::new(), Scalar::new()))
+        .member("nested", Scalar::new().default(false))
+        .member("dev_mode", Scalar::new().default(false))
+        .member("setup_failure_policy", Structure::new()
+            .member("restart_timeout", Numeric::new().min(0).default(10))
+            .member("max_restart_timeout",
+                Numeric::new().min(0).default(300))
+            .member("give_up_after", Numeric::new().min(1).optional()))
+        .member("max_total_instances", Numeric::new().min(0).optional())
+        .member("container_defaults", Structure::new()
+            .member("kill_timeout",
+                Numeric::new().min(0).max(86400).optional())
+            .member("restart_timeout",
+                Numeric::new().min(0).max(86400).optional())
+            .member("fileno_limit", Numeric::new().min(1).optional())
+            .member("stdout_stderr_file", Scalar::new().optional()))
+        .member("control_socket", Scalar::new().optional())
+        .member("run_groups", Mapping::new(
+            Scalar::new(),
+            Structure::new()
+                .member("children", Sequence::new(Scalar::new()))))
+        .member("systemd_scope", Structure::new()
+            .member("slice", Scalar::new().optional())
+            .member("busctl", Scalar::new().default("/usr/bin/busctl"))
+            .optional())
+        .member("manage_firewall", Scalar::new().optional())
+        .member("nft_port_set",
+            Scalar::new().default("inet filter lithos_ports"))
+        .member("firewalld_zone", Scalar::new().optional())
+    }
+
+    /// Default metrics file (`CANTAL_PATH`) for this master config
+    ///
+    /// Includes a hash of the config file path, so that several lithos
+    /// instances with different configs don't share the same file.
+    pub fn default_metrics_path(&self, config_file: &Path) -> PathBuf {
+        if let Some(ref name) = self.instance_name {
+            return self.runtime_dir.join(format!("metrics.{}", name));
+        }
+        let mut buf = [0u8; 4];
+        let mut hash: Blake2b = VariableOutput::new(buf.len())
+            .expect("blake2b");
+        hash.process(config_file.to_string_lossy().as_bytes());
+        hash.variable_result(&mut buf[..]).expect("blake2b");
+        let hex = buf.iter().map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        self.runtime_dir.join(format!("metrics.{}", hex))
+    }
+
+    /// Path to the `lithos_knot` binary
+    ///
+    /// By default it's expected to be next to the current executable.
+    pub fn knot_binary(&self) -> Option<PathBuf> {
+        if let Some(ref path) = self.knot_binary {
+            return Some(path.clone());
+        }
+        env::current_exe().ok()
+            .and_then(|x| x.parent().map(|y| y.join("lithos_knot")))
+    }
+
+    /// Pid file of the `lithos_tree`, i.e. `master.<instance>.pid`
+    pub fn pid_file(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("master.{}.pid", name))
+            }
+            None => self.runtime_dir.join("master.pid"),
+        }
+    }
+
+    /// Marker file of the drained host, i.e. `master.<instance>.drained`
+    ///
+    /// Contains either `draining` or `drained`, when all processes exit.
+    pub fn drain_marker(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("master.{}.drained", name))
+            }
+            None => self.runtime_dir.join("master.drained"),
+        }
+    }
+
+    /// File where the last complete generation of the `kind` config dir
+    /// is recorded, i.e. `sandboxes.<instance>.generation`
+    pub fn generation_file(&self, kind: &str) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("{}.{}.generation", kind, name))
+            }
+            None => self.runtime_dir.join(format!("{}.generation", kind)),
+        }
+    }
+
+    /// Directory containing state dirs of all the containers
+    ///
+    /// For named instance it's `<state-dir>.<instance>`
+    pub fn state_path(&self) -> PathBuf {
+        let path = self.runtime_dir.join(&self.state_dir);
+        match self.instance_name {
+            Some(ref name) => {
+                let mut path = path.into_os_string();
+                path.push(".");
+                path.push(name);
+                PathBuf::from(path)
+            }
+            None => path,
+        }
+    }
+
+    /// Directory with counters files of `lithos_knot` processes
+    pub fn knot_metrics_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("knot-metrics.{}", name))
+            }
+            None => self.runtime_dir.join("knot-metrics"),
+        }
+    }
+
+    /// Directory with address allocations of bridged networks
+    pub fn ipam_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => self.runtime_dir.join(format!("ipam.{}", name)),
+            None => self.runtime_dir.join("ipam"),
+        }
+    }
+
+    /// Directory with instance numbers allocated to children of sandboxes
+    pub fn instances_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("instances.{}", name))
+            }
+            None => self.runtime_dir.join("instances"),
+        }
+    }
+    /// Directory of the last accepted process configs of the sandboxes
+    pub fn accepted_configs_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("accepted.{}", name))
+            }
+            None => self.runtime_dir.join("accepted"),
+        }
+    }
+
+    /// File with facts about the host written by `lithos_tree`
+    pub fn host_facts_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("host-facts.{}.json", name))
+            }
+            None => self.runtime_dir.join("host-facts.json"),
+        }
+    }
+
+    /// Directory with locks limiting number of running commands
+    pub fn command_slots_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("command-slots.{}", name))
+            }
+            None => self.runtime_dir.join("command-slots"),
+        }
+    }
+
+    /// File with ports opened in firewalld, for `manage-firewall`
+    pub fn firewall_ports_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("firewall-ports.{}", name))
+            }
+            None => self.runtime_dir.join("firewall-ports"),
+        }
+    }
+
+    /// Directory with locks of sockets lent to commands
+    pub fn socket_loans_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("socket-loans.{}", name))
+            }
+            None => self.runtime_dir.join("socket-loans"),
+        }
+    }
+
+    /// Directory with config generations of children of sandboxes
+    pub fn child_generations_path(&self) -> PathBuf {
+        match self.instance_name {
+            Some(ref name) => {
+                self.runtime_dir.join(format!("generations.{}", name))
+            }
+            None => self.runtime_dir.join("generations"),
+        }
+    }
+
+    /// Directory with crash reports of all the containers
+    ///
+    /// For named instance it's `<crash-reports-dir>.<instance>`
+    pub fn crash_reports_path(&self) -> PathBuf {
+        let path = self.runtime_dir.join(&self.crash_reports_dir);
+        match self.instance_name {
+            Some(ref name) => {
+                let mut path = path.into_os_string();
+                path.push(".");
+                path.push(name);
+                PathBuf::from(path)
+            }
+            None => path,
+        }
+    }
+
+    /// Path of the control socket of `lithos_tree`
+    ///
+    /// For named instance it's `<control-socket>.<instance>`
+    pub fn control_socket_path(&self) -> Option<PathBuf> {
+        let path = self.runtime_dir.join(self.control_socket.as_ref()?);
+        match self.instance_name {
+            Some(ref name) => {
+                let mut path = path.into_os_string();
+                path.push(".");
+                path.push(name);
+                Some(PathBuf::from(path))
+            }
+            None => Some(path),
+        }
+    }
+
+    /// Path of the event log, see `lithos::events`
+    ///
+    /// Default `events.log` is turned into `events.<instance>.log` for
+    /// named instance, explicitly configured name is used as is.
+    pub fn event_log_path(&self) -> Option<PathBuf> {
+        let name = self.event_log.as_ref()?;
+        match self.instance_name {
+            Some(ref instance) if name == Path::new("events.log") => {
+                Some(self.default_log_dir
+                    .join(format!("events.{}.log", instance)))
+            }
+            _ => Some(self.default_log_dir.join(name)),
+        }
+    }
+
+    /// Name of the parent cgroup for all the containers
+    ///
+    /// Default `lithos.slice` is turned into `lithos-<instance>.slice` for
+    /// named instance, explicitly configured name is used as is.
+    pub fn cgroup_parent(&self) -> Option<String> {
+        match (self.cgroup_name.as_ref(), self.instance_name.as_ref()) {
+            (Some(cgroup), Some(name)) if cgroup == "lithos.slice" => {
+                Some(format!("lithos-{}.slice", name))
+            }
+            (Some(cgroup), _) => Some(cgroup.clone()),
+            (None, _) => None,
+        }
     }
 }
 
 pub fn create_master_dirs(cfg: &MasterConfig) -> Result<(), String> {
     try!(ensure_dir(&cfg.runtime_dir)
         .map_err(|e| format!("Cant create runtime-dir: {}", e)));
-    try!(ensure_dir(&cfg.runtime_dir.join(&cfg.state_dir))
+    try!(ensure_dir(&cfg.state_path())
         .map_err(|e| format!("Cant create state-dir: {}", e)));
     try!(ensure_dir(&cfg.runtime_dir.join(&cfg.mount_dir))
         .map_err(|e| format!("Cant create mount-dir: {}", e)));
@@ -65,3 +492,20 @@ pub fn create_master_dirs(cfg: &MasterConfig) -> Result<(), String> {
         .map_err(|e| format!("Cant create stdio log dir: {}", e)));
     return Ok(());
 }
+
+#[cfg(test)]
+mod test {
+    use super::RunGroup;
+
+    #[test]
+    fn run_group_position() {
+        let group = RunGroup {
+            children: vec!["db".into(), "web/worker".into(), "web".into()],
+        };
+        assert_eq!(group.position("db", "main"), Some(0));
+        assert_eq!(group.position("web", "worker"), Some(1));
+        assert_eq!(group.position("web", "frontend"), Some(2));
+        assert_eq!(group.position("cache", "main"), None);
+        assert_eq!(group.position("webapp", "worker"), None);
+    }
+}
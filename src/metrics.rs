@@ -2,37 +2,140 @@ use std::collections::HashMap;
 
 use libcantal::{Counter, Integer, Collection, Visitor, Name, NameVisitor};
 
+use reason::{Reason, ALL_REASONS};
+use version::build_info;
+
 
 pub struct Process {
     pub started: Counter,
     pub failures: Counter,
     pub deaths: Counter,
     pub running: Integer,
+    pub process_starts: Counter,
+    pub process_deaths: Counter,
+    pub process_failures: Counter,
+    pub process_restarts: Counter,
+    pub health_checks: Counter,
+    pub health_check_failures: Counter,
+    pub health_restarts: Counter,
+    /// Number of instances which failed the last health check
+    pub unhealthy: Integer,
+    pub threads: Integer,
+    pub open_fds: Integer,
+    pub fd_usage_percent: Integer,
+    pub voluntary_ctxt_switches: Integer,
+    pub nonvoluntary_ctxt_switches: Integer,
+    pub generation: Integer,
+}
+
+/// Histogram of durations with fixed buckets
+///
+/// Each bucket counts observations less than or equal to its bound
+/// (cumulative, like in prometheus).
+pub struct Histogram {
+    pub buckets: Vec<(String, u64, Counter)>,
+    pub count: Counter,
+    pub sum_ms: Counter,
+}
+
+/// Final status of the command run by `lithos_cmd`, pushed to statsd
+pub struct Command {
+    pub sandbox: String,
+    pub name: String,
+    pub attempts: Integer,
+    pub exit_code: Integer,
+    pub succeeded: Integer,
+}
+
+pub struct Socket {
+    pub owners: Integer,
+    pub queue: Integer,
+    pub backlog: Integer,
+    pub configured_backlog: Integer,
+    pub queue_full: Counter,
+    pub overflows: Counter,
+    pub early_accepts: Counter,
+    pub unowned_time: Integer,
 }
 
 pub struct Metrics {
     pub restarts: Counter,
+    pub sandboxes_removed: Counter,
     pub sandboxes: Integer,
     pub containers: Integer,
     pub queue: Integer,
+    pub sockets: Integer,
+    pub listen_overflows: Counter,
+    pub starting: Integer,
+    pub held: Integer,
+    pub draining: Integer,
+    pub command_queue: Integer,
+    pub config_log_size: Integer,
+    pub config_generation: Integer,
+    /// Always `1`, the version is in the name of the metric
+    pub build_info: Integer,
+    pub version: String,
+    pub rss_bytes: Integer,
+    pub cpu_ms: Integer,
+    pub children: Integer,
+    /// Time from wakeup of the main loop (by signal or timer) until it
+    /// waits again
+    pub loop_latency: Histogram,
 
     pub started: Counter,
     pub failures: Counter,
     pub deaths: Counter,
     pub running: Integer,
     pub unknown: Integer,
+    pub schema_mismatches: Counter,
+    pub setup_failures: Counter,
+    pub setup_give_ups: Counter,
+    pub config_errors: Counter,
+    pub pending_image: Integer,
 
     pub processes: HashMap<(String, String), Process>,
+    pub addresses: HashMap<String, Socket>,
+    /// Size of the `<sandbox>.log` in `stdio-log-dir` by sandbox name
+    pub stdio_log_size: HashMap<String, Integer>,
+    pub startup: HashMap<&'static str, Histogram>,
+    pub starts: HashMap<Reason, Counter>,
+    pub stops: HashMap<Reason, Counter>,
 }
 
+/// Startup stages of the container measured by `lithos_knot`
+///
+/// The `ready` stage is only measured for containers with `readiness` check
+/// or `sd-notify`.
+pub const STARTUP_STAGES: &[&str] = &[
+    "config", "mounts", "cgroups", "network", "secrets", "exec", "ready",
+];
+/// Upper bounds of the startup histogram buckets in milliseconds
+pub const STARTUP_BUCKETS: &[u64] = &[
+    1, 5, 10, 50, 100, 500, 1000, 5000, 10000,
+];
+/// Upper bounds of the main loop latency buckets in milliseconds
+pub const LOOP_BUCKETS: &[u64] = &[
+    1, 5, 10, 50, 100, 500, 1000, 5000,
+];
+/// File in the state dir of the container where knot puts the timings
+pub const STARTUP_TIMINGS_FILE: &str = "startup_timings.json";
+
 pub struct MasterName(&'static str);
+pub struct VersionName<'a>(&'a str);
 pub struct GlobalName(&'static str);
 pub struct ProcessName<'a>(&'a str, &'a str, &'static str);
+pub struct SocketName<'a>(&'a str, &'static str);
+pub struct SandboxName<'a>(&'a str, &'static str);
+pub struct CommandName<'a>(&'a str, &'a str, &'static str);
+pub struct StageName<'a>(&'a str, &'a str);
+pub struct LatencyName<'a>(&'a str);
+pub struct ReasonName(Reason, &'static str);
 
 impl Metrics {
     pub fn new() -> Metrics {
         Metrics {
             restarts: Counter::new(),
+            sandboxes_removed: Counter::new(),
             sandboxes: Integer::new(),
             containers: Integer::new(),
 
@@ -41,13 +144,60 @@ impl Metrics {
             deaths: Counter::new(),
             running: Integer::new(),
             unknown: Integer::new(),
+            schema_mismatches: Counter::new(),
+            setup_failures: Counter::new(),
+            setup_give_ups: Counter::new(),
+            config_errors: Counter::new(),
+            pending_image: Integer::new(),
             queue: Integer::new(),
+            sockets: Integer::new(),
+            listen_overflows: Counter::new(),
+            starting: Integer::new(),
+            held: Integer::new(),
+            draining: Integer::new(),
+            command_queue: Integer::new(),
+            config_log_size: Integer::new(),
+            config_generation: Integer::new(),
+            build_info: Integer::new(),
+            version: build_info(),
+            rss_bytes: Integer::new(),
+            cpu_ms: Integer::new(),
+            children: Integer::new(),
+            loop_latency: Histogram::new(LOOP_BUCKETS),
 
             processes: HashMap::new(),
+            addresses: HashMap::new(),
+            stdio_log_size: HashMap::new(),
+            startup: STARTUP_STAGES.iter()
+                .map(|&stage| (stage, Histogram::new(STARTUP_BUCKETS)))
+                .collect(),
+            starts: ALL_REASONS.iter().map(|&r| (r, Counter::new())).collect(),
+            stops: ALL_REASONS.iter().map(|&r| (r, Counter::new())).collect(),
         }
     }
 }
 
+impl Histogram {
+    pub fn new(bounds: &[u64]) -> Histogram {
+        Histogram {
+            buckets: bounds.iter()
+                .map(|&b| (format!("le_{}ms", b), b, Counter::new()))
+                .collect(),
+            count: Counter::new(),
+            sum_ms: Counter::new(),
+        }
+    }
+    pub fn observe(&self, ms: u64) {
+        for &(_, bound, ref counter) in &self.buckets {
+            if ms <= bound {
+                counter.incr(1);
+            }
+        }
+        self.count.incr(1);
+        self.sum_ms.incr(ms);
+    }
+}
+
 impl Process {
     pub fn new() -> Process {
         Process {
@@ -55,28 +205,157 @@ impl Process {
             failures: Counter::new(),
             deaths: Counter::new(),
             running: Integer::new(),
+            process_starts: Counter::new(),
+            process_deaths: Counter::new(),
+            process_failures: Counter::new(),
+            process_restarts: Counter::new(),
+            health_checks: Counter::new(),
+            health_check_failures: Counter::new(),
+            health_restarts: Counter::new(),
+            unhealthy: Integer::new(),
+            threads: Integer::new(),
+            open_fds: Integer::new(),
+            fd_usage_percent: Integer::new(),
+            voluntary_ctxt_switches: Integer::new(),
+            nonvoluntary_ctxt_switches: Integer::new(),
+            generation: Integer::new(),
+        }
+    }
+}
+
+impl Command {
+    pub fn new(sandbox: &str, name: &str) -> Command {
+        Command {
+            sandbox: sandbox.to_string(),
+            name: name.to_string(),
+            attempts: Integer::new(),
+            exit_code: Integer::new(),
+            succeeded: Integer::new(),
         }
     }
 }
 
+impl Socket {
+    pub fn new() -> Socket {
+        Socket {
+            owners: Integer::new(),
+            queue: Integer::new(),
+            backlog: Integer::new(),
+            configured_backlog: Integer::new(),
+            queue_full: Counter::new(),
+            overflows: Counter::new(),
+            early_accepts: Counter::new(),
+            unowned_time: Integer::new(),
+        }
+    }
+}
 
 impl Collection for Metrics {
     fn visit<'x>(&'x self, visitor: &mut Visitor<'x>) {
         visitor.metric(&MasterName("restarts"), &self.restarts);
         visitor.metric(&MasterName("sandboxes"), &self.sandboxes);
+        visitor.metric(&MasterName("sandboxes_removed"),
+            &self.sandboxes_removed);
         visitor.metric(&MasterName("containers"), &self.containers);
         visitor.metric(&MasterName("queue"), &self.queue);
+        visitor.metric(&MasterName("sockets"), &self.sockets);
+        visitor.metric(&MasterName("listen_overflows"),
+            &self.listen_overflows);
+        visitor.metric(&MasterName("starting"), &self.starting);
+        visitor.metric(&MasterName("held"), &self.held);
+        visitor.metric(&MasterName("draining"), &self.draining);
+        visitor.metric(&MasterName("command_queue"), &self.command_queue);
+        visitor.metric(&MasterName("config_log_size"),
+            &self.config_log_size);
+        visitor.metric(&MasterName("config_generation"),
+            &self.config_generation);
+        visitor.metric(&VersionName(&self.version), &self.build_info);
+        visitor.metric(&MasterName("rss_bytes"), &self.rss_bytes);
+        visitor.metric(&MasterName("cpu_ms"), &self.cpu_ms);
+        visitor.metric(&MasterName("children"), &self.children);
+        for &(ref name, _, ref counter) in &self.loop_latency.buckets {
+            visitor.metric(&LatencyName(name), counter);
+        }
+        visitor.metric(&LatencyName("count"), &self.loop_latency.count);
+        visitor.metric(&LatencyName("sum_ms"), &self.loop_latency.sum_ms);
 
         visitor.metric(&GlobalName("started"), &self.started);
         visitor.metric(&GlobalName("failures"), &self.failures);
         visitor.metric(&GlobalName("deaths"), &self.deaths);
         visitor.metric(&GlobalName("running"), &self.running);
+        visitor.metric(&GlobalName("schema_mismatches"),
+            &self.schema_mismatches);
+        visitor.metric(&GlobalName("setup_failures"), &self.setup_failures);
+        visitor.metric(&GlobalName("setup_give_ups"), &self.setup_give_ups);
+        visitor.metric(&GlobalName("config_errors"), &self.config_errors);
+        visitor.metric(&GlobalName("pending_image"), &self.pending_image);
         for (&(ref g, ref n), ref p) in &self.processes {
             visitor.metric(&ProcessName(g, n, "started"), &p.started);
             visitor.metric(&ProcessName(g, n, "failures"), &p.failures);
             visitor.metric(&ProcessName(g, n, "deaths"), &p.deaths);
             visitor.metric(&ProcessName(g, n, "running"), &p.running);
+            visitor.metric(&ProcessName(g, n, "process_starts"),
+                &p.process_starts);
+            visitor.metric(&ProcessName(g, n, "process_deaths"),
+                &p.process_deaths);
+            visitor.metric(&ProcessName(g, n, "process_failures"),
+                &p.process_failures);
+            visitor.metric(&ProcessName(g, n, "process_restarts"),
+                &p.process_restarts);
+            visitor.metric(&ProcessName(g, n, "health_checks"),
+                &p.health_checks);
+            visitor.metric(&ProcessName(g, n, "health_check_failures"),
+                &p.health_check_failures);
+            visitor.metric(&ProcessName(g, n, "health_restarts"),
+                &p.health_restarts);
+            visitor.metric(&ProcessName(g, n, "unhealthy"), &p.unhealthy);
+            visitor.metric(&ProcessName(g, n, "threads"), &p.threads);
+            visitor.metric(&ProcessName(g, n, "open_fds"), &p.open_fds);
+            visitor.metric(&ProcessName(g, n, "fd_usage_percent"),
+                &p.fd_usage_percent);
+            visitor.metric(&ProcessName(g, n, "voluntary_ctxt_switches"),
+                &p.voluntary_ctxt_switches);
+            visitor.metric(&ProcessName(g, n, "nonvoluntary_ctxt_switches"),
+                &p.nonvoluntary_ctxt_switches);
+            visitor.metric(&ProcessName(g, n, "generation"), &p.generation);
+        }
+        for (a, s) in &self.addresses {
+            visitor.metric(&SocketName(a, "owners"), &s.owners);
+            visitor.metric(&SocketName(a, "queue"), &s.queue);
+            visitor.metric(&SocketName(a, "backlog"), &s.backlog);
+            visitor.metric(&SocketName(a, "configured_backlog"),
+                &s.configured_backlog);
+            visitor.metric(&SocketName(a, "queue_full"), &s.queue_full);
+            visitor.metric(&SocketName(a, "overflows"), &s.overflows);
+            visitor.metric(&SocketName(a, "early_accepts"),
+                &s.early_accepts);
+            visitor.metric(&SocketName(a, "unowned_time"), &s.unowned_time);
         }
+        for (s, size) in &self.stdio_log_size {
+            visitor.metric(&SandboxName(s, "stdio_log_size"), size);
+        }
+        for (stage, h) in &self.startup {
+            for &(ref name, _, ref counter) in &h.buckets {
+                visitor.metric(&StageName(stage, name), counter);
+            }
+            visitor.metric(&StageName(stage, "count"), &h.count);
+            visitor.metric(&StageName(stage, "sum_ms"), &h.sum_ms);
+        }
+        for (&reason, counter) in &self.starts {
+            visitor.metric(&ReasonName(reason, "starts"), counter);
+        }
+        for (&reason, counter) in &self.stops {
+            visitor.metric(&ReasonName(reason, "stops"), counter);
+        }
+    }
+}
+
+impl Collection for Command {
+    fn visit<'x>(&'x self, visitor: &mut Visitor<'x>) {
+        let (s, n) = (&self.sandbox, &self.name);
+        visitor.metric(&CommandName(s, n, "attempts"), &self.attempts);
+        visitor.metric(&CommandName(s, n, "exit_code"), &self.exit_code);
+        visitor.metric(&CommandName(s, n, "succeeded"), &self.succeeded);
     }
 }
 
@@ -94,6 +373,22 @@ impl Name for MasterName {
     }
 }
 
+impl<'a> Name for VersionName<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "group" => Some("master"),
+            "metric" => Some("build_info"),
+            "version" => Some(self.0),
+            _ => None,
+        }
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", "master");
+        s.visit_pair("metric", "build_info");
+        s.visit_pair("version", self.0);
+    }
+}
+
 impl Name for GlobalName {
     fn get(&self, key: &str) -> Option<&str> {
         match key {
@@ -117,3 +412,85 @@ impl<'a> Name for ProcessName<'a> {
         s.visit_pair("metric", self.2);
     }
 }
+
+impl<'a> Name for CommandName<'a> {
+    fn get(&self, _key: &str) -> Option<&str> {
+        unimplemented!();
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", &format!("commands.{}.{}", self.0, self.1));
+        s.visit_pair("metric", self.2);
+    }
+}
+
+impl<'a> Name for SandboxName<'a> {
+    fn get(&self, _key: &str) -> Option<&str> {
+        unimplemented!();
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", &format!("sandboxes.{}", self.0));
+        s.visit_pair("metric", self.1);
+    }
+}
+
+impl<'a> Name for SocketName<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "group" => Some("sockets"),
+            "address" => Some(self.0),
+            "metric" => Some(self.1),
+            _ => None,
+        }
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", "sockets");
+        s.visit_pair("address", self.0);
+        s.visit_pair("metric", self.1);
+    }
+}
+
+impl<'a> Name for StageName<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "group" => Some("startup"),
+            "stage" => Some(self.0),
+            "metric" => Some(self.1),
+            _ => None,
+        }
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", "startup");
+        s.visit_pair("stage", self.0);
+        s.visit_pair("metric", self.1);
+    }
+}
+
+impl Name for ReasonName {
+    fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "group" => Some("reasons"),
+            "reason" => Some(self.0.as_str()),
+            "metric" => Some(self.1),
+            _ => None,
+        }
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", "reasons");
+        s.visit_pair("reason", self.0.as_str());
+        s.visit_pair("metric", self.1);
+    }
+}
+
+impl<'a> Name for LatencyName<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "group" => Some("loop_latency"),
+            "metric" => Some(self.0),
+            _ => None,
+        }
+    }
+    fn visit(&self, s: &mut NameVisitor) {
+        s.visit_pair("group", "loop_latency");
+        s.visit_pair("metric", self.0);
+    }
+}
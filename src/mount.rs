@@ -1,9 +1,11 @@
 #![allow(dead_code)]
 use std::io::Error as IoError;
 use std::ffi::CString;
+use std::mem::zeroed;
 use std::ptr::null;
 use std::path::Path;
-use libc::{c_ulong, c_int};
+use libc::{c_ulong, c_int, statvfs};
+use libc::{ST_RDONLY, ST_NOATIME, ST_NODIRATIME, ST_RELATIME};
 
 use super::itertools::{NextValue, NextStr, words};
 use super::utils::cpath;
@@ -162,13 +164,47 @@ pub fn mount_private(target: &Path) -> Result<(), String> {
 pub fn mount_pseudo(target: &Path, name: &str, options: &str, readonly: bool)
     -> Result<(), String>
 {
-    let c_name = CString::new(name).unwrap();
-    let c_target = cpath(target);
-    let c_opts = CString::new(options).unwrap();
     let mut flags = MS_NOSUID | MS_NOEXEC | MS_NODEV | MS_NOATIME;
     if readonly {
         flags |= MS_RDONLY;
     }
+    _mount_pseudo(target, name, options, flags)
+}
+
+/// Mounts `proc` with the read-only and atime flags of the current `/proc`
+///
+/// Inside a container these flags of the proc mount are usually locked,
+/// and the kernel refuses a new proc mount which clears any of them.
+pub fn mount_proc_as_current(target: &Path) -> Result<(), String> {
+    let c_proc = CString::new("/proc").unwrap();
+    let mut stat: statvfs = unsafe { zeroed() };
+    if unsafe { statvfs(c_proc.as_ptr(), &mut stat) } != 0 {
+        let err = IoError::last_os_error();
+        return Err(format!("Can't stat /proc: {}", err));
+    }
+    let mut flags = MS_NOSUID | MS_NOEXEC | MS_NODEV;
+    if stat.f_flag & ST_RDONLY != 0 {
+        flags |= MS_RDONLY;
+    }
+    if stat.f_flag & ST_NOATIME != 0 {
+        flags |= MS_NOATIME;
+    } else if stat.f_flag & ST_RELATIME != 0 {
+        flags |= MS_RELATIME;
+    } else {
+        flags |= MS_STRICTATIME;
+    }
+    if stat.f_flag & ST_NODIRATIME != 0 {
+        flags |= MS_NODIRATIME;
+    }
+    _mount_pseudo(target, "proc", "", flags)
+}
+
+fn _mount_pseudo(target: &Path, name: &str, options: &str, flags: c_ulong)
+    -> Result<(), String>
+{
+    let c_name = CString::new(name).unwrap();
+    let c_target = cpath(target);
+    let c_opts = CString::new(options).unwrap();
     debug!("Pseudofs mount {} {} {}", target.display(), name, options);
     let rc = unsafe { mount(
         c_name.as_ptr(),
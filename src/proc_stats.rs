@@ -0,0 +1,200 @@
+//! Resource usage of the container processes read from `/proc`
+use std::fs::{File, read_dir};
+use std::io::{self, Read};
+use std::path::Path;
+
+use libc::{pid_t, sysconf, _SC_CLK_TCK};
+
+
+/// File in the state dir of the child with the pid of the container process
+///
+/// Written by `lithos_knot` on each start of the process. The pid is in the
+/// pid namespace of the knot.
+pub const CONTAINER_PID_FILE: &str = "container.pid";
+
+/// Snapshot of the resource usage of a single process
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProcStats {
+    pub threads: u64,
+    pub voluntary_ctxt_switches: u64,
+    pub nonvoluntary_ctxt_switches: u64,
+    pub open_fds: u64,
+    /// Soft limit of the open files, `None` if unlimited
+    pub fd_limit: Option<u64>,
+}
+
+impl ProcStats {
+    /// Percentage of the file descriptor limit in use
+    pub fn fd_usage_percent(&self) -> Option<u64> {
+        self.fd_limit
+            .and_then(|limit| (self.open_fds * 100).checked_div(limit))
+    }
+}
+
+/// Memory and CPU used by a process
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub rss_bytes: u64,
+    /// User and system time since start of the process
+    pub cpu_ms: u64,
+}
+
+fn read_file<P: AsRef<Path>>(path: P) -> Result<String, io::Error> {
+    let mut buf = String::with_capacity(2048);
+    File::open(path)?.read_to_string(&mut buf)?;
+    Ok(buf)
+}
+
+fn parse_status(data: &str, stats: &mut ProcStats) {
+    for line in data.lines() {
+        let mut pair = line.splitn(2, ':');
+        let key = pair.next().unwrap_or("");
+        let value = match pair.next().and_then(|x| x.trim().parse().ok()) {
+            Some(value) => value,
+            None => continue,
+        };
+        match key {
+            "Threads" => stats.threads = value,
+            "voluntary_ctxt_switches" => {
+                stats.voluntary_ctxt_switches = value;
+            }
+            "nonvoluntary_ctxt_switches" => {
+                stats.nonvoluntary_ctxt_switches = value;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_fd_limit(data: &str) -> Option<u64> {
+    for line in data.lines() {
+        if let Some(rest) = line.strip_prefix("Max open files") {
+            // columns are: name, soft limit, hard limit, units
+            return rest.split_whitespace().next()?.parse().ok();
+        }
+    }
+    None
+}
+
+fn parse_rss(data: &str) -> Option<u64> {
+    for line in data.lines() {
+        if let Some(rest) = line.strip_prefix("VmRSS:") {
+            // value is always in kB
+            let kb: u64 = rest.split_whitespace().next()?.parse().ok()?;
+            return Some(kb * 1024);
+        }
+    }
+    None
+}
+
+/// Returns `utime + stime` from `/proc/<pid>/stat` in clock ticks
+fn parse_cpu_ticks(data: &str) -> Option<u64> {
+    // command name may contain spaces and parens, so skip to the last paren
+    let rest = &data[data.rfind(')')? + 1..];
+    // fields after the name start with the 3rd one (state), utime is 14th
+    let mut fields = rest.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Reads memory and CPU usage of the process
+pub fn read_usage(pid: pid_t) -> Result<Usage, io::Error> {
+    let invalid = |name| io::Error::new(io::ErrorKind::InvalidData,
+        format!("can't parse /proc/{}/{}", pid, name));
+    let rss_bytes = parse_rss(&read_file(&format!("/proc/{}/status", pid))?)
+        .ok_or_else(|| invalid("status"))?;
+    let ticks = parse_cpu_ticks(&read_file(&format!("/proc/{}/stat", pid))?)
+        .ok_or_else(|| invalid("stat"))?;
+    let tick_rate = match unsafe { sysconf(_SC_CLK_TCK) } {
+        x if x > 0 => x as u64,
+        _ => 100,
+    };
+    Ok(Usage { rss_bytes, cpu_ms: ticks * 1000 / tick_rate })
+}
+
+/// Reads statistics of the process
+pub fn read(pid: pid_t) -> Result<ProcStats, io::Error> {
+    let mut stats = ProcStats::default();
+    parse_status(&read_file(&format!("/proc/{}/status", pid))?, &mut stats);
+    stats.fd_limit = parse_fd_limit(
+        &read_file(&format!("/proc/{}/limits", pid))?);
+    stats.open_fds = read_dir(format!("/proc/{}/fd", pid))?.count() as u64;
+    Ok(stats)
+}
+
+/// Returns pid of the process in its innermost pid namespace
+fn parse_ns_pid(data: &str) -> Option<pid_t> {
+    data.lines()
+        .find(|line| line.starts_with("NSpid:"))?
+        .split_whitespace().last()?
+        .parse().ok()
+}
+
+/// Finds the actual process of the container, given the pid of the knot
+///
+/// Knot also runs helper processes (hooks, health checks), so the child
+/// is matched against the pid from `CONTAINER_PID_FILE` in `state_dir`.
+/// Requires `CONFIG_PROC_CHILDREN` in the kernel.
+pub fn container_process(pid: pid_t, state_dir: &Path) -> Option<pid_t> {
+    let ns_pid: pid_t = read_file(state_dir.join(CONTAINER_PID_FILE)).ok()?
+        .trim().parse().ok()?;
+    read_file(&format!("/proc/{}/task/{}/children", pid, pid)).ok()?
+        .split_whitespace()
+        .filter_map(|x| x.parse().ok())
+        .find(|child| {
+            read_file(&format!("/proc/{}/status", child)).ok()
+                .and_then(|data| parse_ns_pid(&data)) == Some(ns_pid)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProcStats, parse_status, parse_fd_limit};
+    use super::{parse_rss, parse_cpu_ticks, parse_ns_pid};
+
+    #[test]
+    fn ns_pid() {
+        assert_eq!(parse_ns_pid("Name:\tsleep\nNSpid:\t12345\t7\n"),
+                   Some(7));
+        assert_eq!(parse_ns_pid("Name:\tsleep\nNSpid:\t12345\n"),
+                   Some(12345));
+        assert_eq!(parse_ns_pid("Name:\tsleep\n"), None);
+    }
+
+    #[test]
+    fn status() {
+        let mut stats = ProcStats::default();
+        parse_status("Name:\tcat\nThreads:\t4\n\
+            voluntary_ctxt_switches:\t10\n\
+            nonvoluntary_ctxt_switches:\t2\n", &mut stats);
+        assert_eq!(stats.threads, 4);
+        assert_eq!(stats.voluntary_ctxt_switches, 10);
+        assert_eq!(stats.nonvoluntary_ctxt_switches, 2);
+    }
+
+    #[test]
+    fn fd_limit() {
+        let data = "Limit                     Soft Limit           \
+                    Hard Limit           Units\n\
+            Max processes             63448                63448                \
+            processes\n\
+            Max open files            1024                 524288               \
+            files\n";
+        assert_eq!(parse_fd_limit(data), Some(1024));
+        assert_eq!(parse_fd_limit(
+            "Max open files            unlimited            unlimited            \
+            files\n"), None);
+    }
+
+    #[test]
+    fn usage() {
+        assert_eq!(parse_rss("Name:\tlithos_tree\nVmRSS:\t  5120 kB\n"),
+                   Some(5120*1024));
+        assert_eq!(parse_rss("Name:\tkthreadd\n"), None);
+        assert_eq!(parse_cpu_ticks("42 (lithos (tree)) S 1 42 42 0 -1 \
+            4194560 1000 0 0 0 150 25 0 0 20 0 1 0 100 0 0\n"),
+            Some(175));
+        assert_eq!(parse_cpu_ticks("42 (x) S 1"), None);
+    }
+}
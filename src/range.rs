@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 use serde::de::{Deserializer, Deserialize, Error};
+use serde::ser::{Serializer, Serialize};
 
 
 #[derive(Clone, Debug)]
@@ -39,6 +40,16 @@ impl<'a> Deserialize<'a> for Range {
     }
 }
 
+impl Serialize for Range {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if self.start == self.end {
+            self.start.to_string().serialize(s)
+        } else {
+            format!("{}-{}", self.start, self.end).serialize(s)
+        }
+    }
+}
+
 pub fn in_range(ranges: &Vec<Range>, value: u32) -> bool {
     for rng in ranges.iter() {
         if rng.start <= value && rng.end >= value {
@@ -0,0 +1,73 @@
+//! Reasons of container starts and stops
+use std::fmt;
+
+
+/// Why container is started or stopped
+///
+/// Used in logs and metrics to distinguish planned restarts from incidents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reason {
+    /// First start of the container after `lithos_tree` is started
+    Initial,
+    /// Container is added by configuration reload
+    Rollout,
+    /// Process exited with a failure
+    Crash,
+    /// Process exited normally (or was stopped by an operator)
+    Exit,
+    /// Container is restarted because its configuration changed
+    ConfigChange,
+    /// Container is removed from the configuration
+    Retired,
+    /// `lithos_tree` is shutting down
+    Shutdown,
+    /// Container is stopped or started via the control socket
+    Operator,
+    /// Host is drained or undrained via the control socket
+    Drain,
+    /// Process of a run group isn't started, because a process it depends
+    /// on (i.e. the previous one in the group) failed to start
+    DependencyFailed,
+    /// Command is terminated because it exceeded its `max-runtime`
+    MaxRuntime,
+}
+
+/// All reasons, i.e. for initializing metrics
+pub const ALL_REASONS: &[Reason] = &[
+    Reason::Initial,
+    Reason::Rollout,
+    Reason::Crash,
+    Reason::Exit,
+    Reason::ConfigChange,
+    Reason::Retired,
+    Reason::Shutdown,
+    Reason::Operator,
+    Reason::Drain,
+    Reason::DependencyFailed,
+    Reason::MaxRuntime,
+];
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        use self::Reason::*;
+        match *self {
+            Initial => "initial",
+            Rollout => "rollout",
+            Crash => "crash",
+            Exit => "exit",
+            ConfigChange => "config-change",
+            Retired => "retired",
+            Shutdown => "shutdown",
+            Operator => "operator",
+            Drain => "drain",
+            DependencyFailed => "dependency-failed",
+            MaxRuntime => "max-runtime",
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -2,11 +2,13 @@ use std::collections::BTreeMap;
 use std::net::IpAddr;
 use std::path::{PathBuf, Path, Component};
 
+use container_config::wrap_into_list;
 use id_map::{IdMap, mapping_validator};
 use ipnetwork::IpNetwork;
 use quire::validate::{Sequence, Mapping, Scalar, Numeric};
-use quire::validate::{Structure};
+use quire::validate::{Structure, Enum, Nothing};
 use range::Range;
+use serde::de::{self, Deserialize, Deserializer};
 
 
 #[derive(Deserialize, Clone)]
@@ -16,6 +18,73 @@ pub struct BridgedNetwork {
     pub network: IpNetwork,
     pub default_gateway: Option<IpAddr>,
     pub after_setup_command: Vec<String>,
+    #[serde(deserialize_with="optional_network", default)]
+    pub ip_pool: Option<IpNetwork>,
+    pub dhcp_command: Vec<String>,
+    pub dhcp_timeout: f32,
+    pub network_hooks: Vec<NetworkHook>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct NetworkHook {
+    pub setup: Vec<String>,
+    pub cleanup: Vec<String>,
+    pub nft_setup: Option<PathBuf>,
+    pub nft_cleanup: Option<PathBuf>,
+}
+
+fn optional_network<'de, D>(d: D) -> Result<Option<IpNetwork>, D::Error>
+    where D: Deserializer<'de>
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LogDir {
+    pub user: u32,
+    pub group: u32,
+    pub mode: u32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HostNetworkPolicy {
+    pub allow_outgoing_ports: Vec<Range>,
+    pub allow_loopback: bool,
+}
+
+/// What is written to the `config-log-dir` for the sandbox
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all="lowercase")]
+pub enum ConfigLog {
+    Off,
+    /// Values of the variables matching `redact-variables` are hidden
+    Redacted,
+    Full,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TmpfsCopyInfo {
+    pub size: usize,
+}
+
+/// File describing the container, for people exec'ing into it
+#[derive(Deserialize, Clone)]
+pub struct InfoFile {
+    /// Path in the container, the image must have a file there
+    pub path: PathBuf,
+    /// Contact info of the team owning the sandbox
+    pub contact: Option<String>,
+}
+
+/// How root filesystem of the container is mounted
+#[derive(Deserialize, Clone)]
+pub enum RootMode {
+    /// Image is bind-mounted read-only (the default)
+    Readonly,
+    /// Image is copied into the tmpfs of limited size
+    TmpfsCopy(TmpfsCopyInfo),
 }
 
 #[derive(Deserialize)]
@@ -26,6 +95,7 @@ pub struct SandboxConfig {
     pub used_images_list: Option<PathBuf>,
     pub log_file: Option<PathBuf>,
     pub log_level: Option<String>,
+    pub log_dirs: BTreeMap<PathBuf, LogDir>,
     pub readonly_paths: BTreeMap<PathBuf, PathBuf>,
     pub writable_paths: BTreeMap<PathBuf, PathBuf>,
     pub allow_users: Vec<Range>,
@@ -40,8 +110,26 @@ pub struct SandboxConfig {
     pub resolv_conf: PathBuf,
     pub hosts_file: PathBuf,
     pub bridged_network: Option<BridgedNetwork>,
-    pub secrets_private_key: Option<PathBuf>,
+    pub host_network_policy: Option<HostNetworkPolicy>,
+    /// Key files tried in order, so keys can be rotated
+    pub secrets_private_key: Vec<PathBuf>,
     pub secrets_namespaces: Vec<String>,
+    pub allow_gpus: bool,
+    pub gpu_driver_dir: Option<PathBuf>,
+    pub allow_fuse: bool,
+    pub root_mode: Option<RootMode>,
+    pub debug_allow_ptrace: bool,
+    pub default_container_config: Option<PathBuf>,
+    pub image_signature_key: Option<PathBuf>,
+    pub template_values: BTreeMap<String, String>,
+    pub config_log: ConfigLog,
+    pub redact_variables: Vec<String>,
+    pub stdio_log_max_size: Option<u64>,
+    /// Host directory where `unix-sockets` of the containers are created
+    pub unix_sockets_dir: Option<PathBuf>,
+    /// Limit of the total size of the environment of the process
+    pub max_environ_size: Option<usize>,
+    pub info_file: Option<InfoFile>,
 }
 
 impl SandboxConfig {
@@ -65,6 +153,13 @@ impl SandboxConfig {
         .member("used_images_list", Scalar::new().optional())
         .member("log_file", Scalar::new().optional())
         .member("log_level", Scalar::new().optional())
+        .member("log_dirs", Mapping::new(
+            Scalar::new(),
+            Structure::new()
+                .member("user", Numeric::new().default(0))
+                .member("group", Numeric::new().default(0))
+                .member("mode", Numeric::new().min(0).max(0o1777)
+                    .default(0o755))))
         .member("readonly_paths", Mapping::new(
             Scalar::new(),
             Scalar::new()))
@@ -89,8 +184,45 @@ impl SandboxConfig {
             .member("network", Scalar::new())
             .member("default_gateway", Scalar::new().optional())
             .member("after_setup_command", Sequence::new(Scalar::new()))
+            .member("ip_pool", Scalar::new().optional())
+            .member("dhcp_command", Sequence::new(Scalar::new()))
+            .member("dhcp_timeout", Numeric::new().min(0).default(10))
+            .member("network_hooks", Sequence::new(Structure::new()
+                .member("setup", Sequence::new(Scalar::new()))
+                .member("cleanup", Sequence::new(Scalar::new()))
+                .member("nft_setup", Scalar::new().optional())
+                .member("nft_cleanup", Scalar::new().optional())))
             .optional())
-        .member("secrets_private_key", Scalar::new().optional())
+        .member("host_network_policy", Structure::new()
+            .member("allow_outgoing_ports", Sequence::new(Scalar::new()))
+            .member("allow_loopback", Scalar::new().default(true))
+            .optional())
+        .member("secrets_private_key", Sequence::new(Scalar::new())
+            .parser(wrap_into_list))
         .member("secrets_namespaces", Sequence::new(Scalar::new()))
+        .member("allow_gpus", Scalar::new().default(false))
+        .member("gpu_driver_dir", Scalar::new().optional())
+        .member("allow_fuse", Scalar::new().default(false))
+        .member("root_mode", Enum::new()
+            .option("Readonly", Nothing)
+            .option("TmpfsCopy", Structure::new()
+                .member("size", Numeric::new().min(0)
+                    .default(100*1024*1024)))
+            .optional())
+        .member("debug_allow_ptrace", Scalar::new().default(false))
+        .member("default_container_config", Scalar::new().optional())
+        .member("image_signature_key", Scalar::new().optional())
+        .member("template_values", Mapping::new(
+            Scalar::new(),
+            Scalar::new()))
+        .member("config_log", Scalar::new().default("full"))
+        .member("redact_variables", Sequence::new(Scalar::new()))
+        .member("stdio_log_max_size", Numeric::new().min(0).optional())
+        .member("unix_sockets_dir", Scalar::new().optional())
+        .member("max_environ_size", Numeric::new().min(0).optional())
+        .member("info_file", Structure::new()
+            .member("path", Scalar::new().default("/etc/lithos-info"))
+            .member("contact", Scalar::new().optional())
+            .optional())
     }
 }
@@ -0,0 +1,230 @@
+//! Layered sandbox config directories (`sandboxes-include`)
+//!
+//! Sandbox configs are read from `sandboxes-dir` and then from directories
+//! matching `sandboxes-include` patterns, in order. When the same
+//! `<sandbox>.yaml` exists in multiple directories, the last one is used,
+//! so fleet-wide sandboxes may be overridden by the host-specific ones.
+//!
+//! Patterns support `*` and `?` wildcards in any path component, matched
+//! directories are sorted by name. Relative patterns are relative to the
+//! directory of the master config, like `sandboxes-dir` is.
+//!
+//! Included directories may be symlinks to generation directories (see
+//! `generation`), the same as `sandboxes-dir`.
+use std::collections::BTreeMap;
+use std::fs::{read_dir, symlink_metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use regex::{Regex, escape};
+
+use generation::{self, COMPLETE_MARKER};
+use master_config::MasterConfig;
+
+
+fn wildcard(part: &str) -> Option<Regex> {
+    if !part.contains(|c: char| c == '*' || c == '?') {
+        return None;
+    }
+    let mut re = String::from("^");
+    for c in part.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Some(Regex::new(&re).expect("escaped regex is valid"))
+}
+
+/// Returns directories matching the `pattern`, sorted by name
+///
+/// Hidden entries are never matched by wildcards.
+pub fn expand(pattern: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut paths = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let regex = component.as_os_str().to_str().and_then(wildcard);
+        let regex = match regex {
+            Some(regex) => regex,
+            None => {
+                for path in &mut paths {
+                    path.push(component.as_os_str());
+                }
+                continue;
+            }
+        };
+        let mut next = Vec::new();
+        for path in &paths {
+            let entries = match read_dir(path) {
+                Ok(entries) => entries,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mut names = Vec::new();
+            for entry in entries {
+                if let Ok(name) = entry?.file_name().into_string() {
+                    if !name.starts_with('.') && regex.is_match(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+            names.sort();
+            next.extend(names.iter().map(|name| path.join(name)));
+        }
+        paths = next;
+    }
+    paths.retain(|p| p.is_dir());
+    Ok(paths)
+}
+
+/// Resolves included directory, returns `None` if it must be skipped
+///
+/// Symlinks to incomplete generations are skipped, and so are generation
+/// directories matched directly, as they're only used via the symlink
+/// (otherwise all the old generations would be included too).
+fn resolve_included(dir: &Path) -> Option<PathBuf> {
+    let is_symlink = symlink_metadata(dir)
+        .map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    if !is_symlink {
+        if dir.join(COMPLETE_MARKER).exists() {
+            debug!("Skipping generation directory {:?}", dir);
+            return None;
+        }
+        return Some(dir.to_path_buf());
+    }
+    match generation::resolve(dir) {
+        Ok(resolved) => Some(resolved.path),
+        Err(e) => {
+            error!("Skipping included sandboxes dir: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns `sandboxes` dir followed by the included dirs, in the order of
+/// precedence (the last one wins)
+pub fn dirs(master: &MasterConfig, master_file: &Path, sandboxes: &Path)
+    -> Vec<PathBuf>
+{
+    let base = master_file.parent().unwrap();
+    let mut result = vec![sandboxes.to_path_buf()];
+    for pattern in &master.sandboxes_include {
+        match expand(&base.join(pattern)) {
+            Ok(dirs) => {
+                result.extend(dirs.iter()
+                    .filter_map(|dir| resolve_included(dir)));
+            }
+            Err(e) => {
+                error!("Can't expand sandboxes-include {:?}: {}",
+                    pattern, e);
+            }
+        }
+    }
+    return result;
+}
+
+fn list_dir(dir: &Path, result: &mut BTreeMap<String, PathBuf>)
+    -> Result<(), io::Error>
+{
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Ok(name) = entry.file_name().into_string() {
+            if name.ends_with(".yaml") {
+                let sandbox = name[..name.len()-5].to_string();
+                result.insert(sandbox, entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns sandbox names mapped to the effective config file
+///
+/// Only an error reading the first dir (`sandboxes-dir`) is returned,
+/// unreadable included dirs are logged and skipped.
+pub fn list(dirs: &[PathBuf]) -> Result<BTreeMap<String, PathBuf>, io::Error>
+{
+    let mut result = BTreeMap::new();
+    for (idx, dir) in dirs.iter().enumerate() {
+        match list_dir(dir, &mut result) {
+            Ok(()) => {}
+            Err(e) if idx > 0 => {
+                error!("Can't read included sandboxes dir {:?}: {}",
+                    dir, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(result)
+}
+
+/// Returns path of the config of a single sandbox
+///
+/// If there is no such sandbox, returns the path in `sandboxes-dir`, so
+/// the caller reports meaningful error when opening it.
+pub fn find(master: &MasterConfig, master_file: &Path, name: &str)
+    -> PathBuf
+{
+    let base = master_file.parent().unwrap().join(&master.sandboxes_dir);
+    let fname = format!("{}.yaml", name);
+    dirs(master, master_file, &base).iter().rev()
+        .map(|dir| dir.join(&fname))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| base.join(&fname))
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::os::unix::fs::symlink;
+    use super::{wildcard, resolve_included, list};
+
+    #[test]
+    fn patterns() {
+        assert!(wildcard("hosts").is_none());
+        let re = wildcard("host-*").unwrap();
+        assert!(re.is_match("host-a1"));
+        assert!(re.is_match("host-"));
+        assert!(!re.is_match("xhost-a1"));
+        let re = wildcard("a?.d").unwrap();
+        assert!(re.is_match("ab.d"));
+        assert!(!re.is_match("abxd"));
+    }
+
+    #[test]
+    fn included_generations() {
+        let dir = temp_dir().join("lithos-test-included-generations");
+        remove_dir_all(&dir).ok();
+        create_dir_all(dir.join("gen-1")).unwrap();
+        create_dir_all(dir.join("gen-2")).unwrap();
+        create_dir_all(dir.join("plain")).unwrap();
+        File::create(dir.join("gen-1/.complete")).unwrap();
+        symlink("gen-1", dir.join("current")).unwrap();
+        symlink("gen-2", dir.join("incomplete")).unwrap();
+        assert_eq!(resolve_included(&dir.join("current")),
+            Some(dir.join("gen-1").canonicalize().unwrap()));
+        assert_eq!(resolve_included(&dir.join("incomplete")), None);
+        assert_eq!(resolve_included(&dir.join("gen-1")), None);
+        assert_eq!(resolve_included(&dir.join("plain")),
+            Some(dir.join("plain")));
+        remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn unreadable_include() {
+        let dir = temp_dir().join("lithos-test-unreadable-include");
+        remove_dir_all(&dir).ok();
+        create_dir_all(dir.join("base")).unwrap();
+        File::create(dir.join("base/web.yaml")).unwrap();
+        let configs = list(&[dir.join("base"), dir.join("missing")])
+            .unwrap();
+        assert_eq!(configs.keys().collect::<Vec<_>>(), vec!["web"]);
+        assert!(list(&[dir.join("missing"), dir.join("base")]).is_err());
+        remove_dir_all(&dir).ok();
+    }
+}
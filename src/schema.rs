@@ -0,0 +1,504 @@
+//! JSON Schema of the configs, generated from the quire validators
+//!
+//! Validators don't expose their settings except through `Debug`, so the
+//! debug output is parsed back into a tree and converted into the schema.
+//! YAML tags can't be expressed in JSON Schema, so each variant of an enum
+//! is marked by the `x-yaml-tag` keyword and a value matching any of the
+//! variants is accepted.
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+use quire::validate::Validator;
+use serde_json::{Map, Value};
+
+use child_config::ChildConfig;
+use container_config::ContainerConfig;
+use master_config::MasterConfig;
+use sandbox_config::SandboxConfig;
+
+
+const DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+/// Config file to dump the schema for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigKind {
+    Master,
+    Sandbox,
+    /// Process config of the sandbox (mapping of the names to children)
+    Child,
+    Container,
+}
+
+/// Value printed by `Debug` of the validator
+#[derive(Debug)]
+enum Node {
+    Str(String),
+    Atom(String),
+    Struct(String, Vec<(String, Node)>),
+    Tuple(String, Vec<Node>),
+    List(Vec<Node>),
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl FromStr for ConfigKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ConfigKind, String> {
+        match s {
+            "master" => Ok(ConfigKind::Master),
+            "sandbox" => Ok(ConfigKind::Sandbox),
+            "child" => Ok(ConfigKind::Child),
+            "container" => Ok(ConfigKind::Container),
+            _ => Err(format!("Unknown config {:?}, expected master, \
+                sandbox, child or container", s)),
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.chars.next();
+        }
+        self.chars.peek().cloned()
+    }
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.peek() {
+            Some(x) if x == c => {
+                self.chars.next();
+                Ok(())
+            }
+            x => Err(format!("Expected {:?}, got {:?}", c, x)),
+        }
+    }
+    fn word(&mut self) -> String {
+        let mut result = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || "\",:()[]{}".contains(c) {
+                break;
+            }
+            result.push(c);
+            self.chars.next();
+        }
+        result
+    }
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(result),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => result.push('\n'),
+                    Some('r') => result.push('\r'),
+                    Some('t') => result.push('\t'),
+                    Some('0') => result.push('\0'),
+                    Some('u') => {
+                        self.expect('{')?;
+                        let code = self.word();
+                        self.expect('}')?;
+                        let c = u32::from_str_radix(&code, 16).ok()
+                            .and_then(::std::char::from_u32)
+                            .ok_or_else(|| {
+                                format!("Bad escape {:?}", code)
+                            })?;
+                        result.push(c);
+                    }
+                    Some(c) => result.push(c),
+                    None => return Err(format!("Unterminated string")),
+                },
+                Some(c) => result.push(c),
+                None => return Err(format!("Unterminated string")),
+            }
+        }
+    }
+    fn items(&mut self, close: char) -> Result<Vec<Node>, String> {
+        let mut result = Vec::new();
+        loop {
+            if self.peek() == Some(close) {
+                self.chars.next();
+                return Ok(result);
+            }
+            result.push(self.node()?);
+            if self.peek() == Some(',') {
+                self.chars.next();
+            }
+        }
+    }
+    fn fields(&mut self) -> Result<Vec<(String, Node)>, String> {
+        let mut result = Vec::new();
+        loop {
+            if self.peek() == Some('}') {
+                self.chars.next();
+                return Ok(result);
+            }
+            let name = self.word();
+            self.expect(':')?;
+            result.push((name, self.node()?));
+            if self.peek() == Some(',') {
+                self.chars.next();
+            }
+        }
+    }
+    fn node(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some('"') => Ok(Node::Str(self.string()?)),
+            Some('(') => {
+                self.chars.next();
+                Ok(Node::Tuple(String::new(), self.items(')')?))
+            }
+            Some('[') => {
+                self.chars.next();
+                Ok(Node::List(self.items(']')?))
+            }
+            Some(c) => {
+                let name = self.word();
+                if name.is_empty() {
+                    return Err(format!("Unexpected {:?}", c));
+                }
+                match self.peek() {
+                    Some('{') => {
+                        self.chars.next();
+                        Ok(Node::Struct(name, self.fields()?))
+                    }
+                    Some('(') => {
+                        self.chars.next();
+                        Ok(Node::Tuple(name, self.items(')')?))
+                    }
+                    _ => Ok(Node::Atom(name)),
+                }
+            }
+            None => Err(format!("Unexpected end of input")),
+        }
+    }
+}
+
+impl Node {
+    fn name(&self) -> &str {
+        match *self {
+            Node::Struct(ref name, _) | Node::Atom(ref name) => name,
+            _ => "",
+        }
+    }
+    fn field(&self, name: &str) -> Option<&Node> {
+        match *self {
+            Node::Struct(_, ref fields) => fields.iter()
+                .find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v),
+            _ => None,
+        }
+    }
+    fn flag(&self, name: &str) -> bool {
+        match self.field(name) {
+            Some(&Node::Atom(ref x)) => x == "true",
+            _ => false,
+        }
+    }
+    /// Returns `x` if the field is `Some(x)`
+    fn some(&self, name: &str) -> Option<&Node> {
+        match self.field(name) {
+            Some(&Node::Tuple(ref n, ref items))
+            if n == "Some" && items.len() == 1 => Some(&items[0]),
+            _ => None,
+        }
+    }
+    fn number(&self) -> Option<Value> {
+        match *self {
+            Node::Atom(ref x) => x.parse::<i64>().ok().map(Value::from)
+                .or_else(|| x.parse::<u64>().ok().map(Value::from)),
+            _ => None,
+        }
+    }
+    fn string(&self) -> Option<&str> {
+        match *self {
+            Node::Str(ref x) => Some(x),
+            _ => None,
+        }
+    }
+    /// Members of the `Structure` or options of the `Enum`
+    fn pairs(&self, name: &str) -> Result<Vec<(&str, &Node)>, String> {
+        let items = match self.field(name) {
+            Some(&Node::List(ref items)) => items,
+            _ => return Err(format!("No {:?} in {:?}", name, self.name())),
+        };
+        items.iter().map(|item| match *item {
+            Node::Tuple(_, ref pair) if pair.len() == 2 => {
+                let key = pair[0].string()
+                    .ok_or_else(|| format!("Bad key {:?}", pair[0]))?;
+                Ok((key, &pair[1]))
+            }
+            _ => Err(format!("Bad item {:?}", item)),
+        }).collect()
+    }
+    /// Mirrors `Validator::default`, a member without default is required
+    fn has_default(&self) -> bool {
+        match self.name() {
+            "Scalar" | "Numeric" | "Directory" => {
+                self.flag("optional") || self.some("default").is_some()
+            }
+            "Enum" => {
+                self.flag("optional") || self.some("default_value").is_some()
+            }
+            "Sequence" => match self.field("min_length") {
+                Some(&Node::Atom(ref x)) => x == "0",
+                _ => true,
+            },
+            "Structure" | "Mapping" | "Anything" => true,
+            _ => false,
+        }
+    }
+    /// Empty value (`key:` in YAML) is accepted
+    fn accepts_null(&self) -> bool {
+        match self.name() {
+            "Structure" | "Mapping" | "Sequence" => true,
+            _ => self.flag("optional"),
+        }
+    }
+}
+
+fn nullable(mut schema: Map<String, Value>) -> Map<String, Value> {
+    if let Some(&mut Value::Array(ref mut types)) = schema.get_mut("type") {
+        types.push("null".into());
+        return schema;
+    }
+    if let Some(Value::String(typ)) = schema.remove("type") {
+        schema.insert("type".into(), json!([typ, "null"]));
+        return schema;
+    }
+    let mut result = Map::new();
+    result.insert("anyOf".into(),
+        json!([Value::Object(schema), {"type": "null"}]));
+    result
+}
+
+fn or_string(schema: Map<String, Value>) -> Map<String, Value> {
+    let mut result = Map::new();
+    result.insert("anyOf".into(),
+        json!([Value::Object(schema), {"type": "string"}]));
+    result
+}
+
+/// Schema of the member, element or variant of the outer validator
+fn nested(node: &Node) -> Result<Map<String, Value>, String> {
+    let schema = convert(node)?;
+    if node.accepts_null() {
+        Ok(nullable(schema))
+    } else {
+        Ok(schema)
+    }
+}
+
+fn convert(node: &Node) -> Result<Map<String, Value>, String> {
+    let mut schema = Map::new();
+    match node.name() {
+        "Scalar" => {
+            schema.insert("type".into(),
+                json!(["string", "number", "boolean"]));
+            if let Some(len) = node.some("min_length").and_then(Node::number) {
+                schema.insert("minLength".into(), len);
+            }
+            if let Some(len) = node.some("max_length").and_then(Node::number) {
+                schema.insert("maxLength".into(), len);
+            }
+            if let Some(val) = node.some("default").and_then(Node::string) {
+                schema.insert("default".into(), val.into());
+            }
+        }
+        "Numeric" => {
+            // strings are allowed for units, like `1k` or `10 MiB`
+            schema.insert("type".into(), json!(["integer", "string"]));
+            if let Some(min) = node.some("min").and_then(Node::number) {
+                schema.insert("minimum".into(), min);
+            }
+            if let Some(max) = node.some("max").and_then(Node::number) {
+                schema.insert("maximum".into(), max);
+            }
+            if let Some(val) = node.some("default").and_then(Node::number) {
+                schema.insert("default".into(), val);
+            }
+        }
+        "Directory" => {
+            schema.insert("type".into(), "string".into());
+            match node.some("absolute") {
+                Some(&Node::Atom(ref x)) if x == "true" => {
+                    schema.insert("pattern".into(), "^/".into());
+                }
+                Some(&Node::Atom(ref x)) if x == "false" => {
+                    schema.insert("pattern".into(), "^[^/]".into());
+                }
+                _ => {}
+            }
+            if let Some(val) = node.some("default").and_then(Node::string) {
+                schema.insert("default".into(), val.into());
+            }
+        }
+        "Structure" => {
+            let mut properties = Map::new();
+            let mut required = Vec::new();
+            let mut either = Vec::new();
+            for (name, member) in node.pairs("members")? {
+                let member_schema = Value::Object(nested(member)?);
+                // quire accepts both `snake_case` and `kebab-case`, docs
+                // use the dashed form
+                let dashed = name.replace("_", "-");
+                if dashed != name {
+                    properties.insert(name.to_string(),
+                        member_schema.clone());
+                    if !member.has_default() {
+                        either.push(json!({"anyOf": [
+                            {"required": [dashed]},
+                            {"required": [name]},
+                        ]}));
+                    }
+                } else if !member.has_default() {
+                    required.push(Value::from(name));
+                }
+                properties.insert(dashed, member_schema);
+            }
+            schema.insert("type".into(), "object".into());
+            schema.insert("properties".into(), Value::Object(properties));
+            // keys starting with underscore are ignored (used for anchors)
+            schema.insert("patternProperties".into(), json!({"^_": {}}));
+            schema.insert("additionalProperties".into(), false.into());
+            if !required.is_empty() {
+                schema.insert("required".into(), Value::Array(required));
+            }
+            if !either.is_empty() {
+                schema.insert("allOf".into(), Value::Array(either));
+            }
+            if node.some("from_scalar").is_some() {
+                schema = or_string(schema);
+            }
+        }
+        "Enum" => {
+            let mut variants = Vec::new();
+            let mut plain = Vec::new();
+            for (tag, option) in node.pairs("options")? {
+                let mut variant = nested(option)?;
+                variant.insert("x-yaml-tag".into(),
+                    format!("!{}", tag).into());
+                variants.push(Value::Object(variant));
+                plain.push(Value::from(tag));
+            }
+            if node.flag("allow_plain") {
+                variants.push(json!({"enum": plain}));
+            }
+            schema.insert("anyOf".into(), Value::Array(variants));
+        }
+        "Mapping" => {
+            let key = node.field("key_element")
+                .ok_or_else(|| format!("No key in mapping"))?;
+            let value = node.field("value_element")
+                .ok_or_else(|| format!("No value in mapping"))?;
+            schema.insert("type".into(), "object".into());
+            schema.insert("propertyNames".into(),
+                Value::Object(convert(key)?));
+            schema.insert("additionalProperties".into(),
+                Value::Object(nested(value)?));
+            if node.some("from_scalar").is_some() {
+                schema = or_string(schema);
+            }
+        }
+        "Sequence" => {
+            let element = node.field("element")
+                .ok_or_else(|| format!("No element in sequence"))?;
+            schema.insert("type".into(), "array".into());
+            schema.insert("items".into(), Value::Object(nested(element)?));
+            match node.field("min_length").and_then(Node::number) {
+                Some(ref len) if len != &Value::from(0) => {
+                    schema.insert("minItems".into(), len.clone());
+                }
+                _ => {}
+            }
+            if node.some("from_scalar").is_some() {
+                schema = or_string(schema);
+            }
+        }
+        "Anything" => {}
+        "Nothing" => {
+            schema.insert("type".into(), "null".into());
+        }
+        name => return Err(format!("Unknown validator {:?}", name)),
+    }
+    Ok(schema)
+}
+
+/// Converts the validator into JSON Schema
+pub fn json_schema(validator: &Validator, title: &str)
+    -> Result<Value, String>
+{
+    let text = format!("{:?}", validator);
+    let mut parser = Parser { chars: text.chars().peekable() };
+    let node = parser.node()?;
+    let mut schema = Map::new();
+    schema.insert("$schema".into(), DRAFT.into());
+    schema.insert("title".into(), title.into());
+    schema.extend(convert(&node)?);
+    Ok(Value::Object(schema))
+}
+
+/// Returns JSON Schema of the config file
+///
+/// Fails if the debug output of some validator isn't understood (e.g. after
+/// an upgrade of quire).
+pub fn config_schema(kind: ConfigKind) -> Result<Value, String> {
+    match kind {
+        ConfigKind::Master => json_schema(&MasterConfig::validator(),
+            "Lithos master config"),
+        ConfigKind::Sandbox => json_schema(&SandboxConfig::validator(),
+            "Lithos sandbox config"),
+        ConfigKind::Child => json_schema(&ChildConfig::mapping_validator(),
+            "Lithos process config"),
+        ConfigKind::Container => json_schema(&ContainerConfig::validator(),
+            "Lithos container config"),
+    }.map_err(|e| format!("Can't make schema of {:?} config: {}", kind, e))
+}
+
+#[cfg(test)]
+mod test {
+    use quire::validate::{Structure, Scalar, Numeric, Enum, Nothing};
+    use super::{json_schema, config_schema, ConfigKind};
+
+    #[test]
+    fn structure() {
+        let validator = Structure::new()
+            .member("image", Scalar::new())
+            .member("max_wait", Numeric::new().min(0).default(300))
+            .member("kind", Enum::new().allow_plain()
+                .option("Daemon", Nothing)
+                .option("Command", Nothing));
+        let schema = json_schema(&validator, "test").unwrap();
+        assert_eq!(schema["required"], json!(["image", "kind"]));
+        let props = &schema["properties"];
+        assert_eq!(props["max-wait"]["minimum"], json!(0));
+        assert_eq!(props["max-wait"]["default"], json!(300));
+        assert_eq!(props["max_wait"], props["max-wait"]);
+        assert_eq!(props["kind"]["anyOf"][1]["x-yaml-tag"], json!("!Command"));
+        assert_eq!(props["kind"]["anyOf"][2]["enum"],
+                   json!(["Daemon", "Command"]));
+    }
+
+    #[test]
+    fn required_either_form() {
+        let validator = Structure::new()
+            .member("image_dir", Scalar::new());
+        let schema = json_schema(&validator, "test").unwrap();
+        assert!(schema.get("required").is_none());
+        assert_eq!(schema["allOf"], json!([{"anyOf": [
+            {"required": ["image-dir"]},
+            {"required": ["image_dir"]},
+        ]}]));
+    }
+
+    #[test]
+    fn configs() {
+        for kind in &[ConfigKind::Master, ConfigKind::Sandbox,
+                      ConfigKind::Child, ConfigKind::Container]
+        {
+            let schema = config_schema(*kind).unwrap();
+            assert!(schema["properties"].is_object() ||
+                    schema["additionalProperties"].is_object());
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use std::io;
-use std::io::{Write, stderr};
+use std::io::{Write, BufRead, BufReader, stderr};
+use std::fs::{File, remove_file, read_dir};
 use std::path::{Path};
+use std::process::Command;
 use std::time::SystemTime;
 
 use log;
@@ -11,16 +13,93 @@ use humantime::format_rfc3339_seconds;
 use super::master_config::MasterConfig;
 use super::utils::{clean_dir};
 use super::cgroup;
+use super::ipam;
+use super::reason::Reason;
 
 
 
-pub fn clean_child(name: &str, master: &MasterConfig, temporary: bool) {
-    let st_dir = master.runtime_dir
-        .join(&master.state_dir).join(name);
+/// Ruleset of `host-network-policy` written by knot into the state dir
+pub const NFTABLES_FILE: &str = "nftables.conf";
+
+/// Returns the nftables table of `host-network-policy` of the state dir
+///
+/// It's declared on the first line of the ruleset, as
+/// `table inet lithos_uid<uid>`.
+fn firewall_table(st_dir: &Path) -> Option<String> {
+    let mut line = String::new();
+    File::open(st_dir.join(NFTABLES_FILE))
+        .and_then(|f| BufReader::new(f).read_line(&mut line))
+        .ok()?;
+    let line = line.trim();
+    if line.starts_with("table inet lithos_uid") {
+        Some(line["table ".len()..].to_string())
+    } else {
+        None
+    }
+}
+
+/// Checks whether another process running as the same user uses the table
+///
+/// State dirs of the stopped processes are removed, so any other ruleset
+/// in the state dirs belongs to a process which is still alive.
+fn firewall_table_in_use(master: &MasterConfig, name: &str, table: &str)
+    -> bool
+{
+    let state_path = master.state_path();
+    let sandboxes = match read_dir(&state_path) {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    for sandbox in sandboxes.filter_map(|e| e.ok()) {
+        let children = match read_dir(sandbox.path()) {
+            Ok(dir) => dir,
+            Err(_) => continue,
+        };
+        for child in children.filter_map(|e| e.ok()) {
+            let path = child.path();
+            if path == state_path.join(name) {
+                continue;
+            }
+            if firewall_table(&path).as_ref().map(|x| &x[..]) == Some(table)
+            {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn remove_firewall_table(table: &str) -> Result<(), String> {
+    let status = Command::new("/usr/sbin/nft")
+        .arg("delete").arg("table").args(table.split(' '))
+        .status()
+        .map_err(|e| format!("can't run nft: {}", e))?;
+    if !status.success() {
+        return Err(format!("nft {}", status));
+    }
+    Ok(())
+}
+
+pub fn clean_child(name: &str, master: &MasterConfig, temporary: bool,
+    reason: Reason)
+{
+    debug!("Cleaning up {:?} (reason: {})", name, reason);
+    let st_dir = master.state_path().join(name);
+    // read before the state dir is removed
+    let table = firewall_table(&st_dir);
     clean_dir(&st_dir, true)
         .map_err(|e| error!("Error removing state dir for {}: {}", name, e))
         .ok();
+    if reason == Reason::Retired {
+        ipam::release(master, name);
+    }
     if !temporary {
+        let counters = master.knot_metrics_path().join(name);
+        remove_file(&counters)
+            .map_err(|e| if e.kind() != io::ErrorKind::NotFound {
+                error!("Error removing counters of {}: {}", name, e)
+            })
+            .ok();
         // If shutdown is temporary (i.e. process failed and we are going to
         // restart it shortly), we don't remove cgroups. Because removing
         // them triggers the following bug in the memory cgroup controller:
@@ -33,12 +112,26 @@ pub fn clean_child(name: &str, master: &MasterConfig, temporary: bool) {
         // Anyway it's possible that we don't need this in the new (unified)
         // cgroup hierarhy which is already there in 4.5, but we don't support
         // it yet.
-        if let Some(ref master_grp) = master.cgroup_name {
-            let cgname = name.replace("/", ":") + ".scope";
-            cgroup::remove_child_cgroup(&cgname, master_grp,
+        if let Some(ref master_grp) = master.cgroup_parent() {
+            cgroup::remove_child_cgroup(name, master_grp,
                                         &master.cgroup_controllers)
                 .map_err(|e| error!("Error removing cgroup: {}", e))
                 .ok();
+            // created only for processes having egress policy
+            cgroup::remove_unified_child(name, master_grp);
+        }
+        // the table is recreated on restart, so it's only removed when
+        // the process is stopped for good
+        if let Some(table) = table {
+            if firewall_table_in_use(master, name, &table) {
+                debug!("Table {} of {} is used by another process",
+                    table, name);
+            } else {
+                remove_firewall_table(&table)
+                    .map_err(|e| error!("Error removing nftables table \
+                        {} of {}: {}", table, name, e))
+                    .ok();
+            }
         }
     }
 }
@@ -82,3 +175,27 @@ pub fn init_logging(cfg: &MasterConfig, suffix: &Path, name: &str,
             .map_err(|e| format!("Can't initialize logging: {}", e))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::io::Write;
+    use super::firewall_table;
+
+    #[test]
+    fn table_of_state_dir() {
+        let dir = temp_dir().join("lithos-test-firewall-table");
+        create_dir_all(&dir).unwrap();
+        assert_eq!(firewall_table(&dir), None);
+        File::create(dir.join("nftables.conf")).unwrap()
+            .write_all(b"table inet lithos_uid1000\n\
+                         delete table inet lithos_uid1000\n").unwrap();
+        assert_eq!(firewall_table(&dir),
+                   Some("inet lithos_uid1000".to_string()));
+        File::create(dir.join("nftables.conf")).unwrap()
+            .write_all(b"flush ruleset\n").unwrap();
+        assert_eq!(firewall_table(&dir), None);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! Counters shared between `lithos_knot` and `lithos_tree`
+//!
+//! Each child has a small memory-mapped file in the runtime dir. Knot
+//! increments counters with atomic operations, tree reads them periodically
+//! and adds them to its own metrics. File outlives knot, so values are
+//! cumulative for the whole lifetime of the child.
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libc::{c_void, mmap, munmap, MAP_FAILED, MAP_SHARED};
+use libc::{PROT_READ, PROT_WRITE};
+
+
+const MAGIC: u64 = 0x314d_534f_4854_494c;  // "LITHOSM1"
+const SIZE: usize = 128;
+
+/// Counters maintained by `lithos_knot`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// Number of times process is started (including restarts inside knot)
+    Starts = 1,
+    /// Number of times process exited
+    Deaths = 2,
+    /// Number of times process exited abnormally
+    Failures = 3,
+    /// Number of times process is restarted by knot itself
+    /// (`restart-process-only`)
+    Restarts = 4,
+    /// Number of health checks run
+    HealthChecks = 5,
+    /// Number of failed health checks
+    HealthCheckFailures = 6,
+    /// Number of times process is stopped because of failed health checks
+    HealthRestarts = 7,
+    /// Gauge: `1` if the last health check failed, `0` otherwise
+    Unhealthy = 8,
+}
+
+/// Counters, values of all other slots are gauges
+pub const ALL_SLOTS: &[Slot] = &[
+    Slot::Starts, Slot::Deaths, Slot::Failures, Slot::Restarts,
+    Slot::HealthChecks, Slot::HealthCheckFailures, Slot::HealthRestarts,
+];
+
+pub struct SharedCounters {
+    ptr: *mut AtomicU64,
+}
+
+fn map(file: &File) -> Result<*mut AtomicU64, io::Error> {
+    let ptr = unsafe {
+        mmap(ptr::null_mut(), SIZE, PROT_READ | PROT_WRITE,
+            MAP_SHARED, file.as_raw_fd(), 0)
+    };
+    if ptr == MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ptr as *mut AtomicU64)
+}
+
+impl SharedCounters {
+    /// Opens (or creates) counters file, used by `lithos_knot`
+    pub fn create(path: &Path) -> Result<SharedCounters, io::Error> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() < SIZE as u64 {
+            file.set_len(SIZE as u64)?;
+        }
+        let counters = SharedCounters { ptr: map(&file)? };
+        if counters.slot(0).load(Ordering::SeqCst) != MAGIC {
+            for idx in 1..SIZE/8 {
+                counters.slot(idx).store(0, Ordering::SeqCst);
+            }
+            counters.slot(0).store(MAGIC, Ordering::SeqCst);
+        }
+        Ok(counters)
+    }
+    /// Opens existing counters file, used by `lithos_tree`
+    pub fn open(path: &Path) -> Result<SharedCounters, io::Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+        if len < 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "counters file is too small"));
+        } else if len < SIZE as u64 {
+            // written by an older knot, new slots are zero
+            file.set_len(SIZE as u64)?;
+        }
+        let counters = SharedCounters { ptr: map(&file)? };
+        if counters.slot(0).load(Ordering::SeqCst) != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "counters file is not initialized"));
+        }
+        Ok(counters)
+    }
+    fn slot(&self, idx: usize) -> &AtomicU64 {
+        assert!(idx < SIZE/8);
+        unsafe { &*self.ptr.add(idx) }
+    }
+    pub fn incr(&self, slot: Slot) {
+        self.slot(slot as usize).fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn set(&self, slot: Slot, value: u64) {
+        self.slot(slot as usize).store(value, Ordering::Relaxed);
+    }
+    pub fn get(&self, slot: Slot) -> u64 {
+        self.slot(slot as usize).load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SharedCounters {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr as *mut c_void, SIZE) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+    use std::process::id;
+    use super::{SharedCounters, Slot};
+
+    #[test]
+    fn shared() {
+        let path = temp_dir().join(format!("lithos-counters-{}", id()));
+        let knot = SharedCounters::create(&path).unwrap();
+        knot.incr(Slot::Starts);
+        knot.incr(Slot::Starts);
+        let tree = SharedCounters::open(&path).unwrap();
+        assert_eq!(tree.get(Slot::Starts), 2);
+        knot.incr(Slot::Deaths);
+        assert_eq!(tree.get(Slot::Deaths), 1);
+        assert_eq!(tree.get(Slot::Failures), 0);
+        knot.set(Slot::Unhealthy, 1);
+        assert_eq!(tree.get(Slot::Unhealthy), 1);
+        remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! Listening sockets of a stopped daemon lent to a command (`inherit-sockets`)
+//!
+//! While the command runs `lithos_cmd` holds a lock on
+//! `<runtime-dir>/socket-loans/<sandbox>.<child>.lock`, and receives sockets
+//! from `lithos_tree` via the control socket. `lithos_tree` lends sockets
+//! only while the lock is held and doesn't start the daemon until it's
+//! released, so the daemon and the command never accept on the same socket
+//! at once. The lock is inherited by `lithos_knot` running the command, so
+//! it's held until both exit, even if `lithos_cmd` is killed while the
+//! command still holds the sockets.
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::socket::{recvmsg, CmsgSpace, ControlMessage, MsgFlags};
+use nix::sys::uio::IoVec;
+
+use master_config::MasterConfig;
+
+
+/// Maximum number of sockets which can be lent at once
+pub const MAX_SOCKETS: usize = 64;
+/// How long `acquire` retries while `lithos_tree` checks the lock
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(1);
+const ACQUIRE_RETRY: Duration = Duration::from_millis(10);
+
+/// Sockets are lent to the command until dropped
+pub struct Loan {
+    lock: File,
+}
+
+impl Loan {
+    /// Returns a duplicate of the lock, to be inherited by `lithos_knot`
+    ///
+    /// The lock is held while any of the duplicates is open.
+    pub fn lock_file(&self) -> Result<File, String> {
+        self.lock.try_clone()
+            .map_err(|e| format!("Can't duplicate loan lock: {}", e))
+    }
+}
+
+fn lock_path(master: &MasterConfig, sandbox: &str, child: &str) -> PathBuf {
+    master.socket_loans_path().join(format!("{}.{}.lock", sandbox, child))
+}
+
+fn try_lock(path: &Path, arg: FlockArg) -> Result<Option<File>, io::Error> {
+    let file = OpenOptions::new()
+        .read(true).write(true).create(true).truncate(false)
+        .open(path)?;
+    match flock(file.as_raw_fd(), arg) {
+        Ok(()) => Ok(Some(file)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Takes the lock, fails if sockets are already lent to another command
+pub fn acquire(master: &MasterConfig, sandbox: &str, child: &str)
+    -> Result<Loan, String>
+{
+    let dir = master.socket_loans_path();
+    create_dir_all(&dir)
+        .map_err(|e| format!("Can't create dir {:?}: {}", dir, e))?;
+    let path = lock_path(master, sandbox, child);
+    let deadline = Instant::now() + ACQUIRE_TIMEOUT;
+    loop {
+        match try_lock(&path, FlockArg::LockExclusiveNonblock) {
+            Ok(Some(lock)) => return Ok(Loan { lock }),
+            // `is_lent` of lithos_tree holds a shared lock for a moment
+            Ok(None) if Instant::now() < deadline => sleep(ACQUIRE_RETRY),
+            Ok(None) => return Err(format!("Sockets of {}/{} are already \
+                lent to another command", sandbox, child)),
+            Err(e) => return Err(format!("Can't lock {:?}: {}", path, e)),
+        }
+    }
+}
+
+/// Returns true if a command holds the sockets of the child
+///
+/// Checked with a shared lock, which is released right away, so checks
+/// don't conflict with each other, and `acquire` retries on conflict.
+pub fn is_lent(master: &MasterConfig, sandbox: &str, child: &str) -> bool {
+    let path = lock_path(master, sandbox, child);
+    if !path.exists() {
+        return false;
+    }
+    match try_lock(&path, FlockArg::LockSharedNonblock) {
+        Ok(Some(_)) => false,
+        Ok(None) => true,
+        Err(e) => {
+            debug!("Can't lock {:?}: {}", path, e);
+            false
+        }
+    }
+}
+
+/// Parses `ok: 3 4` reply into target file descriptors
+fn parse_reply(reply: &str) -> Result<Vec<RawFd>, String> {
+    if reply.starts_with("ok: ") {
+        reply["ok: ".len()..].split_whitespace()
+            .map(|x| x.parse()
+                .map_err(|_| format!("Invalid reply {:?}", reply)))
+            .collect()
+    } else if reply.starts_with("error: ") {
+        Err(reply["error: ".len()..].trim().to_string())
+    } else {
+        Err(format!("Invalid reply {:?}", reply))
+    }
+}
+
+/// Receives sockets of the stopped daemon from `lithos_tree`
+///
+/// Returns pairs of (target fd, received fd). Must be called while the
+/// `Loan` is held.
+pub fn borrow(master: &MasterConfig, _loan: &Loan,
+    sandbox: &str, child: &str)
+    -> Result<Vec<(RawFd, RawFd)>, String>
+{
+    let path = master.control_socket_path()
+        .ok_or_else(|| format!("Control socket is disabled, \
+            it's required for inherit-sockets"))?;
+    let mut sock = UnixStream::connect(&path)
+        .map_err(|e| format!("Can't connect to {:?}: {}. \
+            Probably lithos_tree is not running", path, e))?;
+    sock.write_all(format!("borrow-sockets {} {}\n", sandbox, child)
+                   .as_bytes())
+        .map_err(|e| format!("Error sending command: {}", e))?;
+    // reply is a single message, so fds are received with the first read
+    let mut buf = [0u8; 1024];
+    let mut cmsg: CmsgSpace<[RawFd; MAX_SOCKETS]> = CmsgSpace::new();
+    let (bytes, fds) = {
+        let iov = [IoVec::from_mut_slice(&mut buf[..])];
+        let msg = recvmsg(sock.as_raw_fd(), &iov, Some(&mut cmsg),
+                          MsgFlags::empty())
+            .map_err(|e| format!("Error reading reply: {}", e))?;
+        let mut fds = Vec::new();
+        for item in msg.cmsgs() {
+            if let ControlMessage::ScmRights(received) = item {
+                fds.extend_from_slice(received);
+            }
+        }
+        (msg.bytes, fds)
+    };
+    let reply = String::from_utf8_lossy(&buf[..bytes]);
+    let targets = parse_reply(&reply)?;
+    if targets.len() != fds.len() {
+        return Err(format!("Expected {} sockets, received {}",
+            targets.len(), fds.len()));
+    }
+    Ok(targets.into_iter().zip(fds).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_reply;
+
+    #[test]
+    fn reply() {
+        assert_eq!(parse_reply("ok: 3 4\n").unwrap(), vec![3, 4]);
+        assert_eq!(parse_reply("ok: \n").unwrap(), Vec::<i32>::new());
+        assert_eq!(parse_reply("error: not stopped\n").unwrap_err(),
+                   "not stopped");
+        assert!(parse_reply("ok: x\n").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem::{size_of, zeroed};
+use std::os::unix::io::RawFd;
+
+use libc::{c_void, socklen_t, getsockopt, IPPROTO_TCP, TCP_INFO};
+
+
+// Only the head of the `struct tcp_info` from linux/tcp.h, the kernel
+// truncates the structure to the size we pass in
+#[repr(C)]
+#[allow(dead_code)]
+struct tcp_info {
+    tcpi_state: u8,
+    tcpi_ca_state: u8,
+    tcpi_retransmits: u8,
+    tcpi_probes: u8,
+    tcpi_backoff: u8,
+    tcpi_options: u8,
+    tcpi_wscale: u8,
+    tcpi_flags: u8,
+
+    tcpi_rto: u32,
+    tcpi_ato: u32,
+    tcpi_snd_mss: u32,
+    tcpi_rcv_mss: u32,
+
+    tcpi_unacked: u32,
+    tcpi_sacked: u32,
+}
+
+/// Accept queue of the listening socket
+pub struct ListenQueue {
+    /// Number of connections waiting for `accept()`
+    pub length: u32,
+    /// Backlog that kernel actually uses for this socket
+    pub backlog: u32,
+}
+
+/// Reads accept queue of the listening tcp socket
+///
+/// For listening sockets kernel reports current accept queue size in
+/// `tcpi_unacked` and the effective backlog in `tcpi_sacked`.
+pub fn listen_queue(fd: RawFd) -> Result<ListenQueue, io::Error> {
+    let mut info: tcp_info = unsafe { zeroed() };
+    let mut len = size_of::<tcp_info>() as socklen_t;
+    let rc = unsafe {
+        getsockopt(fd, IPPROTO_TCP, TCP_INFO,
+            &mut info as *mut tcp_info as *mut c_void, &mut len)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ListenQueue {
+        length: info.tcpi_unacked,
+        backlog: info.tcpi_sacked,
+    })
+}
+
+/// Returns `net.core.somaxconn`, the limit of the listen backlog
+///
+/// Kernel silently clamps the backlog passed to `listen()` to this value.
+pub fn somaxconn() -> Result<usize, io::Error> {
+    let mut buf = String::new();
+    File::open("/proc/sys/net/core/somaxconn")?.read_to_string(&mut buf)?;
+    buf.trim().parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+            "bad net.core.somaxconn value"))
+}
+
+/// Returns host-wide `ListenOverflows` counter from `/proc/net/netstat`
+pub fn listen_overflows() -> Result<u64, io::Error> {
+    let f = BufReader::new(File::open("/proc/net/netstat")?);
+    let mut lines = f.lines();
+    while let Some(header) = lines.next() {
+        let header = header?;
+        let values = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        if !header.starts_with("TcpExt:") {
+            continue;
+        }
+        let pair = header.split_whitespace().zip(values.split_whitespace());
+        for (name, value) in pair {
+            if name == "ListenOverflows" {
+                return value.parse()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+                        "bad ListenOverflows value"));
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound,
+        "no ListenOverflows in /proc/net/netstat"))
+}
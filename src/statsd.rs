@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use libcantal::{Collection, Visitor, Value, Name, NameVisitor};
+use libcantal::RawType;
+
+use master_config::StatsdConfig;
+
+
+const MAX_PACKET: usize = 1400;
+/// How often the host name is resolved again, to follow DNS changes
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Pushes metrics of the collection to the statsd daemon
+pub struct Statsd {
+    host: String,
+    port: u16,
+    prefix: String,
+    socket: Option<UdpSocket>,
+    /// Resolved address of the host and when it was resolved
+    target: Option<(SocketAddr, Instant)>,
+    counters: HashMap<String, u64>,
+}
+
+struct Collect<'a> {
+    prefix: &'a str,
+    items: Vec<(String, &'a Value)>,
+}
+
+struct NameBuilder {
+    parts: Vec<String>,
+    metric: Option<String>,
+}
+
+fn sanitize(part: &str) -> String {
+    part.chars().map(|c| match c {
+        ':' | '|' | '@' | '/' | ' ' | '(' | ')' => '_',
+        c => c,
+    }).collect()
+}
+
+impl NameVisitor for NameBuilder {
+    fn visit_pair(&mut self, key: &str, value: &str) {
+        match key {
+            "metric" => self.metric = Some(sanitize(value)),
+            // addresses and versions contain dots which are separators
+            // in statsd
+            "address" | "version" => {
+                self.parts.push(sanitize(&value.replace(".", "_")))
+            }
+            _ => self.parts.push(sanitize(value)),
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for Collect<'a> {
+    fn metric(&mut self, name: &Name, value: &'a Value) {
+        let mut builder = NameBuilder { parts: Vec::new(), metric: None };
+        name.visit(&mut builder);
+        let mut full = String::from(self.prefix);
+        for part in builder.parts.iter().chain(builder.metric.iter()) {
+            if !full.is_empty() {
+                full.push('.');
+            }
+            full.push_str(part);
+        }
+        self.items.push((full, value));
+    }
+}
+
+impl Statsd {
+    pub fn new(cfg: &StatsdConfig) -> Statsd {
+        Statsd {
+            host: cfg.host.clone(),
+            port: cfg.port,
+            prefix: cfg.prefix.clone(),
+            socket: None,
+            target: None,
+            counters: HashMap::new(),
+        }
+    }
+    fn format<C: Collection>(&mut self, coll: &C) -> Vec<String> {
+        let mut collect = Collect { prefix: &self.prefix, items: Vec::new() };
+        coll.visit(&mut collect);
+        let mut lines = Vec::with_capacity(collect.items.len());
+        for (name, value) in collect.items {
+            match value.raw_type() {
+                RawType::Counter => {
+                    // statsd counters are deltas since the last push
+                    let cur = value.to_string().parse::<u64>().unwrap_or(0);
+                    let old = self.counters.insert(name.clone(), cur);
+                    let delta = match old {
+                        Some(old) if old <= cur => cur - old,
+                        // first push or counter was reset
+                        _ => continue,
+                    };
+                    lines.push(format!("{}:{}|c", name, delta));
+                }
+                RawType::Level(_) => {
+                    lines.push(format!("{}:{}|g", name, value));
+                }
+                RawType::State => {}
+            }
+        }
+        lines
+    }
+    fn resolve(&self) -> Result<SocketAddr, io::Error> {
+        (&self.host[..], self.port).to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                format!("can't resolve {:?}", self.host)))
+    }
+    /// Returns address of the host, resolving it once per `RESOLVE_INTERVAL`
+    ///
+    /// If resolving fails, the previous address is used until the next
+    /// interval.
+    fn target(&mut self) -> Result<SocketAddr, io::Error> {
+        let now = Instant::now();
+        let old = match self.target {
+            Some((addr, time)) if time + RESOLVE_INTERVAL > now => {
+                return Ok(addr);
+            }
+            Some((addr, _)) => Some(addr),
+            None => None,
+        };
+        let addr = match (self.resolve(), old) {
+            (Ok(addr), _) => addr,
+            (Err(e), Some(addr)) => {
+                warn!("Can't resolve statsd host, using {}: {}", addr, e);
+                addr
+            }
+            (Err(e), None) => return Err(e),
+        };
+        if old.map(|x| x.is_ipv4() != addr.is_ipv4()).unwrap_or(false) {
+            // socket is bound to the address of the other family
+            self.socket = None;
+        }
+        self.target = Some((addr, now));
+        Ok(addr)
+    }
+    /// Sends all metrics of the collection, splitting them into packets
+    pub fn push<C: Collection>(&mut self, coll: &C) -> Result<(), io::Error> {
+        let lines = self.format(coll);
+        let target = self.target()?;
+        if self.socket.is_none() {
+            let bind = match target {
+                SocketAddr::V4(_) => "0.0.0.0:0",
+                SocketAddr::V6(_) => "[::]:0",
+            };
+            self.socket = Some(UdpSocket::bind(bind)?);
+        }
+        let socket = self.socket.as_ref().unwrap();
+        let mut buf = String::with_capacity(MAX_PACKET);
+        for line in lines {
+            if !buf.is_empty() && buf.len() + line.len() + 1 > MAX_PACKET {
+                socket.send_to(buf.as_bytes(), target)?;
+                buf.clear();
+            }
+            if !buf.is_empty() {
+                buf.push('\n');
+            }
+            buf.push_str(&line);
+        }
+        if !buf.is_empty() {
+            socket.send_to(buf.as_bytes(), target)?;
+        }
+        Ok(())
+    }
+}
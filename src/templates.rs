@@ -0,0 +1,100 @@
+//! Rendering of container configs from templates (`config-template`)
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use quire::{parse_string, Options};
+
+use child_config::{ChildConfig, InlineContainer};
+use container_config::ContainerConfig;
+use master_config::ContainerDefaults;
+
+
+/// Substitutes `{{ name }}` placeholders of the template by the values
+///
+/// Only plain variables are supported: no expressions, filters, loops or
+/// includes, so the template can't access anything except `values`.
+/// Unknown variables are errors.
+pub fn render(template: &str, values: &BTreeMap<String, String>)
+    -> Result<String, String>
+{
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let tail = &rest[start+2..];
+        let end = tail.find("}}")
+            .ok_or_else(|| format!("unclosed `{{{{` at byte {}",
+                template.len() - rest.len() + start))?;
+        let name = tail[..end].trim();
+        match values.get(name) {
+            Some(value) => result.push_str(value),
+            None => return Err(format!("unknown variable {:?}", name)),
+        }
+        rest = &tail[end+2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Renders `config-template` of the child into the embedded `container`
+///
+/// Relative template path is relative to `base` (the directory of the
+/// process config).
+pub fn render_child(child: &mut ChildConfig, base: &Path,
+    values: &BTreeMap<String, String>, defaults: &ContainerDefaults)
+    -> Result<(), String>
+{
+    let path = match child.config_template {
+        Some(ref path) => base.join(path),
+        None => return Ok(()),
+    };
+    if child.container.is_some() {
+        return Err(format!("Both `container` and `config-template` \
+            are specified"));
+    }
+    let mut template = String::with_capacity(4096);
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut template))
+        .map_err(|e| format!("Can't read template {:?}: {}", path, e))?;
+    let text = render(&template, values)
+        .map_err(|e| format!("Error rendering {:?}: {}", path, e))?;
+    let cfg: ContainerConfig = parse_string(&path.display().to_string(),
+            &text, &ContainerConfig::validator_with(defaults),
+            &Options::default())
+        .map_err(|e| format!("Error parsing rendered {:?}: {}", path, e))?;
+    child.container = Some(InlineContainer::new(&cfg)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use super::render;
+
+    fn values() -> BTreeMap<String, String> {
+        vec![
+            ("port".to_string(), "8080".to_string()),
+            ("env".to_string(), "prod".to_string()),
+        ].into_iter().collect()
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(render("a: b\n", &values()).unwrap(), "a: b\n");
+    }
+
+    #[test]
+    fn substitute() {
+        assert_eq!(render("port: {{port}}\nenv: {{ env }}-{{env}}",
+            &values()).unwrap(),
+            "port: 8080\nenv: prod-prod");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(render("{{ host }}", &values()).is_err());
+        assert!(render("port: {{ port", &values()).is_err());
+    }
+}
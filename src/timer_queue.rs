@@ -1,4 +1,5 @@
 use std::cmp::Ordering;
+use std::mem::take;
 use std::time::Instant;
 use std::collections::BinaryHeap;
 
@@ -62,6 +63,37 @@ impl<T> Queue<T> {
     {
         QueueIter { queue: self, max_time: max_time }
     }
+    /// Moves deadline of items matching the predicate to at most `deadline`
+    pub fn expedite<F: Fn(&T) -> bool>(&mut self, deadline: Instant, pred: F)
+    {
+        let items = take(&mut self.0).into_vec();
+        self.0 = items.into_iter().map(|mut item| {
+            if item.deadline > deadline && pred(&item.value) {
+                item.deadline = deadline;
+            }
+            item
+        }).collect();
+    }
+    /// Applies `f` to each item, deadlines are kept
+    pub fn update<F: FnMut(&mut T)>(&mut self, mut f: F) {
+        let items = take(&mut self.0).into_vec();
+        self.0 = items.into_iter().map(|mut item| {
+            f(&mut item.value);
+            item
+        }).collect();
+    }
+    /// Iterates over items in no particular order
+    pub fn iter(&self) -> impl Iterator<Item=&T> {
+        self.0.iter().map(|item| &item.value)
+    }
+    /// Removes and returns all items matching the predicate
+    pub fn extract<F: Fn(&T) -> bool>(&mut self, pred: F) -> Vec<T> {
+        let items = take(&mut self.0).into_vec();
+        let (matched, rest) = items.into_iter()
+            .partition::<Vec<_>, _>(|item| pred(&item.value));
+        self.0 = rest.into_iter().collect();
+        matched.into_iter().map(|item| item.value).collect()
+    }
     pub fn len(&self) -> usize {
         self.0.len()
     }
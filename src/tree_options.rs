@@ -2,13 +2,48 @@ use log;
 use std::env;
 use std::path::PathBuf;
 use std::io::{Write, stdout, stderr};
-use argparse::{ArgumentParser, Parse, StoreOption, StoreTrue, Print};
+use argparse::{Parse, ParseOption, StoreOption, StoreTrue};
+use argparse::{StoreFalse};
+use argparse::{Print, Collect};
 
+use cli;
+use version;
 
+
+#[derive(Clone)]
 pub struct Options {
     pub config_file: PathBuf,
     pub log_stderr: bool,
     pub log_level: Option<log::LogLevel>,
+    pub metrics_path: Option<PathBuf>,
+    pub daemonize: bool,
+    /// Sandboxes or `sandbox/child` pairs to run, empty means all
+    pub only: Vec<String>,
+    /// Sandboxes or `sandbox/child` pairs to skip
+    pub exclude: Vec<String>,
+    /// Don't restart recovered processes which config differs
+    pub ignore_config_drift: bool,
+}
+
+fn matches(filter: &[String], sandbox: &str, child: Option<&str>) -> bool {
+    filter.iter().any(|item| {
+        let mut pair = item.splitn(2, '/');
+        let sitem = pair.next().unwrap_or("");
+        match (pair.next(), child) {
+            (None, _) => sitem == sandbox,
+            (Some(citem), Some(child)) => sitem == sandbox && citem == child,
+            (Some(_), None) => false,
+        }
+    })
+}
+
+fn split_commas(items: Vec<String>) -> Vec<String> {
+    items.iter()
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
 }
 
 impl Options {
@@ -24,9 +59,14 @@ impl Options {
             config_file: PathBuf::from("/etc/lithos/master.yaml"),
             log_stderr: false,
             log_level: None,
+            metrics_path: None,
+            daemonize: false,
+            only: Vec::new(),
+            exclude: Vec::new(),
+            ignore_config_drift: false,
         };
         let parse_result = {
-            let mut ap = ArgumentParser::new();
+            let mut ap = cli::Parser::new();
             ap.set_description("Runs tree of processes");
             ap.refer(&mut options.config_file)
               .add_option(&["-C", "--config"], Parse,
@@ -39,14 +79,105 @@ impl Options {
             ap.refer(&mut options.log_level)
               .add_option(&["--log-level"], StoreOption,
                 "Set log level (default info for now)");
+            ap.refer(&mut options.metrics_path)
+              .add_option(&["--metrics-path"], ParseOption,
+                "Path to the metrics file (overrides CANTAL_PATH and \
+                 `metrics-path` from the config)")
+              .metavar("FILE");
+            ap.refer(&mut options.daemonize)
+              .add_option(&["--daemonize"], StoreTrue,
+                "Detach from the terminal and run in background")
+              .add_option(&["--foreground"], StoreFalse,
+                "Run in foreground (default)");
+            ap.refer(&mut options.only)
+              .add_option(&["--only"], Collect,
+                "Run only these sandboxes or `sandbox/child` processes \
+                 (comma-separated, may be repeated). Other processes \
+                 are stopped as if they were removed from the config")
+              .metavar("NAMES");
+            ap.refer(&mut options.exclude)
+              .add_option(&["--exclude"], Collect,
+                "Don't run these sandboxes or `sandbox/child` processes \
+                 (comma-separated, may be repeated)")
+              .metavar("NAMES");
+            ap.refer(&mut options.ignore_config_drift)
+              .add_option(&["--ignore-config-drift"], StoreTrue,
+                "Keep running processes which config differs from the \
+                 current one when recovering them after restart \
+                 (they get new config on next restart of the process)");
             ap.add_option(&["--version"],
-                Print(env!("CARGO_PKG_VERSION").to_string()),
+                Print(version::build_info()),
                 "Show version");
-            ap.parse(args, stdout, stderr)
+            cli::parse(&ap, args, stdout, stderr)
         };
+        if parse_result.is_ok() && options.daemonize
+            && !options.config_file.is_absolute()
+        {
+            writeln!(stderr,
+                "Config path must be absolute when using --daemonize").ok();
+            return Err(2);
+        }
+        options.only = split_commas(options.only);
+        options.exclude = split_commas(options.exclude);
         match parse_result {
             Ok(()) => Ok(options),
             Err(x) => Err(x),
         }
     }
+    /// Returns true if any process of the sandbox might be run
+    pub fn sandbox_selected(&self, sandbox: &str) -> bool {
+        let only = self.only.is_empty() || self.only.iter()
+            .any(|x| x == sandbox || x.starts_with(&format!("{}/", sandbox)));
+        only && !matches(&self.exclude, sandbox, None)
+    }
+    /// Returns true if the child of the sandbox should be run
+    pub fn child_selected(&self, sandbox: &str, child: &str) -> bool {
+        let only = self.only.is_empty() ||
+            matches(&self.only, sandbox, Some(child));
+        only && !matches(&self.exclude, sandbox, Some(child))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::sink;
+    use super::Options;
+
+    fn parse(args: &[&str]) -> Options {
+        let mut args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        args.insert(0, "lithos_tree".to_string());
+        Options::parse_specific_args(args, &mut sink(), &mut sink())
+            .unwrap_or_else(|code| panic!("can't parse options: {}", code))
+    }
+
+    #[test]
+    fn no_filter() {
+        let opt = parse(&[]);
+        assert!(opt.sandbox_selected("web"));
+        assert!(opt.child_selected("web", "worker"));
+    }
+
+    #[test]
+    fn only() {
+        let opt = parse(&["--only", "db,web/worker", "--only", "cache"]);
+        assert!(opt.sandbox_selected("db"));
+        assert!(opt.child_selected("db", "main"));
+        assert!(opt.sandbox_selected("web"));
+        assert!(opt.child_selected("web", "worker"));
+        assert!(!opt.child_selected("web", "frontend"));
+        assert!(opt.sandbox_selected("cache"));
+        assert!(!opt.sandbox_selected("webapp"));
+    }
+
+    #[test]
+    fn exclude() {
+        let opt = parse(&["--exclude", "db,web/worker"]);
+        assert!(!opt.sandbox_selected("db"));
+        assert!(opt.sandbox_selected("web"));
+        assert!(!opt.child_selected("web", "worker"));
+        assert!(opt.child_selected("web", "frontend"));
+        let opt = parse(&["--only", "web", "--exclude", "web/worker"]);
+        assert!(opt.child_selected("web", "frontend"));
+        assert!(!opt.child_selected("web", "worker"));
+    }
 }
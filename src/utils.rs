@@ -1,24 +1,29 @@
 use std::ptr;
 use std::io;
 use std::fs::{create_dir, remove_dir_all, read_dir, remove_file, remove_dir};
-use std::fs::{metadata};
+use std::fs::{metadata, File};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
-use std::path::Component::Normal;
+use std::path::Component::{Normal, RootDir, CurDir};
 use std::io::Error as IoError;
-use std::io::ErrorKind::{AlreadyExists, NotFound};
+use std::io::ErrorKind::{AlreadyExists, NotFound, InvalidInput};
 use std::ffi::CString;
 use std::env::current_dir;
 
 use nix::sys::signal::Signal;
 use nix::sys::signal::{SIGQUIT, SIGSEGV, SIGBUS, SIGHUP, SIGILL, SIGABRT};
 use nix::sys::signal::{SIGFPE, SIGUSR1, SIGUSR2};
+use libc;
 use libc::{c_int, c_char, timeval, c_void, mode_t, uid_t, gid_t};
 use libc::{chmod, chdir, chown};
+use quire::{parse_config, Options};
 use signal::trap::Trap;
 use range::Range;
 
 
 use super::id_map::IdMap;
+use super::container_config::ContainerConfig;
+use super::master_config::ContainerDefaults;
 
 pub type Time = f64;
 pub type SigNum = i32;
@@ -147,6 +152,37 @@ pub fn ensure_dir(dir: &Path) -> Result<(), String> {
     }
 }
 
+/// Reads container config `path` from the image at `root`
+///
+/// If there is no such file in the image, the `default` config (a path on
+/// the host) is read instead, if set. Settings missing in the config are
+/// taken from `container-defaults` of the master config.
+pub fn read_container_config(root: &Path, path: &Path, default: Option<&Path>,
+    defaults: &ContainerDefaults)
+    -> Result<ContainerConfig, String>
+{
+    let config = temporary_change_root(root, || {
+        if default.is_some() && !path.exists() {
+            return Ok(None);
+        }
+        parse_config(path, &ContainerConfig::validator_with(defaults),
+            &Options::default())
+        .map(Some)
+        .map_err(|e| e.to_string())
+    })?;
+    match (config, default) {
+        (Some(config), _) => Ok(config),
+        (None, Some(default)) => {
+            debug!("No {:?} in {:?}, using {:?}", path, root, default);
+            parse_config(default,
+                &ContainerConfig::validator_with(defaults),
+                &Options::default())
+            .map_err(|e| e.to_string())
+        }
+        (None, None) => unreachable!(),
+    }
+}
+
 pub fn clean_dir(dir: &Path, remove_dir_itself: bool) -> Result<(), String> {
     if let Err(e) = metadata(dir) {
         if e.kind() == NotFound {
@@ -247,6 +283,48 @@ pub fn relative(child: &Path, base: &Path) -> PathBuf {
     return res
 }
 
+/// Opens directory `path` relative to `root` without following symlinks
+///
+/// Each component is opened with `O_NOFOLLOW`, so a directory writable by
+/// the container can't be replaced by a symlink pointing outside of it.
+pub fn open_dir_nofollow(root: &Path, path: &Path) -> Result<File, IoError> {
+    let flags = libc::O_RDONLY|libc::O_DIRECTORY|libc::O_CLOEXEC;
+    let mut dir = open_at(libc::AT_FDCWD, root, flags)?;
+    for cmp in path.components() {
+        match cmp {
+            RootDir | CurDir => continue,
+            Normal(chunk) => {
+                dir = open_at(dir.as_raw_fd(), Path::new(chunk),
+                    flags|libc::O_NOFOLLOW)?;
+            }
+            _ => {
+                return Err(IoError::new(InvalidInput,
+                    format!("bad path {:?}", path)));
+            }
+        }
+    }
+    Ok(dir)
+}
+
+/// Creates (or truncates) file `name` in the directory opened as `dir`
+///
+/// Used for files written after the root of the process is changed.
+pub fn create_at(dir: &File, name: &str) -> Result<File, IoError> {
+    open_at(dir.as_raw_fd(), Path::new(name),
+        libc::O_WRONLY|libc::O_CREAT|libc::O_TRUNC|libc::O_NOFOLLOW|
+        libc::O_CLOEXEC)
+}
+
+fn open_at(dir: c_int, path: &Path, flags: c_int) -> Result<File, IoError> {
+    let fd = unsafe {
+        libc::openat(dir, cpath(path).as_ptr(), flags, 0o644 as mode_t)
+    };
+    if fd < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
 impl FsUidGuard {
     pub fn set(uid: u32, gid: u32) -> FsUidGuard {
         if uid != 0 || gid != 0 {
@@ -0,0 +1,104 @@
+//! Compatibility checks between `lithos_tree` and `lithos_knot` binaries
+use std::path::Path;
+use std::process::Command;
+
+use serde_json::{Value, from_str};
+
+
+/// Version of the current build
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short git hash of the build, empty when built outside of git checkout
+pub const GIT_HASH: &str = env!("LITHOS_GIT_HASH");
+
+/// Version with git hash, e.g. `0.19.0 (git 1a2b3c4)`
+///
+/// Printed by `--version`, so it must start with the plain version, as
+/// `check_knot_binary` parses it.
+pub fn build_info() -> String {
+    if GIT_HASH.is_empty() {
+        VERSION.to_string()
+    } else {
+        format!("{} (git {})", VERSION, GIT_HASH)
+    }
+}
+
+/// Version of the container config format passed via `lithos_knot --config`
+///
+/// Must be bumped on every incompatible change of `ChildInstance`:
+///
+/// * 2 -- `container` (inline container config) is added
+/// * 3 -- `max_runtime` is added
+pub const CONFIG_SCHEMA: u32 = 3;
+
+/// Exit code of `lithos_knot` when it can't understand config of the tree
+pub const EXIT_SCHEMA_MISMATCH: i32 = 4;
+
+fn major_minor(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Returns true if binary of `other` version can be used with ours
+///
+/// Versions are compatible if they have same major version, and for
+/// pre-1.0 versions the minor version must match too.
+pub fn is_compatible(other: &str) -> bool {
+    match (major_minor(VERSION), major_minor(other)) {
+        (Some((0, mine)), Some((0, theirs))) => mine == theirs,
+        (Some((mine, _)), Some((theirs, _))) => mine == theirs,
+        _ => false,
+    }
+}
+
+/// Runs `lithos_knot --version` and checks that it's compatible with us
+pub fn check_knot_binary(path: &Path) -> Result<(), String> {
+    let output = Command::new(path).arg("--version").output()
+        .map_err(|e| format!("Can't run {:?}: {}", path, e))?;
+    if !output.status.success() {
+        return Err(format!("{:?} --version failed: {}",
+            path, output.status));
+    }
+    let version = String::from_utf8_lossy(&output.stdout);
+    if !is_compatible(&version) {
+        return Err(format!("{:?} has version {:?} which is incompatible \
+            with lithos_tree {}", path, version.trim(), VERSION));
+    }
+    Ok(())
+}
+
+/// Checks schema version of the serialized `ChildInstance`
+///
+/// This is done before parsing the config, so the error is clear even if
+/// new config contains fields unknown to this version.
+pub fn check_config_schema(config: &str) -> Result<(), String> {
+    let value: Value = from_str(config)
+        .map_err(|e| format!("Can't parse config: {}", e))?;
+    let schema = match value.get("schema") {
+        None => 1,
+        Some(x) => x.as_u64()
+            .ok_or_else(|| format!("Bad config schema {}", x))?,
+    };
+    if schema != CONFIG_SCHEMA as u64 {
+        return Err(format!("Config schema {} is not supported \
+            by lithos_knot {} (schema {}), probably lithos_tree and \
+            lithos_knot binaries are from different releases",
+            schema, VERSION, CONFIG_SCHEMA));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::major_minor;
+
+    #[test]
+    fn parse() {
+        assert_eq!(major_minor("0.18.4\n"), Some((0, 18)));
+        assert_eq!(major_minor("1.2.0-beta"), Some((1, 2)));
+        assert_eq!(major_minor("0.19.0 (git 1a2b3c4)\n"), Some((0, 19)));
+        assert_eq!(major_minor("garbage"), None);
+    }
+}